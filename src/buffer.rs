@@ -0,0 +1,401 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Write};
+use std::slice;
+
+use libc::size_t;
+
+use crate::error::rustls_result;
+use crate::{ffi_panic_boundary, try_mut_from_ptr, try_ref_from_ptr, BoxCastPtr, CastPtr};
+use crate::{try_mut_slice, try_slice};
+
+/// A heap-allocated sequence of bytes, owned by rustls and handed to C. Read
+/// it with rustls_buffer_data and rustls_buffer_len, and free it with
+/// rustls_buffer_free when done. The contents never change, so the data
+/// pointer stays valid until the buffer is freed.
+pub struct rustls_buffer {
+    _private: [u8; 0],
+}
+
+impl CastPtr for rustls_buffer {
+    type RustType = Vec<u8>;
+}
+
+impl BoxCastPtr for rustls_buffer {}
+
+impl rustls_buffer {
+    /// Return a pointer to the first byte of the buffer. If the buffer is
+    /// empty, the pointer is non-NULL but must not be dereferenced. Returns
+    /// NULL if `buffer` is NULL.
+    #[no_mangle]
+    pub extern "C" fn rustls_buffer_data(buffer: *const rustls_buffer) -> *const u8 {
+        ffi_panic_boundary! {
+            let buffer: &Vec<u8> = try_ref_from_ptr!(buffer);
+            buffer.as_ptr()
+        }
+    }
+
+    /// Return the number of bytes in the buffer, or 0 if `buffer` is NULL.
+    #[no_mangle]
+    pub extern "C" fn rustls_buffer_len(buffer: *const rustls_buffer) -> size_t {
+        ffi_panic_boundary! {
+            let buffer: &Vec<u8> = try_ref_from_ptr!(buffer);
+            buffer.len()
+        }
+    }
+
+    /// Free a rustls_buffer. Calling with NULL is fine.
+    /// Must not be called twice with the same value.
+    #[no_mangle]
+    pub extern "C" fn rustls_buffer_free(buffer: *mut rustls_buffer) {
+        ffi_panic_boundary! {
+            BoxCastPtr::to_box(buffer);
+        }
+    }
+}
+
+/// A byte queue between a socket and a rustls_connection, owned by rustls and
+/// handed to C. Bytes pushed in come out of rustls_ring_buffer_pull in the
+/// same order. Its storage grows as needed, up to the capacity limit given
+/// to rustls_ring_buffer_new, and wraps around internally, so C never has to
+/// shuffle or resize it.
+///
+/// rustls_connection_read_tls_from_ring and
+/// rustls_connection_write_tls_to_ring move TLS bytes between a ring and a
+/// connection directly. This object is not safe for concurrent use.
+pub struct rustls_ring_buffer {
+    _private: [u8; 0],
+}
+
+pub(crate) struct RingBuffer {
+    data: VecDeque<u8>,
+    limit: usize,
+}
+
+impl RingBuffer {
+    pub(crate) fn new(limit: usize) -> RingBuffer {
+        RingBuffer {
+            data: VecDeque::new(),
+            limit,
+        }
+    }
+
+    /// Append as much of `buf` as fits under the limit, and return how much
+    /// that was.
+    pub(crate) fn push(&mut self, buf: &[u8]) -> usize {
+        let n = buf.len().min(self.limit - self.data.len());
+        if self.data.len() + n > self.data.capacity() {
+            // Double, as VecDeque would, but not past the limit.
+            let target = (self.data.capacity() * 2).max(self.data.len() + n);
+            self.data
+                .reserve_exact(target.min(self.limit) - self.data.len());
+        }
+        self.data.extend(&buf[..n]);
+        n
+    }
+
+    /// Move up to `buf.len()` bytes from the front into `buf`, and return
+    /// how many that was.
+    pub(crate) fn pull(&mut self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() {
+            let (front, _) = self.data.as_slices();
+            if front.is_empty() {
+                break;
+            }
+            let m = front.len().min(buf.len() - n);
+            buf[n..n + m].copy_from_slice(&front[..m]);
+            self.data.drain(..m);
+            n += m;
+        }
+        n
+    }
+
+    /// The bytes at the front, in one contiguous run: all of them unless the
+    /// storage has wrapped around.
+    pub(crate) fn front(&self) -> &[u8] {
+        self.data.as_slices().0
+    }
+
+    /// Drop the first `n` bytes, which must be no more than `len()`.
+    pub(crate) fn consume(&mut self, n: usize) {
+        self.data.drain(..n);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.capacity().min(self.limit)
+    }
+}
+
+/// Writing to a ring pushes what fits, and fails with WouldBlock when
+/// nothing does.
+impl Write for RingBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.push(buf) {
+            0 if !buf.is_empty() => Err(ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CastPtr for rustls_ring_buffer {
+    type RustType = RingBuffer;
+}
+
+impl BoxCastPtr for rustls_ring_buffer {}
+
+impl rustls_ring_buffer {
+    /// Create an empty ring buffer that will hold at most `limit` bytes. It
+    /// starts with no storage, and allocates more as bytes are pushed.
+    /// Free it with rustls_ring_buffer_free.
+    #[no_mangle]
+    pub extern "C" fn rustls_ring_buffer_new(limit: size_t) -> *mut rustls_ring_buffer {
+        ffi_panic_boundary! {
+            BoxCastPtr::to_mut_ptr(RingBuffer::new(limit))
+        }
+    }
+
+    /// Append up to `len` bytes from `buf` to the ring, and store how many
+    /// were taken in *out_n. Bytes go in as long as there is room under the
+    /// limit, so fewer than `len` may be taken. If none fit because the ring
+    /// is full, returns RUSTLS_RESULT_WOULD_BLOCK with *out_n set to 0: pull
+    /// some bytes out, then try again.
+    #[no_mangle]
+    pub extern "C" fn rustls_ring_buffer_push(
+        ring: *mut rustls_ring_buffer,
+        buf: *const u8,
+        len: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let ring: &mut RingBuffer = try_mut_from_ptr!(ring);
+            let buf: &[u8] = try_slice!(buf, len);
+            if out_n.is_null() {
+                return rustls_result::NullParameter
+            }
+            let n = ring.push(buf);
+            unsafe {
+                *out_n = n;
+            }
+            if n == 0 && !buf.is_empty() {
+                return rustls_result::WouldBlock;
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Move up to `len` bytes from the front of the ring into `buf`, and
+    /// store how many were moved in *out_n. That is 0 if the ring is empty.
+    #[no_mangle]
+    pub extern "C" fn rustls_ring_buffer_pull(
+        ring: *mut rustls_ring_buffer,
+        buf: *mut u8,
+        len: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let ring: &mut RingBuffer = try_mut_from_ptr!(ring);
+            let buf: &mut [u8] = try_mut_slice!(buf, len);
+            if out_n.is_null() {
+                return rustls_result::NullParameter
+            }
+            let n = ring.pull(buf);
+            unsafe {
+                *out_n = n;
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Return the number of bytes in the ring, or 0 if `ring` is NULL.
+    #[no_mangle]
+    pub extern "C" fn rustls_ring_buffer_len(ring: *const rustls_ring_buffer) -> size_t {
+        ffi_panic_boundary! {
+            let ring: &RingBuffer = try_ref_from_ptr!(ring);
+            ring.len()
+        }
+    }
+
+    /// Return how many bytes the ring can hold before it has to allocate
+    /// more storage. This grows as bytes are pushed, but never past the
+    /// limit given to rustls_ring_buffer_new. Returns 0 if `ring` is NULL.
+    #[no_mangle]
+    pub extern "C" fn rustls_ring_buffer_capacity(ring: *const rustls_ring_buffer) -> size_t {
+        ffi_panic_boundary! {
+            let ring: &RingBuffer = try_ref_from_ptr!(ring);
+            ring.capacity()
+        }
+    }
+
+    /// Free a rustls_ring_buffer, along with any bytes still in it. Calling
+    /// with NULL is fine. Must not be called twice with the same value.
+    #[no_mangle]
+    pub extern "C" fn rustls_ring_buffer_free(ring: *mut rustls_ring_buffer) {
+        ffi_panic_boundary! {
+            BoxCastPtr::to_box(ring);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::VecDeque;
+    use std::ptr::null;
+    use std::slice;
+
+    use super::*;
+
+    #[test]
+    fn test_buffer() {
+        let buffer = rustls_buffer::to_mut_ptr(b"hello".to_vec());
+        let data = rustls_buffer::rustls_buffer_data(buffer);
+        let len = rustls_buffer::rustls_buffer_len(buffer);
+        assert_eq!(unsafe { slice::from_raw_parts(data, len) }, b"hello");
+        rustls_buffer::rustls_buffer_free(buffer);
+
+        let empty = rustls_buffer::to_mut_ptr(Vec::new());
+        assert_ne!(rustls_buffer::rustls_buffer_data(empty), null());
+        assert_eq!(rustls_buffer::rustls_buffer_len(empty), 0);
+        rustls_buffer::rustls_buffer_free(empty);
+    }
+
+    // A small xorshift generator, so that the chunk sizes are arbitrary but
+    // the same on every run.
+    pub(crate) struct XorShift(pub(crate) u64);
+
+    impl XorShift {
+        pub(crate) fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn push(ring: *mut rustls_ring_buffer, data: &[u8]) -> (rustls_result, usize) {
+        let mut n = usize::MAX;
+        let result =
+            rustls_ring_buffer::rustls_ring_buffer_push(ring, data.as_ptr(), data.len(), &mut n);
+        (result, n)
+    }
+
+    fn pull(ring: *mut rustls_ring_buffer, buf: &mut [u8]) -> usize {
+        let mut n = usize::MAX;
+        let result =
+            rustls_ring_buffer::rustls_ring_buffer_pull(ring, buf.as_mut_ptr(), buf.len(), &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        n
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let ring = rustls_ring_buffer::rustls_ring_buffer_new(100);
+        assert_eq!(rustls_ring_buffer::rustls_ring_buffer_capacity(ring), 0);
+        assert_eq!(push(ring, b"hello"), (rustls_result::Ok, 5));
+        let capacity = rustls_ring_buffer::rustls_ring_buffer_capacity(ring);
+        assert!((5..=100).contains(&capacity));
+
+        // Filling up takes what fits, then asks to try again.
+        assert_eq!(push(ring, &[b'x'; 200]), (rustls_result::Ok, 95));
+        assert_eq!(rustls_ring_buffer::rustls_ring_buffer_len(ring), 100);
+        assert_eq!(rustls_ring_buffer::rustls_ring_buffer_capacity(ring), 100);
+        assert_eq!(push(ring, b"!"), (rustls_result::WouldBlock, 0));
+        assert_eq!(push(ring, b""), (rustls_result::Ok, 0));
+
+        let mut buf = [0u8; 8];
+        assert_eq!(pull(ring, &mut buf), 8);
+        assert_eq!(&buf, b"helloxxx");
+        assert_eq!(push(ring, b"!"), (rustls_result::Ok, 1));
+        let mut rest = [0u8; 200];
+        assert_eq!(pull(ring, &mut rest), 93);
+        assert_eq!(rest[92], b'!');
+        assert_eq!(pull(ring, &mut rest), 0);
+        rustls_ring_buffer::rustls_ring_buffer_free(ring);
+    }
+
+    #[test]
+    fn test_ring_buffer_random_chunks() {
+        // Checked against a plain queue, with the storage wrapping around
+        // many times.
+        let ring = rustls_ring_buffer::rustls_ring_buffer_new(1000);
+        let mut expected = VecDeque::new();
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        let mut next = 0u8;
+        let mut buf = [0u8; 700];
+        for _ in 0..5000 {
+            let chunk: Vec<u8> = (0..rng.below(700))
+                .map(|i| next.wrapping_add(i as u8))
+                .collect();
+            let (result, n) = push(ring, &chunk);
+            let room = 1000 - expected.len();
+            assert_eq!(n, chunk.len().min(room));
+            if room == 0 && !chunk.is_empty() {
+                assert_eq!(result, rustls_result::WouldBlock);
+            } else {
+                assert_eq!(result, rustls_result::Ok);
+            }
+            // The stream carries on from the first byte that didn't fit.
+            expected.extend(&chunk[..n]);
+            next = next.wrapping_add(n as u8);
+
+            let want = rng.below(700);
+            let n = pull(ring, &mut buf[..want]);
+            assert_eq!(n, want.min(expected.len()));
+            let pulled: Vec<u8> = expected.drain(..n).collect();
+            assert_eq!(&buf[..n], &pulled[..]);
+            assert_eq!(
+                rustls_ring_buffer::rustls_ring_buffer_len(ring),
+                expected.len()
+            );
+            assert!(rustls_ring_buffer::rustls_ring_buffer_capacity(ring) <= 1000);
+        }
+        rustls_ring_buffer::rustls_ring_buffer_free(ring);
+    }
+
+    #[test]
+    fn test_null_ring_buffer() {
+        let mut n = 0;
+        assert_eq!(rustls_ring_buffer::rustls_ring_buffer_len(null()), 0);
+        assert_eq!(rustls_ring_buffer::rustls_ring_buffer_capacity(null()), 0);
+        assert_eq!(
+            rustls_ring_buffer::rustls_ring_buffer_push(
+                std::ptr::null_mut(),
+                b"x".as_ptr(),
+                1,
+                &mut n
+            ),
+            rustls_result::NullParameter
+        );
+        let ring = rustls_ring_buffer::rustls_ring_buffer_new(10);
+        assert_eq!(
+            rustls_ring_buffer::rustls_ring_buffer_push(ring, null(), 1, &mut n),
+            rustls_result::NullParameter
+        );
+        let mut buf = [0u8; 1];
+        assert_eq!(
+            rustls_ring_buffer::rustls_ring_buffer_pull(
+                ring,
+                buf.as_mut_ptr(),
+                1,
+                std::ptr::null_mut()
+            ),
+            rustls_result::NullParameter
+        );
+        rustls_ring_buffer::rustls_ring_buffer_free(ring);
+        rustls_ring_buffer::rustls_ring_buffer_free(std::ptr::null_mut());
+    }
+
+    #[test]
+    fn test_null_buffer() {
+        assert_eq!(rustls_buffer::rustls_buffer_data(null()), null());
+        assert_eq!(rustls_buffer::rustls_buffer_len(null()), 0);
+        rustls_buffer::rustls_buffer_free(std::ptr::null_mut());
+    }
+}
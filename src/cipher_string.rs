@@ -0,0 +1,517 @@
+use std::ffi::CStr;
+
+use libc::{c_char, size_t};
+use rustls::{SupportedCipherSuite, ALL_CIPHER_SUITES};
+
+use crate::cipher::ciphersuite_from_id;
+use crate::error::rustls_result;
+use crate::ffi_panic_boundary;
+
+// A TLS 1.2 cipher suite rustls implements, as OpenSSL describes it.
+struct OpensslSuite {
+    id: u16,
+    name: &'static str,
+    // The aliases that select this suite, besides COMMON_ALIASES.
+    aliases: &'static [&'static str],
+    strength_bits: u16,
+}
+
+// Aliases that select every suite in SUITES: they are all high-strength
+// ECDHE suites with AEAD ciphers that need TLS 1.2.
+const COMMON_ALIASES: &[&str] = &[
+    "ALL", "DEFAULT", "HIGH", "TLSv1.2", "AEAD", "kECDHE", "kEECDH", "ECDHE", "EECDH", "ECDH",
+];
+
+// In the order OpenSSL lists them, which is the order suites are added in
+// unless the cipher string says otherwise.
+const SUITES: &[OpensslSuite] = &[
+    OpensslSuite {
+        id: 0xc02c,
+        name: "ECDHE-ECDSA-AES256-GCM-SHA384",
+        aliases: &["aECDSA", "ECDSA", "AES", "AES256", "AESGCM"],
+        strength_bits: 256,
+    },
+    OpensslSuite {
+        id: 0xc030,
+        name: "ECDHE-RSA-AES256-GCM-SHA384",
+        aliases: &["aRSA", "AES", "AES256", "AESGCM"],
+        strength_bits: 256,
+    },
+    OpensslSuite {
+        id: 0xcca9,
+        name: "ECDHE-ECDSA-CHACHA20-POLY1305",
+        aliases: &["aECDSA", "ECDSA", "CHACHA20"],
+        strength_bits: 256,
+    },
+    OpensslSuite {
+        id: 0xcca8,
+        name: "ECDHE-RSA-CHACHA20-POLY1305",
+        aliases: &["aRSA", "CHACHA20"],
+        strength_bits: 256,
+    },
+    OpensslSuite {
+        id: 0xc02b,
+        name: "ECDHE-ECDSA-AES128-GCM-SHA256",
+        aliases: &["aECDSA", "ECDSA", "AES", "AES128", "AESGCM"],
+        strength_bits: 128,
+    },
+    OpensslSuite {
+        id: 0xc02f,
+        name: "ECDHE-RSA-AES128-GCM-SHA256",
+        aliases: &["aRSA", "AES", "AES128", "AESGCM"],
+        strength_bits: 128,
+    },
+];
+
+impl OpensslSuite {
+    fn matches(&self, word: &str) -> bool {
+        word == self.name || COMMON_ALIASES.contains(&word) || self.aliases.contains(&word)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    // No prefix: append matching suites that aren't in the list yet.
+    Add,
+    // "-": take matching suites out of the list; later rules may add them back.
+    Delete,
+    // "!": take matching suites out of the list for good.
+    Kill,
+    // "+": move matching suites in the list to its end.
+    MoveToEnd,
+}
+
+// The state of the suites while a cipher string is applied: every suite not
+// yet killed, in order, and whether it is currently in the resulting list.
+struct SuiteList(Vec<(&'static OpensslSuite, bool)>);
+
+impl SuiteList {
+    fn apply(&mut self, op: Op, matches: impl Fn(&OpensslSuite) -> bool) {
+        let suites = std::mem::take(&mut self.0);
+        let (mut hit, mut rest): (Vec<_>, Vec<_>) =
+            suites.into_iter().partition(|&(suite, active)| {
+                matches(suite)
+                    && match op {
+                        Op::Add => !active,
+                        Op::Delete | Op::MoveToEnd => active,
+                        Op::Kill => true,
+                    }
+            });
+        self.0 = match op {
+            Op::Add | Op::MoveToEnd => {
+                hit.iter_mut().for_each(|entry| entry.1 = true);
+                rest.append(&mut hit);
+                rest
+            }
+            // Like OpenSSL, put deleted suites at the front, so re-adding
+            // them restores their relative order.
+            Op::Delete => {
+                hit.iter_mut().for_each(|entry| entry.1 = false);
+                hit.append(&mut rest);
+                hit
+            }
+            Op::Kill => rest,
+        };
+    }
+
+    // "@STRENGTH": sort the list by strength, strongest first, keeping the
+    // order of equally strong suites.
+    fn sort_by_strength(&mut self) {
+        let mut strengths: Vec<u16> = self.0.iter().map(|(s, _)| s.strength_bits).collect();
+        strengths.sort_unstable_by(|a, b| b.cmp(a));
+        strengths.dedup();
+        for bits in strengths {
+            self.apply(Op::MoveToEnd, |s| s.strength_bits == bits);
+        }
+    }
+}
+
+/// Resolve an OpenSSL cipher string, such as "ECDHE+AESGCM:!aNULL", to the
+/// TLS 1.2 suites it selects, in order. See
+/// rustls_ciphersuites_from_openssl_string for the supported syntax. Returns
+/// InvalidParameter if no suite is selected.
+fn tls12_suites_from_openssl_string(
+    spec: &str,
+) -> Result<Vec<SupportedCipherSuite>, rustls_result> {
+    let mut list = SuiteList(SUITES.iter().map(|suite| (suite, false)).collect());
+    let elements = spec
+        .split(|c| matches!(c, ':' | ',' | ' ' | ';'))
+        .filter(|e| !e.is_empty());
+    for element in elements {
+        if element == "@STRENGTH" {
+            list.sort_by_strength();
+            continue;
+        }
+        // Other commands, like @SECLEVEL, don't affect which suites rustls
+        // offers.
+        if element.starts_with('@') {
+            continue;
+        }
+        let (op, rule) = match element.as_bytes()[0] {
+            b'!' => (Op::Kill, &element[1..]),
+            b'-' => (Op::Delete, &element[1..]),
+            b'+' => (Op::MoveToEnd, &element[1..]),
+            _ => (Op::Add, element),
+        };
+        let words: Vec<&str> = rule.split('+').collect();
+        if words.iter().any(|w| w.is_empty()) {
+            continue;
+        }
+        list.apply(op, |suite| words.iter().all(|w| suite.matches(w)));
+    }
+    let suites: Vec<SupportedCipherSuite> = list
+        .0
+        .iter()
+        .filter(|&&(_, active)| active)
+        .filter_map(|(suite, _)| ciphersuite_from_id(suite.id))
+        .collect();
+    if suites.is_empty() {
+        return Err(rustls_result::InvalidParameter);
+    }
+    Ok(suites)
+}
+
+/// Resolve an OpenSSL cipher string to rustls cipher suites: every TLS 1.3
+/// suite, followed by the TLS 1.2 suites the string selects.
+pub(crate) fn suites_from_openssl_string(
+    spec: &str,
+) -> Result<Vec<SupportedCipherSuite>, rustls_result> {
+    let tls12 = tls12_suites_from_openssl_string(spec)?;
+    let mut suites: Vec<SupportedCipherSuite> = ALL_CIPHER_SUITES
+        .iter()
+        .copied()
+        .filter(|cs| matches!(cs, SupportedCipherSuite::Tls13(_)))
+        .collect();
+    suites.extend(tls12);
+    Ok(suites)
+}
+
+/// Translate an OpenSSL cipher string, as accepted by
+/// `SSL_CTX_set_cipher_list`, into the IANA identifiers of the rustls cipher
+/// suites it selects, and write them to `out_ids` in preference order.
+/// *out_n is set to the number of identifiers written.
+///
+/// Elements are separated by ':' (or ',', ';' or spaces). An element is a
+/// cipher name like "ECDHE-RSA-AES128-GCM-SHA256", an alias like "HIGH",
+/// "aRSA" or "AESGCM", or several of those joined with '+' to select only
+/// suites matching all of them. A plain element adds the suites it selects,
+/// a '-' prefix removes them (later elements may add them back), '!' removes
+/// them for good, and '+' moves them to the end. "@STRENGTH" sorts suites by
+/// key length. As in OpenSSL, unknown names and aliases select nothing and
+/// are otherwise ignored, as are other '@' commands.
+///
+/// As in OpenSSL, the cipher string only chooses among TLS 1.2 suites. All
+/// TLS 1.3 suites come first in the output, since OpenSSL enables them
+/// regardless of the cipher string.
+///
+/// Returns RUSTLS_RESULT_INVALID_PARAMETER if `spec` is not UTF-8 or selects
+/// no TLS 1.2 suite, and RUSTLS_RESULT_INSUFFICIENT_SIZE if more than `max`
+/// suites are selected. On error, *out_n is set to 0.
+#[no_mangle]
+pub extern "C" fn rustls_ciphersuites_from_openssl_string(
+    spec: *const c_char,
+    out_ids: *mut u16,
+    max: size_t,
+    out_n: *mut size_t,
+) -> rustls_result {
+    ffi_panic_boundary! {
+        if spec.is_null() || out_ids.is_null() || out_n.is_null() {
+            return rustls_result::NullParameter;
+        }
+        let spec: &CStr = unsafe { CStr::from_ptr(spec) };
+        let suites = match spec.to_str() {
+            Ok(spec) => suites_from_openssl_string(spec),
+            Err(_) => Err(rustls_result::InvalidParameter),
+        };
+        let suites = match suites {
+            Ok(suites) if suites.len() > max => Err(rustls_result::InsufficientSize),
+            suites => suites,
+        };
+        match suites {
+            Ok(suites) => {
+                for (i, cs) in suites.iter().enumerate() {
+                    unsafe {
+                        *out_ids.add(i) = cs.suite().get_u16();
+                    }
+                }
+                unsafe {
+                    *out_n = suites.len();
+                }
+                rustls_result::Ok
+            }
+            Err(e) => {
+                unsafe {
+                    *out_n = 0;
+                }
+                e
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The OpenSSL names of the TLS 1.2 suites `spec` selects.
+    fn names(spec: &str) -> Vec<&'static str> {
+        tls12_suites_from_openssl_string(spec)
+            .unwrap()
+            .iter()
+            .map(|cs| {
+                let id = cs.suite().get_u16();
+                SUITES.iter().find(|s| s.id == id).unwrap().name
+            })
+            .collect()
+    }
+
+    const ALL_TLS12: &[&str] = &[
+        "ECDHE-ECDSA-AES256-GCM-SHA384",
+        "ECDHE-RSA-AES256-GCM-SHA384",
+        "ECDHE-ECDSA-CHACHA20-POLY1305",
+        "ECDHE-RSA-CHACHA20-POLY1305",
+        "ECDHE-ECDSA-AES128-GCM-SHA256",
+        "ECDHE-RSA-AES128-GCM-SHA256",
+    ];
+
+    #[test]
+    fn test_suites_match_rustls() {
+        for suite in SUITES {
+            let cs = ciphersuite_from_id(suite.id).unwrap();
+            assert!(matches!(cs, SupportedCipherSuite::Tls12(_)));
+        }
+        let tls12 = ALL_CIPHER_SUITES
+            .iter()
+            .filter(|cs| matches!(cs, SupportedCipherSuite::Tls12(_)))
+            .count();
+        assert_eq!(tls12, SUITES.len());
+    }
+
+    #[test]
+    fn test_broad_aliases() {
+        for spec in &[
+            "ALL",
+            "DEFAULT",
+            "HIGH",
+            "HIGH:!aNULL:!MD5",
+            "TLSv1.2",
+            "kEECDH",
+        ] {
+            assert_eq!(names(spec), ALL_TLS12, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_mozilla_intermediate() {
+        let spec = "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+                    ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+                    ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+                    DHE-RSA-AES128-GCM-SHA256:DHE-RSA-AES256-GCM-SHA384";
+        assert_eq!(
+            names(spec),
+            vec![
+                "ECDHE-ECDSA-AES128-GCM-SHA256",
+                "ECDHE-RSA-AES128-GCM-SHA256",
+                "ECDHE-ECDSA-AES256-GCM-SHA384",
+                "ECDHE-RSA-AES256-GCM-SHA384",
+                "ECDHE-ECDSA-CHACHA20-POLY1305",
+                "ECDHE-RSA-CHACHA20-POLY1305",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mozilla_old() {
+        let spec = "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+                    ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+                    ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+                    DHE-RSA-AES128-GCM-SHA256:DHE-RSA-AES256-GCM-SHA384:\
+                    DHE-RSA-CHACHA20-POLY1305:ECDHE-ECDSA-AES128-SHA256:\
+                    ECDHE-RSA-AES128-SHA256:ECDHE-ECDSA-AES128-SHA:ECDHE-RSA-AES128-SHA:\
+                    ECDHE-ECDSA-AES256-SHA384:ECDHE-RSA-AES256-SHA384:ECDHE-ECDSA-AES256-SHA:\
+                    ECDHE-RSA-AES256-SHA:DHE-RSA-AES128-SHA256:DHE-RSA-AES256-SHA256:\
+                    AES128-GCM-SHA256:AES256-GCM-SHA384:AES128-SHA256:AES256-SHA256:\
+                    AES128-SHA:AES256-SHA:DES-CBC3-SHA";
+        assert_eq!(names(spec), names("AES128:AES256:CHACHA20"));
+        assert_eq!(
+            names(spec),
+            vec![
+                "ECDHE-ECDSA-AES128-GCM-SHA256",
+                "ECDHE-RSA-AES128-GCM-SHA256",
+                "ECDHE-ECDSA-AES256-GCM-SHA384",
+                "ECDHE-RSA-AES256-GCM-SHA384",
+                "ECDHE-ECDSA-CHACHA20-POLY1305",
+                "ECDHE-RSA-CHACHA20-POLY1305",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_haproxy() {
+        // The classic HAProxy recommendation.
+        let spec = "ECDH+AESGCM:DH+AESGCM:ECDH+AES256:DH+AES256:ECDH+AES128:DH+AES:\
+                    RSA+AESGCM:RSA+AES:!aNULL:!MD5:!DSS";
+        let expected = vec![
+            "ECDHE-ECDSA-AES256-GCM-SHA384",
+            "ECDHE-RSA-AES256-GCM-SHA384",
+            "ECDHE-ECDSA-AES128-GCM-SHA256",
+            "ECDHE-RSA-AES128-GCM-SHA256",
+        ];
+        assert_eq!(names(spec), expected);
+        assert_eq!(names("ECDHE+AESGCM:!aNULL:!SHA1"), expected);
+        assert_eq!(
+            names("ECDHE+AESGCM:ECDHE+CHACHA20"),
+            vec![
+                "ECDHE-ECDSA-AES256-GCM-SHA384",
+                "ECDHE-RSA-AES256-GCM-SHA384",
+                "ECDHE-ECDSA-AES128-GCM-SHA256",
+                "ECDHE-RSA-AES128-GCM-SHA256",
+                "ECDHE-ECDSA-CHACHA20-POLY1305",
+                "ECDHE-RSA-CHACHA20-POLY1305",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_narrow_aliases() {
+        assert_eq!(
+            names("aECDSA"),
+            vec![
+                "ECDHE-ECDSA-AES256-GCM-SHA384",
+                "ECDHE-ECDSA-CHACHA20-POLY1305",
+                "ECDHE-ECDSA-AES128-GCM-SHA256",
+            ]
+        );
+        assert_eq!(names("aRSA+AES128"), vec!["ECDHE-RSA-AES128-GCM-SHA256"]);
+        assert_eq!(
+            names("ECDSA+AES256:CHACHA20+aRSA"),
+            vec![
+                "ECDHE-ECDSA-AES256-GCM-SHA384",
+                "ECDHE-RSA-CHACHA20-POLY1305",
+            ]
+        );
+        // "RSA" is RSA key exchange, which rustls doesn't implement.
+        assert_eq!(names("ALL:!RSA"), ALL_TLS12);
+        // Aliases are case sensitive, as in OpenSSL.
+        assert_eq!(names("ALL:!arsa"), ALL_TLS12);
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(
+            names("ALL:+CHACHA20"),
+            vec![
+                "ECDHE-ECDSA-AES256-GCM-SHA384",
+                "ECDHE-RSA-AES256-GCM-SHA384",
+                "ECDHE-ECDSA-AES128-GCM-SHA256",
+                "ECDHE-RSA-AES128-GCM-SHA256",
+                "ECDHE-ECDSA-CHACHA20-POLY1305",
+                "ECDHE-RSA-CHACHA20-POLY1305",
+            ]
+        );
+        // Deleted suites can be added back, at the end.
+        assert_eq!(
+            names("ALL:-aECDSA:aECDSA+AES"),
+            vec![
+                "ECDHE-RSA-AES256-GCM-SHA384",
+                "ECDHE-RSA-CHACHA20-POLY1305",
+                "ECDHE-RSA-AES128-GCM-SHA256",
+                "ECDHE-ECDSA-AES256-GCM-SHA384",
+                "ECDHE-ECDSA-AES128-GCM-SHA256",
+            ]
+        );
+        // Killed suites can't.
+        assert_eq!(
+            names("ALL:!aECDSA:aECDSA"),
+            vec![
+                "ECDHE-RSA-AES256-GCM-SHA384",
+                "ECDHE-RSA-CHACHA20-POLY1305",
+                "ECDHE-RSA-AES128-GCM-SHA256",
+            ]
+        );
+        // Adding suites that are already listed doesn't move them.
+        assert_eq!(names("AES128:ALL")[..2], ALL_TLS12[4..]);
+        // "+" only moves suites that are listed.
+        assert_eq!(names("AES128:+AES256"), &ALL_TLS12[4..]);
+        assert_eq!(
+            names("AES128:AES256:@STRENGTH"),
+            vec![
+                "ECDHE-ECDSA-AES256-GCM-SHA384",
+                "ECDHE-RSA-AES256-GCM-SHA384",
+                "ECDHE-ECDSA-AES128-GCM-SHA256",
+                "ECDHE-RSA-AES128-GCM-SHA256",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_syntax() {
+        assert_eq!(
+            names("AES128 AES256,CHACHA20;@SECLEVEL=2"),
+            names("AES128:AES256:CHACHA20")
+        );
+        assert_eq!(names("::ALL::"), ALL_TLS12);
+        assert_eq!(names("ALL:+:!:ECDHE+"), ALL_TLS12);
+        // An unknown word makes its whole element select nothing.
+        assert_eq!(names("ALL:!aRSA+BOGUS"), ALL_TLS12);
+    }
+
+    #[test]
+    fn test_no_suites() {
+        for spec in &[
+            "",
+            "RC4:MD5",
+            "nonsense",
+            "ALL:!ALL",
+            "aNULL",
+            "TLS_AES_128_GCM_SHA256",
+        ] {
+            assert_eq!(
+                tls12_suites_from_openssl_string(spec).err(),
+                Some(rustls_result::InvalidParameter),
+                "{}",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn test_rustls_ciphersuites_from_openssl_string() {
+        let mut ids = [0u16; 16];
+        let mut n = 0;
+        let result = rustls_ciphersuites_from_openssl_string(
+            "ECDHE+AESGCM:!aECDSA\0".as_ptr() as *const c_char,
+            ids.as_mut_ptr(),
+            ids.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(&ids[..n], &[0x1302, 0x1301, 0x1303, 0xc030, 0xc02f]);
+
+        let result = rustls_ciphersuites_from_openssl_string(
+            "ECDHE+AESGCM:!aECDSA\0".as_ptr() as *const c_char,
+            ids.as_mut_ptr(),
+            4,
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::InsufficientSize);
+        assert_eq!(n, 0);
+
+        let result = rustls_ciphersuites_from_openssl_string(
+            "RC4\0".as_ptr() as *const c_char,
+            ids.as_mut_ptr(),
+            ids.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::InvalidParameter);
+        assert_eq!(n, 0);
+
+        let result = rustls_ciphersuites_from_openssl_string(
+            std::ptr::null(),
+            ids.as_mut_ptr(),
+            ids.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::NullParameter);
+    }
+}
@@ -1,28 +1,51 @@
 use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::convert::{TryFrom, TryInto};
 use std::ffi::{CStr, OsStr};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, ErrorKind, Write};
+use std::mem::size_of;
+use std::net::IpAddr;
+use std::ptr::{self, null, null_mut};
 use std::slice;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
 
-use libc::{c_char, size_t};
-use rustls::client::{ResolvesClientCert, ServerCertVerified, ServerCertVerifier};
+#[cfg(debug_assertions)]
+use once_cell::sync::Lazy;
+
+use libc::{c_char, c_void, size_t};
+use rustls::client::{
+    ClientSessionMemoryCache, ClientSessionStore, HandshakeSignatureValid, ResolvesClientCert,
+    Resumption, ServerCertVerified, ServerCertVerifier, Tls12ClientSessionValue, Tls12Resumption,
+    Tls13ClientSessionValue, WebPkiVerifier,
+};
 use rustls::{
     sign::CertifiedKey, Certificate, CertificateError, ClientConfig, ClientConnection,
-    ProtocolVersion, RootCertStore, SupportedCipherSuite, WantsVerifier, ALL_CIPHER_SUITES,
+    DigitallySignedStruct, NamedGroup, RootCertStore, SupportedCipherSuite,
+    SupportedProtocolVersion, WantsVerifier, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES,
+    DEFAULT_VERSIONS,
 };
 
-use crate::cipher::{rustls_certified_key, rustls_root_cert_store, rustls_supported_ciphersuite};
+use crate::cipher::{
+    certified_key_from_der, certified_key_matches, chain_verifies, ciphersuite_from_id,
+    ciphersuite_from_name, rustls_certified_key, rustls_root_cert_store,
+    rustls_supported_ciphersuite, spki_der, spki_public_key, spki_sha256, tls_server_end_point,
+    verified_path, RootStore, Roots, RootsVerifier, VerifiedChain,
+};
+use crate::cipher_string::suites_from_openssl_string;
 use crate::connection::{rustls_connection, Connection};
+use crate::enums::protocol_versions_from_u16s;
 use crate::error::rustls_result::{InvalidParameter, NullParameter};
-use crate::error::{self, rustls_result};
+use crate::error::{self, rustls_result, LocalCertError};
+use crate::keylog::{rustls_keylog_log_callback, CallbackKeyLog, FileKeyLog, SSLKEYLOGFILE};
 use crate::rslice::NulByte;
-use crate::rslice::{rustls_slice_bytes, rustls_slice_slice_bytes, rustls_str};
+use crate::rslice::{rustls_slice_bytes, rustls_slice_slice_bytes, rustls_slice_u16, rustls_str};
 use crate::{
-    ffi_panic_boundary, try_arc_from_ptr, try_box_from_ptr, try_mut_from_ptr, try_ref_from_ptr,
-    try_slice, userdata_get, ArcCastPtr, BoxCastPtr, CastConstPtr, CastPtr,
+    cert_verified_set, ffi_panic_boundary, os_str_from_bytes, try_arc_from_ptr, try_box_from_ptr,
+    try_mut_from_ptr, try_mut_slice, try_ref_from_ptr, try_slice, userdata_get, userdata_push,
+    verified_chain_set, verified_chain_take, verify_name_get, ArcCastPtr, BoxCastPtr, CastConstPtr,
+    CastPtr,
 };
 
 /// A client config being constructed. A builder can be modified by,
@@ -30,7 +53,7 @@ use crate::{
 /// done configuring settings, call rustls_client_config_builder_build
 /// to turn it into a *rustls_client_config. This object is not safe
 /// for concurrent mutation. Under the hood, it corresponds to a
-/// `Box<ClientConfig>`.
+/// `Box` holding the settings until they are built into a `ClientConfig`.
 /// <https://docs.rs/rustls/latest/rustls/struct.ConfigBuilder.html>
 pub struct rustls_client_config_builder {
     // We use the opaque struct pattern to tell C about our types without
@@ -39,12 +62,37 @@ pub struct rustls_client_config_builder {
     _private: [u8; 0],
 }
 
+#[derive(Clone)]
 pub(crate) struct ClientConfigBuilder {
-    base: rustls::ConfigBuilder<ClientConfig, WantsVerifier>,
+    cipher_suites: Vec<SupportedCipherSuite>,
+    versions: Vec<&'static SupportedProtocolVersion>,
     verifier: Arc<dyn ServerCertVerifier>,
+    // How many trusted roots `verifier` checks against, if it uses any.
+    root_count: usize,
     alpn_protocols: Vec<Vec<u8>>,
     enable_sni: bool,
     cert_resolver: Option<Arc<dyn rustls::client::ResolvesClientCert>>,
+    spki_pins: Option<SpkiPins>,
+    skip_hostname_verification: bool,
+    verification_time: Option<SystemTime>,
+    // None disables resumption.
+    session_store: Option<Arc<dyn ClientSessionStore>>,
+    enable_tickets: bool,
+    enable_early_data: bool,
+    max_fragment_size: Option<usize>,
+    key_log: Option<KeyLogSetting>,
+    ct_policy: Option<CtPolicy>,
+    // Only the usable records; empty if DANE is off.
+    tlsa_records: Vec<TlsaRecord>,
+    userdata: *mut c_void,
+}
+
+// A callback's userdata is only settled at build time, since it defaults to
+// the config userdata.
+#[derive(Clone)]
+enum KeyLogSetting {
+    Log(Arc<dyn rustls::KeyLog>),
+    Callback(CallbackKeyLog),
 }
 
 impl CastPtr for rustls_client_config_builder {
@@ -53,8 +101,50 @@ impl CastPtr for rustls_client_config_builder {
 
 impl BoxCastPtr for rustls_client_config_builder {}
 
+impl ClientConfigBuilder {
+    fn new(
+        cipher_suites: Vec<SupportedCipherSuite>,
+        versions: Vec<&'static SupportedProtocolVersion>,
+    ) -> Self {
+        ClientConfigBuilder {
+            cipher_suites,
+            versions,
+            verifier: Arc::new(NoneVerifier),
+            root_count: 0,
+            cert_resolver: None,
+            alpn_protocols: vec![],
+            enable_sni: true,
+            spki_pins: None,
+            skip_hostname_verification: false,
+            verification_time: None,
+            session_store: Some(Arc::new(ClientSessionMemoryCache::new(256))),
+            enable_tickets: true,
+            enable_early_data: false,
+            max_fragment_size: None,
+            key_log: None,
+            ct_policy: None,
+            tlsa_records: vec![],
+            userdata: null_mut(),
+        }
+    }
+
+    /// Check that `cipher_suites` and `versions` can be combined into a
+    /// config, and return the corresponding rustls builder.
+    fn base(
+        cipher_suites: &[SupportedCipherSuite],
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> Result<rustls::ConfigBuilder<ClientConfig, WantsVerifier>, rustls_result> {
+        rustls::ClientConfig::builder()
+            .with_cipher_suites(cipher_suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .map_err(|_| InvalidParameter)
+    }
+}
+
 /// A client config that is done being constructed and is now read-only.
-/// Under the hood, this object corresponds to an `Arc<ClientConfig>`.
+/// Under the hood, this object corresponds to an `Arc` holding an
+/// `Arc<ClientConfig>` and the config userdata.
 /// <https://docs.rs/rustls/latest/rustls/struct.ClientConfig.html>
 pub struct rustls_client_config {
     // We use the opaque struct pattern to tell C about our types without
@@ -63,12 +153,47 @@ pub struct rustls_client_config {
     _private: [u8; 0],
 }
 
+/// What a rustls_client_config points to: the rustls config, and the
+/// settings connections take from it that rustls doesn't know about.
+pub(crate) struct BuiltClientConfig {
+    config: Arc<ClientConfig>,
+    userdata: *mut c_void,
+    // Kept for introspection, since rustls doesn't expose them.
+    versions: Vec<&'static SupportedProtocolVersion>,
+    cipher_suites: Vec<SupportedCipherSuite>,
+    root_count: usize,
+}
+
 impl CastConstPtr for rustls_client_config {
-    type RustType = ClientConfig;
+    type RustType = BuiltClientConfig;
 }
 
 impl ArcCastPtr for rustls_client_config {}
 
+/// In debug builds, how many references C holds to each live
+/// rustls_client_config, by address. This is kept apart from the config so
+/// that rustls_client_config_free can catch a reference C doesn't hold
+/// without reading memory that may already have been freed.
+#[cfg(debug_assertions)]
+static CONFIG_C_REFS: Lazy<Mutex<HashMap<usize, usize>>> = Lazy::new(Default::default);
+
+#[cfg(debug_assertions)]
+fn config_c_refs() -> MutexGuard<'static, HashMap<usize, usize>> {
+    // Each edit of the map completes or never starts, so a panic while the
+    // lock was held leaves it consistent.
+    CONFIG_C_REFS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Hand a newly built config to C, which holds the one reference to it.
+fn config_to_c(config: BuiltClientConfig) -> *const rustls_client_config {
+    let ptr = ArcCastPtr::to_const_ptr(config);
+    #[cfg(debug_assertions)]
+    config_c_refs().insert(ptr as usize, 1);
+    ptr
+}
+
 struct NoneVerifier;
 
 impl ServerCertVerifier for NoneVerifier {
@@ -87,514 +212,3794 @@ impl ServerCertVerifier for NoneVerifier {
     }
 }
 
-impl rustls_client_config_builder {
-    /// Create a rustls_client_config_builder. Caller owns the memory and must
-    /// eventually call rustls_client_config_builder_build, then free the
-    /// resulting rustls_client_config.
-    /// This uses rustls safe default values
-    /// for the cipher suites, key exchange groups and protocol versions.
-    /// This starts out with no trusted roots.
-    /// Caller must add roots with rustls_client_config_builder_load_roots_from_file
-    /// or provide a custom verifier.
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_new() -> *mut rustls_client_config_builder {
-        ffi_panic_boundary! {
-            let builder = ClientConfigBuilder {
-                base: rustls::ClientConfig::builder().with_safe_defaults(),
-                verifier: Arc::new(NoneVerifier),
-                cert_resolver: None,
-                alpn_protocols: vec![],
-                enable_sni: true,
-            };
-            BoxCastPtr::to_mut_ptr(builder)
-        }
+// A verifier that accepts every server certificate. See
+// rustls_client_config_builder_dangerous_disable_cert_verification.
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
     }
+}
 
-    /// Create a rustls_client_config_builder. Caller owns the memory and must
-    /// eventually call rustls_client_config_builder_build, then free the
-    /// resulting rustls_client_config. Specify cipher suites in preference
-    /// order; the `cipher_suites` parameter must point to an array containing
-    /// `len` pointers to `rustls_supported_ciphersuite` previously obtained
-    /// from `rustls_all_ciphersuites_get_entry()`, or to a provided array,
-    /// RUSTLS_DEFAULT_CIPHER_SUITES or RUSTLS_ALL_CIPHER_SUITES. Set the TLS
-    /// protocol versions to use when negotiating a TLS session.
-    ///
-    /// `tls_version` is the version of the protocol, as defined in rfc8446,
-    /// ch. 4.2.1 and end of ch. 5.1. Some values are defined in
-    /// `rustls_tls_version` for convenience, and the arrays
-    /// RUSTLS_DEFAULT_VERSIONS or RUSTLS_ALL_VERSIONS can be used directly.
-    ///
-    /// `versions` will only be used during the call and the application retains
-    /// ownership. `len` is the number of consecutive `uint16_t` pointed to by `versions`.
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_new_custom(
-        cipher_suites: *const *const rustls_supported_ciphersuite,
-        cipher_suites_len: size_t,
-        tls_versions: *const u16,
-        tls_versions_len: size_t,
-        builder_out: *mut *mut rustls_client_config_builder,
-    ) -> rustls_result {
-        ffi_panic_boundary! {
-            let cipher_suites: &[*const rustls_supported_ciphersuite] = try_slice!(cipher_suites, cipher_suites_len);
-            let mut cs_vec: Vec<SupportedCipherSuite> = Vec::new();
-            for &cs in cipher_suites.iter() {
-                let cs = try_ref_from_ptr!(cs);
-                match ALL_CIPHER_SUITES.iter().find(|&acs| cs.eq(acs)) {
-                    Some(scs) => cs_vec.push(*scs),
-                    None => return InvalidParameter,
-                }
-            }
+/// SHA-256 hashes of the SubjectPublicKeyInfos a server's chain must include
+/// one of. See rustls_client_config_builder_set_pinned_spki_sha256.
+#[derive(Clone)]
+struct SpkiPins {
+    hashes: Vec<[u8; 32]>,
+    leaf_only: bool,
+}
 
-            let tls_versions: &[u16] = try_slice!(tls_versions, tls_versions_len);
-            let mut versions = vec![];
-            for version_number in tls_versions {
-                let proto = ProtocolVersion::from(*version_number);
-                if proto == rustls::version::TLS12.version {
-                    versions.push(&rustls::version::TLS12);
-                } else if proto == rustls::version::TLS13.version {
-                    versions.push(&rustls::version::TLS13);
-                }
-            }
+impl SpkiPins {
+    fn matches(&self, cert: &Certificate) -> bool {
+        match spki_sha256(cert) {
+            Some(hash) => self.hashes.contains(&hash),
+            None => false,
+        }
+    }
 
-            let result = rustls::ClientConfig::builder().with_cipher_suites(&cs_vec).with_safe_default_kx_groups().with_protocol_versions(&versions);
-            let base = match result {
-                Ok(new) => new,
-                Err(_) => return rustls_result::InvalidParameter,
-            };
-            let config_builder = ClientConfigBuilder {
-                base,
-                verifier: Arc::new(NoneVerifier),
-                cert_resolver: None,
-                alpn_protocols: vec![],
-                enable_sni: true,
-            };
+    fn matches_spki(&self, spki: &[u8]) -> bool {
+        let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+        self.hashes.iter().any(|hash| hash[..] == *digest.as_ref())
+    }
+}
 
-            BoxCastPtr::set_mut_ptr(builder_out, config_builder);
-            rustls_result::Ok
+// Treat a certificate that was only rejected for not matching the server
+// name as verified. WebPkiVerifier checks the name last, so by then the
+// chain and its validity have already been checked.
+fn ignore_name_mismatch(
+    verified: Result<ServerCertVerified, rustls::Error>,
+) -> Result<ServerCertVerified, rustls::Error> {
+    match verified {
+        Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => {
+            Ok(ServerCertVerified::assertion())
         }
+        verified => verified,
     }
 }
 
-/// Input to a custom certificate verifier callback. See
-/// rustls_client_config_builder_dangerous_set_certificate_verifier().
-///
-/// server_name can contain a hostname, an IPv4 address in textual form, or an
-/// IPv6 address in textual form.
-#[allow(non_camel_case_types)]
-#[repr(C)]
-pub struct rustls_verify_server_cert_params<'a> {
-    pub end_entity_cert_der: rustls_slice_bytes<'a>,
-    pub intermediate_certs_der: &'a rustls_slice_slice_bytes<'a>,
-    pub server_name: rustls_str<'a>,
-    pub ocsp_response: rustls_slice_bytes<'a>,
+// Runs another verifier, ignoring only a server name mismatch. See
+// rustls_client_config_builder_dangerous_skip_hostname_verification.
+struct SkipHostnameVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
 }
 
-/// User-provided input to a custom certificate verifier callback. See
-/// rustls_client_config_builder_dangerous_set_certificate_verifier().
-#[allow(non_camel_case_types)]
-pub type rustls_verify_server_cert_user_data = *mut libc::c_void;
+impl ServerCertVerifier for SkipHostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        ignore_name_mismatch(self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        ))
+    }
 
-// According to the nomicon https://doc.rust-lang.org/nomicon/ffi.html#the-nullable-pointer-optimization):
-// > Option<extern "C" fn(c_int) -> c_int> is a correct way to represent a
-// > nullable function pointer using the C ABI (corresponding to the C type int (*)(int)).
-// So we use Option<...> here. This is the type that is passed from C code.
-#[allow(non_camel_case_types)]
-pub type rustls_verify_server_cert_callback = Option<
-    unsafe extern "C" fn(
-        userdata: rustls_verify_server_cert_user_data,
-        params: *const rustls_verify_server_cert_params,
-    ) -> u32,
->;
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
 
-// This is the same as a rustls_verify_server_cert_callback after unwrapping
-// the Option (which is equivalent to checking for null).
-type VerifyCallback = unsafe extern "C" fn(
-    userdata: rustls_verify_server_cert_user_data,
-    params: *const rustls_verify_server_cert_params,
-) -> u32;
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
 
-// An implementation of rustls::ServerCertVerifier based on a C callback.
-struct Verifier {
-    callback: VerifyCallback,
+    fn request_scts(&self) -> bool {
+        self.inner.request_scts()
+    }
 }
 
-/// Safety: Verifier is Send because we don't allocate or deallocate any of its
-/// fields.
-unsafe impl Send for Verifier {}
-/// Safety: Verifier is Sync if the C code that passes us a callback that
-/// obeys the concurrency safety requirements documented in
-/// rustls_client_config_builder_dangerous_set_certificate_verifier.
-unsafe impl Sync for Verifier {}
+// Runs another verifier as if the current time were `time`. See
+// rustls_client_config_builder_set_verification_time.
+struct FixedTimeVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    time: SystemTime,
+}
 
-impl rustls::client::ServerCertVerifier for Verifier {
+impl ServerCertVerifier for FixedTimeVerifier {
     fn verify_server_cert(
         &self,
         end_entity: &Certificate,
         intermediates: &[Certificate],
         server_name: &rustls::ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
+        scts: &mut dyn Iterator<Item = &[u8]>,
         ocsp_response: &[u8],
         _now: SystemTime,
     ) -> Result<ServerCertVerified, rustls::Error> {
-        let cb = self.callback;
-        let server_name: Cow<'_, str> = match server_name {
-            rustls::ServerName::DnsName(n) => n.as_ref().into(),
-            rustls::ServerName::IpAddress(ip) => ip.to_string().into(),
-            _ => return Err(rustls::Error::General("unknown name type".to_string())),
-        };
-        let server_name: rustls_str = match server_name.as_ref().try_into() {
-            Ok(r) => r,
-            Err(NulByte {}) => return Err(rustls::Error::General("NUL byte in SNI".to_string())),
-        };
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            self.time,
+        )
+    }
 
-        let intermediates: Vec<_> = intermediates.iter().map(|cert| cert.as_ref()).collect();
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
 
-        let intermediates = rustls_slice_slice_bytes {
-            inner: &intermediates,
-        };
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
 
-        let params = rustls_verify_server_cert_params {
-            end_entity_cert_der: end_entity.as_ref().into(),
-            intermediate_certs_der: &intermediates,
-            server_name,
-            ocsp_response: ocsp_response.into(),
-        };
-        let userdata = userdata_get().map_err(|_| {
-            rustls::Error::General("internal error with thread-local storage".to_string())
-        })?;
-        let result: u32 = unsafe { cb(userdata, &params) };
-        let result: rustls_result =
-            rustls_result::try_from(result).unwrap_or(rustls_result::General);
-        match result {
-            rustls_result::Ok => Ok(ServerCertVerified::assertion()),
-            r => Err(error::cert_result_to_error(r)),
-        }
+    fn request_scts(&self) -> bool {
+        self.inner.request_scts()
     }
 }
 
-impl rustls_client_config_builder {
-    /// Set a custom server certificate verifier.
-    ///
-    /// The callback must not capture any of the pointers in its
-    /// rustls_verify_server_cert_params.
-    /// If `userdata` has been set with rustls_connection_set_userdata, it
-    /// will be passed to the callback. Otherwise the userdata param passed to
-    /// the callback will be NULL.
-    ///
-    /// The callback must be safe to call on any thread at any time, including
-    /// multiple concurrent calls. So, for instance, if the callback mutates
-    /// userdata (or other shared state), it must use synchronization primitives
-    /// to make such mutation safe.
-    ///
-    /// The callback receives certificate chain information as raw bytes.
-    /// Currently this library offers no functions to parse the certificates,
-    /// so you'll need to bring your own certificate parsing library
-    /// if you need to parse them.
-    ///
-    /// If the custom verifier accepts the certificate, it should return
-    /// RUSTLS_RESULT_OK. Otherwise, it may return any other rustls_result error.
-    /// Feel free to use an appropriate error from the RUSTLS_RESULT_CERT_*
-    /// section.
-    ///
-    /// <https://docs.rs/rustls/latest/rustls/client/struct.DangerousClientConfig.html#method.set_certificate_verifier>
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_dangerous_set_certificate_verifier(
-        config_builder: *mut rustls_client_config_builder,
-        callback: rustls_verify_server_cert_callback,
-    ) -> rustls_result {
-        ffi_panic_boundary! {
-            let config_builder = try_mut_from_ptr!(config_builder);
-            let callback: VerifyCallback = match callback {
-                Some(cb) => cb,
-                None => return rustls_result::InvalidParameter,
-            };
+// Keys another session store on the current connection's verification name,
+// if one was given to rustls_client_connection_new_with_verify_hostname, and
+// on the SNI name otherwise. A session is only resumed by a connection that
+// would have verified the server's certificate against the same name.
+struct VerifyNameSessionStore {
+    inner: Arc<dyn ClientSessionStore>,
+}
 
-            let verifier: Verifier = Verifier{callback};
-            config_builder.verifier = Arc::new(verifier);
-            rustls_result::Ok
+impl VerifyNameSessionStore {
+    fn key(server_name: &rustls::ServerName) -> Cow<'_, rustls::ServerName> {
+        match verify_name_get() {
+            Ok(Some(name)) => Cow::Owned(name),
+            _ => Cow::Borrowed(server_name),
         }
     }
+}
 
-    /// Use the trusted root certificates from the provided store.
-    ///
-    /// This replaces any trusted roots already configured with copies
-    /// from `roots`. This adds 1 to the refcount for `roots`. When you
-    /// call rustls_client_config_free or rustls_client_config_builder_free,
-    /// those will subtract 1 from the refcount for `roots`.
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_use_roots(
-        config_builder: *mut rustls_client_config_builder,
-        roots: *const rustls_root_cert_store,
-    ) -> rustls_result {
-        ffi_panic_boundary! {
-            let builder = try_mut_from_ptr!(config_builder);
-            let root_store: &RootCertStore = try_ref_from_ptr!(roots);
-            builder.verifier = Arc::new(rustls::client::WebPkiVerifier::new(root_store.clone(), None));
-            rustls_result::Ok
-        }
+impl ClientSessionStore for VerifyNameSessionStore {
+    fn set_kx_hint(&self, server_name: &rustls::ServerName, group: NamedGroup) {
+        self.inner.set_kx_hint(&Self::key(server_name), group)
     }
 
-    /// Add trusted root certificates from the named file, which should contain
-    /// PEM-formatted certificates.
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_load_roots_from_file(
-        config_builder: *mut rustls_client_config_builder,
-        filename: *const c_char,
-    ) -> rustls_result {
-        ffi_panic_boundary! {
-            let config_builder = try_mut_from_ptr!(config_builder);
-            let filename: &CStr = unsafe {
-                if filename.is_null() {
-                    return rustls_result::NullParameter;
-                }
-                CStr::from_ptr(filename)
-            };
+    fn kx_hint(&self, server_name: &rustls::ServerName) -> Option<NamedGroup> {
+        self.inner.kx_hint(&Self::key(server_name))
+    }
 
-            let filename: &[u8] = filename.to_bytes();
-            let filename: &str = match std::str::from_utf8(filename) {
-                Ok(s) => s,
-                Err(_) => return rustls_result::Io,
-            };
-            let filename: &OsStr = OsStr::new(filename);
-            let mut cafile = match File::open(filename) {
-                Ok(f) => f,
-                Err(_) => return rustls_result::Io,
-            };
+    fn set_tls12_session(&self, server_name: &rustls::ServerName, value: Tls12ClientSessionValue) {
+        self.inner.set_tls12_session(&Self::key(server_name), value)
+    }
 
-            let mut bufreader = BufReader::new(&mut cafile);
-            let certs = match rustls_pemfile::certs(&mut bufreader) {
-                Ok(certs) => certs,
-                Err(_) => return rustls_result::Io,
-            };
+    fn tls12_session(&self, server_name: &rustls::ServerName) -> Option<Tls12ClientSessionValue> {
+        self.inner.tls12_session(&Self::key(server_name))
+    }
 
-            let mut roots = RootCertStore::empty();
-            let (_, failed) = roots.add_parsable_certificates(&certs);
-            if failed > 0 {
-                return rustls_result::CertificateParseError;
-            }
+    fn remove_tls12_session(&self, server_name: &rustls::ServerName) {
+        self.inner.remove_tls12_session(&Self::key(server_name))
+    }
 
-            config_builder.verifier = Arc::new(rustls::client::WebPkiVerifier::new(roots, None));
-            rustls_result::Ok
-        }
+    fn insert_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.inner
+            .insert_tls13_ticket(&Self::key(server_name), value)
     }
 
-    /// Set the ALPN protocol list to the given protocols. `protocols` must point
-    /// to a buffer of `rustls_slice_bytes` (built by the caller) with `len`
-    /// elements. Each element of the buffer must be a rustls_slice_bytes whose
-    /// data field points to a single ALPN protocol ID. Standard ALPN protocol
-    /// IDs are defined at
-    /// <https://www.iana.org/assignments/tls-extensiontype-values/tls-extensiontype-values.xhtml#alpn-protocol-ids>.
-    ///
-    /// This function makes a copy of the data in `protocols` and does not retain
-    /// any pointers, so the caller can free the pointed-to memory after calling.
-    ///
-    /// <https://docs.rs/rustls/latest/rustls/client/struct.ClientConfig.html#structfield.alpn_protocols>
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_set_alpn_protocols(
-        builder: *mut rustls_client_config_builder,
-        protocols: *const rustls_slice_bytes,
-        len: size_t,
-    ) -> rustls_result {
-        ffi_panic_boundary! {
-            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
-            let protocols: &[rustls_slice_bytes] = try_slice!(protocols, len);
+    fn take_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+    ) -> Option<Tls13ClientSessionValue> {
+        self.inner.take_tls13_ticket(&Self::key(server_name))
+    }
+}
 
-            let mut vv: Vec<Vec<u8>> = Vec::with_capacity(protocols.len());
-            for p in protocols {
-                let v: &[u8] = try_slice!(p.data, p.len);
-                vv.push(v.to_vec());
-            }
-            config.alpn_protocols = vv;
-            rustls_result::Ok
+// Runs another verifier against the current connection's verification name,
+// if one was given to rustls_client_connection_new_with_verify_hostname. It
+// also notes that verification happened, which tells full handshakes apart
+// from resumed ones.
+struct VerifyNameVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+}
+
+impl ServerCertVerifier for VerifyNameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if cert_verified_set().is_err() {
+            return Err(rustls::Error::General(
+                "internal error with thread-local storage".to_string(),
+            ));
         }
+        let verify_name = match verify_name_get() {
+            Ok(name) => name,
+            Err(_) => {
+                return Err(rustls::Error::General(
+                    "internal error with thread-local storage".to_string(),
+                ))
+            }
+        };
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            verify_name.as_ref().unwrap_or(server_name),
+            scts,
+            ocsp_response,
+            now,
+        )
     }
 
-    /// Enable or disable SNI.
-    /// <https://docs.rs/rustls/latest/rustls/struct.ClientConfig.html#structfield.enable_sni>
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_set_enable_sni(
-        config: *mut rustls_client_config_builder,
-        enable: bool,
-    ) {
-        ffi_panic_boundary! {
-            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(config);
-            config.enable_sni = enable;
-        }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
     }
 
-    /// Provide the configuration a list of certificates where the connection
-    /// will select the first one that is compatible with the server's signature
-    /// verification capabilities. Clients that want to support both ECDSA and
-    /// RSA certificates will want the ECSDA to go first in the list.
-    ///
-    /// The built configuration will keep a reference to all certified keys
-    /// provided. The client may `rustls_certified_key_free()` afterwards
-    /// without the configuration losing them. The same certified key may also
-    /// be used in multiple configs.
-    ///
-    /// EXPERIMENTAL: installing a client authentication callback will replace any
-    /// configured certified keys and vice versa.
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_set_certified_key(
-        builder: *mut rustls_client_config_builder,
-        certified_keys: *const *const rustls_certified_key,
-        certified_keys_len: size_t,
-    ) -> rustls_result {
-        ffi_panic_boundary! {
-            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
-            let keys_ptrs: &[*const rustls_certified_key] = try_slice!(certified_keys, certified_keys_len);
-            let mut keys: Vec<Arc<CertifiedKey>> = Vec::new();
-            for &key_ptr in keys_ptrs {
-                let certified_key: Arc<CertifiedKey> = try_arc_from_ptr!(key_ptr);
-                keys.push(certified_key);
-            }
-            config.cert_resolver = Some(Arc::new(ResolvesClientCertFromChoices { keys }));
-            rustls_result::Ok
-        }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn request_scts(&self) -> bool {
+        self.inner.request_scts()
     }
 }
 
-/// Always send the same client certificate.
-struct ResolvesClientCertFromChoices {
-    keys: Vec<Arc<CertifiedKey>>,
+// A certificate transparency log, identified by the SHA-256 hash of its
+// SubjectPublicKeyInfo.
+#[derive(Clone)]
+struct CtLog {
+    id: [u8; 32],
+    key: Vec<u8>,
 }
 
-impl ResolvesClientCert for ResolvesClientCertFromChoices {
-    fn resolve(
+// See rustls_client_config_builder_set_ct_logs.
+#[derive(Clone)]
+struct CtPolicy {
+    logs: Vec<CtLog>,
+    validation_deadline: SystemTime,
+}
+
+// When the ct-logs crate's list was published, in seconds since the Unix
+// epoch: ct-logs 0.9.0 was released on 2021-04-10.
+#[cfg(feature = "bundled_ct_logs")]
+const BUNDLED_CT_LOGS_TIMESTAMP: u64 = 1_618_075_330;
+
+#[cfg(feature = "bundled_ct_logs")]
+fn bundled_ct_logs() -> Option<(Vec<CtLog>, u64)> {
+    let logs = ct_logs::LOGS
+        .iter()
+        .map(|log| CtLog {
+            id: log.id,
+            key: log.key.to_vec(),
+        })
+        .collect();
+    Some((logs, BUNDLED_CT_LOGS_TIMESTAMP))
+}
+
+#[cfg(not(feature = "bundled_ct_logs"))]
+fn bundled_ct_logs() -> Option<(Vec<CtLog>, u64)> {
+    None
+}
+
+// Runs another verifier, then checks the SCTs the server sent against the
+// policy's logs, the same way rustls's CertificateTransparencyPolicy does.
+struct CtVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    policy: CtPolicy,
+}
+
+impl ServerCertVerifier for CtVerifier {
+    fn verify_server_cert(
         &self,
-        _acceptable_issuers: &[&[u8]],
-        sig_schemes: &[rustls::SignatureScheme],
-    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
-        for key in self.keys.iter() {
-            if key.key.choose_scheme(sig_schemes).is_some() {
-                return Some(key.clone());
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let scts: Vec<&[u8]> = scts.collect();
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            &mut scts.iter().copied(),
+            ocsp_response,
+            now,
+        )?;
+        if self.policy.validation_deadline < now {
+            return Err(LocalCertError::error(rustls_result::CtLogsExpired));
+        }
+        let now_ms = match now.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(d) => d.as_millis() as u64,
+            Err(_) => return Err(rustls::Error::FailedToGetCurrentTime),
+        };
+        let logs: Vec<sct::Log> = self
+            .policy
+            .logs
+            .iter()
+            .map(|log| sct::Log {
+                description: "",
+                url: "",
+                operated_by: "",
+                key: &log.key,
+                id: log.id,
+                max_merge_delay: 0,
+            })
+            .collect();
+        let logs: Vec<&sct::Log> = logs.iter().collect();
+        let mut last_sct_error = None;
+        for sct in scts {
+            match sct::verify_sct(&end_entity.0, sct, now_ms, &logs) {
+                Ok(_) => return Ok(verified),
+                Err(e) if e.should_be_fatal() => return Err(rustls::Error::InvalidSct(e)),
+                Err(e) => last_sct_error = Some(e),
             }
         }
-        None
+        match last_sct_error {
+            Some(e) => Err(rustls::Error::InvalidSct(e)),
+            None => Ok(verified),
+        }
     }
 
-    fn has_certs(&self) -> bool {
-        !self.keys.is_empty()
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn request_scts(&self) -> bool {
+        true
     }
 }
 
-impl rustls_client_config_builder {
-    /// Turn a *rustls_client_config_builder (mutable) into a const *rustls_client_config
-    /// (read-only).
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_build(
-        builder: *mut rustls_client_config_builder,
-    ) -> *const rustls_client_config {
-        ffi_panic_boundary! {
-            let builder: Box<ClientConfigBuilder> = try_box_from_ptr!(builder);
-            let config = builder.base.with_custom_certificate_verifier(builder.verifier);
-            let mut config = match builder.cert_resolver {
-                Some(r) => config.with_client_cert_resolver(r),
-                None => config.with_no_client_auth(),
-            };
-            config.alpn_protocols = builder.alpn_protocols;
-            config.enable_sni = builder.enable_sni;
-            ArcCastPtr::to_const_ptr(config)
+// Runs another verifier, then requires the verified chain to contain a
+// pinned public key.
+struct PinningVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: SpkiPins,
+    skip_hostname_verification: bool,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        if self.pins.matches(end_entity) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        if !self.pins.leaf_only {
+            // The server may send certificates that aren't on the path to a
+            // root. A pinned intermediate only counts if the end-entity
+            // certificate verifies with that intermediate as its root.
+            let mut pinned = RootCertStore::empty();
+            for cert in intermediates.iter().filter(|c| self.pins.matches(c)) {
+                pinned.add(cert).ok();
+            }
+            if !pinned.is_empty() {
+                let verifier = WebPkiVerifier::new(pinned, None);
+                let verified = verifier.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    &mut std::iter::empty(),
+                    ocsp_response,
+                    now,
+                );
+                let verified = if self.skip_hostname_verification {
+                    ignore_name_mismatch(verified)
+                } else {
+                    verified
+                };
+                if verified.is_ok() {
+                    return verified;
+                }
+            }
+            // A trusted root can be pinned too. Verifying against roots
+            // records the chain, which names the root it was anchored by.
+            if let Ok(Some(chain)) = verified_chain_take() {
+                let pinned =
+                    matches!(chain.anchor_spki(), Some(spki) if self.pins.matches_spki(spki));
+                verified_chain_set(chain).ok();
+                if pinned {
+                    return Ok(ServerCertVerified::assertion());
+                }
+            }
         }
+        Err(LocalCertError::error(rustls_result::PinViolation))
     }
 
-    /// "Free" a client_config_builder without building it into a rustls_client_config.
-    /// Normally builders are built into rustls_client_config via `rustls_client_config_builder_build`
-    /// and may not be free'd or otherwise used afterwards.
-    /// Use free only when the building of a config has to be aborted before a config
-    /// was created.
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_builder_free(config: *mut rustls_client_config_builder) {
-        ffi_panic_boundary! {
-            BoxCastPtr::to_box(config);
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn request_scts(&self) -> bool {
+        self.inner.request_scts()
+    }
+}
+
+// Accepts exactly one end-entity certificate. See
+// rustls_client_config_builder_set_pinned_cert_der.
+struct PinnedCertVerifier {
+    cert: Certificate,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if *end_entity == self.cert {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(LocalCertError::error(rustls_result::PinnedCertMismatch))
         }
     }
 }
 
-impl rustls_client_config {
-    /// "Free" a rustls_client_config previously returned from
-    /// rustls_client_config_builder_build. Since rustls_client_config is actually an
-    /// atomically reference-counted pointer, extant client connections may still
-    /// hold an internal reference to the Rust object. However, C code must
-    /// consider this pointer unusable after "free"ing it.
-    /// Calling with NULL is fine. Must not be called twice with the same value.
-    #[no_mangle]
-    pub extern "C" fn rustls_client_config_free(config: *const rustls_client_config) {
-        ffi_panic_boundary! {
-            rustls_client_config::free(config);
+// A TLSA record's certificate usage (RFC 6698 section 2.1.1).
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TlsaUsage {
+    PkixTa,
+    PkixEe,
+    DaneTa,
+    DaneEe,
+}
+
+// A usable TLSA record. See rustls_client_config_builder_set_tlsa_records.
+#[derive(Clone, Debug)]
+struct TlsaRecord {
+    usage: TlsaUsage,
+    // Selector 1: match the SubjectPublicKeyInfo rather than the whole
+    // certificate.
+    spki_only: bool,
+    // None for an exact match.
+    digest: Option<&'static ring::digest::Algorithm>,
+    data: Vec<u8>,
+}
+
+impl TlsaRecord {
+    /// Returns None for a record that is unusable (RFC 6698 section 4.1):
+    /// one with a usage, selector or matching type this library doesn't
+    /// know, or a digest of the wrong length.
+    fn new(usage: u8, selector: u8, matching_type: u8, data: &[u8]) -> Option<TlsaRecord> {
+        let usage = match usage {
+            0 => TlsaUsage::PkixTa,
+            1 => TlsaUsage::PkixEe,
+            2 => TlsaUsage::DaneTa,
+            3 => TlsaUsage::DaneEe,
+            _ => return None,
+        };
+        let spki_only = match selector {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let digest = match matching_type {
+            0 => None,
+            1 => Some(&ring::digest::SHA256),
+            2 => Some(&ring::digest::SHA512),
+            _ => return None,
+        };
+        let expected_len = digest.map_or(data.len(), |d| d.output_len);
+        if data.is_empty() || data.len() != expected_len {
+            return None;
         }
+        Some(TlsaRecord {
+            usage,
+            spki_only,
+            digest,
+            data: data.to_vec(),
+        })
     }
 
-    /// Create a new rustls_connection containing a client connection and return
-    /// it in the output parameter `out`. If this returns an error code, the
-    /// memory pointed to by `conn_out` remains unchanged. If this returns a
-    /// non-error, the memory pointed to by `conn_out` is modified to point at a
-    /// valid rustls_connection. The caller now owns the rustls_connection and must
-    /// call `rustls_connection_free` when done with it.
-    ///
-    /// The server_name parameter can contain a hostname or an IP address in
-    /// textual form (IPv4 or IPv6). This function will return an error if it
-    /// cannot be parsed as one of those types.
-    #[no_mangle]
-    pub extern "C" fn rustls_client_connection_new(
-        config: *const rustls_client_config,
-        server_name: *const c_char,
-        conn_out: *mut *mut rustls_connection,
-    ) -> rustls_result {
-        ffi_panic_boundary! {
-        let server_name: &CStr = unsafe {
-            if server_name.is_null() {
-                return NullParameter;
+    fn matches(&self, cert: &Certificate) -> bool {
+        let selected = if self.spki_only {
+            match spki_der(cert) {
+                Some(spki) => spki,
+                None => return false,
             }
-            CStr::from_ptr(server_name)
-        };
-        let config: Arc<ClientConfig> = try_arc_from_ptr!(config);
-        let server_name: &str = match server_name.to_str() {
-            Ok(s) => s,
-            Err(std::str::Utf8Error { .. }) => return rustls_result::InvalidDnsNameError,
+        } else {
+            &cert.0
         };
-        let server_name: rustls::ServerName = match server_name.try_into() {
-            Ok(sn) => sn,
-            Err(_) => return rustls_result::InvalidDnsNameError,
+        match self.digest {
+            Some(digest) => ring::digest::digest(digest, selected).as_ref() == self.data,
+            None => selected == self.data,
+        }
+    }
+}
+
+// Authenticates the server with TLSA records, per RFC 6698 and RFC 7671.
+// `inner` is the PKIX verification that PKIX-TA and PKIX-EE records build on.
+struct DaneVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    records: Vec<TlsaRecord>,
+    skip_hostname_verification: bool,
+}
+
+impl DaneVerifier {
+    // Verify `end_entity` with `anchor`, one of the certificates the server
+    // sent, as the only trust anchor. Returns the verified path, ending with
+    // `anchor`, if it can be worked out.
+    fn verify_with_anchor(
+        &self,
+        anchor: &Certificate,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &rustls::ServerName,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<Option<Vec<Certificate>>, rustls::Error> {
+        let mut roots = RootCertStore::empty();
+        if roots.add(anchor).is_err() {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::BadEncoding,
+            ));
+        }
+        let verifier = WebPkiVerifier::new(roots, None);
+        let verified = verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            &mut std::iter::empty(),
+            ocsp_response,
+            now,
+        );
+        let verified = if self.skip_hostname_verification {
+            ignore_name_mismatch(verified)
+        } else {
+            verified
         };
-        let client = ClientConnection::new(config, server_name).unwrap();
+        verified?;
+        let path = verified_path(end_entity, intermediates, |intermediates| {
+            chain_verifies(&verifier, end_entity, intermediates, server_name, now)
+        });
+        Ok(path.map(|mut path| {
+            path.push(anchor.clone());
+            path
+        }))
+    }
+}
 
-        // We've succeeded. Put the client on the heap, and transfer ownership
-        // to the caller. After this point, we must return rustls_result::Ok so the
-        // caller knows it is responsible for this memory.
-        let c = Connection::from_client(client);
-        BoxCastPtr::set_mut_ptr(conn_out, c);
-        rustls_result::Ok
+impl ServerCertVerifier for DaneVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let scts: Vec<&[u8]> = scts.collect();
+        // Run at most once, and only if a PKIX record needs it.
+        let mut pkix: Option<Result<(), rustls::Error>> = None;
+        let mut pkix_verify = || {
+            pkix.get_or_insert_with(|| {
+                self.inner
+                    .verify_server_cert(
+                        end_entity,
+                        intermediates,
+                        server_name,
+                        &mut scts.iter().copied(),
+                        ocsp_response,
+                        now,
+                    )
+                    .map(|_| ())
+            })
+            .clone()
+        };
+        // The error for a record that matched a certificate whose chain then
+        // failed verification, which says more than TlsaMismatch.
+        let mut failure = None;
+        for record in &self.records {
+            let verified = match record.usage {
+                // RFC 7671 section 5.1: neither the server name nor the
+                // validity period is checked.
+                TlsaUsage::DaneEe if record.matches(end_entity) => {
+                    // No chain was verified, whatever a PKIX record's
+                    // verification recorded.
+                    verified_chain_take().ok();
+                    return Ok(ServerCertVerified::assertion());
+                }
+                TlsaUsage::PkixEe if record.matches(end_entity) => pkix_verify(),
+                TlsaUsage::DaneEe | TlsaUsage::PkixEe => continue,
+                // The trust anchor has to be one of the certificates the
+                // server sent (RFC 7671 section 5.2.2), and only counts if
+                // the end-entity certificate verifies up to it.
+                TlsaUsage::DaneTa | TlsaUsage::PkixTa => {
+                    let mut anchored = None;
+                    for anchor in intermediates.iter().filter(|c| record.matches(c)) {
+                        anchored = Some(self.verify_with_anchor(
+                            anchor,
+                            end_entity,
+                            intermediates,
+                            server_name,
+                            ocsp_response,
+                            now,
+                        ));
+                        if let Some(Ok(_)) = anchored {
+                            break;
+                        }
+                    }
+                    match anchored {
+                        Some(Ok(_)) if record.usage == TlsaUsage::PkixTa => pkix_verify(),
+                        Some(Ok(Some(path))) => {
+                            verified_chain_set(VerifiedChain::known(path)).ok();
+                            Ok(())
+                        }
+                        Some(Ok(None)) => {
+                            verified_chain_take().ok();
+                            Ok(())
+                        }
+                        Some(Err(e)) => Err(e),
+                        None => continue,
+                    }
+                }
+            };
+            match verified {
+                Ok(()) => return Ok(ServerCertVerified::assertion()),
+                Err(e) => failure = Some(e),
+            }
         }
+        Err(failure.unwrap_or_else(|| LocalCertError::error(rustls_result::TlsaMismatch)))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn request_scts(&self) -> bool {
+        self.inner.request_scts()
+    }
+}
+
+// The most TLS 1.3 tickets kept per server, matching rustls's
+// ClientSessionMemoryCache.
+const MAX_TLS13_TICKETS_PER_SERVER: usize = 8;
+
+// Remembers sessions for up to `max_servers` servers, forgetting the one
+// added longest ago when full. Unlike ClientSessionMemoryCache, memory is
+// only allocated as servers are added. See
+// rustls_client_config_builder_set_session_cache_size.
+struct ClientSessionCache {
+    max_servers: usize,
+    servers: Mutex<ServerSessions>,
+}
+
+#[derive(Default)]
+struct ServerSessions {
+    map: HashMap<rustls::ServerName, ServerData>,
+    // First item is the oldest server.
+    oldest: VecDeque<rustls::ServerName>,
+}
+
+#[derive(Default)]
+struct ServerData {
+    kx_hint: Option<NamedGroup>,
+    tls12: Option<Tls12ClientSessionValue>,
+    // Oldest first.
+    tls13: VecDeque<Tls13ClientSessionValue>,
+}
+
+impl ClientSessionCache {
+    fn new(max_servers: usize) -> Self {
+        ClientSessionCache {
+            max_servers,
+            servers: Mutex::new(ServerSessions::default()),
+        }
+    }
+
+    // A panic while the lock was held leaves the sessions consistent, since
+    // each edit either completes or never touches the map.
+    fn lock(&self) -> MutexGuard<'_, ServerSessions> {
+        self.servers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Apply `f` to the data for `server_name`, adding it if needed.
+    fn edit(&self, server_name: &rustls::ServerName, f: impl FnOnce(&mut ServerData)) {
+        let mut servers = self.lock();
+        let servers = &mut *servers;
+        if !servers.map.contains_key(server_name) {
+            if servers.oldest.len() >= self.max_servers {
+                if let Some(oldest) = servers.oldest.pop_front() {
+                    servers.map.remove(&oldest);
+                }
+            }
+            servers.oldest.push_back(server_name.clone());
+        }
+        f(servers.map.entry(server_name.clone()).or_default());
+    }
+
+    // Apply `f` to the data for `server_name`, if there is any.
+    fn get<T>(
+        &self,
+        server_name: &rustls::ServerName,
+        f: impl FnOnce(&mut ServerData) -> Option<T>,
+    ) -> Option<T> {
+        self.lock().map.get_mut(server_name).and_then(f)
+    }
+}
+
+impl ClientSessionStore for ClientSessionCache {
+    fn set_kx_hint(&self, server_name: &rustls::ServerName, group: NamedGroup) {
+        self.edit(server_name, |data| data.kx_hint = Some(group));
+    }
+
+    fn kx_hint(&self, server_name: &rustls::ServerName) -> Option<NamedGroup> {
+        self.get(server_name, |data| data.kx_hint)
+    }
+
+    fn set_tls12_session(&self, server_name: &rustls::ServerName, value: Tls12ClientSessionValue) {
+        self.edit(server_name, |data| data.tls12 = Some(value));
+    }
+
+    fn tls12_session(&self, server_name: &rustls::ServerName) -> Option<Tls12ClientSessionValue> {
+        self.get(server_name, |data| data.tls12.clone())
+    }
+
+    fn remove_tls12_session(&self, server_name: &rustls::ServerName) {
+        self.get(server_name, |data| data.tls12.take());
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.edit(server_name, |data| {
+            if data.tls13.len() == MAX_TLS13_TICKETS_PER_SERVER {
+                data.tls13.pop_front();
+            }
+            data.tls13.push_back(value);
+        });
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+    ) -> Option<Tls13ClientSessionValue> {
+        self.get(server_name, |data| data.tls13.pop_back())
+    }
+}
+
+impl rustls_client_config_builder {
+    /// Create a rustls_client_config_builder. Caller owns the memory and must
+    /// eventually call rustls_client_config_builder_build, then free the
+    /// resulting rustls_client_config.
+    /// This uses rustls safe default values
+    /// for the cipher suites, key exchange groups and protocol versions.
+    /// This starts out with no trusted roots: no built-in or system roots are
+    /// ever added implicitly, so a config can trust exactly the anchors the
+    /// caller chooses. Caller must add roots with
+    /// rustls_client_config_builder_use_roots or
+    /// rustls_client_config_builder_load_roots_from_file, or provide a custom
+    /// verifier.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_new() -> *mut rustls_client_config_builder {
+        ffi_panic_boundary! {
+            let builder = ClientConfigBuilder::new(
+                DEFAULT_CIPHER_SUITES.to_vec(),
+                DEFAULT_VERSIONS.to_vec(),
+            );
+            BoxCastPtr::to_mut_ptr(builder)
+        }
+    }
+
+    /// Create a rustls_client_config_builder. Caller owns the memory and must
+    /// eventually call rustls_client_config_builder_build, then free the
+    /// resulting rustls_client_config. Specify cipher suites in preference
+    /// order; the `cipher_suites` parameter must point to an array containing
+    /// `len` pointers to `rustls_supported_ciphersuite` previously obtained
+    /// from `rustls_all_ciphersuites_get_entry()`, or to a provided array,
+    /// RUSTLS_DEFAULT_CIPHER_SUITES or RUSTLS_ALL_CIPHER_SUITES. Set the TLS
+    /// protocol versions to use when negotiating a TLS session.
+    ///
+    /// `tls_version` is the version of the protocol, as defined in rfc8446,
+    /// ch. 4.2.1 and end of ch. 5.1. Some values are defined in
+    /// `rustls_tls_version` for convenience, and the arrays
+    /// RUSTLS_DEFAULT_VERSIONS or RUSTLS_ALL_VERSIONS can be used directly.
+    ///
+    /// `versions` will only be used during the call and the application retains
+    /// ownership. `len` is the number of consecutive `uint16_t` pointed to by `versions`.
+    /// An empty list returns RUSTLS_RESULT_INVALID_PARAMETER, and a version
+    /// rustls does not implement returns RUSTLS_RESULT_UNSUPPORTED_PROTOCOL_VERSION.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_new_custom(
+        cipher_suites: *const *const rustls_supported_ciphersuite,
+        cipher_suites_len: size_t,
+        tls_versions: *const u16,
+        tls_versions_len: size_t,
+        builder_out: *mut *mut rustls_client_config_builder,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let cipher_suites: &[*const rustls_supported_ciphersuite] = try_slice!(cipher_suites, cipher_suites_len);
+            let mut cs_vec: Vec<SupportedCipherSuite> = Vec::new();
+            for &cs in cipher_suites.iter() {
+                let cs = try_ref_from_ptr!(cs);
+                match ALL_CIPHER_SUITES.iter().find(|&acs| cs.eq(acs)) {
+                    Some(scs) => cs_vec.push(*scs),
+                    None => return InvalidParameter,
+                }
+            }
+
+            let tls_versions: &[u16] = try_slice!(tls_versions, tls_versions_len);
+            let versions = match protocol_versions_from_u16s(tls_versions) {
+                Ok(versions) => versions,
+                Err(e) => return e,
+            };
+
+            if let Err(e) = ClientConfigBuilder::base(&cs_vec, &versions) {
+                return e;
+            }
+            let config_builder = ClientConfigBuilder::new(cs_vec, versions);
+
+            BoxCastPtr::set_mut_ptr(builder_out, config_builder);
+            rustls_result::Ok
+        }
+    }
+
+    /// Set the config userdata: an application pointer that every connection
+    /// made with the built config can retrieve with
+    /// rustls_client_connection_get_config_userdata. It is also the userdata
+    /// passed to the config's callbacks, like a custom certificate verifier
+    /// or client certificate resolver, for connections that have no userdata
+    /// of their own (see rustls_connection_set_userdata), and to a key log
+    /// callback registered without userdata.
+    ///
+    /// The pointer is never dereferenced by this library. The caller must
+    /// keep what it points to valid for as long as the config or any
+    /// connection made with it might use it.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_userdata(
+        builder: *mut rustls_client_config_builder,
+        userdata: *mut c_void,
+    ) {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            config.userdata = userdata;
+        }
+    }
+
+    /// Create a copy of `builder` with all the same settings. The caller owns
+    /// the copy, and can go on configuring and build it independently of the
+    /// original. This is useful for deriving several configs that differ in
+    /// only a few settings, like ALPN protocols or client certificates, from
+    /// one base builder.
+    ///
+    /// Objects the builder holds by reference, like root stores, certified
+    /// keys and verifiers, are shared between the copies rather than copied.
+    /// So is the session cache: call
+    /// rustls_client_config_builder_set_session_cache_size on the copy to give
+    /// it a cache of its own, e.g. if the copies use different client
+    /// certificates. Returns NULL if `builder` is NULL.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_clone(
+        builder: *const rustls_client_config_builder,
+    ) -> *mut rustls_client_config_builder {
+        ffi_panic_boundary! {
+            let builder: &ClientConfigBuilder = try_ref_from_ptr!(builder);
+            BoxCastPtr::to_mut_ptr(builder.clone())
+        }
+    }
+
+    /// Replace the cipher suites used by this builder. `suite_ids` must point
+    /// to an array of `len` 16-bit IANA cipher suite identifiers (see
+    /// rustls_supported_ciphersuite_get_suite), in preference order. The
+    /// order is preserved and honored during negotiation.
+    ///
+    /// Returns RUSTLS_RESULT_UNSUPPORTED_CIPHER_SUITE if any identifier is not
+    /// implemented by rustls, and stores the index of the first such
+    /// identifier in *out_failed_index unless `out_failed_index` is NULL.
+    /// Returns RUSTLS_RESULT_INVALID_PARAMETER if the list is empty or has no
+    /// suite usable with the builder's protocol versions. On error, the
+    /// builder is unchanged.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_ciphersuites(
+        builder: *mut rustls_client_config_builder,
+        suite_ids: *const u16,
+        len: size_t,
+        out_failed_index: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let builder: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let suite_ids: &[u16] = try_slice!(suite_ids, len);
+            let mut cipher_suites: Vec<SupportedCipherSuite> = Vec::with_capacity(suite_ids.len());
+            for (i, &id) in suite_ids.iter().enumerate() {
+                match ciphersuite_from_id(id) {
+                    Some(cs) => cipher_suites.push(cs),
+                    None => {
+                        unsafe {
+                            if let Some(out_failed_index) = out_failed_index.as_mut() {
+                                *out_failed_index = i;
+                            }
+                        }
+                        return rustls_result::UnsupportedCipherSuite;
+                    }
+                }
+            }
+            if let Err(e) = ClientConfigBuilder::base(&cipher_suites, &builder.versions) {
+                return e;
+            }
+            builder.cipher_suites = cipher_suites;
+            rustls_result::Ok
+        }
+    }
+
+    /// Replace the cipher suites used by this builder, looking them up by
+    /// name. `names` must point to an array of `count` NUL-terminated strings,
+    /// in preference order. Names are matched case-insensitively, ignoring
+    /// surrounding whitespace, and may be given either in rustls' spelling
+    /// ("TLS13_AES_128_GCM_SHA256") or IANA's ("TLS_AES_128_GCM_SHA256").
+    ///
+    /// Returns RUSTLS_RESULT_UNSUPPORTED_CIPHER_SUITE if any name does not
+    /// match a cipher suite implemented by rustls, and stores the index of the
+    /// first such name in *out_failed_index unless `out_failed_index` is NULL.
+    /// Otherwise behaves like rustls_client_config_builder_set_ciphersuites.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_ciphersuites_by_name(
+        builder: *mut rustls_client_config_builder,
+        names: *const *const c_char,
+        count: size_t,
+        out_failed_index: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let builder: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let names: &[*const c_char] = try_slice!(names, count);
+            let mut cipher_suites: Vec<SupportedCipherSuite> = Vec::with_capacity(names.len());
+            for (i, &name) in names.iter().enumerate() {
+                if name.is_null() {
+                    return NullParameter;
+                }
+                let name: &CStr = unsafe { CStr::from_ptr(name) };
+                let found = name.to_str().ok().and_then(ciphersuite_from_name);
+                match found {
+                    Some(cs) => cipher_suites.push(cs),
+                    None => {
+                        unsafe {
+                            if let Some(out_failed_index) = out_failed_index.as_mut() {
+                                *out_failed_index = i;
+                            }
+                        }
+                        return rustls_result::UnsupportedCipherSuite;
+                    }
+                }
+            }
+            if let Err(e) = ClientConfigBuilder::base(&cipher_suites, &builder.versions) {
+                return e;
+            }
+            builder.cipher_suites = cipher_suites;
+            rustls_result::Ok
+        }
+    }
+
+    /// Replace the cipher suites used by this builder with those selected by
+    /// an OpenSSL cipher string like "ECDHE+AESGCM:!aNULL", in the order
+    /// rustls_ciphersuites_from_openssl_string produces them. TLS 1.3 suites
+    /// are always included, since OpenSSL cipher strings don't apply to them.
+    ///
+    /// Returns RUSTLS_RESULT_INVALID_PARAMETER if `spec` selects no TLS 1.2
+    /// suite, or no suite usable with the builder's protocol versions. On
+    /// error, the builder is unchanged.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_openssl_cipher_string(
+        builder: *mut rustls_client_config_builder,
+        spec: *const c_char,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let builder: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            if spec.is_null() {
+                return NullParameter;
+            }
+            let spec: &CStr = unsafe { CStr::from_ptr(spec) };
+            let spec = match spec.to_str() {
+                Ok(spec) => spec,
+                Err(_) => return InvalidParameter,
+            };
+            let cipher_suites = match suites_from_openssl_string(spec) {
+                Ok(cipher_suites) => cipher_suites,
+                Err(e) => return e,
+            };
+            if let Err(e) = ClientConfigBuilder::base(&cipher_suites, &builder.versions) {
+                return e;
+            }
+            builder.cipher_suites = cipher_suites;
+            rustls_result::Ok
+        }
+    }
+}
+
+/// Input to a custom certificate verifier callback. See
+/// rustls_client_config_builder_dangerous_set_certificate_verifier().
+///
+/// server_name can contain a hostname, an IPv4 address in textual form, or an
+/// IPv6 address in textual form.
+///
+/// ocsp_response holds the DER-encoded OCSPResponse the server stapled,
+/// exactly as received; it is empty (len 0) if the server didn't staple one.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct rustls_verify_server_cert_params<'a> {
+    pub end_entity_cert_der: rustls_slice_bytes<'a>,
+    pub intermediate_certs_der: &'a rustls_slice_slice_bytes<'a>,
+    pub server_name: rustls_str<'a>,
+    pub ocsp_response: rustls_slice_bytes<'a>,
+}
+
+/// User-provided input to a custom certificate verifier callback. See
+/// rustls_client_config_builder_dangerous_set_certificate_verifier().
+#[allow(non_camel_case_types)]
+pub type rustls_verify_server_cert_user_data = *mut libc::c_void;
+
+// According to the nomicon https://doc.rust-lang.org/nomicon/ffi.html#the-nullable-pointer-optimization):
+// > Option<extern "C" fn(c_int) -> c_int> is a correct way to represent a
+// > nullable function pointer using the C ABI (corresponding to the C type int (*)(int)).
+// So we use Option<...> here. This is the type that is passed from C code.
+#[allow(non_camel_case_types)]
+pub type rustls_verify_server_cert_callback = Option<
+    unsafe extern "C" fn(
+        userdata: rustls_verify_server_cert_user_data,
+        params: *const rustls_verify_server_cert_params,
+    ) -> u32,
+>;
+
+// This is the same as a rustls_verify_server_cert_callback after unwrapping
+// the Option (which is equivalent to checking for null).
+type VerifyCallback = unsafe extern "C" fn(
+    userdata: rustls_verify_server_cert_user_data,
+    params: *const rustls_verify_server_cert_params,
+) -> u32;
+
+// An implementation of rustls::ServerCertVerifier based on a C callback.
+struct Verifier {
+    callback: VerifyCallback,
+}
+
+/// Safety: Verifier is Send because we don't allocate or deallocate any of its
+/// fields.
+unsafe impl Send for Verifier {}
+/// Safety: Verifier is Sync if the C code that passes us a callback that
+/// obeys the concurrency safety requirements documented in
+/// rustls_client_config_builder_dangerous_set_certificate_verifier.
+unsafe impl Sync for Verifier {}
+
+impl rustls::client::ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cb = self.callback;
+        let server_name: Cow<'_, str> = match server_name {
+            rustls::ServerName::DnsName(n) => n.as_ref().into(),
+            rustls::ServerName::IpAddress(ip) => ip.to_string().into(),
+            _ => return Err(rustls::Error::General("unknown name type".to_string())),
+        };
+        let server_name: rustls_str = match server_name.as_ref().try_into() {
+            Ok(r) => r,
+            Err(NulByte {}) => return Err(rustls::Error::General("NUL byte in SNI".to_string())),
+        };
+
+        let intermediates: Vec<_> = intermediates.iter().map(|cert| cert.as_ref()).collect();
+
+        let intermediates = rustls_slice_slice_bytes {
+            inner: &intermediates,
+        };
+
+        let params = rustls_verify_server_cert_params {
+            end_entity_cert_der: end_entity.as_ref().into(),
+            intermediate_certs_der: &intermediates,
+            server_name,
+            ocsp_response: ocsp_response.into(),
+        };
+        let userdata = userdata_get().map_err(|_| {
+            rustls::Error::General("internal error with thread-local storage".to_string())
+        })?;
+        let result: u32 = unsafe { cb(userdata, &params) };
+        let result: rustls_result =
+            rustls_result::try_from(result).unwrap_or(rustls_result::General);
+        match result {
+            rustls_result::Ok => Ok(ServerCertVerified::assertion()),
+            // Results that aren't about certificates still reject the
+            // certificate, rather than looking like an internal error.
+            r => match error::cert_result_to_error(r) {
+                rustls::Error::General(_) => Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                )),
+                e => Err(e),
+            },
+        }
+    }
+}
+
+/// A TLSA record (RFC 6698) for the server, as found in DNS. See
+/// rustls_client_config_builder_set_tlsa_records().
+///
+/// `usage`, `selector` and `matching_type` are the record's first three
+/// fields, and `data` holds its certificate association data as raw bytes,
+/// not hex.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct rustls_tlsa_record<'a> {
+    pub usage: u8,
+    pub selector: u8,
+    pub matching_type: u8,
+    pub data: rustls_slice_bytes<'a>,
+}
+
+impl rustls_client_config_builder {
+    /// Set a custom server certificate verifier.
+    ///
+    /// The callback replaces certificate verification against trusted roots:
+    /// it is called once per full handshake with the server's certificate
+    /// chain as DER (the end-entity certificate, then any intermediates in
+    /// the order the server sent them), the server name the connection was
+    /// created with, and any stapled OCSP response. Handshakes that resume an
+    /// earlier session don't call it again.
+    ///
+    /// The callback runs on the thread that called
+    /// rustls_connection_process_new_packets, from inside that call. The
+    /// pointers in its rustls_verify_server_cert_params are only valid until
+    /// it returns, so it must copy anything it wants to keep.
+    /// If `userdata` has been set with rustls_connection_set_userdata, it
+    /// will be passed to the callback. Otherwise the userdata param passed to
+    /// the callback will be NULL. This is the place for per-connection state.
+    ///
+    /// The callback must return normally: unwinding out of it (for instance
+    /// a C++ exception) or leaving it with longjmp is undefined behavior.
+    ///
+    /// The callback must be safe to call on any thread at any time, including
+    /// multiple concurrent calls. So, for instance, if the callback mutates
+    /// userdata (or other shared state), it must use synchronization primitives
+    /// to make such mutation safe.
+    ///
+    /// The callback receives certificate chain information as raw bytes.
+    /// Currently this library offers no functions to parse the certificates,
+    /// so you'll need to bring your own certificate parsing library
+    /// if you need to parse them.
+    ///
+    /// If the custom verifier accepts the certificate, it should return
+    /// RUSTLS_RESULT_OK. Otherwise, it may return any other rustls_result error.
+    /// Feel free to use an appropriate error from the RUSTLS_RESULT_CERT_*
+    /// section. Any value other than RUSTLS_RESULT_OK fails the handshake;
+    /// codes outside the RUSTLS_RESULT_CERT_* section, and values that
+    /// aren't a rustls_result at all, are reported as
+    /// RUSTLS_RESULT_CERT_APPLICATION_VERIFICATION_FAILURE.
+    ///
+    /// <https://docs.rs/rustls/latest/rustls/client/struct.DangerousClientConfig.html#method.set_certificate_verifier>
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_dangerous_set_certificate_verifier(
+        config_builder: *mut rustls_client_config_builder,
+        callback: rustls_verify_server_cert_callback,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config_builder = try_mut_from_ptr!(config_builder);
+            let callback: VerifyCallback = match callback {
+                Some(cb) => cb,
+                None => return rustls_result::InvalidParameter,
+            };
+
+            let verifier: Verifier = Verifier{callback};
+            config_builder.verifier = Arc::new(verifier);
+            config_builder.root_count = 0;
+            rustls_result::Ok
+        }
+    }
+
+    /// Accept any certificate the server presents, for any server name,
+    /// without checking who issued it, whether it has expired, or whether it
+    /// has been revoked.
+    ///
+    /// DANGER: this offers no protection against an attacker who can
+    /// intercept the connection, since any certificate they present is
+    /// accepted. It is meant for testing, and for reaching devices whose
+    /// self-signed certificates can't be verified any other way.
+    ///
+    /// Everything else about the connection is unchanged: traffic is still
+    /// encrypted, the server must still prove it holds the private key for
+    /// the certificate it presented, and ALPN and session resumption work as
+    /// usual. The accepted certificates can be inspected afterwards with
+    /// rustls_connection_get_peer_certificate, for instance to log them.
+    ///
+    /// This replaces any trusted roots or custom verifier configured before.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_dangerous_disable_cert_verification(
+        config_builder: *mut rustls_client_config_builder,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config_builder = try_mut_from_ptr!(config_builder);
+            config_builder.verifier = Arc::new(NoVerification);
+            config_builder.root_count = 0;
+            rustls_result::Ok
+        }
+    }
+
+    /// Pin the server's public key: in addition to the usual verification,
+    /// require the verified certificate chain to include a certificate whose
+    /// SubjectPublicKeyInfo has one of `count` SHA-256 hashes.
+    ///
+    /// `hashes` points to `count` 32-byte hashes, each the SHA-256 digest of
+    /// a DER-encoded SubjectPublicKeyInfo, as in HTTP Public Key Pinning.
+    /// They are copied; the caller may free them once this returns.
+    ///
+    /// If `pin_leaf_only` is true, only the end-entity certificate is
+    /// checked against the pins. Otherwise an intermediate certificate sent
+    /// by the server may match too, but only if it is on a verified path
+    /// from the end-entity certificate; certificates the server sends that
+    /// aren't part of the chain don't count. So may the trusted root the
+    /// chain was verified up to, when verifying against a
+    /// rustls_root_cert_store or roots loaded from a file.
+    ///
+    /// Pinning is applied on top of whatever verification the builder ends
+    /// up with, whichever order the functions are called in; a chain that
+    /// fails that verification is reported with the usual certificate error.
+    /// A chain that verifies but doesn't match a pin fails the handshake with
+    /// RUSTLS_RESULT_PIN_VIOLATION.
+    ///
+    /// Returns `InvalidParameter` if `count` is zero. Calling this again
+    /// replaces the pins set before.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_pinned_spki_sha256(
+        config_builder: *mut rustls_client_config_builder,
+        hashes: *const [u8; 32],
+        count: size_t,
+        pin_leaf_only: bool,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config_builder = try_mut_from_ptr!(config_builder);
+            let hashes: &[[u8; 32]] = try_slice!(hashes, count);
+            if hashes.is_empty() {
+                return InvalidParameter;
+            }
+            config_builder.spki_pins = Some(SpkiPins {
+                hashes: hashes.to_vec(),
+                leaf_only: pin_leaf_only,
+            });
+            rustls_result::Ok
+        }
+    }
+
+    /// Accept only the given end-entity certificate: the certificate the
+    /// server presents must be byte-for-byte identical to the `len` bytes of
+    /// DER at `der`. This is meant for devices with long-lived self-signed
+    /// certificates.
+    ///
+    /// DANGER: nothing else about the certificate is checked. No chain is
+    /// built, so trusted roots are ignored; the server name isn't compared
+    /// with the certificate; and the certificate may have expired. The
+    /// server must still prove that it holds the certificate's private key.
+    ///
+    /// The certificate is copied; the caller may free `der` once this
+    /// returns. A server presenting any other certificate fails the
+    /// handshake with RUSTLS_RESULT_PINNED_CERT_MISMATCH.
+    ///
+    /// Returns `CertificateParseError` if `der` isn't a certificate. This
+    /// replaces any trusted roots or custom verifier configured before.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_pinned_cert_der(
+        config_builder: *mut rustls_client_config_builder,
+        der: *const u8,
+        len: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config_builder = try_mut_from_ptr!(config_builder);
+            let der: &[u8] = try_slice!(der, len);
+            if webpki::EndEntityCert::try_from(der).is_err() {
+                return rustls_result::CertificateParseError;
+            }
+            config_builder.verifier = Arc::new(PinnedCertVerifier {
+                cert: Certificate(der.to_vec()),
+            });
+            config_builder.root_count = 0;
+            rustls_result::Ok
+        }
+    }
+
+    /// Verify the server's certificate chain as usual, but don't check that
+    /// the certificate is valid for the server name the connection was
+    /// created with. This is for reaching servers by an address their
+    /// certificate doesn't name, like OpenSSL's verification without
+    /// X509_check_host.
+    ///
+    /// DANGER: any server with a certificate from a trusted root can
+    /// impersonate any other. Unlike
+    /// rustls_client_config_builder_dangerous_disable_cert_verification, the
+    /// chain is still checked, so untrusted, expired or otherwise invalid
+    /// certificates are rejected with the usual errors.
+    ///
+    /// This applies to whichever trusted roots or custom verifier the builder
+    /// ends up with, whichever order the functions are called in. With a
+    /// custom verifier, only a RUSTLS_RESULT_CERT_NOT_VALID_FOR_NAME result is
+    /// ignored. The server name is still sent in the SNI extension.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_dangerous_skip_hostname_verification(
+        config_builder: *mut rustls_client_config_builder,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config_builder = try_mut_from_ptr!(config_builder);
+            config_builder.skip_hostname_verification = true;
+            rustls_result::Ok
+        }
+    }
+
+    /// Check the validity periods of server certificates as if the current
+    /// time were `unix_seconds` seconds after the Unix epoch, instead of
+    /// reading the system clock. This is for tests with fixed or expired
+    /// certificates, and for machines whose clock can't be trusted. Passing 0
+    /// goes back to using the system clock.
+    ///
+    /// Only certificate verification sees this time; everything else, such
+    /// as session ticket lifetimes, still uses the system clock. With a
+    /// custom verifier, it is the only time the verifier is given.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_verification_time(
+        config_builder: *mut rustls_client_config_builder,
+        unix_seconds: u64,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config_builder = try_mut_from_ptr!(config_builder);
+            config_builder.verification_time = match unix_seconds {
+                0 => None,
+                secs => match SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs)) {
+                    Some(time) => Some(time),
+                    None => return InvalidParameter,
+                },
+            };
+            rustls_result::Ok
+        }
+    }
+
+    /// Check the signed certificate timestamps (SCTs) servers send against
+    /// the given certificate transparency logs. `log_keys` points to `count`
+    /// DER-encoded SubjectPublicKeyInfo structures, one per log; each log is
+    /// identified by the SHA-256 hash of its key, per RFC 6962. To use the
+    /// list bundled with rustls-ffi instead, see
+    /// rustls_client_config_builder_set_bundled_ct_logs.
+    ///
+    /// If the server sends SCTs, at least one must be valid and issued by
+    /// one of these logs, or the handshake fails with one of the
+    /// RUSTLS_RESULT_CERT_SCT_* errors. As in rustls, certificates without
+    /// SCTs are accepted, and only SCTs delivered in the TLS handshake are
+    /// checked, not ones embedded in the certificate.
+    ///
+    /// CT logs come and go, so after `validation_deadline` (in seconds since
+    /// the Unix epoch) the logs can't be relied on, and every handshake fails
+    /// with RUSTLS_RESULT_CT_LOGS_EXPIRED until the logs are replaced. Pass a
+    /// deadline shortly after the next time you expect to update the log
+    /// list.
+    ///
+    /// Passing a `count` of 0 turns CT verification back off. Returns
+    /// InvalidParameter if any log key can't be parsed or
+    /// `validation_deadline` is out of range.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_ct_logs(
+        builder: *mut rustls_client_config_builder,
+        log_keys: *const rustls_slice_bytes,
+        count: size_t,
+        validation_deadline: u64,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let log_keys: &[rustls_slice_bytes] = try_slice!(log_keys, count);
+            if log_keys.is_empty() {
+                config.ct_policy = None;
+                return rustls_result::Ok;
+            }
+            let validation_deadline = match SystemTime::UNIX_EPOCH
+                .checked_add(Duration::from_secs(validation_deadline))
+            {
+                Some(deadline) => deadline,
+                None => return InvalidParameter,
+            };
+            let mut logs = Vec::with_capacity(log_keys.len());
+            for spki in log_keys {
+                let spki: &[u8] = try_slice!(spki.data, spki.len);
+                let key = match spki_public_key(spki) {
+                    Some(key) => key,
+                    None => return InvalidParameter,
+                };
+                let mut id = [0; 32];
+                id.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, spki).as_ref());
+                logs.push(CtLog {
+                    id,
+                    key: key.to_vec(),
+                });
+            }
+            config.ct_policy = Some(CtPolicy {
+                logs,
+                validation_deadline,
+            });
+            rustls_result::Ok
+        }
+    }
+
+    /// Check the signed certificate timestamps servers send against the
+    /// certificate transparency logs bundled with rustls-ffi, from the
+    /// ct-logs crate, as rustls_client_config_builder_set_ct_logs does for
+    /// logs the caller provides. This replaces any logs set earlier.
+    ///
+    /// The bundled list only changes when rustls-ffi is rebuilt, so logs
+    /// started since then are unknown and SCTs from them are rejected. Use
+    /// rustls_bundled_ct_logs_timestamp to decide whether the list is recent
+    /// enough, and pick `validation_deadline` accordingly.
+    ///
+    /// This requires rustls-ffi to be built with the `bundled_ct_logs` cargo
+    /// feature. Otherwise it always returns Unsupported and leaves the
+    /// builder unchanged.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_bundled_ct_logs(
+        builder: *mut rustls_client_config_builder,
+        validation_deadline: u64,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let logs = match bundled_ct_logs() {
+                Some((logs, _)) => logs,
+                None => return rustls_result::Unsupported,
+            };
+            let validation_deadline = match SystemTime::UNIX_EPOCH
+                .checked_add(Duration::from_secs(validation_deadline))
+            {
+                Some(deadline) => deadline,
+                None => return InvalidParameter,
+            };
+            config.ct_policy = Some(CtPolicy {
+                logs,
+                validation_deadline,
+            });
+            rustls_result::Ok
+        }
+    }
+
+    /// Authenticate servers with the `count` TLSA records at `records`
+    /// (DANE, RFC 6698 and RFC 7671). The caller is responsible for looking
+    /// the records up and validating them with DNSSEC; since they belong to
+    /// one server's name and port, a config with TLSA records should only be
+    /// used for connections to that server. The records are copied; the
+    /// caller may free them once this returns.
+    ///
+    /// The server is accepted if any record matches:
+    ///
+    /// - DANE-EE (3): the end-entity certificate matches. Nothing else is
+    ///   checked, not even the server name or the validity period.
+    /// - DANE-TA (2): a certificate the server sent matches, and the
+    ///   end-entity certificate verifies with it as the only trust anchor,
+    ///   including the server name and validity checks.
+    /// - PKIX-EE (1) and PKIX-TA (0): as DANE-EE and DANE-TA, and the chain
+    ///   also passes the builder's usual verification, against its trusted
+    ///   roots or custom verifier.
+    ///
+    /// DANE-EE and DANE-TA records replace the usual verification; with
+    /// only those, no trusted roots are needed. If no record matches, the
+    /// handshake fails with RUSTLS_RESULT_TLSA_MISMATCH. If a record matches
+    /// but the chain then fails verification, that failure is reported
+    /// instead, with the usual certificate error.
+    ///
+    /// Records with an unknown usage, selector or matching type, or with a
+    /// digest of the wrong length, are unusable and ignored. If no record is
+    /// usable, or `count` is 0, servers are verified as if this had never
+    /// been called, as RFC 6698 section 4.1 requires. Calling this again
+    /// replaces the records set before.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_tlsa_records(
+        config_builder: *mut rustls_client_config_builder,
+        records: *const rustls_tlsa_record,
+        count: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config_builder = try_mut_from_ptr!(config_builder);
+            let records: &[rustls_tlsa_record] = try_slice!(records, count);
+            let mut usable = Vec::with_capacity(records.len());
+            for record in records {
+                let data: &[u8] = try_slice!(record.data.data, record.data.len);
+                if let Some(record) =
+                    TlsaRecord::new(record.usage, record.selector, record.matching_type, data)
+                {
+                    usable.push(record);
+                }
+            }
+            config_builder.tlsa_records = usable;
+            rustls_result::Ok
+        }
+    }
+
+    /// Use the trusted root certificates from the provided store.
+    ///
+    /// This replaces any trusted roots already configured. Builders and
+    /// configs that use the same store share one copy of its roots instead
+    /// of each copying them. Certificates added to `roots` later are not
+    /// seen by this builder. `roots` may be freed as soon as this returns.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_use_roots(
+        config_builder: *mut rustls_client_config_builder,
+        roots: *const rustls_root_cert_store,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let builder = try_mut_from_ptr!(config_builder);
+            let root_store: &RootStore = try_ref_from_ptr!(roots);
+            builder.verifier = root_store.server_cert_verifier();
+            builder.root_count = root_store.roots().len();
+            rustls_result::Ok
+        }
+    }
+
+    /// Add trusted root certificates from the named file, which should contain
+    /// PEM-formatted certificates. This replaces any trusted roots already
+    /// configured.
+    ///
+    /// On Unix, `filename` is used as raw bytes and need not be valid UTF-8.
+    /// Elsewhere it must be UTF-8.
+    ///
+    /// Returns RUSTLS_RESULT_IO if the file can't be opened or read,
+    /// RUSTLS_RESULT_NO_CERTIFICATES_FOUND if it contains no certificates,
+    /// and RUSTLS_RESULT_CERTIFICATE_PARSE_ERROR if it is malformed or any of
+    /// the certificates in it could not be parsed. Since no certificate is
+    /// ever silently skipped, this behaves like the `strict` mode of the
+    /// rustls_root_cert_store loaders; to load the usable certificates from a
+    /// file and count the rest, use rustls_root_cert_store_add_pem.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_load_roots_from_file(
+        config_builder: *mut rustls_client_config_builder,
+        filename: *const c_char,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config_builder = try_mut_from_ptr!(config_builder);
+            let filename: &CStr = unsafe {
+                if filename.is_null() {
+                    return rustls_result::NullParameter;
+                }
+                CStr::from_ptr(filename)
+            };
+
+            let filename: &OsStr = match os_str_from_bytes(filename.to_bytes()) {
+                Some(f) => f,
+                None => return rustls_result::Io,
+            };
+            let mut cafile = match File::open(filename) {
+                Ok(f) => f,
+                Err(_) => return rustls_result::Io,
+            };
+
+            let mut bufreader = BufReader::new(&mut cafile);
+            let certs = match rustls_pemfile::certs(&mut bufreader) {
+                Ok(certs) => certs,
+                Err(e) if e.kind() == ErrorKind::InvalidData => return rustls_result::CertificateParseError,
+                Err(_) => return rustls_result::Io,
+            };
+            if certs.is_empty() {
+                return rustls_result::NoCertificatesFound;
+            }
+
+            let mut roots = Roots::empty();
+            let (_, failed) = roots.add_parsable_certificates(&certs);
+            if failed > 0 {
+                return rustls_result::CertificateParseError;
+            }
+
+            config_builder.root_count = roots.len();
+            config_builder.verifier = Arc::new(RootsVerifier::new(roots));
+            rustls_result::Ok
+        }
+    }
+
+    /// Set the ALPN protocol list to the given protocols. `protocols` must point
+    /// to a buffer of `rustls_slice_bytes` (built by the caller) with `len`
+    /// elements. Each element of the buffer must be a rustls_slice_bytes whose
+    /// data field points to a single ALPN protocol ID. Standard ALPN protocol
+    /// IDs are defined at
+    /// <https://www.iana.org/assignments/tls-extensiontype-values/tls-extensiontype-values.xhtml#alpn-protocol-ids>.
+    ///
+    /// This function makes a copy of the data in `protocols` and does not retain
+    /// any pointers, so the caller can free the pointed-to memory after calling.
+    /// If any element has a NULL `data` field, this returns
+    /// RUSTLS_RESULT_NULL_PARAMETER and leaves the configured list unchanged.
+    ///
+    /// <https://docs.rs/rustls/latest/rustls/client/struct.ClientConfig.html#structfield.alpn_protocols>
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_alpn_protocols(
+        builder: *mut rustls_client_config_builder,
+        protocols: *const rustls_slice_bytes,
+        len: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let protocols: &[rustls_slice_bytes] = try_slice!(protocols, len);
+
+            let mut vv: Vec<Vec<u8>> = Vec::with_capacity(protocols.len());
+            for p in protocols {
+                let v: &[u8] = try_slice!(p.data, p.len);
+                vv.push(v.to_vec());
+            }
+            config.alpn_protocols = vv;
+            rustls_result::Ok
+        }
+    }
+
+    /// Enable or disable SNI. SNI is enabled by default.
+    ///
+    /// When disabled, the ClientHello carries no server_name extension, so a
+    /// server that picks its certificate by SNI will present its default
+    /// certificate. The name passed to rustls_client_connection_new is still
+    /// required, and the server's certificate is still verified against it.
+    /// <https://docs.rs/rustls/latest/rustls/struct.ClientConfig.html#structfield.enable_sni>
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_enable_sni(
+        config: *mut rustls_client_config_builder,
+        enable: bool,
+    ) {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(config);
+            config.enable_sni = enable;
+        }
+    }
+
+    /// Never resume TLS sessions: connections made with the built config
+    /// neither store nor offer session IDs or tickets, so every connection
+    /// performs a full handshake.
+    /// <https://docs.rs/rustls/latest/rustls/client/struct.Resumption.html#method.disabled>
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_disable_resumption(
+        builder: *mut rustls_client_config_builder,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            config.session_store = None;
+            rustls_result::Ok
+        }
+    }
+
+    /// Remember TLS sessions for up to `max_entries` servers, so that later
+    /// connections to them can resume. When the cache is full, the server
+    /// added to it longest ago is forgotten. Memory is only allocated as
+    /// servers are added, so very large values are fine. Passing 0 is the same
+    /// as rustls_client_config_builder_disable_resumption.
+    ///
+    /// This replaces the default cache, rustls's in-memory cache of 256
+    /// entries, where each TLS 1.3 ticket, TLS 1.2 session and key exchange
+    /// hint takes one entry; it also replaces any earlier call to this
+    /// function or rustls_client_config_builder_disable_resumption.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_session_cache_size(
+        builder: *mut rustls_client_config_builder,
+        max_entries: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            config.session_store = match max_entries {
+                0 => None,
+                n => Some(Arc::new(ClientSessionCache::new(n))),
+            };
+            rustls_result::Ok
+        }
+    }
+
+    /// Enable or disable offering early data (0-RTT) to servers. Early data
+    /// is disabled by default.
+    ///
+    /// Early data can only be sent when resuming a TLS 1.3 session with a
+    /// server that allowed it, so the first connection to a server never
+    /// offers it. Enabling this only makes the client offer early data;
+    /// nothing is sent as early data unless the application writes it with
+    /// rustls_client_connection_write_early_data.
+    ///
+    /// Early data is not protected against replay, so it should only carry
+    /// requests that are safe to repeat.
+    /// <https://docs.rs/rustls/latest/rustls/client/struct.ClientConfig.html#structfield.enable_early_data>
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_enable_early_data(
+        builder: *mut rustls_client_config_builder,
+        enable: bool,
+    ) {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            config.enable_early_data = enable;
+        }
+    }
+
+    /// Limit the size of the TLS records this client emits to `max_fragment_size`
+    /// bytes, including the 5-byte record header. Encryption adds its own
+    /// overhead on top of this, so records may be a few dozen bytes larger on
+    /// the wire. Pass 0 to remove the limit, which is the default.
+    ///
+    /// Returns InvalidParameter if `max_fragment_size` is not between 32 and
+    /// 16389, the range rustls supports.
+    /// <https://docs.rs/rustls/latest/rustls/client/struct.ClientConfig.html#structfield.max_fragment_size>
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_max_fragment_size(
+        builder: *mut rustls_client_config_builder,
+        max_fragment_size: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            config.max_fragment_size = match max_fragment_size {
+                0 => None,
+                32..=16389 => Some(max_fragment_size),
+                _ => return InvalidParameter,
+            };
+            rustls_result::Ok
+        }
+    }
+
+    /// Append the secrets of every TLS session made with this config to the
+    /// file named by the SSLKEYLOGFILE environment variable, in the NSS key
+    /// log format understood by Wireshark.
+    ///
+    /// WARNING: this writes secrets to disk. Anyone who can read the file can
+    /// decrypt the traffic of every logged session. Only use it for debugging.
+    ///
+    /// Returns EnvironmentNotSet if SSLKEYLOGFILE is not set, and Io if the
+    /// file cannot be opened for appending. Either way no key log is
+    /// installed.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_enable_keylog_file(
+        builder: *mut rustls_client_config_builder,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let path = match std::env::var_os(SSLKEYLOGFILE) {
+                Some(path) => path,
+                None => return rustls_result::EnvironmentNotSet,
+            };
+            match FileKeyLog::open(&path) {
+                Ok(key_log) => config.key_log = Some(KeyLogSetting::Log(Arc::new(key_log))),
+                Err(_) => return rustls_result::Io,
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Like rustls_client_config_builder_enable_keylog_file, but append to
+    /// the file at `filename` regardless of SSLKEYLOGFILE.
+    ///
+    /// WARNING: this writes secrets to disk. Anyone who can read the file can
+    /// decrypt the traffic of every logged session. Only use it for debugging.
+    ///
+    /// Returns Io if the file cannot be opened for appending, in which case
+    /// no key log is installed.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_keylog_file(
+        builder: *mut rustls_client_config_builder,
+        filename: *const c_char,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let filename: &CStr = unsafe {
+                if filename.is_null() {
+                    return rustls_result::NullParameter;
+                }
+                CStr::from_ptr(filename)
+            };
+            let filename: &OsStr = match os_str_from_bytes(filename.to_bytes()) {
+                Some(f) => f,
+                None => return rustls_result::Io,
+            };
+            match FileKeyLog::open(filename) {
+                Ok(key_log) => config.key_log = Some(KeyLogSetting::Log(Arc::new(key_log))),
+                Err(_) => return rustls_result::Io,
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Pass the secrets of every TLS session made with this config to
+    /// `callback`, along with `userdata`, or the config userdata (see
+    /// rustls_client_config_builder_set_userdata) if `userdata` is NULL. See
+    /// rustls_keylog_log_callback for the requirements on the callback.
+    /// Passing NULL for `callback` removes any key log installed on the
+    /// builder.
+    ///
+    /// WARNING: anything that sees these secrets can decrypt the traffic of
+    /// the sessions they belong to. Only use this for debugging.
+    ///
+    /// Any key log set by rustls_client_config_builder_enable_keylog_file or
+    /// rustls_client_config_builder_set_keylog_file is replaced.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_keylog_callback(
+        builder: *mut rustls_client_config_builder,
+        callback: rustls_keylog_log_callback,
+        userdata: *mut c_void,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            config.key_log =
+                callback.map(|cb| KeyLogSetting::Callback(CallbackKeyLog::new(cb, userdata)));
+            rustls_result::Ok
+        }
+    }
+
+    /// Enable or disable TLS 1.2 session tickets (RFC 5077). Tickets are
+    /// enabled by default. When disabled, TLS 1.2 sessions can still be
+    /// resumed by session ID, provided the server keeps a session cache.
+    ///
+    /// This only affects TLS 1.2. TLS 1.3 always resumes with tickets, and is
+    /// unaffected by this setting. Note that while TLS 1.3 is enabled, the
+    /// ClientHello still asks for a ticket, since it may be answered in TLS
+    /// 1.3; use rustls_client_config_builder_new_custom to allow only TLS 1.2
+    /// if the extension must not be sent at all.
+    /// <https://docs.rs/rustls/latest/rustls/client/enum.Tls12Resumption.html>
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_enable_tickets(
+        builder: *mut rustls_client_config_builder,
+        enable: bool,
+    ) {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            config.enable_tickets = enable;
+        }
+    }
+
+    /// Present a client certificate to servers that ask for one.
+    ///
+    /// `cert_chain_pem` must point to `chain_len` bytes of PEM-encoded
+    /// certificates, with the end-entity (leaf) certificate first, and
+    /// `key_pem` to `key_len` bytes holding the PEM-encoded private key for
+    /// the leaf, in PKCS#8, PKCS#1 (RSA) or SEC1 (EC) format; see
+    /// rustls_certified_key_build for how the key is chosen. Both are
+    /// copied; the caller may free them once this returns.
+    ///
+    /// Returns `CertificateParseError` if the chain can't be parsed or is
+    /// empty, `NoPrivateKeyFound`, `UnsupportedPrivateKeyType` or
+    /// `PrivateKeyParseError` if there's no usable key, and
+    /// `CertKeyMismatch` if the key doesn't belong to the leaf certificate.
+    /// On error the builder is unchanged.
+    ///
+    /// This replaces any certified keys or client authentication callback
+    /// configured before.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_client_cert_pem(
+        builder: *mut rustls_client_config_builder,
+        cert_chain_pem: *const u8,
+        chain_len: size_t,
+        key_pem: *const u8,
+        key_len: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let certified_key = match rustls_certified_key::certified_key_build(
+                cert_chain_pem, chain_len, key_pem, key_len) {
+                Ok(key) => key,
+                Err(rr) => return rr,
+            };
+            config.set_single_client_cert(certified_key)
+        }
+    }
+
+    /// Present a client certificate to servers that ask for one, from DER.
+    ///
+    /// `certs_der` and `cert_lens` are parallel arrays of `cert_count`
+    /// entries: `certs_der[i]` points to `cert_lens[i]` bytes holding one
+    /// DER-encoded certificate. The chain is sent in the order given, so the
+    /// end-entity (leaf) certificate must come first. `key_der` points to
+    /// `key_len` bytes holding the leaf's private key as DER-encoded PKCS#8,
+    /// PKCS#1 (RSA) or SEC1 (EC). Everything is copied; the caller may free
+    /// the buffers once this returns. The copy of the key bytes is zeroed
+    /// once the key has been parsed; the parsed key itself is held by the
+    /// crypto library and isn't zeroed when the config is freed.
+    ///
+    /// Returns `InvalidParameter` if `cert_count` is zero or any certificate
+    /// is empty, `CertificateParseError` if the leaf can't be parsed,
+    /// `UnsupportedPrivateKeyType` if the key uses an algorithm rustls can't
+    /// sign with, `PrivateKeyParseError` if the key can't be parsed, and
+    /// `CertKeyMismatch` if the key doesn't belong to the leaf. On error
+    /// the builder is unchanged.
+    ///
+    /// This replaces any certified keys or client authentication callback
+    /// configured before.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_client_cert_der(
+        builder: *mut rustls_client_config_builder,
+        certs_der: *const *const u8,
+        cert_lens: *const size_t,
+        cert_count: size_t,
+        key_der: *const u8,
+        key_len: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let certs_der: &[*const u8] = try_slice!(certs_der, cert_count);
+            let cert_lens: &[size_t] = try_slice!(cert_lens, cert_count);
+            let key_der: &[u8] = try_slice!(key_der, key_len);
+            let mut chain: Vec<Certificate> = Vec::with_capacity(cert_count);
+            for (&der, &len) in certs_der.iter().zip(cert_lens) {
+                let der: &[u8] = try_slice!(der, len);
+                if der.is_empty() {
+                    return InvalidParameter;
+                }
+                chain.push(Certificate(der.to_vec()));
+            }
+            let certified_key = match certified_key_from_der(chain, key_der) {
+                Ok(key) => key,
+                Err(rr) => return rr,
+            };
+            config.set_single_client_cert(certified_key)
+        }
+    }
+
+    /// Register a callback that chooses the client certificate to send each
+    /// time a server asks for one, for instance to present a different
+    /// identity to each backend. If `userdata` has been set with
+    /// rustls_connection_set_userdata, it will be passed to the callback.
+    /// Otherwise the userdata param passed to the callback will be NULL.
+    ///
+    /// If the callback returns NULL the handshake continues without a client
+    /// certificate; whether that succeeds is up to the server. See
+    /// rustls_client_cert_resolver_callback for the rules the callback must
+    /// follow. It is not permitted to pass a NULL value for `callback`.
+    ///
+    /// This replaces any certified keys or client certificate configured
+    /// before, and vice versa.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_client_cert_resolver(
+        builder: *mut rustls_client_config_builder,
+        callback: rustls_client_cert_resolver_callback,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let callback: ClientCertResolverCallback = match callback {
+                Some(cb) => cb,
+                None => return rustls_result::NullParameter,
+            };
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            config.cert_resolver = Some(Arc::new(ClientCertResolver { callback }));
+            rustls_result::Ok
+        }
+    }
+
+    /// Provide the configuration a list of certificates where the connection
+    /// will select the first one that is compatible with the server's signature
+    /// verification capabilities. Clients that want to support both ECDSA and
+    /// RSA certificates will want the ECSDA to go first in the list.
+    ///
+    /// The built configuration will keep a reference to all certified keys
+    /// provided. The client may `rustls_certified_key_free()` afterwards
+    /// without the configuration losing them. The same certified key may also
+    /// be used in multiple configs.
+    ///
+    /// EXPERIMENTAL: installing a client authentication callback will replace any
+    /// configured certified keys and vice versa.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_set_certified_key(
+        builder: *mut rustls_client_config_builder,
+        certified_keys: *const *const rustls_certified_key,
+        certified_keys_len: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            let keys_ptrs: &[*const rustls_certified_key] = try_slice!(certified_keys, certified_keys_len);
+            let mut keys: Vec<Arc<CertifiedKey>> = Vec::new();
+            for &key_ptr in keys_ptrs {
+                let certified_key: Arc<CertifiedKey> = try_arc_from_ptr!(key_ptr);
+                keys.push(certified_key);
+            }
+            config.cert_resolver = Some(Arc::new(ResolvesClientCertFromChoices { keys }));
+            rustls_result::Ok
+        }
+    }
+}
+
+/// Always send the same client certificate.
+struct ResolvesClientCertFromChoices {
+    keys: Vec<Arc<CertifiedKey>>,
+}
+
+impl ResolvesClientCert for ResolvesClientCertFromChoices {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        sig_schemes: &[rustls::SignatureScheme],
+    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        for key in self.keys.iter() {
+            if key.key.choose_scheme(sig_schemes).is_some() {
+                return Some(key.clone());
+            }
+        }
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        !self.keys.is_empty()
+    }
+}
+
+/// A server's request for a client certificate, as passed to a
+/// rustls_client_cert_resolver_callback.
+///
+/// `acceptable_issuers` holds the DER-encoded distinguished names of the
+/// certificate authorities the server will accept, and may be empty.
+/// `signature_schemes` holds the TLS signature schemes the server supports.
+///
+/// The memory exposed is only available for the duration of the callback.
+#[repr(C)]
+pub struct rustls_client_cert_request<'a> {
+    pub acceptable_issuers: *const rustls_slice_slice_bytes<'a>,
+    pub signature_schemes: rustls_slice_u16<'a>,
+}
+
+/// Any context information the callback will receive when invoked.
+pub type rustls_client_cert_resolver_userdata = *mut c_void;
+
+/// Prototype of a callback that chooses the client certificate to send when a
+/// server asks for one. `userdata` will be set based on
+/// rustls_connection_set_userdata.
+///
+/// The callback returns a `rustls_certified_key` to authenticate with, or
+/// NULL to continue the handshake without a client certificate. The
+/// connection takes its own reference to a returned key, so the callback may
+/// free it at any time afterwards.
+///
+/// NOTE:
+/// - the passed in `request` and all its values are only available during the
+///   callback invocation.
+/// - the callback runs inside rustls_connection_process_new_packets, on the
+///   thread that called it. It must not call back into rustls-ffi functions
+///   on the same connection.
+/// - the passed callback function must be safe to call multiple times concurrently
+///   with the same userdata, unless there is only a single config and connection
+///   where it is installed.
+pub type rustls_client_cert_resolver_callback = Option<
+    unsafe extern "C" fn(
+        userdata: rustls_client_cert_resolver_userdata,
+        request: *const rustls_client_cert_request,
+    ) -> *const rustls_certified_key,
+>;
+
+// This is the same as a rustls_client_cert_resolver_callback after unwrapping
+// the Option (which is equivalent to checking for null).
+type ClientCertResolverCallback = unsafe extern "C" fn(
+    userdata: rustls_client_cert_resolver_userdata,
+    request: *const rustls_client_cert_request,
+) -> *const rustls_certified_key;
+
+/// Implementation of rustls::ResolvesClientCert that asks a C callback
+/// which certificate to send.
+struct ClientCertResolver {
+    callback: ClientCertResolverCallback,
+}
+
+impl ResolvesClientCert for ClientCertResolver {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sig_schemes: &[rustls::SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        let acceptable_issuers = rustls_slice_slice_bytes {
+            inner: acceptable_issuers,
+        };
+        let mapped_sigs: Vec<u16> = sig_schemes.iter().map(|s| s.get_u16()).collect();
+        let request = rustls_client_cert_request {
+            acceptable_issuers: &acceptable_issuers,
+            signature_schemes: (&*mapped_sigs).into(),
+        };
+
+        let cb = self.callback;
+        let userdata = match userdata_get() {
+            Ok(u) => u,
+            Err(_) => return None,
+        };
+        let key_ptr: *const rustls_certified_key = unsafe { cb(userdata, &request) };
+        let certified_key: Arc<CertifiedKey> = try_arc_from_ptr!(key_ptr);
+        Some(certified_key)
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// This struct can be considered thread safe, as long
+/// as the registered callbacks are thread safe. This is
+/// documented as a requirement in the API.
+unsafe impl Sync for ClientCertResolver {}
+unsafe impl Send for ClientCertResolver {}
+
+impl ClientConfigBuilder {
+    /// Send `certified_key` to servers that ask for a client certificate,
+    /// after checking that its key matches its leaf certificate.
+    fn set_single_client_cert(&mut self, certified_key: CertifiedKey) -> rustls_result {
+        if let Err(rr) = certified_key_matches(&certified_key) {
+            return rr;
+        }
+        self.cert_resolver = Some(Arc::new(ResolvesClientCertFromChoices {
+            keys: vec![Arc::new(certified_key)],
+        }));
+        rustls_result::Ok
+    }
+
+    fn build(self) -> Result<BuiltClientConfig, rustls_result> {
+        let base = ClientConfigBuilder::base(&self.cipher_suites, &self.versions)?;
+        let skip_hostname_verification = self.skip_hostname_verification;
+        let mut verifier = self.verifier;
+        if skip_hostname_verification {
+            verifier = Arc::new(SkipHostnameVerifier { inner: verifier });
+        }
+        if !self.tlsa_records.is_empty() {
+            verifier = Arc::new(DaneVerifier {
+                inner: verifier,
+                records: self.tlsa_records,
+                skip_hostname_verification,
+            });
+        }
+        if let Some(policy) = self.ct_policy {
+            verifier = Arc::new(CtVerifier {
+                inner: verifier,
+                policy,
+            });
+        }
+        if let Some(pins) = self.spki_pins {
+            verifier = Arc::new(PinningVerifier {
+                inner: verifier,
+                pins,
+                skip_hostname_verification,
+            });
+        }
+        // Outermost, so that every check above sees the same time.
+        if let Some(time) = self.verification_time {
+            verifier = Arc::new(FixedTimeVerifier {
+                inner: verifier,
+                time,
+            });
+        }
+        let verifier = Arc::new(VerifyNameVerifier { inner: verifier });
+        let config = base.with_custom_certificate_verifier(verifier);
+        let mut config = match self.cert_resolver {
+            Some(r) => config.with_client_cert_resolver(r),
+            None => config.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn_protocols;
+        config.enable_sni = self.enable_sni;
+        config.enable_early_data = self.enable_early_data;
+        config.max_fragment_size = self.max_fragment_size;
+        match self.key_log {
+            Some(KeyLogSetting::Log(key_log)) => config.key_log = key_log,
+            Some(KeyLogSetting::Callback(key_log)) => {
+                config.key_log = Arc::new(key_log.or_userdata(self.userdata))
+            }
+            None => {}
+        }
+        config.resumption = match self.session_store {
+            Some(store) => Resumption::store(Arc::new(VerifyNameSessionStore { inner: store }))
+                .tls12_resumption(if self.enable_tickets {
+                    Tls12Resumption::SessionIdOrTickets
+                } else {
+                    Tls12Resumption::SessionIdOnly
+                }),
+            None => Resumption::disabled(),
+        };
+        let versions = self.versions;
+        // rustls keeps suites that no enabled version can use, but they
+        // are never offered.
+        let cipher_suites = self
+            .cipher_suites
+            .into_iter()
+            .filter(|cs| versions.contains(&cs.version()))
+            .collect();
+        Ok(BuiltClientConfig {
+            config: Arc::new(config),
+            userdata: self.userdata,
+            versions,
+            cipher_suites,
+            root_count: self.root_count,
+        })
+    }
+}
+
+impl rustls_client_config_builder {
+    /// Turn a *rustls_client_config_builder (mutable) into a const *rustls_client_config
+    /// (read-only). Returns NULL if the builder's settings can't be combined
+    /// into a valid configuration; use rustls_client_config_builder_build_ex
+    /// to find out why.
+    ///
+    /// This consumes the builder: it is freed whether or not building
+    /// succeeds, and must not be passed to any other function afterwards. The
+    /// resulting config can't be changed, so it is safe to share between
+    /// threads and connections.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_build(
+        builder: *mut rustls_client_config_builder,
+    ) -> *const rustls_client_config {
+        ffi_panic_boundary! {
+            let builder: Box<ClientConfigBuilder> = try_box_from_ptr!(builder);
+            match builder.build() {
+                Ok(config) => config_to_c(config),
+                Err(_) => null(),
+            }
+        }
+    }
+
+    /// Build a rustls_client_config as rustls_client_config_builder_build
+    /// does, reporting why that failed. Returns InvalidParameter if the
+    /// builder's cipher suites and protocol versions can't be combined. On
+    /// success the memory pointed to by `config_out` is set to the new
+    /// config, which the caller frees with rustls_client_config_free;
+    /// otherwise it is left unchanged.
+    ///
+    /// Like rustls_client_config_builder_build, this consumes the builder
+    /// whether or not building succeeds, unless `config_out` is NULL.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_build_ex(
+        builder: *mut rustls_client_config_builder,
+        config_out: *mut *const rustls_client_config,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            if config_out.is_null() {
+                return NullParameter;
+            }
+            let builder: Box<ClientConfigBuilder> = try_box_from_ptr!(builder);
+            match builder.build() {
+                Ok(config) => {
+                    unsafe {
+                        *config_out = config_to_c(config);
+                    }
+                    rustls_result::Ok
+                }
+                Err(rr) => rr,
+            }
+        }
+    }
+
+    /// "Free" a client_config_builder without building it into a rustls_client_config.
+    /// Normally builders are built into rustls_client_config via `rustls_client_config_builder_build`
+    /// and may not be free'd or otherwise used afterwards.
+    /// Use free only when the building of a config has to be aborted before a config
+    /// was created.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_builder_free(config: *mut rustls_client_config_builder) {
+        ffi_panic_boundary! {
+            BoxCastPtr::to_box(config);
+        }
+    }
+}
+
+/// When the certificate transparency log list used by
+/// rustls_client_config_builder_set_bundled_ct_logs was published, in
+/// seconds since the Unix epoch, or 0 if rustls-ffi was built without the
+/// `bundled_ct_logs` cargo feature.
+#[no_mangle]
+pub extern "C" fn rustls_bundled_ct_logs_timestamp() -> u64 {
+    match bundled_ct_logs() {
+        Some((_, timestamp)) => timestamp,
+        None => 0,
+    }
+}
+
+impl rustls_client_config {
+    /// Take another reference to `config`, so that it stays usable until a
+    /// matching rustls_client_config_free. This suits bindings whose wrapper
+    /// objects can share a config and be released independently: each takes
+    /// its own reference and frees it when done. The same pointer is used for
+    /// every reference. Calling with NULL is fine.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_incref(config: *const rustls_client_config) {
+        ffi_panic_boundary! {
+            if config.is_null() {
+                return;
+            }
+            #[cfg(debug_assertions)]
+            {
+                let mut c_refs = config_c_refs();
+                let held = c_refs.get_mut(&(config as usize));
+                debug_assert!(held.is_some(), "rustls_client_config_incref: config already freed");
+                if let Some(n) = held {
+                    *n += 1;
+                }
+            }
+            rustls_client_config::incref(config);
+        }
+    }
+
+    /// Release one reference to a rustls_client_config: the one returned from
+    /// rustls_client_config_builder_build, or one taken with
+    /// rustls_client_config_incref. Since rustls_client_config is actually an
+    /// atomically reference-counted pointer, extant client connections may still
+    /// hold an internal reference to the Rust object, which is dropped once
+    /// the last reference goes. C code must consider the pointer unusable
+    /// once it has released every reference it took.
+    /// Calling with NULL is fine. Releasing more references than were taken
+    /// is undefined behavior. Debug builds of rustls-ffi check for it, and
+    /// panic, with the panic caught and nothing freed, rather than release a
+    /// reference that C does not hold.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_free(config: *const rustls_client_config) {
+        ffi_panic_boundary! {
+            if config.is_null() {
+                return;
+            }
+            #[cfg(debug_assertions)]
+            {
+                let mut c_refs = config_c_refs();
+                let held = c_refs.get(&(config as usize)).copied();
+                debug_assert!(held.is_some(), "rustls_client_config_free: reference count underflow");
+                match held {
+                    Some(1) => c_refs.remove(&(config as usize)),
+                    Some(n) => c_refs.insert(config as usize, n - 1),
+                    None => None,
+                };
+            }
+            rustls_client_config::free(config);
+        }
+    }
+
+    /// Write the TLS protocol versions enabled in `config` to `versions_out`,
+    /// as the integers defined in rustls_tls_version, and store how many
+    /// were written in *out_n. Returns RUSTLS_RESULT_INSUFFICIENT_SIZE, with
+    /// *out_n set to 0, if there are more than `count` of them.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_get_versions(
+        config: *const rustls_client_config,
+        versions_out: *mut u16,
+        count: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &BuiltClientConfig = try_ref_from_ptr!(config);
+            if versions_out.is_null() || out_n.is_null() {
+                return NullParameter
+            }
+            let versions = &config.versions;
+            if versions.len() > count {
+                unsafe {
+                    *out_n = 0;
+                }
+                return rustls_result::InsufficientSize;
+            }
+            for (i, version) in versions.iter().enumerate() {
+                unsafe {
+                    *versions_out.add(i) = version.version.get_u16();
+                }
+            }
+            unsafe {
+                *out_n = versions.len();
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Return the number of ALPN protocols `config` offers, or 0 if `config`
+    /// is NULL.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_get_alpn_count(
+        config: *const rustls_client_config,
+    ) -> size_t {
+        ffi_panic_boundary! {
+            let config: &BuiltClientConfig = try_ref_from_ptr!(config);
+            config.config.alpn_protocols.len()
+        }
+    }
+
+    /// Write the ALPN protocol at position `index` in `config`'s preference
+    /// order to `buf`, and store its length in *out_n. Returns
+    /// RUSTLS_RESULT_NOT_FOUND if `index` is not less than
+    /// rustls_client_config_get_alpn_count, and
+    /// RUSTLS_RESULT_INSUFFICIENT_SIZE if the protocol is longer than `count`.
+    /// On error, *out_n is set to 0.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_get_alpn(
+        config: *const rustls_client_config,
+        index: size_t,
+        buf: *mut u8,
+        count: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let config: &BuiltClientConfig = try_ref_from_ptr!(config);
+            if buf.is_null() || out_n.is_null() {
+                return NullParameter
+            }
+            let (protocol, result) = match config.config.alpn_protocols.get(index) {
+                Some(p) if p.len() > count => (None, rustls_result::InsufficientSize),
+                Some(p) => (Some(p), rustls_result::Ok),
+                None => (None, rustls_result::NotFound),
+            };
+            unsafe {
+                match protocol {
+                    Some(p) => {
+                        std::ptr::copy_nonoverlapping(p.as_ptr(), buf, p.len());
+                        *out_n = p.len();
+                    }
+                    None => *out_n = 0,
+                }
+            }
+            result
+        }
+    }
+
+    /// Return the number of cipher suites `config` offers, or 0 if `config`
+    /// is NULL. Suites that none of the enabled protocol versions can use
+    /// are not counted.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_get_ciphersuite_count(
+        config: *const rustls_client_config,
+    ) -> size_t {
+        ffi_panic_boundary! {
+            let config: &BuiltClientConfig = try_ref_from_ptr!(config);
+            config.cipher_suites.len()
+        }
+    }
+
+    /// Return the cipher suite at position `index` in `config`'s preference
+    /// order, or NULL if `index` is not less than
+    /// rustls_client_config_get_ciphersuite_count. Like the result of
+    /// rustls_all_ciphersuites_get_entry, the returned pointer is valid for
+    /// the lifetime of the program.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_get_ciphersuite(
+        config: *const rustls_client_config,
+        index: size_t,
+    ) -> *const rustls_supported_ciphersuite {
+        ffi_panic_boundary! {
+            let config: &BuiltClientConfig = try_ref_from_ptr!(config);
+            let suite = match config.cipher_suites.get(index) {
+                Some(cs) => cs,
+                None => return null(),
+            };
+            for cs in ALL_CIPHER_SUITES {
+                // This type annotation is here to enforce the lifetime stated
+                // in the doccomment - that the returned pointer lives as long
+                // as the program.
+                let cs: &'static SupportedCipherSuite = cs;
+                if suite == cs {
+                    return cs as *const SupportedCipherSuite as *const _;
+                }
+            }
+            null()
+        }
+    }
+
+    /// Return the number of root certificates `config` trusts, as configured
+    /// with rustls_client_config_builder_use_roots or
+    /// rustls_client_config_builder_load_roots_from_file. This is 0 if
+    /// `config` is NULL, or if it verifies servers some other way, such as
+    /// with a custom verifier or a pinned certificate.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_config_root_cert_count(
+        config: *const rustls_client_config,
+    ) -> size_t {
+        ffi_panic_boundary! {
+            let config: &BuiltClientConfig = try_ref_from_ptr!(config);
+            config.root_count
+        }
+    }
+
+    /// Create a new rustls_connection containing a client connection and return
+    /// it in the output parameter `out`. If this returns an error code, the
+    /// memory pointed to by `conn_out` remains unchanged. If this returns a
+    /// non-error, the memory pointed to by `conn_out` is modified to point at a
+    /// valid rustls_connection. The caller now owns the rustls_connection and must
+    /// call `rustls_connection_free` when done with it.
+    ///
+    /// The server_name parameter can contain a hostname or an IP address in
+    /// textual form (IPv4 or IPv6). This function will return an error if it
+    /// cannot be parsed as one of those types. A hostname may end in a single
+    /// dot, which is dropped, and may contain Unicode labels, which are
+    /// converted to their ASCII (punycode) form as IDNA specifies; names that
+    /// fail that conversion get InvalidDnsNameError too. It is sent to the server in
+    /// the SNI extension (unless SNI was disabled with
+    /// rustls_client_config_builder_set_enable_sni) and the server's
+    /// certificate is verified against it.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_connection_new(
+        config: *const rustls_client_config,
+        server_name: *const c_char,
+        conn_out: *mut *mut rustls_connection,
+    ) -> rustls_result {
+        if server_name.is_null() {
+            return NullParameter;
+        }
+        let len = unsafe { CStr::from_ptr(server_name) }.to_bytes().len();
+        Self::rustls_client_connection_new_slice(config, server_name, len, conn_out)
+    }
+
+    /// Like rustls_client_connection_new, but `server_name` is given as a
+    /// pointer and length rather than a NUL-terminated string, so a name
+    /// inside a larger buffer can be used without copying it. The
+    /// `server_name_len` bytes must be ASCII (or UTF-8) text; a NUL byte
+    /// among them makes the name invalid, and InvalidDnsNameError is
+    /// returned.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_connection_new_slice(
+        config: *const rustls_client_config,
+        server_name: *const c_char,
+        server_name_len: size_t,
+        conn_out: *mut *mut rustls_connection,
+    ) -> rustls_result {
+        let options = rustls_client_connection_options {
+            server_name,
+            server_name_len,
+            ..rustls_client_connection_options::default()
+        };
+        Self::rustls_client_connection_new_ex(config, &options, conn_out)
+    }
+
+    /// Like rustls_client_connection_new, but verify the server's certificate
+    /// against `verify_hostname` instead of `sni_hostname`. `sni_hostname` is
+    /// still sent in the ClientHello (if SNI is enabled). This is useful when
+    /// the name a server is reached by differs from the name on its
+    /// certificate, e.g. when connecting through a fronting proxy.
+    ///
+    /// Both names are parsed the same way as `server_name` in
+    /// rustls_client_connection_new, and InvalidDnsNameError is returned if
+    /// either cannot be parsed. If `verify_hostname` is NULL, or names the
+    /// same host as `sni_hostname`, this behaves exactly like
+    /// rustls_client_connection_new.
+    ///
+    /// Any custom verifier set with
+    /// rustls_client_config_builder_dangerous_set_certificate_verifier sees
+    /// `verify_hostname` as the server_name in its parameters.
+    ///
+    /// Sessions are cached under `verify_hostname` rather than `sni_hostname`,
+    /// so a connection only resumes a session whose certificate was verified
+    /// against the name it expects.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_connection_new_with_verify_hostname(
+        config: *const rustls_client_config,
+        sni_hostname: *const c_char,
+        verify_hostname: *const c_char,
+        conn_out: *mut *mut rustls_connection,
+    ) -> rustls_result {
+        if sni_hostname.is_null() {
+            return NullParameter;
+        }
+        let c_str_len = |s| unsafe { CStr::from_ptr(s) }.to_bytes().len();
+        let options = rustls_client_connection_options {
+            server_name: sni_hostname,
+            server_name_len: c_str_len(sni_hostname),
+            verify_hostname,
+            verify_hostname_len: if verify_hostname.is_null() {
+                0
+            } else {
+                c_str_len(verify_hostname)
+            },
+            ..rustls_client_connection_options::default()
+        };
+        Self::rustls_client_connection_new_ex(config, &options, conn_out)
+    }
+
+    /// Create a new client connection as rustls_client_connection_new does,
+    /// with the settings in `options`. This takes the place of a separate
+    /// constructor for each combination of per-connection settings; see
+    /// rustls_client_connection_options for how a caller built against an
+    /// older rustls-ffi keeps working with a newer one.
+    ///
+    /// Returns InvalidParameter if `options->struct_size` isn't a size this
+    /// version knows, including one from a newer version with fields it can't
+    /// honor. Returns InvalidDnsNameError if a name can't be parsed. On
+    /// success the memory pointed to by `conn_out` is set to the new
+    /// connection, which the caller frees with rustls_connection_free;
+    /// otherwise it is left unchanged.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_connection_new_ex(
+        config: *const rustls_client_config,
+        options: *const rustls_client_connection_options,
+        conn_out: *mut *mut rustls_connection,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+        let built: Arc<BuiltClientConfig> = try_arc_from_ptr!(config);
+        if options.is_null() {
+            return NullParameter;
+        }
+        let options = match unsafe { rustls_client_connection_options::read(options) } {
+            Some(o) => o,
+            None => return InvalidParameter,
+        };
+        let server_name: &[u8] =
+            try_slice!(options.server_name as *const u8, options.server_name_len);
+        let server_name = match server_name_from_bytes(server_name) {
+            Some(sn) => sn,
+            None => return rustls_result::InvalidDnsNameError,
+        };
+        let verify_name = if options.verify_hostname.is_null() {
+            None
+        } else {
+            let verify_hostname: &[u8] =
+                try_slice!(options.verify_hostname as *const u8, options.verify_hostname_len);
+            match server_name_from_bytes(verify_hostname) {
+                Some(vn) if vn == server_name => None,
+                Some(vn) => Some(vn),
+                None => return rustls_result::InvalidDnsNameError,
+            }
+        };
+
+        // We've succeeded. Put the client on the heap, and transfer ownership
+        // to the caller. After this point, we must return rustls_result::Ok so the
+        // caller knows it is responsible for this memory.
+        let mut conn = match new_connection(&built, server_name, verify_name) {
+            Ok(c) => c,
+            Err(e) => return e,
+        };
+        conn.set_userdata(options.userdata);
+        BoxCastPtr::set_mut_ptr(conn_out, conn);
+        rustls_result::Ok
+        }
+    }
+
+    /// Like rustls_client_connection_new, but take the server name from a
+    /// rustls_server_name made earlier with rustls_server_name_new, so it
+    /// isn't parsed again for every connection. `name` is only borrowed:
+    /// the application still frees it when it is done making connections.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_connection_new_with_name(
+        config: *const rustls_client_config,
+        name: *const rustls_server_name,
+        conn_out: *mut *mut rustls_connection,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+        let built: Arc<BuiltClientConfig> = try_arc_from_ptr!(config);
+        let server_name: &rustls::ServerName = try_ref_from_ptr!(name);
+        match new_connection(&built, server_name.clone(), None) {
+            Ok(c) => BoxCastPtr::set_mut_ptr(conn_out, c),
+            Err(e) => return e,
+        }
+        rustls_result::Ok
+        }
+    }
+
+    /// Reuse the client connection `conn` for a new connection to
+    /// `server_name`, made from `config` as rustls_client_connection_new
+    /// would, instead of freeing it and making another. The old TLS session
+    /// is dropped without sending anything more, so send close_notify first
+    /// if the peer should see a clean close.
+    ///
+    /// The connection's userdata, log callback, buffer limit and
+    /// rustls_connection_get_counters totals carry over. So does a
+    /// handshake-done callback that hasn't run yet; one that ran for the old
+    /// handshake is gone and must be set again. Everything else starts
+    /// afresh, including any name given to
+    /// rustls_client_connection_new_with_verify_hostname, buffered TLS output
+    /// and unread plaintext. `config` need not be the one `conn` was made
+    /// with.
+    ///
+    /// Pointers returned earlier by rustls_connection_peek_tls_output,
+    /// rustls_connection_peek_plaintext and the like point into buffers
+    /// this reuses; dereferencing one after the reset is undefined behavior.
+    ///
+    /// Returns InvalidParameter if `conn` is a server connection and
+    /// InvalidDnsNameError if `server_name` can't be parsed; `conn` is left
+    /// unchanged in either case.
+    #[no_mangle]
+    pub extern "C" fn rustls_client_connection_reset(
+        conn: *mut rustls_connection,
+        config: *const rustls_client_config,
+        server_name: *const c_char,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+        let conn: &mut Connection = try_mut_from_ptr!(conn);
+        let built: Arc<BuiltClientConfig> = try_arc_from_ptr!(config);
+        if server_name.is_null() {
+            return NullParameter;
+        }
+        if conn.as_client().is_none() {
+            return InvalidParameter;
+        }
+        let server_name = match server_name_from_c_str(server_name) {
+            Some(sn) => sn,
+            None => return rustls_result::InvalidDnsNameError,
+        };
+        match new_connection(&built, server_name, None) {
+            Ok(c) => conn.reset(c),
+            Err(e) => return e,
+        }
+        rustls_result::Ok
+        }
+    }
+}
+
+/// A hostname or IP address that has already been parsed, for making many
+/// client connections to the same server.
+/// <https://docs.rs/rustls/latest/rustls/enum.ServerName.html>
+pub struct rustls_server_name {
+    _private: [u8; 0],
+}
+
+impl CastConstPtr for rustls_server_name {
+    type RustType = rustls::ServerName;
+}
+
+impl ArcCastPtr for rustls_server_name {}
+
+impl rustls_server_name {
+    /// Parse `name`, a NUL-terminated hostname or textual IP address, the way
+    /// rustls_client_connection_new would, and store the result in *name_out.
+    /// Returns InvalidDnsNameError if it can't be parsed, leaving *name_out
+    /// unchanged. On success the caller owns the rustls_server_name and must
+    /// free it with rustls_server_name_free; it can be used by any number of
+    /// connections, from any thread, in the meantime.
+    #[no_mangle]
+    pub extern "C" fn rustls_server_name_new(
+        name: *const c_char,
+        name_out: *mut *const rustls_server_name,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let name_out: &mut *const rustls_server_name = unsafe {
+                match name_out.as_mut() {
+                    Some(n) => n,
+                    None => return NullParameter,
+                }
+            };
+            if name.is_null() {
+                return NullParameter;
+            }
+            let server_name = match server_name_from_c_str(name) {
+                Some(sn) => sn,
+                None => return rustls_result::InvalidDnsNameError,
+            };
+            *name_out = ArcCastPtr::to_const_ptr(server_name);
+            rustls_result::Ok
+        }
+    }
+
+    /// Check whether `name`, `name_len` bytes long and not necessarily
+    /// NUL-terminated, would be accepted as a server name by
+    /// rustls_client_connection_new and the other connection constructors,
+    /// after the same normalization. Returns RUSTLS_RESULT_OK if so, and
+    /// InvalidDnsNameError if not. Useful for checking configuration up front.
+    /// Nothing is allocated or logged unless the name has Unicode labels.
+    #[no_mangle]
+    pub extern "C" fn rustls_server_name_validate(
+        name: *const c_char,
+        name_len: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let name: &[u8] = try_slice!(name as *const u8, name_len);
+            match validate_server_name(name) {
+                Some(_) => rustls_result::Ok,
+                None => rustls_result::InvalidDnsNameError,
+            }
+        }
+    }
+
+    /// Free a rustls_server_name made by rustls_server_name_new. Connections
+    /// made with it are unaffected. Calling with NULL is fine. Must not be
+    /// called twice with the same value.
+    #[no_mangle]
+    pub extern "C" fn rustls_server_name_free(name: *const rustls_server_name) {
+        ffi_panic_boundary! {
+            rustls_server_name::free(name);
+        }
+    }
+}
+
+/// Write up to `count` bytes from `buf` as early data (0-RTT), to be sent
+/// along with the ClientHello. On success, store the number of bytes actually
+/// written in *out_n; this may be less than `count` if the server limits how
+/// much early data it takes. Returns EarlyDataUnavailable if early data can't
+/// be written on this connection: early data was not enabled with
+/// rustls_client_config_builder_set_enable_early_data, no resumable TLS 1.3
+/// session allowing early data was found for the server, or the ClientHello
+/// has already been sent. Returns InvalidParameter for server connections.
+///
+/// Early data has to be written after creating the connection and before the
+/// first call to rustls_connection_write_tls. The server may reject it, in
+/// which case the bytes are discarded. Once rustls_connection_is_handshaking
+/// returns false, call rustls_client_connection_is_early_data_accepted: if it
+/// reports false, the application must write the same bytes again with
+/// rustls_connection_write.
+/// <https://docs.rs/rustls/latest/rustls/client/struct.ClientConnection.html#method.early_data>
+#[no_mangle]
+pub extern "C" fn rustls_client_connection_write_early_data(
+    conn: *mut rustls_connection,
+    buf: *const u8,
+    count: size_t,
+    out_n: *mut size_t,
+) -> rustls_result {
+    ffi_panic_boundary! {
+        let conn: &mut Connection = try_mut_from_ptr!(conn);
+        let write_buf: &[u8] = try_slice!(buf, count);
+        if out_n.is_null() {
+            return NullParameter
+        }
+        if let Some(e) = conn.fatal_error() {
+            return e;
+        }
+        let client = match conn.as_client_mut() {
+            Some(c) => c,
+            None => return rustls_result::InvalidParameter,
+        };
+        let mut early_data = match client.early_data() {
+            Some(early_data) => early_data,
+            None => return rustls_result::EarlyDataUnavailable,
+        };
+        let n_written: usize = match early_data.write(write_buf) {
+            Ok(n) => n,
+            Err(_) => return rustls_result::Io,
+        };
+        conn.wrote_plaintext(n_written);
+        unsafe {
+            *out_n = n_written;
+        }
+        rustls_result::Ok
+    }
+}
+
+/// Store in *out whether the server accepted the early data written with
+/// rustls_client_connection_write_early_data. This is only meaningful once
+/// the handshake has completed; before then, *out is always false. If it is
+/// false after the handshake, any early data was discarded by the server and
+/// must be written again with rustls_connection_write. Returns
+/// InvalidParameter for server connections.
+/// <https://docs.rs/rustls/latest/rustls/client/struct.ClientConnection.html#method.is_early_data_accepted>
+#[no_mangle]
+pub extern "C" fn rustls_client_connection_is_early_data_accepted(
+    conn: *const rustls_connection,
+    out: *mut bool,
+) -> rustls_result {
+    ffi_panic_boundary! {
+        let conn: &Connection = try_ref_from_ptr!(conn);
+        if out.is_null() {
+            return NullParameter
+        }
+        let client = match conn.as_client() {
+            Some(c) => c,
+            None => return rustls_result::InvalidParameter,
+        };
+        unsafe {
+            *out = client.is_early_data_accepted();
+        }
+        rustls_result::Ok
+    }
+}
+
+/// Store in *out the config userdata of the rustls_client_config `conn` was
+/// made with, as set by rustls_client_config_builder_set_userdata, or NULL if
+/// none was set. Returns InvalidParameter for server connections.
+#[no_mangle]
+pub extern "C" fn rustls_client_connection_get_config_userdata(
+    conn: *const rustls_connection,
+    out: *mut *mut c_void,
+) -> rustls_result {
+    ffi_panic_boundary! {
+        let conn: &Connection = try_ref_from_ptr!(conn);
+        if out.is_null() {
+            return NullParameter
+        }
+        if conn.as_client().is_none() {
+            return rustls_result::InvalidParameter;
+        }
+        unsafe {
+            *out = conn.config_userdata();
+        }
+        rustls_result::Ok
+    }
+}
+
+/// A summary of a completed handshake, filled in by
+/// rustls_client_connection_get_handshake_info. This is a plain struct that C
+/// allocates; the C declaration in rustls.h has the same layout:
+///
+/// - `version`: the TLS protocol version, e.g. 0x0304 for TLS 1.3.
+/// - `ciphersuite`: the IANA ID of the negotiated cipher suite.
+/// - `alpn`, `alpn_len`: the negotiated ALPN protocol, in the first
+///   `alpn_len` bytes of `alpn`. `alpn_len` is 0 if none was negotiated.
+/// - `resumed`: whether an earlier session was resumed, rather than the
+///   server's certificate being verified afresh.
+#[repr(C)]
+pub struct rustls_handshake_info {
+    pub version: u16,
+    pub ciphersuite: u16,
+    pub alpn: [u8; 32],
+    pub alpn_len: size_t,
+    pub resumed: bool,
+}
+
+/// Fill in *out with a summary of the handshake on client connection `conn`.
+/// Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE while still handshaking,
+/// InvalidParameter for server connections, and InsufficientSize if the
+/// negotiated ALPN protocol is longer than 32 bytes. *out is only written on
+/// success.
+#[no_mangle]
+pub extern "C" fn rustls_client_connection_get_handshake_info(
+    conn: *const rustls_connection,
+    out: *mut rustls_handshake_info,
+) -> rustls_result {
+    ffi_panic_boundary! {
+        let conn: &Connection = try_ref_from_ptr!(conn);
+        if out.is_null() {
+            return NullParameter
+        }
+        if conn.as_client().is_none() {
+            return rustls_result::InvalidParameter;
+        }
+        if conn.is_handshaking() {
+            return rustls_result::HandshakeNotComplete;
+        }
+        let (version, suite) = match (conn.protocol_version(), conn.negotiated_cipher_suite()) {
+            (Some(v), Some(cs)) => (v, cs),
+            _ => return rustls_result::HandshakeNotComplete,
+        };
+        let mut info = rustls_handshake_info {
+            version: version.get_u16(),
+            ciphersuite: suite.suite().get_u16(),
+            alpn: [0; 32],
+            alpn_len: 0,
+            resumed: !conn.server_cert_verified(),
+        };
+        if let Some(alpn) = conn.alpn_protocol() {
+            if alpn.len() > info.alpn.len() {
+                return rustls_result::InsufficientSize;
+            }
+            info.alpn[..alpn.len()].copy_from_slice(alpn);
+            info.alpn_len = alpn.len();
+        }
+        unsafe {
+            *out = info;
+        }
+        rustls_result::Ok
+    }
+}
+
+/// Store in *out whether the completed handshake on client connection `conn`
+/// resumed an earlier session, by session ID, ticket or TLS 1.3 PSK, rather
+/// than doing a full handshake. The same as the `resumed` field filled in by
+/// rustls_client_connection_get_handshake_info.
+///
+/// rustls doesn't report this directly. A resumed handshake never asks for
+/// the server's certificate to be verified, so that is what rustls-ffi
+/// watches for. Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE while still
+/// handshaking, and InvalidParameter for server connections.
+#[no_mangle]
+pub extern "C" fn rustls_client_connection_was_resumed(
+    conn: *const rustls_connection,
+    out: *mut bool,
+) -> rustls_result {
+    ffi_panic_boundary! {
+        let conn: &Connection = try_ref_from_ptr!(conn);
+        if out.is_null() {
+            return NullParameter
+        }
+        if conn.as_client().is_none() {
+            return rustls_result::InvalidParameter;
+        }
+        if conn.is_handshaking() {
+            return rustls_result::HandshakeNotComplete;
+        }
+        unsafe {
+            *out = !conn.server_cert_verified();
+        }
+        rustls_result::Ok
+    }
+}
+
+/// Copy the `tls-server-end-point` channel binding (RFC 5929) for the
+/// connection into `buf`, storing its length in *out_n. This is the hash of
+/// the server's end-entity certificate, by the digest of the certificate's
+/// signature algorithm, or SHA-256 if that is MD5 or SHA-1. SCRAM-SHA-256-PLUS,
+/// as PostgreSQL uses, needs it to tie authentication to the TLS connection.
+/// 64 bytes is always enough.
+///
+/// Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE until the handshake has
+/// completed, RUSTLS_RESULT_INVALID_PARAMETER if `conn` is a server
+/// connection, RUSTLS_RESULT_UNSUPPORTED if the certificate's signature
+/// algorithm has no single digest, as with Ed25519, or can't be parsed, and
+/// RUSTLS_RESULT_INSUFFICIENT_SIZE, with *out_n set to 0, if `len` is too
+/// small.
+#[no_mangle]
+pub extern "C" fn rustls_client_connection_get_channel_binding_tls_server_end_point(
+    conn: *const rustls_connection,
+    buf: *mut u8,
+    len: size_t,
+    out_n: *mut size_t,
+) -> rustls_result {
+    ffi_panic_boundary! {
+        let conn: &Connection = try_ref_from_ptr!(conn);
+        let buf: &mut [u8] = try_mut_slice!(buf, len);
+        if out_n.is_null() {
+            return NullParameter
+        }
+        if conn.as_client().is_none() {
+            return rustls_result::InvalidParameter;
+        }
+        if conn.is_handshaking() {
+            return rustls_result::HandshakeNotComplete;
+        }
+        let leaf = match conn.peer_certificates().and_then(|certs| certs.first()) {
+            Some(leaf) => leaf,
+            None => return rustls_result::NoCertificatesPresented,
+        };
+        let hash = match tls_server_end_point(leaf) {
+            Some(hash) => hash,
+            None => return rustls_result::Unsupported,
+        };
+        let hash = hash.as_ref();
+        if hash.len() > buf.len() {
+            unsafe {
+                *out_n = 0;
+            }
+            return rustls_result::InsufficientSize;
+        }
+        buf[..hash.len()].copy_from_slice(hash);
+        unsafe {
+            *out_n = hash.len();
+        }
+        rustls_result::Ok
+    }
+}
+
+// The chain `conn`'s server certificate was verified along, for the
+// rustls_client_connection_get_verified_chain functions.
+fn verified_chain(conn: &Connection) -> Result<&[Certificate], rustls_result> {
+    if conn.as_client().is_none() {
+        return Err(rustls_result::InvalidParameter);
+    }
+    if conn.is_handshaking() {
+        return Err(rustls_result::HandshakeNotComplete);
+    }
+    conn.verified_chain()
+        .ok_or(rustls_result::VerifiedChainUnavailable)
+}
+
+/// Store in *out the number of entries in the chain the server's certificate
+/// was verified along. See rustls_client_connection_get_verified_chain.
+///
+/// Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE until the handshake has
+/// completed, RUSTLS_RESULT_INVALID_PARAMETER if `conn` is a server
+/// connection, and RUSTLS_RESULT_VERIFIED_CHAIN_UNAVAILABLE if no chain was
+/// recorded.
+#[no_mangle]
+pub extern "C" fn rustls_client_connection_get_verified_chain_count(
+    conn: *const rustls_connection,
+    out: *mut size_t,
+) -> rustls_result {
+    ffi_panic_boundary! {
+        let conn: &Connection = try_ref_from_ptr!(conn);
+        if out.is_null() {
+            return NullParameter
+        }
+        let chain = match verified_chain(conn) {
+            Ok(chain) => chain,
+            Err(e) => return e,
+        };
+        unsafe {
+            *out = chain.len();
+        }
+        rustls_result::Ok
+    }
+}
+
+/// Store a pointer to the DER encoding of the entry at `index` in the chain
+/// the server's certificate was verified along in *out_der, and its length
+/// in *out_len.
+///
+/// Unlike the certificates from rustls_connection_get_peer_certificate_der,
+/// which are what the server sent, this is the path verification took:
+/// index 0 is the end-entity certificate, followed by the intermediates that
+/// were needed, each the issuer of the one before, whatever order the server
+/// sent them in. Certificates the server sent that aren't on the path are
+/// left out. The last entry is the trust anchor. Trusted roots only keep the
+/// subject of their certificate, so for a root the entry is its DER-encoded
+/// subject Name rather than a certificate. For a DANE-TA record (see
+/// rustls_client_config_builder_set_tlsa_records) the anchor is a full
+/// certificate the server sent.
+///
+/// The chain is only recorded when verifying against trusted roots or with
+/// DANE-TA, PKIX-TA or PKIX-EE records. A custom verifier, a pinned
+/// certificate, a DANE-EE record or disabled verification don't say how
+/// they accepted the certificate, and resumed handshakes don't verify it
+/// again, so for those this returns RUSTLS_RESULT_VERIFIED_CHAIN_UNAVAILABLE.
+/// It also returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE until the handshake
+/// has completed, RUSTLS_RESULT_INVALID_PARAMETER if `conn` is a server
+/// connection, and RUSTLS_RESULT_NOT_FOUND if `index` is not less than the
+/// count from rustls_client_connection_get_verified_chain_count.
+///
+/// With trusted roots, the path is worked out the first time either of these
+/// functions is called for a connection, so handshakes don't pay for it
+/// unless it is used. The returned pointer is valid until the connection is
+/// freed or reset.
+#[no_mangle]
+pub extern "C" fn rustls_client_connection_get_verified_chain(
+    conn: *const rustls_connection,
+    index: size_t,
+    out_der: *mut *const u8,
+    out_len: *mut size_t,
+) -> rustls_result {
+    ffi_panic_boundary! {
+        let conn: &Connection = try_ref_from_ptr!(conn);
+        if out_der.is_null() || out_len.is_null() {
+            return NullParameter
+        }
+        let cert = match verified_chain(conn).map(|chain| chain.get(index)) {
+            Ok(Some(cert)) => cert,
+            Ok(None) => return rustls_result::NotFound,
+            Err(e) => return e,
+        };
+        unsafe {
+            *out_der = cert.0.as_ptr();
+            *out_len = cert.0.len();
+        }
+        rustls_result::Ok
+    }
+}
+
+/// Settings for rustls_client_connection_new_ex. Zero-initialize this, so
+/// that any field you don't set gets its default, then fill in:
+///
+/// - `struct_size`: `sizeof(rustls_client_connection_options)`, as seen by
+///   the caller. New fields are only ever added at the end, so this tells
+///   rustls-ffi which fields the caller knows about; the others get their
+///   defaults. It must be a size some version of rustls-ffi gave this
+///   struct: currently the whole struct, or the part up to and including
+///   `userdata`.
+/// - `server_name` and `server_name_len`: the server's name, as for
+///   rustls_client_connection_new_slice. Required.
+/// - `userdata`: passed to callbacks, as if set with
+///   rustls_connection_set_userdata right away. Default NULL.
+/// - `verify_hostname` and `verify_hostname_len`: a name to verify the
+///   server's certificate against instead of `server_name`, as for
+///   rustls_client_connection_new_with_verify_hostname. Default NULL, which
+///   verifies against `server_name`. Added after `userdata`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct rustls_client_connection_options {
+    pub struct_size: size_t,
+    pub server_name: *const c_char,
+    pub server_name_len: size_t,
+    pub userdata: *mut c_void,
+    pub verify_hostname: *const c_char,
+    pub verify_hostname_len: size_t,
+}
+
+impl Default for rustls_client_connection_options {
+    fn default() -> Self {
+        rustls_client_connection_options {
+            struct_size: size_of::<Self>(),
+            server_name: null(),
+            server_name_len: 0,
+            userdata: null_mut(),
+            verify_hostname: null(),
+            verify_hostname_len: 0,
+        }
+    }
+}
+
+impl rustls_client_connection_options {
+    /// The sizes this struct has had, oldest first: each ends where the
+    /// next version's new fields begin.
+    fn known_sizes() -> [usize; 2] {
+        let o = Self::default();
+        let start = &o as *const Self as usize;
+        [
+            &o.verify_hostname as *const _ as usize - start,
+            size_of::<Self>(),
+        ]
+    }
+
+    /// Read a caller's options, whichever version of the struct it has,
+    /// filling in defaults for fields it doesn't know about. Returns None if
+    /// its struct_size isn't one we know. `options` must be non-NULL.
+    unsafe fn read(options: *const Self) -> Option<Self> {
+        // Only struct_size can be relied on until it's been checked.
+        let size = ptr::read(options as *const size_t);
+        if !Self::known_sizes().contains(&size) {
+            return None;
+        }
+        let mut read = Self::default();
+        ptr::copy_nonoverlapping(
+            options as *const u8,
+            &mut read as *mut Self as *mut u8,
+            size,
+        );
+        Some(read)
+    }
+}
+
+/// Make a client connection to `server_name` from `built`, verifying the
+/// server's certificate against `verify_name` if one is given.
+fn new_connection(
+    built: &BuiltClientConfig,
+    server_name: rustls::ServerName,
+    verify_name: Option<rustls::ServerName>,
+) -> Result<Connection, rustls_result> {
+    // rustls looks up a session to resume while making the connection, so
+    // the session store needs to see the verification name already.
+    let guard = match userdata_push(null_mut(), None, verify_name.clone()) {
+        Ok(g) => g,
+        Err(_) => return Err(rustls_result::Panic),
+    };
+    let client = ClientConnection::new(built.config.clone(), server_name).unwrap();
+    if guard.try_drop().is_err() {
+        return Err(rustls_result::Panic);
+    }
+    let mut c = Connection::from_client(client);
+    c.set_config_userdata(built.userdata);
+    if let Some(vn) = verify_name {
+        c.set_verify_name(vn);
+    }
+    Ok(c)
+}
+
+/// Parse a NUL-terminated hostname or textual IP address. The pointer must
+/// be non-NULL.
+fn server_name_from_c_str(name: *const c_char) -> Option<rustls::ServerName> {
+    let name: &CStr = unsafe { CStr::from_ptr(name) };
+    server_name_from_bytes(name.to_bytes())
+}
+
+/// Parse a hostname or textual IP address.
+fn server_name_from_bytes(name: &[u8]) -> Option<rustls::ServerName> {
+    validate_server_name(name)?.as_ref().try_into().ok()
+}
+
+/// Check that `name` is a hostname or textual IP address rustls will take,
+/// and return it in the form to hand to rustls. NUL bytes are never part
+/// of a valid name. Hostnames are normalized first with
+/// `normalize_hostname`, then checked the way rustls checks them. This
+/// doesn't allocate unless the name has Unicode labels to convert.
+fn validate_server_name(name: &[u8]) -> Option<Cow<'_, str>> {
+    if name.contains(&0) {
+        return None;
+    }
+    let name = std::str::from_utf8(name).ok()?;
+    if name.parse::<IpAddr>().is_ok() {
+        return Some(Cow::Borrowed(name));
+    }
+    let name = normalize_hostname(name)?;
+    webpki::DnsNameRef::try_from_ascii_str(&name).ok()?;
+    Some(name)
+}
+
+/// Bring a hostname into the form webpki expects: drop a single trailing
+/// dot, as in a fully qualified name, and convert any Unicode labels to
+/// their ASCII (punycode) form. Returns None if the name ends in an empty
+/// label or IDNA processing rejects it. ASCII names are otherwise left for
+/// webpki to judge.
+fn normalize_hostname(name: &str) -> Option<Cow<'_, str>> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.ends_with('.') {
+        return None;
+    }
+    if name.is_ascii() {
+        return Some(Cow::Borrowed(name));
+    }
+    idna::domain_to_ascii_strict(name).ok().map(Cow::Owned)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::ptr::{null, null_mut};
+
+    use super::*;
+
+    fn verifier(builder: *mut rustls_client_config_builder) -> Arc<dyn ServerCertVerifier> {
+        let builder: &mut ClientConfigBuilder = crate::try_from_mut(builder).unwrap();
+        builder.verifier.clone()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_use_roots_shares_store() {
+        let store = rustls_root_cert_store::rustls_root_cert_store_new();
+        let pem = include_str!("../testdata/minica.pem");
+        let add_pem = || {
+            rustls_root_cert_store::rustls_root_cert_store_add_pem(
+                store,
+                pem.as_ptr(),
+                pem.len(),
+                true,
+            )
+        };
+        assert_eq!(add_pem(), rustls_result::Ok);
+
+        let builders: Vec<*mut rustls_client_config_builder> = (0..3)
+            .map(|_| rustls_client_config_builder::rustls_client_config_builder_new())
+            .collect();
+        for builder in &builders[..2] {
+            let result = rustls_client_config_builder::rustls_client_config_builder_use_roots(
+                *builder, store,
+            );
+            assert_eq!(result, rustls_result::Ok);
+        }
+        assert!(Arc::ptr_eq(&verifier(builders[0]), &verifier(builders[1])));
+
+        // Changing the store gives later users a new copy and leaves the
+        // existing one alone.
+        assert_eq!(add_pem(), rustls_result::Ok);
+        rustls_client_config_builder::rustls_client_config_builder_use_roots(builders[2], store);
+        assert!(!Arc::ptr_eq(&verifier(builders[0]), &verifier(builders[2])));
+        assert!(Arc::ptr_eq(&verifier(builders[0]), &verifier(builders[1])));
+
+        for builder in builders {
+            rustls_client_config_builder::rustls_client_config_builder_free(builder);
+        }
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
+    }
+
+    fn set_client_cert_pem(chain: &str, key: &str) -> rustls_result {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_client_cert_pem(
+            builder,
+            chain.as_ptr(),
+            chain.len(),
+            key.as_ptr(),
+            key.len(),
+        );
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+        result
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_set_client_cert_pem() {
+        let cert = include_str!("../testdata/localhost/cert.pem");
+        let key = include_str!("../testdata/localhost/key.pem");
+        assert_eq!(set_client_cert_pem(cert, key), rustls_result::Ok);
+
+        let malformed = "-----BEGIN CERTIFICATE-----\n!!!!\n-----END CERTIFICATE-----\n";
+        assert_eq!(
+            set_client_cert_pem(malformed, key),
+            rustls_result::CertificateParseError
+        );
+        assert_eq!(
+            set_client_cert_pem("", key),
+            rustls_result::CertificateParseError
+        );
+        assert_eq!(
+            set_client_cert_pem(cert, "not a key"),
+            rustls_result::NoPrivateKeyFound
+        );
+        assert_eq!(
+            set_client_cert_pem(cert, include_str!("../testdata/example.com/key.pem")),
+            rustls_result::CertKeyMismatch
+        );
+    }
+
+    fn set_client_cert_der(chain: &[&[u8]], key: &[u8]) -> rustls_result {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let ptrs: Vec<*const u8> = chain.iter().map(|c| c.as_ptr()).collect();
+        let lens: Vec<size_t> = chain.iter().map(|c| c.len()).collect();
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_client_cert_der(
+            builder,
+            ptrs.as_ptr(),
+            lens.as_ptr(),
+            chain.len(),
+            key.as_ptr(),
+            key.len(),
+        );
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+        result
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_set_client_cert_der() {
+        let cert: &[u8] = include_bytes!("../testdata/ecdsa/cert.der");
+        let ca: &[u8] = include_bytes!("../testdata/minica.der");
+        let key: &[u8] = include_bytes!("../testdata/ecdsa/key.der");
+        assert_eq!(set_client_cert_der(&[cert], key), rustls_result::Ok);
+        assert_eq!(set_client_cert_der(&[cert, ca], key), rustls_result::Ok);
+
+        assert_eq!(
+            set_client_cert_der(&[], key),
+            rustls_result::InvalidParameter
+        );
+        assert_eq!(
+            set_client_cert_der(&[cert, &[]], key),
+            rustls_result::InvalidParameter
+        );
+        assert_eq!(
+            set_client_cert_der(&[&cert[1..]], key),
+            rustls_result::CertificateParseError
+        );
+        assert_eq!(
+            set_client_cert_der(&[cert], &key[..key.len() - 1]),
+            rustls_result::PrivateKeyParseError
+        );
+        // The key belongs to the leaf, not the CA.
+        assert_eq!(
+            set_client_cert_der(&[ca, cert], key),
+            rustls_result::CertKeyMismatch
+        );
+    }
+
+    #[test]
+    fn test_config_builder() {
+        let builder: *mut rustls_client_config_builder =
+            rustls_client_config_builder::rustls_client_config_builder_new();
+        let h1 = "http/1.1".as_bytes();
+        let h2 = "h2".as_bytes();
+        let alpn: Vec<rustls_slice_bytes> = vec![h1.into(), h2.into()];
+        rustls_client_config_builder::rustls_client_config_builder_set_alpn_protocols(
+            builder,
+            alpn.as_ptr(),
+            alpn.len(),
+        );
+        rustls_client_config_builder::rustls_client_config_builder_set_enable_sni(builder, false);
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        {
+            let config2 = &try_ref_from_ptr!(config).config;
+            assert_eq!(config2.enable_sni, false);
+            assert_eq!(config2.alpn_protocols, vec![h1, h2]);
+        }
+        rustls_client_config::rustls_client_config_free(config)
+    }
+
+    #[test]
+    fn test_config_builder_build_ex() {
+        let mut config: *const rustls_client_config = null();
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_build_ex(
+                builder,
+                null_mut()
+            ),
+            rustls_result::NullParameter
+        );
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_build_ex(
+                builder,
+                &mut config
+            ),
+            rustls_result::Ok
+        );
+        assert_ne!(config, null());
+        rustls_client_config::rustls_client_config_free(config);
+
+        // The setters refuse such a combination, so make it directly.
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        {
+            let b: &mut ClientConfigBuilder = try_mut_from_ptr!(builder);
+            b.versions = vec![&rustls::version::TLS13];
+            b.cipher_suites = vec![rustls::cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256];
+        }
+        let mut config: *const rustls_client_config = null();
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_build_ex(
+                builder,
+                &mut config
+            ),
+            rustls_result::InvalidParameter
+        );
+        assert_eq!(config, null());
+    }
+
+    #[test]
+    fn test_set_bundled_ct_logs() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_bundled_ct_logs(
+            builder,
+            u64::MAX,
+        );
+        let timestamp = rustls_bundled_ct_logs_timestamp();
+        if cfg!(feature = "bundled_ct_logs") {
+            assert_eq!(result, InvalidParameter);
+            assert!(try_ref_from_ptr!(builder).ct_policy.is_none());
+            let deadline = timestamp + 365 * 24 * 60 * 60;
+            assert_eq!(
+                rustls_client_config_builder::rustls_client_config_builder_set_bundled_ct_logs(
+                    builder, deadline
+                ),
+                rustls_result::Ok
+            );
+            let policy = try_ref_from_ptr!(builder).ct_policy.as_ref().unwrap();
+            assert!(!policy.logs.is_empty());
+            assert_eq!(
+                policy.validation_deadline,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(deadline)
+            );
+        } else {
+            assert_eq!(result, rustls_result::Unsupported);
+            assert_eq!(timestamp, 0);
+            assert!(try_ref_from_ptr!(builder).ct_policy.is_none());
+        }
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+    }
+
+    #[test]
+    fn test_config_incref() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let first = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        let weak = Arc::downgrade(&try_arc_from_ptr!(first));
+        rustls_client_config::rustls_client_config_incref(first);
+        let second = first;
+        rustls_client_config::rustls_client_config_incref(first);
+        let third = first;
+        assert_eq!(weak.strong_count(), 3);
+
+        // Owners finish in a different order from the one they took their
+        // references in; the config lives until the last one is released.
+        rustls_client_config::rustls_client_config_free(second);
+        assert_eq!(weak.strong_count(), 2);
+        rustls_client_config::rustls_client_config_free(first);
+        assert_eq!(weak.strong_count(), 1);
+        assert_eq!(
+            rustls_client_config::rustls_client_config_root_cert_count(third),
+            0
+        );
+        rustls_client_config::rustls_client_config_incref(third);
+        let fourth = third;
+        rustls_client_config::rustls_client_config_free(third);
+        assert!(weak.upgrade().is_some());
+        rustls_client_config::rustls_client_config_free(fourth);
+        assert!(weak.upgrade().is_none());
+
+        rustls_client_config::rustls_client_config_incref(null());
+        rustls_client_config::rustls_client_config_free(null());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::ptr::{null, null_mut};
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_config_free_underflow() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        // Keep the memory alive, so that an over-release can't touch freed
+        // memory or a config another test has built at the same address.
+        let arc = try_arc_from_ptr!(config);
+        rustls_client_config::rustls_client_config_incref(config);
+        rustls_client_config::rustls_client_config_free(config);
+        rustls_client_config::rustls_client_config_free(config);
+        assert_eq!(Arc::strong_count(&arc), 1);
 
-    use super::*;
+        // C holds no reference any more, so this is caught and does nothing.
+        rustls_client_config::rustls_client_config_free(config);
+        assert_eq!(Arc::strong_count(&arc), 1);
+        rustls_client_config::rustls_client_config_incref(config);
+        assert_eq!(Arc::strong_count(&arc), 1);
+    }
 
     #[test]
-    fn test_config_builder() {
-        let builder: *mut rustls_client_config_builder =
-            rustls_client_config_builder::rustls_client_config_builder_new();
-        let h1 = "http/1.1".as_bytes();
-        let h2 = "h2".as_bytes();
-        let alpn: Vec<rustls_slice_bytes> = vec![h1.into(), h2.into()];
+    #[cfg_attr(miri, ignore)]
+    fn test_config_introspection() {
+        let by_id = rustls_supported_ciphersuite::rustls_supported_ciphersuite_get_by_id;
+        // The TLS 1.2 suite can't be used with TLS 1.3 only, so isn't listed.
+        let suites = [by_id(0x1302), by_id(0xc02b), by_id(0x1301)];
+        let versions = [0x0304];
+        let mut builder: *mut rustls_client_config_builder = null_mut();
+        let result = rustls_client_config_builder::rustls_client_config_builder_new_custom(
+            suites.as_ptr(),
+            suites.len(),
+            versions.as_ptr(),
+            versions.len(),
+            &mut builder,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let alpn: Vec<rustls_slice_bytes> =
+            vec![b"h2".as_ref().into(), b"http/1.1".as_ref().into()];
         rustls_client_config_builder::rustls_client_config_builder_set_alpn_protocols(
             builder,
             alpn.as_ptr(),
             alpn.len(),
         );
-        rustls_client_config_builder::rustls_client_config_builder_set_enable_sni(builder, false);
+        let store = rustls_root_cert_store::rustls_root_cert_store_new();
+        let pem = include_str!("../testdata/minica.pem");
+        let result = rustls_root_cert_store::rustls_root_cert_store_add_pem(
+            store,
+            pem.as_ptr(),
+            pem.len(),
+            true,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        rustls_client_config_builder::rustls_client_config_builder_use_roots(builder, store);
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
         let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
-        {
-            let config2 = try_ref_from_ptr!(config);
-            assert_eq!(config2.enable_sni, false);
-            assert_eq!(config2.alpn_protocols, vec![h1, h2]);
-        }
-        rustls_client_config::rustls_client_config_free(config)
+        assert_ne!(config, null());
+
+        let mut versions_out = [0u16; 2];
+        let mut n = 0;
+        let result = rustls_client_config::rustls_client_config_get_versions(
+            config,
+            versions_out.as_mut_ptr(),
+            versions_out.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(&versions_out[..n], &[0x0304]);
+        let result = rustls_client_config::rustls_client_config_get_versions(
+            config,
+            versions_out.as_mut_ptr(),
+            0,
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::InsufficientSize);
+        assert_eq!(n, 0);
+
+        assert_eq!(
+            rustls_client_config::rustls_client_config_get_alpn_count(config),
+            2
+        );
+        let mut buf = [0u8; 8];
+        let mut get_alpn = |index, count| {
+            let result = rustls_client_config::rustls_client_config_get_alpn(
+                config,
+                index,
+                buf.as_mut_ptr(),
+                count,
+                &mut n,
+            );
+            (result, buf[..n].to_vec())
+        };
+        assert_eq!(get_alpn(0, 8), (rustls_result::Ok, b"h2".to_vec()));
+        assert_eq!(get_alpn(1, 8), (rustls_result::Ok, b"http/1.1".to_vec()));
+        assert_eq!(get_alpn(1, 7), (rustls_result::InsufficientSize, vec![]));
+        assert_eq!(get_alpn(2, 8), (rustls_result::NotFound, vec![]));
+
+        assert_eq!(
+            rustls_client_config::rustls_client_config_get_ciphersuite_count(config),
+            2
+        );
+        assert_eq!(
+            rustls_client_config::rustls_client_config_get_ciphersuite(config, 0),
+            suites[0]
+        );
+        assert_eq!(
+            rustls_client_config::rustls_client_config_get_ciphersuite(config, 1),
+            suites[2]
+        );
+        assert_eq!(
+            rustls_client_config::rustls_client_config_get_ciphersuite(config, 2),
+            null()
+        );
+        assert_eq!(
+            rustls_client_config::rustls_client_config_root_cert_count(config),
+            1
+        );
+        rustls_client_config::rustls_client_config_free(config);
+
+        // A default config with a custom verifier.
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        rustls_client_config_builder::rustls_client_config_builder_dangerous_disable_cert_verification(builder);
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        let result = rustls_client_config::rustls_client_config_get_versions(
+            config,
+            versions_out.as_mut_ptr(),
+            versions_out.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(&versions_out[..n], &[0x0304, 0x0303]);
+        assert_eq!(
+            rustls_client_config::rustls_client_config_get_alpn_count(config),
+            0
+        );
+        assert_eq!(
+            rustls_client_config::rustls_client_config_get_ciphersuite_count(config),
+            DEFAULT_CIPHER_SUITES.len()
+        );
+        assert_eq!(
+            rustls_client_config::rustls_client_config_root_cert_count(config),
+            0
+        );
+        rustls_client_config::rustls_client_config_free(config);
+
+        assert_eq!(
+            rustls_client_config::rustls_client_config_get_alpn_count(null()),
+            0
+        );
+        assert_eq!(
+            rustls_client_config::rustls_client_config_get_versions(
+                null(),
+                versions_out.as_mut_ptr(),
+                versions_out.len(),
+                &mut n,
+            ),
+            rustls_result::NullParameter
+        );
+    }
+
+    #[test]
+    fn test_set_openssl_cipher_string() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let set = |spec: &str| {
+            let spec = format!("{}\0", spec);
+            rustls_client_config_builder::rustls_client_config_builder_set_openssl_cipher_string(
+                builder,
+                spec.as_ptr() as *const c_char,
+            )
+        };
+        assert_eq!(set("ECDHE+CHACHA20:!aNULL"), rustls_result::Ok);
+        assert_eq!(set("RC4"), rustls_result::InvalidParameter);
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        let suites: Vec<u16> = (0
+            ..rustls_client_config::rustls_client_config_get_ciphersuite_count(config))
+            .map(|i| {
+                rustls_supported_ciphersuite::rustls_supported_ciphersuite_get_suite(
+                    rustls_client_config::rustls_client_config_get_ciphersuite(config, i),
+                )
+            })
+            .collect();
+        assert_eq!(suites, vec![0x1302, 0x1301, 0x1303, 0xcca9, 0xcca8]);
+        rustls_client_config::rustls_client_config_free(config);
     }
 
     // Build a client connection and test the getters and initial values.
@@ -647,6 +4052,42 @@ mod tests {
         rustls_connection::rustls_connection_free(conn);
     }
 
+    unsafe extern "C" fn ignore_log(
+        _userdata: *mut c_void,
+        _params: *const crate::log::rustls_log_params,
+    ) {
+    }
+
+    // Building several configs, and logging from connections of each, must
+    // not try to register a logger more than once.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_multiple_configs_with_logging() {
+        let level = "debug\0".as_ptr() as *const c_char;
+        let expected = if cfg!(feature = "no_log_capture") {
+            rustls_result::Unsupported
+        } else {
+            rustls_result::Ok
+        };
+        assert_eq!(crate::log::rustls_init_logging(level), expected);
+        for _ in 0..2 {
+            let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+            let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+            assert_ne!(config, null());
+            let mut conn: *mut rustls_connection = null_mut();
+            let result = rustls_client_config::rustls_client_connection_new(
+                config,
+                "example.com\0".as_ptr() as *const c_char,
+                &mut conn,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            rustls_connection::rustls_connection_set_log_callback(conn, Some(ignore_log));
+            rustls_connection::rustls_connection_free(conn);
+            rustls_client_config::rustls_client_config_free(config);
+        }
+        assert_eq!(crate::log::rustls_init_logging(level), expected);
+    }
+
     #[test]
     #[cfg_attr(miri, ignore)]
     fn test_client_connection_new_ipaddress() {
@@ -663,4 +4104,633 @@ mod tests {
             panic!("expected RUSTLS_RESULT_OK, got {:?}", result);
         }
     }
+
+    fn connection_new_slice(
+        config: *const rustls_client_config,
+        name: &[u8],
+    ) -> (rustls_result, *mut rustls_connection) {
+        let mut conn: *mut rustls_connection = null_mut();
+        let result = rustls_client_config::rustls_client_connection_new_slice(
+            config,
+            name.as_ptr() as *const c_char,
+            name.len(),
+            &mut conn,
+        );
+        (result, conn)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_connection_new_slice() {
+        let builder: *mut rustls_client_config_builder =
+            rustls_client_config_builder::rustls_client_config_builder_new();
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+
+        // Only the given bytes are the name, without any terminator.
+        let url = b"https://example.com/index.html";
+        let (result, conn) = connection_new_slice(config, &url[8..19]);
+        assert_eq!(result, rustls_result::Ok);
+        let mut hello = Vec::new();
+        let conn_ref: &mut Connection = crate::try_from_mut(conn).unwrap();
+        conn_ref.write_tls(&mut hello).unwrap();
+        let sni = b"\x00\x0bexample.com";
+        assert!(hello.windows(sni.len()).any(|w| w == sni));
+        rustls_connection::rustls_connection_free(conn);
+
+        let (result, conn) = connection_new_slice(config, b"198.51.100.198");
+        assert_eq!(result, rustls_result::Ok);
+        rustls_connection::rustls_connection_free(conn);
+
+        for name in [
+            &b"example.com\0"[..],
+            b"exam\0ple.com",
+            b"",
+            b"\xffexample.com",
+        ] {
+            let (result, conn) = connection_new_slice(config, name);
+            assert_eq!(result, rustls_result::InvalidDnsNameError);
+            assert!(conn.is_null());
+        }
+        let mut conn: *mut rustls_connection = null_mut();
+        assert_eq!(
+            rustls_client_config::rustls_client_connection_new_slice(config, null(), 0, &mut conn),
+            rustls_result::NullParameter
+        );
+
+        // The NUL-terminated entry point shares the same checks.
+        assert_eq!(
+            rustls_client_config::rustls_client_connection_new(
+                config,
+                b"\xffexample.com\0".as_ptr() as *const c_char,
+                &mut conn,
+            ),
+            rustls_result::InvalidDnsNameError
+        );
+        assert_eq!(
+            rustls_client_config::rustls_client_connection_new(
+                config,
+                "\0".as_ptr() as *const c_char,
+                &mut conn,
+            ),
+            rustls_result::InvalidDnsNameError
+        );
+        assert_eq!(
+            rustls_client_config::rustls_client_connection_new(config, null(), &mut conn),
+            rustls_result::NullParameter
+        );
+        assert!(conn.is_null());
+        rustls_client_config::rustls_client_config_free(config);
+    }
+
+    fn connection_new_ex<T>(
+        config: *const rustls_client_config,
+        options: &T,
+    ) -> (rustls_result, *mut rustls_connection) {
+        let mut conn: *mut rustls_connection = null_mut();
+        let result = rustls_client_config::rustls_client_connection_new_ex(
+            config,
+            options as *const T as *const rustls_client_connection_options,
+            &mut conn,
+        );
+        (result, conn)
+    }
+
+    fn connection_userdata(conn: *const rustls_connection) -> *mut c_void {
+        let mut userdata = null_mut();
+        let result = rustls_connection::rustls_connection_get_userdata(conn, &mut userdata);
+        assert_eq!(result, rustls_result::Ok);
+        userdata
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_connection_new_ex() {
+        let builder: *mut rustls_client_config_builder =
+            rustls_client_config_builder::rustls_client_config_builder_new();
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        let name = "example.com";
+        let mut marker = 0u8;
+        let userdata = &mut marker as *mut u8 as *mut c_void;
+
+        let options = rustls_client_connection_options {
+            server_name: name.as_ptr() as *const c_char,
+            server_name_len: name.len(),
+            userdata,
+            ..Default::default()
+        };
+        let (result, conn) = connection_new_ex(config, &options);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(connection_userdata(conn), userdata);
+        rustls_connection::rustls_connection_free(conn);
+
+        // verify_hostname is parsed like the server name.
+        let bad_name = "not a hostname";
+        let verifying = rustls_client_connection_options {
+            verify_hostname: bad_name.as_ptr() as *const c_char,
+            verify_hostname_len: bad_name.len(),
+            ..options
+        };
+        let (result, conn) = connection_new_ex(config, &verifying);
+        assert_eq!(result, rustls_result::InvalidDnsNameError);
+        assert!(conn.is_null());
+
+        // A caller built before verify_hostname existed passes a shorter
+        // struct. Nothing past its end is read, and the newer fields get
+        // their defaults; the same bytes with a newer size are rejected.
+        #[repr(C)]
+        struct OlderOptions {
+            struct_size: size_t,
+            server_name: *const c_char,
+            server_name_len: size_t,
+            userdata: *mut c_void,
+        }
+        #[repr(C)]
+        struct OlderOptionsThenJunk {
+            options: OlderOptions,
+            junk: [usize; 2],
+        }
+        let older = OlderOptionsThenJunk {
+            options: OlderOptions {
+                struct_size: size_of::<OlderOptions>(),
+                server_name: name.as_ptr() as *const c_char,
+                server_name_len: name.len(),
+                userdata,
+            },
+            junk: [bad_name.as_ptr() as usize, bad_name.len()],
+        };
+        assert_eq!(
+            size_of::<OlderOptions>(),
+            rustls_client_connection_options::known_sizes()[0]
+        );
+        let (result, conn) = connection_new_ex(config, &older.options);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(connection_userdata(conn), userdata);
+        rustls_connection::rustls_connection_free(conn);
+        let newer = OlderOptionsThenJunk {
+            options: OlderOptions {
+                struct_size: size_of::<rustls_client_connection_options>(),
+                ..older.options
+            },
+            ..older
+        };
+        let (result, conn) = connection_new_ex(config, &newer);
+        assert_eq!(result, rustls_result::InvalidDnsNameError);
+        assert!(conn.is_null());
+
+        // Sizes no version has had, including a newer caller's.
+        let full = size_of::<rustls_client_connection_options>();
+        for struct_size in [0, size_of::<size_t>(), full - 1, full + 8] {
+            let options = rustls_client_connection_options {
+                struct_size,
+                ..options
+            };
+            let (result, conn) = connection_new_ex(config, &options);
+            assert_eq!(result, rustls_result::InvalidParameter);
+            assert!(conn.is_null());
+        }
+
+        let mut conn: *mut rustls_connection = null_mut();
+        assert_eq!(
+            rustls_client_config::rustls_client_connection_new_ex(config, null(), &mut conn),
+            rustls_result::NullParameter
+        );
+        let (result, _) = connection_new_ex(null(), &options);
+        assert_eq!(result, rustls_result::NullParameter);
+        rustls_client_config::rustls_client_config_free(config);
+    }
+
+    #[test]
+    fn test_normalize_server_name() {
+        let dns = |name: &str| match server_name_from_bytes(name.as_bytes()) {
+            Some(rustls::ServerName::DnsName(n)) => Some(n.as_ref().to_string()),
+            Some(_) => panic!("{} parsed as an IP address", name),
+            None => None,
+        };
+        assert_eq!(dns("example.com."), Some("example.com".to_string()));
+        assert_eq!(dns("Example.COM"), Some("example.com".to_string()));
+        assert_eq!(
+            dns("b\u{fc}cher.example"),
+            Some("xn--bcher-kva.example".to_string())
+        );
+        assert_eq!(
+            dns("B\u{dc}CHER.example."),
+            Some("xn--bcher-kva.example".to_string())
+        );
+        assert_eq!(
+            dns("\u{4f8b}\u{3048}.test"),
+            Some("xn--r8jz45g.test".to_string())
+        );
+        for invalid in [
+            ".",
+            "example.com..",
+            "exa mple.com",
+            "b\u{fc}cher\u{fffd}.example",
+            "xn--b\u{fc}cher.example",
+            "b\u{fc} cher.example",
+            "b\u{fc}cher..example",
+        ] {
+            assert_eq!(dns(invalid), None, "{}", invalid);
+        }
+        assert!(matches!(
+            server_name_from_bytes(b"198.51.100.198"),
+            Some(rustls::ServerName::IpAddress(_))
+        ));
+
+        let mut name: *const rustls_server_name = null();
+        assert_eq!(
+            rustls_server_name::rustls_server_name_new(
+                "b\u{fc}cher\u{fffd}.example\0".as_ptr() as *const c_char,
+                &mut name
+            ),
+            rustls_result::InvalidDnsNameError
+        );
+        assert!(name.is_null());
+    }
+
+    // Names and whether every constructor should accept them.
+    const SERVER_NAME_CASES: &[(&[u8], bool)] = &[
+        (b"example.com", true),
+        (b"example.com.", true),
+        (b"Example.COM", true),
+        (b"localhost", true),
+        (b"a-b.example", true),
+        ("b\u{fc}cher.example".as_bytes(), true),
+        ("\u{4f8b}\u{3048}.test.".as_bytes(), true),
+        (b"198.51.100.198", true),
+        (b"2001:db8::1", true),
+        (b"", false),
+        (b".", false),
+        (b"example.com..", false),
+        (b"example..com", false),
+        (b".example.com", false),
+        (b"exa mple.com", false),
+        (b"exam\0ple.com", false),
+        (b"\xffexample.com", false),
+        ("b\u{fc}cher\u{fffd}.example".as_bytes(), false),
+        (b"[2001:db8::1]", false),
+    ];
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_server_name_validate() {
+        let builder: *mut rustls_client_config_builder =
+            rustls_client_config_builder::rustls_client_config_builder_new();
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        let expected = |valid: bool| match valid {
+            true => rustls_result::Ok,
+            false => rustls_result::InvalidDnsNameError,
+        };
+        for &(name, valid) in SERVER_NAME_CASES {
+            let result = rustls_server_name::rustls_server_name_validate(
+                name.as_ptr() as *const c_char,
+                name.len(),
+            );
+            assert_eq!(result, expected(valid), "{:?}", name);
+
+            let (result, conn) = connection_new_slice(config, name);
+            assert_eq!(result, expected(valid), "{:?}", name);
+            rustls_connection::rustls_connection_free(conn);
+
+            if name.contains(&0) {
+                continue;
+            }
+            let mut c_name = name.to_vec();
+            c_name.push(0);
+            let mut conn: *mut rustls_connection = null_mut();
+            let result = rustls_client_config::rustls_client_connection_new(
+                config,
+                c_name.as_ptr() as *const c_char,
+                &mut conn,
+            );
+            assert_eq!(result, expected(valid), "{:?}", name);
+            rustls_connection::rustls_connection_free(conn);
+
+            let mut server_name: *const rustls_server_name = null();
+            let result = rustls_server_name::rustls_server_name_new(
+                c_name.as_ptr() as *const c_char,
+                &mut server_name,
+            );
+            assert_eq!(result, expected(valid), "{:?}", name);
+            rustls_server_name::rustls_server_name_free(server_name);
+        }
+
+        // Only the given bytes are looked at.
+        let url = b"https://example.com/";
+        assert_eq!(
+            rustls_server_name::rustls_server_name_validate(url[8..].as_ptr() as *const c_char, 11),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            rustls_server_name::rustls_server_name_validate(null(), 0),
+            rustls_result::NullParameter
+        );
+        rustls_client_config::rustls_client_config_free(config);
+    }
+
+    #[test]
+    fn test_server_name_new() {
+        let mut name: *const rustls_server_name = null();
+        for bad in [
+            &b"\0"[..],
+            b"exa mple.com\0",
+            b"\xffexample.com\0",
+            b"example..com\0",
+        ] {
+            assert_eq!(
+                rustls_server_name::rustls_server_name_new(
+                    bad.as_ptr() as *const c_char,
+                    &mut name
+                ),
+                rustls_result::InvalidDnsNameError
+            );
+            assert!(name.is_null());
+        }
+        assert_eq!(
+            rustls_server_name::rustls_server_name_new(null(), &mut name),
+            rustls_result::NullParameter
+        );
+        assert_eq!(
+            rustls_server_name::rustls_server_name_new(
+                "example.com\0".as_ptr() as *const c_char,
+                null_mut()
+            ),
+            rustls_result::NullParameter
+        );
+        for good in ["example.com\0", "198.51.100.198\0", "2001:db8::1\0"] {
+            assert_eq!(
+                rustls_server_name::rustls_server_name_new(
+                    good.as_ptr() as *const c_char,
+                    &mut name
+                ),
+                rustls_result::Ok
+            );
+            assert!(!name.is_null());
+            rustls_server_name::rustls_server_name_free(name);
+            name = null();
+        }
+        rustls_server_name::rustls_server_name_free(null());
+
+        let mut conn: *mut rustls_connection = null_mut();
+        let builder: *mut rustls_client_config_builder =
+            rustls_client_config_builder::rustls_client_config_builder_new();
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        assert_eq!(
+            rustls_client_config::rustls_client_connection_new_with_name(config, null(), &mut conn),
+            rustls_result::NullParameter
+        );
+        assert!(conn.is_null());
+        rustls_client_config::rustls_client_config_free(config);
+    }
+
+    // Create a uniquely named file in the temporary directory with the given
+    // contents, returning its path.
+    #[cfg(unix)]
+    fn temp_file(name: &[u8], contents: &[u8]) -> std::path::PathBuf {
+        let mut file_name = format!("rustls-ffi-test-{}-", std::process::id()).into_bytes();
+        file_name.extend_from_slice(name);
+        let path = std::env::temp_dir().join(os_str_from_bytes(&file_name).unwrap());
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    fn load_roots_from_file(path: &std::path::Path) -> rustls_result {
+        use std::os::unix::ffi::OsStrExt;
+        let builder: *mut rustls_client_config_builder =
+            rustls_client_config_builder::rustls_client_config_builder_new();
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_load_roots_from_file(
+                builder,
+                path.as_ptr(),
+            );
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+        result
+    }
+
+    #[test]
+    #[cfg(unix)]
+    #[cfg_attr(miri, ignore)]
+    fn test_load_roots_from_file() {
+        let bundle = concat!(
+            include_str!("../testdata/minica.pem"),
+            include_str!("../testdata/localhost/cert.pem"),
+        );
+        let path = temp_file(b"bundle.pem", bundle.as_bytes());
+        assert_eq!(load_roots_from_file(&path), rustls_result::Ok);
+        std::fs::remove_file(&path).unwrap();
+
+        // Not valid UTF-8.
+        let path = temp_file(b"bundle-\xff.pem", bundle.as_bytes());
+        assert!(path.to_str().is_none());
+        assert_eq!(load_roots_from_file(&path), rustls_result::Ok);
+        std::fs::remove_file(&path).unwrap();
+
+        let path = temp_file(b"empty.pem", b"no certificates here\n");
+        assert_eq!(
+            load_roots_from_file(&path),
+            rustls_result::NoCertificatesFound
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let malformed = b"-----BEGIN CERTIFICATE-----\n!!!!\n-----END CERTIFICATE-----\n";
+        let path = temp_file(b"malformed.pem", malformed);
+        assert_eq!(
+            load_roots_from_file(&path),
+            rustls_result::CertificateParseError
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let path = std::env::temp_dir().join("rustls-ffi-test-does-not-exist.pem");
+        assert_eq!(load_roots_from_file(&path), rustls_result::Io);
+    }
+
+    // Decode hex the way TLSA data is written in zone files, ignoring
+    // whitespace.
+    pub(crate) fn unhex(hex: &str) -> Vec<u8> {
+        let digits: Vec<u8> = hex
+            .bytes()
+            .filter(|b| !b.is_ascii_whitespace())
+            .map(|b| (b as char).to_digit(16).unwrap() as u8)
+            .collect();
+        digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect()
+    }
+
+    #[test]
+    fn test_tlsa_record_usability() {
+        // The examples in RFC 6698 section 2.3. The RFC elides most of the
+        // certificate in the last one.
+        let sha256 = unhex("d2abde240d7cd3ee6b4b28c54df034b9 7983a1d16e8a410e4561cb106618e971");
+        let sha512 = unhex(
+            "92003ba34942dc74152e2f2c408d29ec a5a520e7f2e06bb944f4dca346baf63c
+             1b177615d466f6c4b71c216a50292bd5 8c9ebdd2f74e38fe51ffd48c43326cbc",
+        );
+        let cert = unhex("30820307308201efa003020102");
+
+        let record = TlsaRecord::new(0, 0, 1, &sha256).unwrap();
+        assert_eq!(record.usage, TlsaUsage::PkixTa);
+        assert!(!record.spki_only);
+        assert_eq!(record.digest, Some(&ring::digest::SHA256));
+        let record = TlsaRecord::new(1, 1, 2, &sha512).unwrap();
+        assert_eq!(record.usage, TlsaUsage::PkixEe);
+        assert!(record.spki_only);
+        assert_eq!(record.digest, Some(&ring::digest::SHA512));
+        let record = TlsaRecord::new(3, 0, 0, &cert).unwrap();
+        assert_eq!(record.usage, TlsaUsage::DaneEe);
+        assert_eq!(record.digest, None);
+        assert_eq!(record.data, cert);
+        assert_eq!(
+            TlsaRecord::new(2, 1, 1, &sha256).unwrap().usage,
+            TlsaUsage::DaneTa
+        );
+
+        // Unknown usages, selectors and matching types, and data that can't
+        // be the digest the record claims, are unusable.
+        assert!(TlsaRecord::new(4, 0, 1, &sha256).is_none());
+        assert!(TlsaRecord::new(255, 0, 1, &sha256).is_none());
+        assert!(TlsaRecord::new(3, 2, 1, &sha256).is_none());
+        assert!(TlsaRecord::new(3, 0, 3, &sha256).is_none());
+        assert!(TlsaRecord::new(3, 0, 1, &sha512).is_none());
+        assert!(TlsaRecord::new(3, 0, 2, &sha256).is_none());
+        assert!(TlsaRecord::new(3, 0, 0, &[]).is_none());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_tlsa_record_matches() {
+        let der = |pem: &str| {
+            Certificate(
+                rustls_pemfile::certs(&mut pem.as_bytes())
+                    .unwrap()
+                    .remove(0),
+            )
+        };
+        let cert = der(include_str!("../testdata/localhost/cert.pem"));
+        let other = der(include_str!("../testdata/example.com/cert.pem"));
+        // Computed with `openssl x509 -outform der | openssl dgst -sha256`,
+        // and for the SubjectPublicKeyInfo with
+        // `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der |
+        // openssl dgst -sha512`.
+        let cert_sha256 = unhex("120b1d2f61671ac7db558366da18e85a9b2b8c0f8982583b541ec1eeeae0160c");
+        let spki_sha512 = unhex(
+            "fa993aea90889aaecbd4b288b0f5d281015494841eda96b87f25c66646539ae5
+             5cd83c23da707eae98a6038af3a9651b89c3252a6283972e62506de9cc53996e",
+        );
+
+        let records = [
+            TlsaRecord::new(3, 0, 1, &cert_sha256).unwrap(),
+            TlsaRecord::new(3, 1, 2, &spki_sha512).unwrap(),
+            TlsaRecord::new(3, 0, 0, &cert.0).unwrap(),
+            TlsaRecord::new(3, 1, 0, spki_der(&cert).unwrap()).unwrap(),
+        ];
+        for record in &records {
+            assert!(record.matches(&cert), "{:?}", record);
+            assert!(!record.matches(&other), "{:?}", record);
+        }
+        // A digest of the certificate doesn't match its key, and vice versa.
+        assert!(!TlsaRecord::new(3, 1, 1, &cert_sha256)
+            .unwrap()
+            .matches(&cert));
+        assert!(!TlsaRecord::new(3, 0, 2, &spki_sha512)
+            .unwrap()
+            .matches(&cert));
+        assert!(!records[0].matches(&Certificate(b"not a cert".to_vec())));
+        assert!(!records[1].matches(&Certificate(b"not a cert".to_vec())));
+    }
+
+    // A chain captured from www.netflix.com in April 2017, taken from
+    // webpki's tests: the leaf and intermediate the server sent, and the
+    // root it chains to.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_dane_captured_chain() {
+        let certs = |pem: &[u8]| -> Vec<Certificate> {
+            rustls_pemfile::certs(&mut &*pem)
+                .unwrap()
+                .into_iter()
+                .map(Certificate)
+                .collect()
+        };
+        let chain = certs(include_bytes!("../testdata/netflix/chain.pem"));
+        let root = certs(include_bytes!("../testdata/netflix/root.pem")).remove(0);
+        let mut with_root = chain.clone();
+        with_root.push(root.clone());
+        let mut roots = RootCertStore::empty();
+        roots.add(&root).unwrap();
+        let webpki: Arc<dyn ServerCertVerifier> = Arc::new(WebPkiVerifier::new(roots, None));
+        let captured = SystemTime::UNIX_EPOCH + Duration::from_secs(1_492_441_716);
+        let now = SystemTime::now();
+
+        // Computed with `openssl x509 -pubkey -noout | openssl pkey -pubin
+        // -outform der | openssl dgst -sha256`, and for the intermediate
+        // with `openssl x509 -outform der | openssl dgst -sha256`.
+        let leaf_spki = unhex("f0a7cdaa4a45e92da94aaaa460295119aba317e5d743a69bd47f42da3bfbf8ed");
+        let intermediate_cert =
+            unhex("564dbc6a76550fd2d4f4bac6448a1157b33cbd8e0b3fd76d72107540964a85b5");
+        let root_spki = unhex("495a96ba6bad782407bd521a00bace657bb355555e4bb7f8146c71bba57e7ace");
+
+        let verify = |record: (u8, u8, u8, &[u8]), sent: &[Certificate], name: &str, now| {
+            let (usage, selector, matching_type, data) = record;
+            let verifier = DaneVerifier {
+                inner: webpki.clone(),
+                records: vec![TlsaRecord::new(usage, selector, matching_type, data).unwrap()],
+                skip_hostname_verification: false,
+            };
+            verifier
+                .verify_server_cert(
+                    &sent[0],
+                    &sent[1..],
+                    &rustls::ServerName::try_from(name).unwrap(),
+                    &mut std::iter::empty(),
+                    &[],
+                    now,
+                )
+                .map(|_| ())
+                .map_err(error::map_error)
+        };
+        let www = "www.netflix.com";
+
+        // DANE-EE checks neither the name nor the validity period.
+        let dane_ee = (3, 1, 1, &leaf_spki[..]);
+        assert_eq!(verify(dane_ee, &chain, www, captured), Ok(()));
+        assert_eq!(verify(dane_ee, &chain, www, now), Ok(()));
+        assert_eq!(verify(dane_ee, &chain, "example.com", now), Ok(()));
+
+        // DANE-TA anchored at the intermediate checks both.
+        let dane_ta = (2, 0, 1, &intermediate_cert[..]);
+        assert_eq!(verify(dane_ta, &chain, www, captured), Ok(()));
+        assert_eq!(
+            verify(dane_ta, &chain, www, now),
+            Err(rustls_result::CertExpired)
+        );
+        assert_eq!(
+            verify(dane_ta, &chain, "example.com", captured),
+            Err(rustls_result::CertNotValidForName)
+        );
+
+        // A trust anchor only counts if the server sent it.
+        let dane_ta_root = (2, 1, 1, &root_spki[..]);
+        assert_eq!(
+            verify(dane_ta_root, &chain, www, captured),
+            Err(rustls_result::TlsaMismatch)
+        );
+        assert_eq!(verify(dane_ta_root, &with_root, www, captured), Ok(()));
+
+        // The PKIX usages also need the chain to verify to a trusted root.
+        let pkix_ee = (1, 1, 1, &leaf_spki[..]);
+        assert_eq!(verify(pkix_ee, &chain, www, captured), Ok(()));
+        assert_eq!(
+            verify(pkix_ee, &chain, www, now),
+            Err(rustls_result::CertExpired)
+        );
+        let pkix_ta = (0, 1, 1, &root_spki[..]);
+        assert_eq!(verify(pkix_ta, &with_root, www, captured), Ok(()));
+        assert_eq!(
+            verify(pkix_ta, &chain, www, captured),
+            Err(rustls_result::TlsaMismatch)
+        );
+    }
 }
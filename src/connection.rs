@@ -1,41 +1,98 @@
-use std::io::{ErrorKind, Read, Write};
+use std::io::{ErrorKind, IoSlice, IoSliceMut, Read, Write};
+use std::time::{Duration, Instant};
 use std::{ffi::c_void, ptr::null};
 use std::{ptr::null_mut, slice};
 
-use libc::{size_t, EINVAL, EIO};
+use libc::{c_char, c_int, size_t, EAGAIN, EINVAL, EIO};
 use rustls::{
     Certificate, ClientConnection, ServerConnection, SupportedCipherSuite, ALL_CIPHER_SUITES,
 };
 
 use crate::io::{
-    rustls_write_vectored_callback, CallbackReader, CallbackWriter, ReadCallback,
-    VectoredCallbackWriter, VectoredWriteCallback, WriteCallback,
+    rustls_iovec_mut, rustls_write_vectored_callback, CallbackReader, CallbackWriter,
+    CountingStream, FdStream, ReadCallback, VectoredCallbackWriter, VectoredWriteCallback,
+    WriteCallback,
 };
 use crate::log::{ensure_log_registered, rustls_log_callback};
 
+use crate::buffer::{rustls_buffer, rustls_ring_buffer, RingBuffer};
 use crate::BoxCastPtr;
 use crate::{
-    cipher::{rustls_certificate, rustls_supported_ciphersuite},
+    cert_verified_get, try_mut_from_ptr, try_mut_slice, try_slice, userdata_push,
+    verified_chain_take, CastPtr,
+};
+use crate::{
+    cipher::{rustls_certificate, rustls_supported_ciphersuite, VerifiedChain},
     error::{map_error, rustls_io_result, rustls_result},
     io::{rustls_read_callback, rustls_write_callback},
-    try_callback,
+    pem, try_callback,
 };
 use crate::{ffi_panic_boundary, try_ref_from_ptr};
-use crate::{try_mut_from_ptr, try_slice, userdata_push, CastPtr};
 use rustls_result::NullParameter;
 
 pub(crate) struct Connection {
     conn: rustls::Connection,
     userdata: *mut c_void,
     log_callback: rustls_log_callback,
+    verify_name: Option<rustls::ServerName>,
+    config_userdata: *mut c_void,
+    sent_close_notify: bool,
+    handshake_done_callback: rustls_handshake_done_callback,
+    handshake_done_userdata: *mut c_void,
+    server_cert_verified: bool,
+    // The path the server's certificate was verified along, ending with the
+    // trust anchor, if the verifier recorded one.
+    verified_chain: Option<VerifiedChain>,
+    plaintext_bytes_to_read: usize,
+    // TLS bytes taken out of rustls, so that they could be counted, but not
+    // yet written. They come before anything rustls still holds.
+    tls_out: Vec<u8>,
+    buffer_limit: Option<usize>,
+    last_error: Option<String>,
+    // Plaintext taken out of rustls so that it could be peeked at, but not
+    // yet consumed. It comes before anything rustls still holds.
+    plaintext_in: Vec<u8>,
+    // Set once no more TLS data will come from the peer: it sent
+    // close_notify, the transport hit EOF, or processing failed.
+    closed: bool,
+    // Set once the peer's close_notify has been processed.
+    peer_closed: bool,
+    // The error processing packets failed with, after which the connection
+    // is dead. Data-plane calls other than writing TLS return it.
+    fatal_error: Option<rustls_result>,
+    counters: rustls_counters,
+    created: Instant,
+    // How long after `created` the handshake finished, once it has.
+    handshake_duration: Option<Duration>,
 }
 
+// rustls's default limit on its outgoing buffers.
+const DEFAULT_BUFFER_LIMIT: usize = 64 * 1024;
+
 impl Connection {
     pub(crate) fn from_client(conn: ClientConnection) -> Self {
         Connection {
             conn: conn.into(),
             userdata: null_mut(),
             log_callback: None,
+            verify_name: None,
+            config_userdata: null_mut(),
+            sent_close_notify: false,
+            handshake_done_callback: None,
+            handshake_done_userdata: null_mut(),
+            server_cert_verified: false,
+            verified_chain: None,
+            plaintext_bytes_to_read: 0,
+            tls_out: Vec::new(),
+            buffer_limit: Some(DEFAULT_BUFFER_LIMIT),
+            last_error: None,
+            plaintext_in: Vec::new(),
+            closed: false,
+            peer_closed: false,
+            fatal_error: None,
+            counters: rustls_counters::default(),
+            created: Instant::now(),
+            handshake_duration: None,
         }
     }
 
@@ -44,10 +101,373 @@ impl Connection {
             conn: conn.into(),
             userdata: null_mut(),
             log_callback: None,
+            verify_name: None,
+            config_userdata: null_mut(),
+            sent_close_notify: false,
+            handshake_done_callback: None,
+            handshake_done_userdata: null_mut(),
+            server_cert_verified: false,
+            verified_chain: None,
+            plaintext_bytes_to_read: 0,
+            tls_out: Vec::new(),
+            buffer_limit: Some(DEFAULT_BUFFER_LIMIT),
+            last_error: None,
+            plaintext_in: Vec::new(),
+            closed: false,
+            peer_closed: false,
+            fatal_error: None,
+            counters: rustls_counters::default(),
+            created: Instant::now(),
+            handshake_duration: None,
+        }
+    }
+
+    /// Verify the peer's certificate against `name` rather than the name
+    /// the connection was created with.
+    pub(crate) fn set_verify_name(&mut self, name: rustls::ServerName) {
+        self.verify_name = Some(name);
+    }
+
+    pub(crate) fn set_userdata(&mut self, userdata: *mut c_void) {
+        self.userdata = userdata;
+    }
+
+    /// Take over `fresh`'s TLS state, so that this connection can be reused
+    /// for a new one. The userdata, callbacks, buffer limit and counters
+    /// carry over; the buffers are emptied but keep their allocations.
+    pub(crate) fn reset(&mut self, fresh: Connection) {
+        let mut tls_out = std::mem::take(&mut self.tls_out);
+        tls_out.clear();
+        let mut plaintext_in = std::mem::take(&mut self.plaintext_in);
+        plaintext_in.clear();
+        *self = Connection {
+            userdata: self.userdata,
+            log_callback: self.log_callback,
+            handshake_done_callback: self.handshake_done_callback,
+            handshake_done_userdata: self.handshake_done_userdata,
+            buffer_limit: self.buffer_limit,
+            counters: self.counters,
+            tls_out,
+            plaintext_in,
+            ..fresh
+        };
+        self.apply_buffer_limit();
+    }
+
+    /// Remember the userdata of the config this connection was made with.
+    pub(crate) fn set_config_userdata(&mut self, userdata: *mut c_void) {
+        self.config_userdata = userdata;
+    }
+
+    pub(crate) fn config_userdata(&self) -> *mut c_void {
+        self.config_userdata
+    }
+
+    /// Whether the server's certificate was verified during this connection's
+    /// handshake. Resumed handshakes skip verification.
+    pub(crate) fn server_cert_verified(&self) -> bool {
+        self.server_cert_verified
+    }
+
+    pub(crate) fn verified_chain(&self) -> Option<&[Certificate]> {
+        self.verified_chain.as_ref().and_then(VerifiedChain::path)
+    }
+
+    /// A one-line description of the connection's state, for
+    /// rustls_connection_debug_dump. It mentions no secrets.
+    fn debug_summary(&mut self) -> String {
+        let tls_to_write = self.tls_bytes_to_write();
+        let side = match self.conn {
+            rustls::Connection::Client(_) => "client",
+            rustls::Connection::Server(_) => "server",
+        };
+        let version = self
+            .protocol_version()
+            .map_or("none".to_string(), |v| format!("{:?}", v));
+        let suite = self
+            .negotiated_cipher_suite()
+            .map_or("none".to_string(), |cs| format!("{:?}", cs.suite()));
+        // Only a client can tell, from whether it verified a certificate.
+        let resumed = match self.conn {
+            rustls::Connection::Client(_) if !self.is_handshaking() => {
+                if self.server_cert_verified {
+                    "no"
+                } else {
+                    "yes"
+                }
+            }
+            _ => "unknown",
+        };
+        let fatal_error = self
+            .fatal_error
+            .map_or(String::new(), |e| format!(" fatal_error={:?}", e));
+        format!(
+            "{} handshaking={} wants_read={} wants_write={} plaintext_to_read={} \
+             tls_to_write={} version={} suite={} resumed={} close_notify_sent={} \
+             close_notify_received={}{}",
+            side,
+            self.is_handshaking(),
+            self.wants_read(),
+            self.wants_write(),
+            self.plaintext_bytes_to_read,
+            tls_to_write,
+            version,
+            suite,
+            resumed,
+            self.sent_close_notify,
+            self.peer_closed,
+            fatal_error,
+        )
+    }
+
+    /// Whether there are TLS bytes to write, here or in rustls.
+    pub(crate) fn wants_write(&self) -> bool {
+        !self.tls_out.is_empty() || self.conn.wants_write()
+    }
+
+    /// Read TLS bytes from `rd`, like rustls's read_tls, noting when the
+    /// transport has reached EOF.
+    pub(crate) fn read_tls(&mut self, rd: &mut dyn Read) -> std::io::Result<usize> {
+        let result = self.conn.read_tls(rd);
+        match result {
+            Ok(0) => self.closed = true,
+            Ok(n) => self.counters.tls_read += n as u64,
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Write pending TLS bytes to `wr`, with a single write. This takes the
+    /// place of rustls's write_tls, so that bytes moved into `tls_out` go
+    /// out first.
+    pub(crate) fn write_tls(&mut self, wr: &mut dyn Write) -> std::io::Result<usize> {
+        if self.tls_out.is_empty() {
+            let n = self.conn.write_tls(wr)?;
+            self.counters.tls_written += n as u64;
+            return Ok(n);
+        }
+        let n = wr.write_vectored(&[IoSlice::new(&self.tls_out)])?;
+        let n = n.min(self.tls_out.len());
+        self.consume_tls_out(n);
+        Ok(n)
+    }
+
+    // Drop the first `n` bytes of `tls_out`, which have been sent.
+    fn consume_tls_out(&mut self, n: usize) {
+        self.tls_out.drain(..n);
+        self.counters.tls_written += n as u64;
+        self.apply_buffer_limit();
+    }
+
+    /// The exact number of TLS bytes waiting to be written. rustls doesn't
+    /// say, so move them all into `tls_out` and count them there.
+    pub(crate) fn tls_bytes_to_write(&mut self) -> usize {
+        while self.conn.wants_write() {
+            // Writing to a Vec can't fail.
+            let _ = self.conn.write_tls(&mut self.tls_out);
+        }
+        self.apply_buffer_limit();
+        self.tls_out.len()
+    }
+
+    pub(crate) fn set_buffer_limit(&mut self, limit: Option<usize>) {
+        self.buffer_limit = limit;
+        self.apply_buffer_limit();
+    }
+
+    // Bytes in `tls_out` still count against the buffer limit, so rustls gets
+    // whatever is left of it.
+    fn apply_buffer_limit(&mut self) {
+        let limit = self
+            .buffer_limit
+            .map(|limit| limit.saturating_sub(self.tls_out.len()));
+        self.conn.set_buffer_limit(limit);
+    }
+
+    /// Read plaintext into `buf`, starting with any in `plaintext_in`. Once
+    /// that runs out, this behaves like rustls's Reader.
+    pub(crate) fn read_plaintext(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let held = self.plaintext_in.len().min(buf.len());
+        buf[..held].copy_from_slice(&self.plaintext_in[..held]);
+        self.plaintext_in.drain(..held);
+        let result = if held == buf.len() {
+            Ok(held)
+        } else {
+            match self.conn.reader().read(&mut buf[held..]) {
+                Ok(n) => Ok(held + n),
+                // Report what we have; the next read will see the error.
+                Err(_) if held > 0 => Ok(held),
+                Err(e) => Err(e),
+            }
+        };
+        if let Ok(n) = result {
+            self.consumed_plaintext(n);
+        }
+        result
+    }
+
+    /// Read as much plaintext as is available into `buf`, and return how
+    /// much that was. Whatever stops it, end of stream included, is left for
+    /// the next read to report.
+    pub(crate) fn fill_plaintext(&mut self, buf: &mut [u8]) -> usize {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read_plaintext(&mut buf[filled..]) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => filled += n,
+            }
+        }
+        filled
+    }
+
+    /// Move all the plaintext rustls holds to the end of `plaintext_in`. The
+    /// result is what rustls's Reader said when it ran out: Ok for a clean
+    /// close, otherwise the error a read would return.
+    pub(crate) fn take_plaintext(&mut self) -> std::io::Result<()> {
+        const CHUNK: usize = 4096;
+        loop {
+            let start = self.plaintext_in.len();
+            self.plaintext_in.resize(start + CHUNK, 0);
+            let result = self.conn.reader().read(&mut self.plaintext_in[start..]);
+            let n = *result.as_ref().unwrap_or(&0);
+            self.plaintext_in.truncate(start + n);
+            match result {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Note that rustls has accepted `n` bytes of plaintext to send.
+    pub(crate) fn wrote_plaintext(&mut self, n: usize) {
+        self.counters.plaintext_written += n as u64;
+    }
+
+    // Note that `n` bytes of plaintext have been handed to the caller.
+    fn consumed_plaintext(&mut self, n: usize) {
+        self.plaintext_bytes_to_read = self.plaintext_bytes_to_read.saturating_sub(n);
+        self.counters.plaintext_read += n as u64;
+    }
+
+    // Remember why an I/O call failed, for rustls_connection_get_last_error,
+    // or forget the last failure if it succeeded. WouldBlock is neither: it
+    // only means "try again later".
+    fn note_io_result<T>(&mut self, result: &std::io::Result<T>) {
+        match result {
+            Ok(_) => self.last_error = None,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => self.last_error = Some(e.to_string()),
+        }
+    }
+
+    // Note whether processing packets means nothing more will arrive.
+    fn note_closed(&mut self, result: &Result<rustls::IoState, rustls::Error>) {
+        match result {
+            Ok(io_state) if io_state.peer_has_closed() => {
+                self.closed = true;
+                self.peer_closed = true;
+            }
+            Ok(_) => (),
+            Err(_) => self.closed = true,
+        }
+    }
+
+    /// The error that killed this connection, if processing packets has
+    /// failed.
+    pub(crate) fn fatal_error(&self) -> Option<rustls_result> {
+        self.fatal_error
+    }
+
+    // Record when the handshake finished, the first time processing leaves
+    // it done.
+    fn note_handshake_progress(&mut self) {
+        if self.handshake_duration.is_none() && !self.conn.is_handshaking() {
+            self.handshake_duration = Some(self.created.elapsed());
+        }
+    }
+
+    // Like note_io_result, for calls that fail with a TLS error.
+    fn note_tls_result<T>(&mut self, result: &Result<T, rustls::Error>) {
+        self.last_error = result.as_ref().err().map(|e| e.to_string());
+    }
+
+    // Run `f`, which may process packets, with this connection's userdata,
+    // log callback and name to verify available to the callbacks it invokes.
+    // Notes whether the server's certificate was verified along the way.
+    fn with_callbacks<T>(
+        &mut self,
+        f: impl FnOnce(&mut rustls::Connection) -> T,
+    ) -> Result<T, rustls_result> {
+        let guard = match userdata_push(
+            self.callback_userdata(),
+            self.log_callback,
+            self.verify_name.clone(),
+        ) {
+            Ok(g) => g,
+            Err(_) => return Err(rustls_result::Panic),
+        };
+        let result = f(&mut self.conn);
+        if matches!(cert_verified_get(), Ok(true)) {
+            self.server_cert_verified = true;
+        }
+        if let Ok(Some(chain)) = verified_chain_take() {
+            self.verified_chain = Some(chain);
+        }
+        if guard.try_drop().is_err() {
+            return Err(rustls_result::Panic);
+        }
+        Ok(result)
+    }
+
+    // Process new packets with callbacks set up, keeping track of the
+    // plaintext that arrives and of any failure.
+    fn process_packets(&mut self) -> Result<rustls::IoState, rustls_result> {
+        let result = self.with_callbacks(|c| c.process_new_packets())?;
+        self.note_tls_result(&result);
+        self.note_closed(&result);
+        self.note_handshake_progress();
+        let io_state = result.map_err(|e| {
+            let e = map_error(e);
+            self.fatal_error = Some(e);
+            e
+        })?;
+        self.plaintext_bytes_to_read = io_state.plaintext_bytes_to_read() + self.plaintext_in.len();
+        Ok(io_state)
+    }
+
+    // Invoke the handshake-done callback, if one is set and the handshake
+    // is over, and say whether it ran. It is cleared first so that it runs
+    // only once. `conn` is the C pointer to this connection, passed through
+    // to the callback.
+    fn notify_handshake_done(&mut self, conn: *mut rustls_connection) -> bool {
+        if self.is_handshaking() {
+            return false;
+        }
+        match self.handshake_done_callback.take() {
+            Some(cb) => {
+                let userdata = if self.handshake_done_userdata.is_null() {
+                    self.callback_userdata()
+                } else {
+                    self.handshake_done_userdata
+                };
+                unsafe { cb(userdata, conn) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The userdata passed to callbacks: the connection's own, falling back
+    // to the config's.
+    fn callback_userdata(&self) -> *mut c_void {
+        if self.userdata.is_null() {
+            self.config_userdata
+        } else {
+            self.userdata
         }
     }
 
-    #[allow(dead_code)]
     pub(crate) fn as_client(&self) -> Option<&ClientConnection> {
         match &self.conn {
             rustls::Connection::Client(c) => Some(c),
@@ -62,7 +482,6 @@ impl Connection {
         }
     }
 
-    #[allow(dead_code)]
     pub(crate) fn as_client_mut(&mut self) -> Option<&mut ClientConnection> {
         match &mut self.conn {
             rustls::Connection::Client(c) => Some(c),
@@ -70,6 +489,14 @@ impl Connection {
         }
     }
 
+    /// The certificates the peer presented, once the handshake is complete.
+    fn verified_peer_certificates(&self) -> &[Certificate] {
+        if self.is_handshaking() {
+            return &[];
+        }
+        self.peer_certificates().unwrap_or(&[])
+    }
+
     #[allow(dead_code)]
     pub(crate) fn as_server_mut(&mut self) -> Option<&mut ServerConnection> {
         match &mut self.conn {
@@ -93,10 +520,96 @@ impl std::ops::DerefMut for Connection {
     }
 }
 
+/// A callback for rustls_connection_set_handshake_done_callback. It is called
+/// once, with the userdata given when it was registered, when the handshake
+/// on `conn` completes successfully. The callback may use `conn`, for
+/// instance to start writing plaintext, but must not free it.
+#[allow(non_camel_case_types)]
+pub type rustls_handshake_done_callback =
+    Option<unsafe extern "C" fn(userdata: *mut c_void, conn: *mut rustls_connection)>;
+
+/// Map an error from rustls' plaintext reader to a rustls_result.
+///
+/// The reader reports its state through the error kind alone: a clean close
+/// (close_notify received) is `Ok(0)` and never reaches here, WouldBlock
+/// means no plaintext is buffered yet, and UnexpectedEof means the transport
+/// ended without close_notify, i.e. the stream may have been truncated.
+fn map_read_error(e: &std::io::Error) -> rustls_result {
+    match e.kind() {
+        ErrorKind::WouldBlock => rustls_result::PlaintextEmpty,
+        ErrorKind::UnexpectedEof => rustls_result::UnexpectedEof,
+        _ => rustls_result::Io,
+    }
+}
+
+// Map an error from complete_io to a rustls_result. TLS errors come wrapped
+// in an InvalidData io::Error.
+fn map_complete_io_error(e: &std::io::Error) -> rustls_result {
+    if let Some(tls_error) = e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        return map_error(tls_error.clone());
+    }
+    match e.kind() {
+        ErrorKind::WouldBlock => rustls_result::WouldBlock,
+        ErrorKind::UnexpectedEof => rustls_result::UnexpectedEof,
+        _ => rustls_result::Io,
+    }
+}
+
 pub struct rustls_connection {
     _private: [u8; 0],
 }
 
+/// What a connection can do next, filled in by
+/// rustls_connection_process_new_packets_ex. This is a plain struct that C
+/// allocates:
+///
+/// - `bytes_to_read`: plaintext bytes waiting for rustls_connection_read.
+/// - `bytes_to_write`: TLS bytes waiting for rustls_connection_write_tls.
+/// - `handshaking`: whether the handshake is still in progress.
+/// - `peer_closed`: whether the peer has sent close_notify. Once the
+///   `bytes_to_read` have been read, no more plaintext will arrive.
+#[repr(C)]
+pub struct rustls_io_state {
+    pub bytes_to_read: size_t,
+    pub bytes_to_write: size_t,
+    pub handshaking: bool,
+    pub peer_closed: bool,
+}
+
+/// Running totals of the bytes that have passed through a connection, filled
+/// in by rustls_connection_get_counters. This is a plain struct that C
+/// allocates:
+///
+/// - `plaintext_written`: plaintext accepted by rustls_connection_write,
+///   rustls_connection_write_all and, for clients,
+///   rustls_client_connection_write_early_data.
+/// - `plaintext_read`: plaintext handed to the application by
+///   rustls_connection_read and the other reading functions.
+/// - `tls_written`: TLS bytes handed to the network, however they were sent.
+///   Handshake messages, alerts and record overhead are all included.
+/// - `tls_read`: TLS bytes taken from the network, however they arrived.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct rustls_counters {
+    pub plaintext_written: u64,
+    pub plaintext_read: u64,
+    pub tls_written: u64,
+    pub tls_read: u64,
+}
+
+/// Flags for rustls_connection_poll_interest, to be combined with bitwise OR.
+#[repr(C)]
+#[allow(dead_code)]
+pub enum rustls_interest {
+    /// rustls_connection_wants_read is true: read TLS bytes when readable.
+    Read = 1,
+    /// rustls_connection_wants_write is true: write TLS bytes when writable.
+    Write = 2,
+    /// No more TLS bytes will come from the peer: it sent close_notify, the
+    /// transport reached EOF, or processing failed.
+    Closed = 4,
+}
+
 impl CastPtr for rustls_connection {
     type RustType = Connection;
 }
@@ -106,7 +619,9 @@ impl BoxCastPtr for rustls_connection {}
 impl rustls_connection {
     /// Set the userdata pointer associated with this connection. This will be passed
     /// to any callbacks invoked by the connection, if you've set up callbacks in the config.
-    /// The pointed-to data must outlive the connection.
+    /// The pointed-to data must outlive the connection. Until this is set, client
+    /// connections pass the config userdata instead, if
+    /// rustls_client_config_builder_set_userdata was used.
     #[no_mangle]
     pub extern "C" fn rustls_connection_set_userdata(
         conn: *mut rustls_connection,
@@ -116,6 +631,25 @@ impl rustls_connection {
         conn.userdata = userdata;
     }
 
+    /// Store in *out the userdata pointer set with rustls_connection_set_userdata,
+    /// or NULL if none was set. Rust never dereferences it.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_get_userdata(
+        conn: *const rustls_connection,
+        out: *mut *mut c_void,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if out.is_null() {
+                return NullParameter
+            }
+            unsafe {
+                *out = conn.userdata;
+            }
+            rustls_result::Ok
+        }
+    }
+
     /// Set the logging callback for this connection. The log callback will be invoked
     /// with the userdata parameter previously set by rustls_connection_set_userdata, or
     /// NULL if no userdata was set.
@@ -154,7 +688,43 @@ impl rustls_connection {
             let callback: ReadCallback = try_callback!(callback);
 
             let mut reader = CallbackReader { callback, userdata };
-            let n_read: usize = match conn.read_tls(&mut reader) {
+            let result = conn.read_tls(&mut reader);
+            conn.note_io_result(&result);
+            let n_read: usize = match result {
+                Ok(n) => n,
+                Err(e) => return rustls_io_result(e.raw_os_error().unwrap_or(EIO)),
+            };
+            unsafe {
+                *out_n = n_read;
+            }
+
+            rustls_io_result(0)
+        }
+    }
+
+    /// Read some TLS bytes from the file descriptor `fd` into internal buffers,
+    /// like rustls_connection_read_tls with a callback that calls `read(2)` once.
+    /// `fd` is typically a socket, blocking or not. It is never closed.
+    ///
+    /// Returns 0 for success, with *out_n set to the number of bytes read; 0
+    /// bytes means the peer has closed the transport (EOF). Otherwise returns
+    /// the errno from `read(2)`: EAGAIN or EWOULDBLOCK for a non-blocking `fd`
+    /// with nothing to read, in which case try again once it is readable.
+    /// Returns ENOSYS on platforms without file descriptors, such as Windows.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_read_tls_from_fd(
+        conn: *mut rustls_connection,
+        fd: c_int,
+        out_n: *mut size_t,
+    ) -> rustls_io_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if out_n.is_null() {
+                return rustls_io_result(EINVAL)
+            }
+            let result = conn.read_tls(&mut FdStream { fd });
+            conn.note_io_result(&result);
+            let n_read: usize = match result {
                 Ok(n) => n,
                 Err(e) => return rustls_io_result(e.raw_os_error().unwrap_or(EIO)),
             };
@@ -166,6 +736,95 @@ impl rustls_connection {
         }
     }
 
+    /// Move TLS bytes from `ring` into the connection's internal buffers, as
+    /// rustls_connection_read_tls would from the network, and store how many
+    /// were taken in *out_n. The bytes are taken straight from the ring's
+    /// storage. Call rustls_connection_process_new_packets afterwards as
+    /// usual.
+    ///
+    /// Returns EAGAIN if the ring is empty: an empty ring never means EOF,
+    /// so report that with rustls_connection_read_tls. Otherwise returns 0,
+    /// or EIO if rustls can't take any more bytes until it has processed the
+    /// ones it has.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_read_tls_from_ring(
+        conn: *mut rustls_connection,
+        ring: *mut rustls_ring_buffer,
+        out_n: *mut size_t,
+    ) -> rustls_io_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            let ring: &mut RingBuffer = try_mut_from_ptr!(ring);
+            if out_n.is_null() {
+                return rustls_io_result(EINVAL)
+            }
+            if ring.len() == 0 {
+                return rustls_io_result(EAGAIN)
+            }
+            // The ring's bytes may be in two runs; take the second too if
+            // rustls still has room after the first.
+            let mut n_read: usize = 0;
+            while ring.len() > 0 {
+                let mut front: &[u8] = ring.front();
+                let available = front.len();
+                let result = conn.read_tls(&mut front);
+                conn.note_io_result(&result);
+                match result {
+                    Ok(n) => {
+                        ring.consume(n);
+                        n_read += n;
+                        if n < available {
+                            break;
+                        }
+                    }
+                    Err(_) if n_read > 0 => break,
+                    Err(e) => return rustls_io_result(e.raw_os_error().unwrap_or(EIO)),
+                }
+            }
+            unsafe {
+                *out_n = n_read;
+            }
+            rustls_io_result(0)
+        }
+    }
+
+    /// Move pending TLS bytes from the connection into `ring`, as many as fit
+    /// under its limit, and store how many were moved in *out_n. This
+    /// replaces a loop over rustls_connection_write_tls with a callback that
+    /// copies into a buffer of your own.
+    ///
+    /// Returns 0 once everything pending has been moved, or EAGAIN if the
+    /// ring filled first: pull bytes out of it, then call this again.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_write_tls_to_ring(
+        conn: *mut rustls_connection,
+        ring: *mut rustls_ring_buffer,
+        out_n: *mut size_t,
+    ) -> rustls_io_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            let ring: &mut RingBuffer = try_mut_from_ptr!(ring);
+            if out_n.is_null() {
+                return rustls_io_result(EINVAL)
+            }
+            let mut n_written: usize = 0;
+            let mut result = rustls_io_result(0);
+            while conn.wants_write() {
+                match conn.write_tls(ring) {
+                    Ok(n) => n_written += n,
+                    Err(_) => {
+                        result = rustls_io_result(EAGAIN);
+                        break;
+                    }
+                }
+            }
+            unsafe {
+                *out_n = n_written;
+            }
+            result
+        }
+    }
+
     /// Write some TLS bytes to the network. The actual network I/O is performed by
     /// `callback`, which you provide. Rustls will invoke your callback with a
     /// suitable buffer containing TLS bytes to send. You don't have to write them
@@ -191,7 +850,101 @@ impl rustls_connection {
             let callback: WriteCallback = try_callback!(callback);
 
             let mut writer = CallbackWriter { callback, userdata };
-            let n_written: usize = match conn.write_tls(&mut writer) {
+            let result = conn.write_tls(&mut writer);
+            conn.note_io_result(&result);
+            let n_written: usize = match result {
+                Ok(n) => n,
+                Err(e) => return rustls_io_result(e.raw_os_error().unwrap_or(EIO)),
+            };
+            unsafe {
+                *out_n = n_written;
+            }
+
+            rustls_io_result(0)
+        }
+    }
+
+    /// Write all pending TLS bytes to the network through `callback`, calling
+    /// it as many times as it takes. `callback` and `userdata` are as for
+    /// rustls_connection_write_tls. *out_total is set to the number of bytes
+    /// the callback took, however this returns.
+    ///
+    /// Returns 0 once nothing is left to write, as
+    /// rustls_connection_wants_write would report. If the callback can't take
+    /// any more, by returning EAGAIN or EWOULDBLOCK or by accepting 0 bytes,
+    /// this stops and returns EAGAIN: the rest stays queued, so call again
+    /// once the network is ready. Any other error from the callback is
+    /// returned as is, and the bytes it didn't take stay queued too.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_write_tls_drain(
+        conn: *mut rustls_connection,
+        callback: rustls_write_callback,
+        userdata: *mut c_void,
+        out_total: *mut size_t,
+    ) -> rustls_io_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if out_total.is_null() {
+                return rustls_io_result(EINVAL)
+            }
+            let callback: WriteCallback = try_callback!(callback);
+
+            let mut writer = CallbackWriter { callback, userdata };
+            let mut total = 0;
+            let mut status = 0;
+            while conn.wants_write() {
+                let result = conn.write_tls(&mut writer);
+                conn.note_io_result(&result);
+                match result {
+                    Ok(0) => {
+                        status = EAGAIN;
+                        break;
+                    }
+                    Ok(n) => total += n,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        status = EAGAIN;
+                        break;
+                    }
+                    Err(e) => {
+                        status = e.raw_os_error().unwrap_or(EIO);
+                        break;
+                    }
+                }
+            }
+            unsafe {
+                *out_total = total;
+            }
+            rustls_io_result(status)
+        }
+    }
+
+    /// Write some pending TLS bytes to the file descriptor `fd`, like
+    /// rustls_connection_write_tls with a callback that calls `write(2)` once.
+    /// `fd` is typically a socket, blocking or not. It is never closed.
+    ///
+    /// Returns 0 for success, with *out_n set to the number of bytes written.
+    /// That may be fewer than are pending; anything not written stays queued
+    /// in the connection for the next call, so keep calling while
+    /// rustls_connection_wants_write returns true. Otherwise returns the errno
+    /// from the write: EAGAIN or EWOULDBLOCK for a non-blocking `fd` that is
+    /// full, in which case try again once it is writable, or an error such as
+    /// EPIPE or ECONNRESET if the peer has gone away. On Linux and Android,
+    /// writing to a closed socket returns EPIPE rather than raising SIGPIPE.
+    /// Returns ENOSYS on platforms without file descriptors, such as Windows.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_write_tls_to_fd(
+        conn: *mut rustls_connection,
+        fd: c_int,
+        out_n: *mut size_t,
+    ) -> rustls_io_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if out_n.is_null() {
+                return rustls_io_result(EINVAL)
+            }
+            let result = conn.write_tls(&mut FdStream { fd });
+            conn.note_io_result(&result);
+            let n_written: usize = match result {
                 Ok(n) => n,
                 Err(e) => return rustls_io_result(e.raw_os_error().unwrap_or(EIO)),
             };
@@ -203,10 +956,105 @@ impl rustls_connection {
         }
     }
 
+    /// Do TLS I/O on the file descriptor `fd` until there is progress to report,
+    /// blocking as `fd` does. This is rustls's `complete_io`, for simple programs
+    /// that would rather not drive rustls_connection_wants_read and
+    /// rustls_connection_wants_write themselves. `fd` is never closed.
+    ///
+    /// While handshaking, reads and writes until the handshake completes.
+    /// Afterwards, writes out any pending TLS bytes if there are some, and
+    /// otherwise reads and processes at least one batch of TLS bytes, after
+    /// which rustls_connection_read may have plaintext for you. Packets are
+    /// processed as by rustls_connection_process_new_packets, including any
+    /// callbacks.
+    ///
+    /// *out_read and *out_written are set to the number of TLS bytes read
+    /// from and written to `fd`, whatever the result; either may be NULL.
+    ///
+    /// Returns RUSTLS_RESULT_OK on success, or the TLS error if processing
+    /// failed. If `fd` is non-blocking and not ready, returns
+    /// RUSTLS_RESULT_WOULD_BLOCK; that is not fatal, so call again once it is.
+    /// RUSTLS_RESULT_UNEXPECTED_EOF means the peer closed the transport
+    /// mid-handshake, and RUSTLS_RESULT_IO is any other I/O error.
+    /// <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.complete_io>
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_complete_io_fd(
+        conn: *mut rustls_connection,
+        fd: c_int,
+        out_read: *mut size_t,
+        out_written: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn_ptr = conn;
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
+            let mut stream = CountingStream {
+                inner: FdStream { fd },
+                read: 0,
+                written: 0,
+                eof: false,
+            };
+            // complete_io writes straight from rustls, so anything we're
+            // holding has to go first.
+            let mut result = Ok(0);
+            while !conn.tls_out.is_empty() && result.is_ok() {
+                result = conn.write_tls(&mut stream);
+            }
+            // Those writes are counted already; the rest are rustls's own.
+            let flushed = stream.written;
+            // complete_io doesn't report the IoState, so ask for it afterwards.
+            // All the TLS bytes read have been processed by then.
+            let result = conn.with_callbacks(|c| {
+                let result = result.and_then(|_| c.complete_io(&mut stream));
+                (result, c.process_new_packets())
+            });
+            let result = match result {
+                Ok((result, io_state)) => {
+                    conn.note_closed(&io_state);
+                    conn.note_handshake_progress();
+                    if let Err(e) = &io_state {
+                        conn.fatal_error = Some(map_error(e.clone()));
+                    }
+                    if stream.eof {
+                        conn.closed = true;
+                    }
+                    conn.counters.tls_read += stream.read as u64;
+                    conn.counters.tls_written += (stream.written - flushed) as u64;
+                    if let Ok(io_state) = io_state {
+                        conn.plaintext_bytes_to_read =
+                            io_state.plaintext_bytes_to_read() + conn.plaintext_in.len();
+                    }
+                    conn.note_io_result(&result);
+                    match result {
+                        Ok(_) => rustls_result::Ok,
+                        Err(e) => map_complete_io_error(&e),
+                    }
+                }
+                Err(e) => return e,
+            };
+            unsafe {
+                if let Some(out_read) = out_read.as_mut() {
+                    *out_read = stream.read;
+                }
+                if let Some(out_written) = out_written.as_mut() {
+                    *out_written = stream.written;
+                }
+            }
+            if result == rustls_result::Ok {
+                conn.notify_handshake_done(conn_ptr);
+            }
+            result
+        }
+    }
+
     /// Write all available TLS bytes to the network. The actual network I/O is performed by
     /// `callback`, which you provide. Rustls will invoke your callback with an array
-    /// of rustls_slice_bytes, each containing a buffer with TLS bytes to send.
-    /// You don't have to write them all, just as many as you are willing.
+    /// of rustls_iovec, each containing a buffer with TLS bytes to send, so that it can
+    /// pass them straight to `writev` without copying them into one buffer first.
+    /// You don't have to write them all, just as many as you are willing; a write
+    /// may end part way through an iovec, and the next call resumes from there.
     /// The `userdata` parameter is passed through directly to `callback`. Note that
     /// this is distinct from the `userdata` parameter set with
     /// `rustls_connection_set_userdata`.
@@ -228,7 +1076,9 @@ impl rustls_connection {
             let callback: VectoredWriteCallback = try_callback!(callback);
 
             let mut writer = VectoredCallbackWriter { callback, userdata };
-            let n_written: usize = match conn.write_tls(&mut writer) {
+            let result = conn.write_tls(&mut writer);
+            conn.note_io_result(&result);
+            let n_written: usize = match result {
                 Ok(n) => n,
                 Err(e) => return rustls_io_result(e.raw_os_error().unwrap_or(EIO)),
             };
@@ -240,28 +1090,311 @@ impl rustls_connection {
         }
     }
 
+    /// Register `callback` to be called, with `userdata`, from inside the
+    /// rustls_connection_process_new_packets call that completes the
+    /// handshake. It is called at most once, and not at all if the handshake
+    /// fails; errors are reported through the result code as usual. A later
+    /// registration replaces an earlier one, and NULL removes it. If
+    /// `userdata` is NULL, the callback gets the same userdata as the
+    /// connection's other callbacks instead.
+    ///
+    /// Returns RUSTLS_RESULT_HANDSHAKE_ALREADY_COMPLETE, without calling
+    /// `callback`, if the handshake has already completed, so check
+    /// rustls_connection_is_handshaking instead in that case.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_set_handshake_done_callback(
+        conn: *mut rustls_connection,
+        callback: rustls_handshake_done_callback,
+        userdata: *mut c_void,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if !conn.is_handshaking() {
+                return rustls_result::HandshakeAlreadyComplete;
+            }
+            conn.handshake_done_callback = callback;
+            conn.handshake_done_userdata = userdata;
+            rustls_result::Ok
+        }
+    }
+
     /// Decrypt any available ciphertext from the internal buffer and put it
     /// into the internal plaintext buffer, potentially making bytes available
-    /// for rustls_connection_read().
+    /// for rustls_connection_read(). If this completes the handshake, the
+    /// callback set with rustls_connection_set_handshake_done_callback is
+    /// called before returning.
+    ///
+    /// An error here is fatal: rustls may have queued an alert for the peer,
+    /// and the connection is good for nothing else. From then on this and the
+    /// other data-plane calls, such as rustls_connection_read,
+    /// rustls_connection_write, rustls_connection_pump and
+    /// rustls_connection_complete_io_fd, return the same error without doing
+    /// anything. Only rustls_connection_write_tls and the other ways of
+    /// sending TLS bytes keep working, so that the alert can still be flushed
+    /// to the peer. (rustls_connection_read_tls still takes bytes, for
+    /// callers that read before they look at results, but they are never
+    /// processed.)
+    ///
+    /// rustls_connection_process_new_packets_ex does the same and also reports
+    /// the connection's state, saving further calls to find out what to do next.
     /// <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.process_new_packets>
     #[no_mangle]
     pub extern "C" fn rustls_connection_process_new_packets(
         conn: *mut rustls_connection,
     ) -> rustls_result {
         ffi_panic_boundary! {
+            let conn_ptr = conn;
             let conn: &mut Connection = try_mut_from_ptr!(conn);
-            let guard = match userdata_push(conn.userdata, conn.log_callback) {
-                Ok(g) => g,
-                Err(_) => return rustls_result::Panic,
-            };
-            let result = match conn.process_new_packets() {
-                Ok(_) => rustls_result::Ok,
-                Err(e) => map_error(e),
-            };
-            match guard.try_drop() {
-                Ok(()) => result,
-                Err(_) => rustls_result::Panic,
+            if let Some(e) = conn.fatal_error() {
+                return e;
             }
+            if let Err(e) = conn.process_packets() {
+                return e;
+            }
+            conn.notify_handshake_done(conn_ptr);
+            rustls_result::Ok
+        }
+    }
+
+    /// Process new packets like rustls_connection_process_new_packets, and on
+    /// success fill in *out with what the caller can do next. This is the fast
+    /// path for event loops: one call replaces separate ones to
+    /// rustls_connection_is_handshaking, rustls_connection_wants_write and
+    /// friends. *out reflects any handshake-done callback that ran, and is only
+    /// written on success.
+    /// <https://docs.rs/rustls/latest/rustls/struct.IoState.html>
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_process_new_packets_ex(
+        conn: *mut rustls_connection,
+        out: *mut rustls_io_state,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn_ptr = conn;
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if out.is_null() {
+                return NullParameter
+            }
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
+            let io_state = match conn.process_packets() {
+                Ok(io_state) => io_state,
+                Err(e) => return e,
+            };
+            // The callback may have written, leaving io_state out of date.
+            let bytes_to_write = if conn.notify_handshake_done(conn_ptr) {
+                conn.tls_bytes_to_write()
+            } else {
+                io_state.tls_bytes_to_write() + conn.tls_out.len()
+            };
+            unsafe {
+                *out = rustls_io_state {
+                    bytes_to_read: conn.plaintext_bytes_to_read,
+                    bytes_to_write,
+                    handshaking: conn.is_handshaking(),
+                    peer_closed: io_state.peer_has_closed(),
+                };
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Copy a description of the most recent failure on this connection into
+    /// `buf`, as UTF-8 followed by a NUL, and store its length, not counting
+    /// the NUL, in *out_n. This carries the detail a rustls_result can't, such
+    /// as which certificate problem failed the handshake or which errno a
+    /// callback returned.
+    ///
+    /// Failures of rustls_connection_read_tls, rustls_connection_write_tls and
+    /// their variants, rustls_connection_process_new_packets,
+    /// rustls_connection_complete_io_fd, rustls_connection_read,
+    /// rustls_connection_read_vectored and rustls_connection_peek_plaintext
+    /// are recorded, including those caused by
+    /// callbacks. A successful call to any of them clears the message, so an
+    /// empty string means the last one succeeded. "Try again" outcomes, such
+    /// as RUSTLS_RESULT_PLAINTEXT_EMPTY or EAGAIN, leave it alone.
+    ///
+    /// If `len` is too small for the message and its NUL, returns
+    /// RUSTLS_RESULT_INSUFFICIENT_SIZE with *out_n set to 0.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_get_last_error(
+        conn: *const rustls_connection,
+        buf: *mut c_char,
+        len: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if buf.is_null() || out_n.is_null() {
+                return NullParameter
+            }
+            let message = conn.last_error.as_deref().unwrap_or("");
+            if message.len() >= len {
+                unsafe {
+                    *out_n = 0;
+                }
+                return rustls_result::InsufficientSize;
+            }
+            unsafe {
+                std::ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buf, message.len());
+                *buf.add(message.len()) = 0;
+                *out_n = message.len();
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Copy a one-line, human-readable snapshot of the connection's state
+    /// into `buf`, as UTF-8 followed by a NUL, and store its length, not
+    /// counting the NUL, in *out_n. It covers whether the connection is
+    /// handshaking, whether it wants to read or write, how much plaintext is
+    /// waiting to be read and how many TLS bytes to be written, the
+    /// negotiated protocol version and cipher suite, whether a client's
+    /// session was resumed, whether close_notify has been sent and received,
+    /// and any fatal error. This is meant for logs and bug reports when a
+    /// connection seems stuck: the format may change between releases, so
+    /// don't parse it. It never includes keys or other secrets.
+    ///
+    /// Counting the TLS bytes moves them out of rustls, as
+    /// rustls_connection_tls_bytes_to_write does, which is why `conn` isn't
+    /// const; nothing observable changes. If `len` is too small for the
+    /// snapshot and its NUL, returns RUSTLS_RESULT_INSUFFICIENT_SIZE with
+    /// *out_n set to 0. 256 bytes is plenty.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_debug_dump(
+        conn: *mut rustls_connection,
+        buf: *mut c_char,
+        len: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if buf.is_null() || out_n.is_null() {
+                return NullParameter
+            }
+            let summary = conn.debug_summary();
+            if summary.len() >= len {
+                unsafe {
+                    *out_n = 0;
+                }
+                return rustls_result::InsufficientSize;
+            }
+            unsafe {
+                std::ptr::copy_nonoverlapping(summary.as_ptr() as *const c_char, buf, summary.len());
+                *buf.add(summary.len()) = 0;
+                *out_n = summary.len();
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Store in *out_flags what an event loop should wait for on `conn`'s
+    /// transport, as a combination of rustls_interest flags:
+    /// RUSTLS_INTEREST_READ exactly when rustls_connection_wants_read
+    /// returns true, RUSTLS_INTEREST_WRITE exactly when
+    /// rustls_connection_wants_write does, and RUSTLS_INTEREST_CLOSED once
+    /// nothing more will come from the peer. CLOSED is set when
+    /// rustls_connection_process_new_packets sees the peer's close_notify or
+    /// fails, or when reading TLS bytes hits EOF.
+    ///
+    /// A connection with CLOSED set and WRITE clear has no more I/O to do:
+    /// read out any plaintext it holds, then stop watching its transport.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_poll_interest(
+        conn: *const rustls_connection,
+        out_flags: *mut u32,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if out_flags.is_null() {
+                return NullParameter
+            }
+            let mut flags = 0;
+            if conn.wants_read() {
+                flags |= rustls_interest::Read as u32;
+            }
+            if conn.wants_write() {
+                flags |= rustls_interest::Write as u32;
+            }
+            if conn.closed {
+                flags |= rustls_interest::Closed as u32;
+            }
+            unsafe {
+                *out_flags = flags;
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Copy the connection's running byte counts, described at
+    /// rustls_counters, into *out. The counts only grow. A call that transfers
+    /// nothing, such as one returning RUSTLS_RESULT_WOULD_BLOCK or EAGAIN,
+    /// adds nothing, so retrying never counts a byte twice.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_get_counters(
+        conn: *const rustls_connection,
+        out: *mut rustls_counters,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if out.is_null() {
+                return NullParameter
+            }
+            unsafe {
+                *out = conn.counters;
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Store in *out whether the peer's close_notify alert has been processed,
+    /// meaning it will send nothing more. This says nothing about plaintext
+    /// still waiting to be read: it becomes true as soon as
+    /// rustls_connection_process_new_packets sees the alert, even if data
+    /// sent before it has not been read yet. An EOF or error on the
+    /// transport does not set it.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_peer_closed(
+        conn: *const rustls_connection,
+        out: *mut bool,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if out.is_null() {
+                return NullParameter
+            }
+            unsafe {
+                *out = conn.peer_closed;
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Store in *out_micros how long the handshake took, in microseconds:
+    /// the time from creating the connection to the packet processing that
+    /// completed the handshake, full or resumed. The clock is monotonic, so
+    /// changes to the system time don't affect it.
+    ///
+    /// Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE, leaving *out_micros
+    /// untouched, if the handshake has not completed yet.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_handshake_duration_us(
+        conn: *const rustls_connection,
+        out_micros: *mut u64,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if out_micros.is_null() {
+                return NullParameter
+            }
+            let duration = match conn.handshake_duration {
+                Some(d) => d,
+                None => return rustls_result::HandshakeNotComplete,
+            };
+            unsafe {
+                *out_micros = duration.as_micros() as u64;
+            }
+            rustls_result::Ok
         }
     }
 
@@ -283,6 +1416,84 @@ impl rustls_connection {
         }
     }
 
+    /// Store in *out the exact number of TLS bytes waiting to be sent, as
+    /// rustls_connection_write_tls would write them if the callback accepted
+    /// everything. It is non-zero exactly when rustls_connection_wants_write
+    /// returns true, and goes down by however many bytes each
+    /// rustls_connection_write_tls call writes.
+    ///
+    /// To count the bytes, rustls-ffi moves them out of rustls into a buffer
+    /// of its own; they still count against rustls_connection_set_buffer_limit.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_tls_bytes_to_write(
+        conn: *mut rustls_connection,
+        out: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if out.is_null() {
+                return NullParameter
+            }
+            unsafe {
+                *out = conn.tls_bytes_to_write();
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Borrow the TLS bytes waiting to be sent, so they can be handed to the
+    /// network without first copying them into a buffer of your own. Stores
+    /// a pointer to them in *out_ptr and their length in *out_len; that is all
+    /// the pending bytes, in one contiguous run. When nothing is pending,
+    /// *out_len is 0 and *out_ptr must not be dereferenced.
+    ///
+    /// Send as many of the bytes as you can, then call
+    /// rustls_connection_consume_tls_output to say how many went. Until then,
+    /// they stay queued, and rustls_connection_write_tls would send them too.
+    ///
+    /// The pointer is only valid until the next call on this connection,
+    /// other than rustls_connection_peek_tls_output itself. Any other call,
+    /// including rustls_connection_consume_tls_output and
+    /// rustls_connection_free, may move or free the bytes. Peek again
+    /// afterwards rather than reusing it.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_peek_tls_output(
+        conn: *mut rustls_connection,
+        out_ptr: *mut *const u8,
+        out_len: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if out_ptr.is_null() || out_len.is_null() {
+                return NullParameter
+            }
+            let len = conn.tls_bytes_to_write();
+            unsafe {
+                *out_ptr = conn.tls_out.as_ptr();
+                *out_len = len;
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Mark the first `n` bytes returned by rustls_connection_peek_tls_output
+    /// as sent, removing them from the queue. Returns InvalidParameter, and
+    /// consumes nothing, if `n` is more than the last peek returned.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_consume_tls_output(
+        conn: *mut rustls_connection,
+        n: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if n > conn.tls_out.len() {
+                return rustls_result::InvalidParameter;
+            }
+            conn.consume_tls_out(n);
+            rustls_result::Ok
+        }
+    }
+
     /// <https://docs.rs/rustls/latest/rustls/struct.CommonState.html#method.is_handshaking>
     #[no_mangle]
     pub extern "C" fn rustls_connection_is_handshaking(conn: *const rustls_connection) -> bool {
@@ -293,9 +1504,14 @@ impl rustls_connection {
     }
 
     /// Sets a limit on the internal buffers used to buffer unsent plaintext (prior
-    /// to completing the TLS handshake) and unsent TLS records. By default, there
-    /// is no limit. The limit can be set at any time, even if the current buffer
+    /// to completing the TLS handshake) and unsent TLS records. By default, the
+    /// limit is 64 KiB. The limit can be set at any time, even if the current buffer
     /// use is higher.
+    ///
+    /// Once the limit is reached, rustls_connection_write accepts fewer bytes
+    /// than offered, and returns RUSTLS_RESULT_OK with *out_n set to 0 when the
+    /// buffers are full. That means "call rustls_connection_write_tls to send
+    /// what is buffered, then retry", not an error.
     /// <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.set_buffer_limit>
     #[no_mangle]
     pub extern "C" fn rustls_connection_set_buffer_limit(conn: *mut rustls_connection, n: usize) {
@@ -305,13 +1521,73 @@ impl rustls_connection {
         }
     }
 
-    /// Queues a close_notify fatal alert to be sent in the next write_tls call.
+    /// Queues a close_notify alert to be sent in the next write_tls call, telling
+    /// the peer that no more data will be sent, so that it sees a clean EOF
+    /// rather than a truncated connection. Plaintext already written is sent
+    /// first.
+    ///
+    /// Only the first call queues the alert; later calls do nothing. It may also
+    /// be called while handshaking, to abandon the handshake: the alert is
+    /// queued after any pending handshake messages. In that case the peer may
+    /// report an error rather than a clean EOF, since the alert can't always
+    /// be protected the way it expects.
     /// <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.send_close_notify>
     #[no_mangle]
     pub extern "C" fn rustls_connection_send_close_notify(conn: *mut rustls_connection) {
         ffi_panic_boundary! {
             let conn: &mut Connection = try_mut_from_ptr!(conn);
-            conn.send_close_notify();
+            if !conn.sent_close_notify {
+                conn.sent_close_notify = true;
+                conn.send_close_notify();
+            }
+        }
+    }
+
+    /// Shut down the sending side of the connection, copying the TLS bytes
+    /// that have to go out into `tls_out` rather than writing them through a
+    /// callback. It does what rustls_connection_send_close_notify followed by
+    /// draining rustls_connection_write_tls would: the close_notify is queued on
+    /// the first call (or not at all, if it was already sent) and then up to
+    /// `out_len` pending bytes are copied into `tls_out`, anything queued
+    /// before the alert first. *out_n is set to the number of bytes copied.
+    ///
+    /// *out_complete is set to true once every pending byte, the close_notify
+    /// included, has been handed over; send those `*out_n` bytes and you are
+    /// done. Until then, send what you got and call again. Calling it after
+    /// completion is harmless: it copies nothing and reports complete again.
+    ///
+    /// This only closes the sending side. To wait for the peer's close_notify
+    /// as well, keep reading until rustls_connection_read returns
+    /// RUSTLS_RESULT_OK with 0 bytes.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_shutdown(
+        conn: *mut rustls_connection,
+        tls_out: *mut u8,
+        out_len: size_t,
+        out_n: *mut size_t,
+        out_complete: *mut bool,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            let tls_out: &mut [u8] = try_mut_slice!(tls_out, out_len);
+            if out_n.is_null() || out_complete.is_null() {
+                return NullParameter
+            }
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
+            if !conn.sent_close_notify {
+                conn.sent_close_notify = true;
+                conn.send_close_notify();
+            }
+            let n = conn.tls_bytes_to_write().min(tls_out.len());
+            tls_out[..n].copy_from_slice(&conn.tls_out[..n]);
+            conn.consume_tls_out(n);
+            unsafe {
+                *out_n = n;
+                *out_complete = !conn.wants_write();
+            }
+            rustls_result::Ok
         }
     }
 
@@ -338,6 +1614,81 @@ impl rustls_connection {
         }
     }
 
+    /// Store in *out the number of certificates the peer presented: the end
+    /// entity certificate followed by any intermediates. This is 0 until the
+    /// handshake has completed, and also if the peer sent no certificates,
+    /// as clients usually don't.
+    /// <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.peer_certificates>
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_get_peer_certificate_count(
+        conn: *const rustls_connection,
+        out: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if out.is_null() {
+                return NullParameter
+            }
+            unsafe {
+                *out = conn.verified_peer_certificates().len();
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Store a pointer to the DER encoding of the peer's certificate at
+    /// `index` in *out_der, and its length in *out_len. Index 0 is the end
+    /// entity certificate. Returns RUSTLS_RESULT_NOT_FOUND if `index` is not
+    /// less than the count from rustls_connection_get_peer_certificate_count.
+    ///
+    /// The certificates don't change once the handshake is complete, so the
+    /// returned pointer is valid until the connection is freed.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_get_peer_certificate_der(
+        conn: *const rustls_connection,
+        index: size_t,
+        out_der: *mut *const u8,
+        out_len: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if out_der.is_null() || out_len.is_null() {
+                return NullParameter
+            }
+            let cert = match conn.verified_peer_certificates().get(index) {
+                Some(cert) => cert,
+                None => return rustls_result::NotFound,
+            };
+            unsafe {
+                *out_der = cert.0.as_ptr();
+                *out_len = cert.0.len();
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// PEM-encode the certificate chain the peer presented, end entity first,
+    /// and store it in *out as a newly allocated rustls_buffer, which the
+    /// caller must free with rustls_buffer_free. Before the handshake has
+    /// completed, or if the peer sent no certificates, the buffer is empty.
+    /// The output is the same as `openssl x509` would write for each
+    /// certificate, so it can be passed to tools that read PEM bundles.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_get_peer_chain_pem(
+        conn: *const rustls_connection,
+        out: *mut *mut rustls_buffer,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if out.is_null() {
+                return NullParameter
+            }
+            let pem = pem::encode_certificates(conn.verified_peer_certificates());
+            BoxCastPtr::set_mut_ptr(out, pem);
+            rustls_result::Ok
+        }
+    }
+
     /// Get the ALPN protocol that was negotiated, if any. Stores a pointer to a
     /// borrowed buffer of bytes, and that buffer's len, in the output parameters.
     /// The borrow lives as long as the connection.
@@ -355,10 +1706,28 @@ impl rustls_connection {
         protocol_out: *mut *const u8,
         protocol_out_len: *mut usize,
     ) {
+        rustls_connection::rustls_connection_get_alpn_protocol_ex(
+            conn,
+            protocol_out,
+            protocol_out_len,
+        );
+    }
+
+    /// Like rustls_connection_get_alpn_protocol, but returns a rustls_result,
+    /// so that "no ALPN" can be told apart from an error. If the connection
+    /// is still handshaking, or no ALPN protocol was negotiated, stores NULL
+    /// and 0 in the output parameters and returns RUSTLS_RESULT_OK.
+    /// Returns RUSTLS_RESULT_NULL_PARAMETER if any parameter is NULL.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_get_alpn_protocol_ex(
+        conn: *const rustls_connection,
+        protocol_out: *mut *const u8,
+        protocol_out_len: *mut usize,
+    ) -> rustls_result {
         ffi_panic_boundary! {
             let conn: &Connection = try_ref_from_ptr!(conn);
             if protocol_out.is_null() || protocol_out_len.is_null() {
-                return
+                return NullParameter
             }
             match conn.alpn_protocol() {
                 Some(p) => unsafe {
@@ -370,12 +1739,14 @@ impl rustls_connection {
                     *protocol_out_len = 0;
                 }
             }
+            rustls_result::Ok
         }
     }
 
     /// Return the TLS protocol version that has been negotiated. Before this
-    /// has been decided during the handshake, this will return 0. Otherwise,
-    /// the u16 version number as defined in the relevant RFC is returned.
+    /// has been decided during the handshake, this will return 0, which is
+    /// never a valid version number. Otherwise, the u16 version number as
+    /// defined in the relevant RFC is returned (see `rustls_tls_version`).
     /// <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.protocol_version>
     /// <https://docs.rs/rustls/latest/rustls/internal/msgs/enums/enum.ProtocolVersion.html>
     #[no_mangle]
@@ -418,11 +1789,55 @@ impl rustls_connection {
         }
     }
 
+    /// Derive `out_len` bytes of keying material from the connection's secrets
+    /// into `out`, as defined by RFC 5705 (TLS 1.2) and RFC 8446 section 7.5
+    /// (TLS 1.3). Both peers get the same bytes for the same `label` and
+    /// context.
+    ///
+    /// If `has_context` is false, no context is used and `context` may be NULL.
+    /// With TLS 1.2 this gives different output than an empty context; with
+    /// TLS 1.3 the two are equivalent.
+    ///
+    /// Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE, leaving `out` untouched,
+    /// if the handshake has not completed yet.
+    /// <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.export_keying_material>
+    #[no_mangle]
+    #[allow(clippy::too_many_arguments)]
+    pub extern "C" fn rustls_connection_export_keying_material(
+        conn: *const rustls_connection,
+        out: *mut u8,
+        out_len: size_t,
+        label: *const u8,
+        label_len: size_t,
+        context: *const u8,
+        context_len: size_t,
+        has_context: bool,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            let out: &mut [u8] = try_mut_slice!(out, out_len);
+            let label: &[u8] = try_slice!(label, label_len);
+            let context: Option<&[u8]> = if has_context {
+                Some(try_slice!(context, context_len))
+            } else {
+                None
+            };
+            if conn.is_handshaking() {
+                return rustls_result::HandshakeNotComplete;
+            }
+            match conn.export_keying_material(out, label, context) {
+                Ok(_) => rustls_result::Ok,
+                Err(e) => map_error(e),
+            }
+        }
+    }
+
     /// Write up to `count` plaintext bytes from `buf` into the `rustls_connection`.
     /// This will increase the number of output bytes available to
     /// `rustls_connection_write_tls`.
     /// On success, store the number of bytes actually written in *out_n
-    /// (this may be less than `count`).
+    /// (this may be less than `count`, and is 0 if the limit set by
+    /// rustls_connection_set_buffer_limit has been reached).
     /// <https://docs.rs/rustls/latest/rustls/struct.Writer.html#method.write>
     #[no_mangle]
     pub extern "C" fn rustls_connection_write(
@@ -437,10 +1852,14 @@ impl rustls_connection {
             if out_n.is_null() {
                 return NullParameter
             }
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
             let n_written: usize = match conn.writer().write(write_buf) {
                 Ok(n) => n,
                 Err(_) => return rustls_result::Io,
             };
+            conn.wrote_plaintext(n_written);
             unsafe {
                 *out_n = n_written;
             }
@@ -448,33 +1867,138 @@ impl rustls_connection {
         }
     }
 
-    /// Read up to `count` plaintext bytes from the `rustls_connection` into `buf`.
-    /// On success, store the number of bytes read in *out_n (this may be less
-    /// than `count`). A success with *out_n set to 0 means "all bytes currently
-    /// available have been read, but more bytes may become available after
-    /// subsequent calls to rustls_connection_read_tls and
-    /// rustls_connection_process_new_packets."
+    /// Flush plaintext written with rustls_connection_write into TLS records
+    /// ready for rustls_connection_write_tls.
     ///
-    /// Subtle note: Even though this function only writes to `buf` and does not
-    /// read from it, the memory in `buf` must be initialized before the call (for
-    /// Rust-internal reasons). Initializing a buffer once and then using it
-    /// multiple times without zeroizing before each call is fine.
-    /// <https://docs.rs/rustls/latest/rustls/struct.Reader.html#method.read>
+    /// Once the handshake is complete, rustls encrypts plaintext as soon as it
+    /// is written, so there is nothing left for this to do and it always
+    /// succeeds. Plaintext written before that is held until the handshake
+    /// completes, whether or not this is called.
+    /// <https://docs.rs/rustls/latest/rustls/struct.Writer.html#method.flush>
     #[no_mangle]
-    pub extern "C" fn rustls_connection_read(
+    pub extern "C" fn rustls_connection_flush(conn: *mut rustls_connection) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
+            match conn.writer().flush() {
+                Ok(()) => rustls_result::Ok,
+                Err(_) => rustls_result::Io,
+            }
+        }
+    }
+
+    /// Write all `count` plaintext bytes from `buf` into the `rustls_connection`,
+    /// storing the number of bytes accepted in *out_n. Like
+    /// rustls_connection_write, this only buffers: no network I/O happens, and
+    /// the caller still has to send the resulting TLS bytes with
+    /// rustls_connection_write_tls.
+    ///
+    /// Fewer than `count` bytes are only accepted if the limit set with
+    /// rustls_connection_set_buffer_limit is reached. In that case this returns
+    /// RUSTLS_RESULT_BUFFER_LIMIT_REACHED, with *out_n set to the number of
+    /// bytes that were accepted; send the pending TLS bytes and write the rest.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_write_all(
         conn: *mut rustls_connection,
-        buf: *mut u8,
+        buf: *const u8,
         count: size_t,
         out_n: *mut size_t,
     ) -> rustls_result {
         ffi_panic_boundary! {
             let conn: &mut Connection = try_mut_from_ptr!(conn);
-            if buf.is_null() {
-                return NullParameter
+            let write_buf: &[u8] = try_slice!(buf, count);
+            if out_n.is_null() {
+                return NullParameter
+            }
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
+            let mut n_written: usize = 0;
+            while n_written < write_buf.len() {
+                match conn.writer().write(&write_buf[n_written..]) {
+                    Ok(0) => break,
+                    Ok(n) => n_written += n,
+                    Err(_) => return rustls_result::Io,
+                }
+            }
+            conn.wrote_plaintext(n_written);
+            unsafe {
+                *out_n = n_written;
+            }
+            if n_written < write_buf.len() {
+                return rustls_result::BufferLimitReached;
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Store in *out the number of plaintext bytes received and waiting to be
+    /// read with rustls_connection_read. The count is exact: it is what the
+    /// last successful rustls_connection_process_new_packets (or
+    /// rustls_connection_complete_io_fd) left buffered, less whatever has
+    /// been read since. Plaintext only arrives when packets are processed, so
+    /// calls to rustls_connection_read_tls alone don't change it.
+    /// <https://docs.rs/rustls/latest/rustls/struct.IoState.html#method.plaintext_bytes_to_read>
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_plaintext_bytes_to_read(
+        conn: *const rustls_connection,
+        out: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &Connection = try_ref_from_ptr!(conn);
+            if out.is_null() {
+                return NullParameter
+            }
+            unsafe {
+                *out = conn.plaintext_bytes_to_read;
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Read up to `count` plaintext bytes from the `rustls_connection` into `buf`.
+    /// On success, store the number of bytes read in *out_n (this may be less
+    /// than `count`).
+    ///
+    /// Once the plaintext received so far has been read, the result tells the
+    /// caller why there is no more:
+    ///  - RUSTLS_RESULT_PLAINTEXT_EMPTY: nothing is available right now. More
+    ///    may arrive after further calls to rustls_connection_read_tls and
+    ///    rustls_connection_process_new_packets.
+    ///  - RUSTLS_RESULT_OK with *out_n set to 0 (and `count` non-zero): the peer
+    ///    sent close_notify. This is a clean end of stream; no more data will
+    ///    arrive.
+    ///  - RUSTLS_RESULT_UNEXPECTED_EOF: the transport reached EOF without a
+    ///    close_notify, so the data may have been truncated.
+    ///
+    /// Subtle note: Even though this function only writes to `buf` and does not
+    /// read from it, the memory in `buf` must be initialized before the call (for
+    /// Rust-internal reasons). Initializing a buffer once and then using it
+    /// multiple times without zeroizing before each call is fine. This function
+    /// never clears `buf` itself: it copies in the *out_n bytes it reads and
+    /// leaves the rest of `buf` as it was, so the cost of a call doesn't
+    /// depend on `count`.
+    /// <https://docs.rs/rustls/latest/rustls/struct.Reader.html#method.read>
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_read(
+        conn: *mut rustls_connection,
+        buf: *mut u8,
+        count: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if buf.is_null() {
+                return NullParameter
             }
             if out_n.is_null() {
                 return NullParameter
             }
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
 
             // Safety: the memory pointed at by buf must be initialized
             // (required by documentation of this function).
@@ -482,12 +2006,72 @@ impl rustls_connection {
                 slice::from_raw_parts_mut(buf, count)
             };
 
-            let n_read: usize = match conn.reader().read(read_buf) {
+            let result = conn.read_plaintext(read_buf);
+            conn.note_io_result(&result);
+            let n_read: usize = match result {
                 Ok(n) => n,
-                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return rustls_result::UnexpectedEof,
-                Err(e) if e.kind() == ErrorKind::WouldBlock => return rustls_result::PlaintextEmpty,
-                Err(_) => return rustls_result::Io,
+                Err(e) => return map_read_error(&e),
+            };
+            unsafe {
+                *out_n = n_read;
+            }
+            rustls_result::Ok
+        }
+    }
+
+    /// Read plaintext from the `rustls_connection` into the `count` buffers in
+    /// `iov`, filling each in turn before moving on to the next, and store the
+    /// total number of bytes read in *out_n. This stops when either the
+    /// buffers or the available plaintext run out, so a single call can fill
+    /// both halves of a ring buffer that wraps around.
+    ///
+    /// When no bytes could be read, the result means the same as for
+    /// rustls_connection_read: RUSTLS_RESULT_PLAINTEXT_EMPTY if nothing is
+    /// available yet, RUSTLS_RESULT_OK with *out_n set to 0 for a clean end of
+    /// stream, and RUSTLS_RESULT_UNEXPECTED_EOF for a truncated one. As with
+    /// rustls_connection_read, the buffers must be initialized memory.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_read_vectored(
+        conn: *mut rustls_connection,
+        iov: *mut rustls_iovec_mut,
+        count: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if iov.is_null() || out_n.is_null() {
+                return NullParameter
+            }
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
+            // Safety: IoSliceMut is documented to be ABI-compatible with iovec
+            // on Unix and WSABUF on Windows, and the buffers must be
+            // initialized (required by documentation of this function).
+            let bufs: &mut [IoSliceMut] = unsafe {
+                slice::from_raw_parts_mut(iov as *mut IoSliceMut, count)
             };
+
+            let mut n_read: usize = 0;
+            for buf in bufs.iter_mut().filter(|b| !b.is_empty()) {
+                match conn.read_plaintext(buf) {
+                    Ok(n) => {
+                        n_read += n;
+                        if n < buf.len() {
+                            break;
+                        }
+                    }
+                    // Whatever stopped this read will stop the next call too,
+                    // so report the bytes we have.
+                    Err(_) if n_read > 0 => break,
+                    Err(e) => {
+                        let result = map_read_error(&e);
+                        conn.note_io_result::<()>(&Err(e));
+                        return result;
+                    }
+                }
+            }
+            conn.last_error = None;
             unsafe {
                 *out_n = n_read;
             }
@@ -495,12 +2079,171 @@ impl rustls_connection {
         }
     }
 
+    /// Borrow the plaintext received so far, so it can be parsed where it is
+    /// rather than copied out with rustls_connection_read. Stores a pointer to
+    /// it in *out_ptr and its length in *out_len; that is all the plaintext
+    /// available, in one contiguous run, including any left over from an
+    /// earlier peek. Call rustls_connection_consume_plaintext when done with
+    /// some or all of it. Bytes not consumed are still there for the next
+    /// peek or read.
+    ///
+    /// When no plaintext is available, *out_len is 0, *out_ptr must not be
+    /// dereferenced, and the result means the same as for
+    /// rustls_connection_read: RUSTLS_RESULT_PLAINTEXT_EMPTY if more may still
+    /// arrive, RUSTLS_RESULT_OK for a clean end of stream, and
+    /// RUSTLS_RESULT_UNEXPECTED_EOF for a truncated one.
+    ///
+    /// The pointer is only valid until the next call on this connection,
+    /// other than rustls_connection_peek_plaintext itself. Any other call,
+    /// including rustls_connection_consume_plaintext,
+    /// rustls_connection_read_tls and rustls_connection_free, may move or free
+    /// the bytes. Peek again afterwards rather than reusing it.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_peek_plaintext(
+        conn: *mut rustls_connection,
+        out_ptr: *mut *const u8,
+        out_len: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if out_ptr.is_null() || out_len.is_null() {
+                return NullParameter
+            }
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
+            let result = conn.take_plaintext();
+            unsafe {
+                *out_ptr = conn.plaintext_in.as_ptr();
+                *out_len = conn.plaintext_in.len();
+            }
+            if !conn.plaintext_in.is_empty() {
+                conn.last_error = None;
+                return rustls_result::Ok;
+            }
+            conn.note_io_result(&result);
+            match result {
+                Ok(()) => rustls_result::Ok,
+                Err(e) => map_read_error(&e),
+            }
+        }
+    }
+
+    /// Mark the first `n` bytes returned by rustls_connection_peek_plaintext
+    /// as read, removing them. Returns InvalidParameter, and consumes nothing,
+    /// if `n` is more than the last peek returned.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_consume_plaintext(
+        conn: *mut rustls_connection,
+        n: size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
+            if n > conn.plaintext_in.len() {
+                return rustls_result::InvalidParameter;
+            }
+            conn.plaintext_in.drain(..n);
+            conn.consumed_plaintext(n);
+            rustls_result::Ok
+        }
+    }
+
+    /// Feed TLS bytes from `tls_in`, process them, and read the resulting
+    /// plaintext into `plain_out`, all in one call: the equivalent of
+    /// alternating rustls_connection_read_tls,
+    /// rustls_connection_process_new_packets and rustls_connection_read until
+    /// `tls_in` runs out. Callbacks run as they would for those functions.
+    ///
+    /// *out_tls_consumed is set to the number of bytes of `tls_in` that were
+    /// fed to rustls, and *out_plain_written to the number of plaintext bytes
+    /// written to `plain_out`, whatever the result. Bytes beyond
+    /// *out_tls_consumed were not looked at; pass them again next time.
+    ///
+    /// Feeding stops early, with RUSTLS_RESULT_OK, once `plain_out` is full
+    /// and decrypted plaintext is still waiting. Read it (or pump again with
+    /// a fresh `plain_out`) before offering more input.
+    ///
+    /// If processing fails, returns that error. Every byte fed before the
+    /// failure counts as consumed, and plaintext from records before the bad
+    /// one is still written to `plain_out`; the connection is unusable after
+    /// that, as with rustls_connection_process_new_packets. If rustls can't
+    /// take the input, returns RUSTLS_RESULT_IO.
+    ///
+    /// Running out of plaintext isn't an error here: a clean close, a
+    /// truncated stream and "nothing yet" all just end the reading, and a
+    /// later rustls_connection_read tells them apart.
+    #[no_mangle]
+    pub extern "C" fn rustls_connection_pump(
+        conn: *mut rustls_connection,
+        tls_in: *const u8,
+        tls_in_len: size_t,
+        out_tls_consumed: *mut size_t,
+        plain_out: *mut u8,
+        plain_out_len: size_t,
+        out_plain_written: *mut size_t,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            let conn_ptr = conn;
+            let conn: &mut Connection = try_mut_from_ptr!(conn);
+            let tls_in: &[u8] = try_slice!(tls_in, tls_in_len);
+            let plain_out: &mut [u8] = try_mut_slice!(plain_out, plain_out_len);
+            if out_tls_consumed.is_null() || out_plain_written.is_null() {
+                return NullParameter
+            }
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
+
+            let mut input: &[u8] = tls_in;
+            let mut written: usize = 0;
+            let result = loop {
+                // Make room in rustls before giving it more.
+                written += conn.fill_plaintext(&mut plain_out[written..]);
+                if input.is_empty() {
+                    break rustls_result::Ok;
+                }
+                if written == plain_out.len() && conn.plaintext_bytes_to_read > 0 {
+                    break rustls_result::Ok;
+                }
+                let result = conn.read_tls(&mut input);
+                conn.note_io_result(&result);
+                match result {
+                    Ok(0) => break rustls_result::Ok,
+                    Ok(_) => {}
+                    Err(_) => break rustls_result::Io,
+                }
+                if let Err(e) = conn.process_packets() {
+                    // Hand over what arrived before the failure.
+                    written += conn.fill_plaintext(&mut plain_out[written..]);
+                    break e;
+                }
+                conn.notify_handshake_done(conn_ptr);
+            };
+            unsafe {
+                *out_tls_consumed = tls_in.len() - input.len();
+                *out_plain_written = written;
+            }
+            result
+        }
+    }
+
     /// Read up to `count` plaintext bytes from the `rustls_connection` into `buf`.
     /// On success, store the number of bytes read in *out_n (this may be less
-    /// than `count`). A success with *out_n set to 0 means "all bytes currently
-    /// available have been read, but more bytes may become available after
-    /// subsequent calls to rustls_connection_read_tls and
-    /// rustls_connection_process_new_packets."
+    /// than `count`).
+    ///
+    /// Once the plaintext received so far has been read, the result tells the
+    /// caller why there is no more:
+    ///  - RUSTLS_RESULT_PLAINTEXT_EMPTY: nothing is available right now. More
+    ///    may arrive after further calls to rustls_connection_read_tls and
+    ///    rustls_connection_process_new_packets.
+    ///  - RUSTLS_RESULT_OK with *out_n set to 0 (and `count` non-zero): the peer
+    ///    sent close_notify. This is a clean end of stream; no more data will
+    ///    arrive.
+    ///  - RUSTLS_RESULT_UNEXPECTED_EOF: the transport reached EOF without a
+    ///    close_notify, so the data may have been truncated.
     ///
     /// This experimental API is only available when using a nightly Rust compiler
     /// and enabling the `read_buf` Cargo feature. It will be deprecated and later
@@ -521,18 +2264,30 @@ impl rustls_connection {
             if buf.is_null() || out_n.is_null() {
                 return NullParameter
             }
+            if let Some(e) = conn.fatal_error() {
+                return e;
+            }
             let read_buf: &mut [std::mem::MaybeUninit<u8>] = unsafe {
                 slice::from_raw_parts_mut(buf, count)
             };
 
             let mut read_buf: std::io::BorrowedBuf<'_> = read_buf.into();
 
-            let n_read: usize = match conn.reader().read_buf(read_buf.unfilled()) {
+            let result = if conn.plaintext_in.is_empty() {
+                conn.reader().read_buf(read_buf.unfilled())
+            } else {
+                // Plaintext already taken out of rustls goes first.
+                let n = count.min(conn.plaintext_in.len());
+                read_buf.unfilled().append(&conn.plaintext_in[..n]);
+                conn.plaintext_in.drain(..n);
+                Ok(())
+            };
+            conn.note_io_result(&result);
+            let n_read: usize = match result {
                 Ok(()) => read_buf.filled().len(),
-                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return rustls_result::UnexpectedEof,
-                Err(e) if e.kind() == ErrorKind::WouldBlock => return rustls_result::PlaintextEmpty,
-                Err(_) => return rustls_result::Io,
+                Err(e) => return map_read_error(&e),
             };
+            conn.consumed_plaintext(n_read);
             unsafe {
                 *out_n = n_read;
             }
@@ -551,3 +2306,7026 @@ impl rustls_connection {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cmp::min;
+    use std::collections::VecDeque;
+    use std::ptr::{null, null_mut};
+    use std::sync::Arc;
+
+    use libc::c_char;
+    use rustls::sign::CertifiedKey;
+
+    use crate::buffer::tests::XorShift;
+    use crate::cipher::{
+        rustls_certificate, rustls_certified_key, rustls_client_cert_verifier,
+        rustls_client_cert_verifier_optional, rustls_root_cert_store,
+    };
+    use crate::client::{
+        rustls_client_cert_request, rustls_client_config, rustls_client_config_builder,
+        rustls_client_connection_get_handshake_info, rustls_handshake_info, rustls_server_name,
+        rustls_tlsa_record, tests::unhex,
+    };
+    use crate::enums::rustls_tls_version;
+    use crate::rslice::{
+        rustls_slice_bytes, rustls_slice_slice_bytes_get, rustls_slice_slice_bytes_len,
+    };
+    use crate::server::{rustls_server_config, rustls_server_config_builder};
+    use crate::ArcCastPtr;
+
+    use super::*;
+
+    // Read bytes from userdata (a `*mut VecDeque<u8>`) into the provided buffer.
+    pub(crate) unsafe extern "C" fn vecdeque_read(
+        userdata: *mut c_void,
+        buf: *mut u8,
+        n: usize,
+        out_n: *mut usize,
+    ) -> rustls_io_result {
+        let vecdeq: *mut VecDeque<u8> = userdata as *mut _;
+        (*vecdeq).make_contiguous();
+        let first: &[u8] = (*vecdeq).as_slices().0;
+        let n = min(n, first.len());
+        std::ptr::copy_nonoverlapping(first.as_ptr(), buf, n);
+        (*vecdeq).drain(0..n).count();
+        *out_n = n;
+        rustls_io_result(0)
+    }
+
+    // Write bytes from the provided buffer into userdata (a `*mut VecDeque<u8>`).
+    pub(crate) unsafe extern "C" fn vecdeque_write(
+        userdata: *mut c_void,
+        buf: *const u8,
+        n: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_io_result {
+        let vecdeq: *mut VecDeque<u8> = userdata as *mut _;
+        let buf = slice::from_raw_parts(buf, n);
+        (*vecdeq).extend(buf);
+        *out_n = n;
+        rustls_io_result(0)
+    }
+
+    // A certificate verifier callback that accepts any server certificate.
+    pub(crate) unsafe extern "C" fn accept_any_server_cert(
+        _userdata: *mut c_void,
+        _params: *const crate::client::rustls_verify_server_cert_params,
+    ) -> u32 {
+        rustls_result::Ok as u32
+    }
+
+    pub(crate) fn make_certified_key(cert_pem: &str, key_pem: &str) -> *const rustls_certified_key {
+        let mut certified_key: *const rustls_certified_key = null();
+        let result = rustls_certified_key::rustls_certified_key_build(
+            cert_pem.as_ptr(),
+            cert_pem.len(),
+            key_pem.as_ptr(),
+            key_pem.len(),
+            &mut certified_key,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        certified_key
+    }
+
+    // Install the localhost certificate on `builder` and build it.
+    pub(crate) fn build_server_config(
+        builder: *mut rustls_server_config_builder,
+    ) -> *const rustls_server_config {
+        build_server_config_with(
+            builder,
+            include_str!("../testdata/localhost/cert.pem"),
+            include_str!("../testdata/localhost/key.pem"),
+        )
+    }
+
+    // Install `chain_pem` and `key_pem` on `builder` and build it.
+    pub(crate) fn build_server_config_with(
+        builder: *mut rustls_server_config_builder,
+        chain_pem: &str,
+        key_pem: &str,
+    ) -> *const rustls_server_config {
+        let certified_key = make_certified_key(chain_pem, key_pem);
+        let result = rustls_server_config_builder::rustls_server_config_builder_set_certified_keys(
+            builder,
+            &certified_key,
+            1,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        rustls_certified_key::rustls_certified_key_free(certified_key);
+        let config = rustls_server_config_builder::rustls_server_config_builder_build(builder);
+        assert_ne!(config, null());
+        config
+    }
+
+    // Replace `config` with a copy that `f` has changed, for settings the
+    // builder doesn't offer.
+    fn modified_server_config(
+        config: *const rustls_server_config,
+        f: impl FnOnce(&mut rustls::ServerConfig),
+    ) -> *const rustls_server_config {
+        let mut modified = rustls::ServerConfig::clone(try_ref_from_ptr!(config));
+        rustls_server_config::rustls_server_config_free(config);
+        f(&mut modified);
+        rustls_server_config::to_const_ptr(modified)
+    }
+
+    // Install a verifier that accepts any server certificate on `builder` and
+    // build it.
+    pub(crate) fn build_client_config(
+        builder: *mut rustls_client_config_builder,
+    ) -> *const rustls_client_config {
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_dangerous_set_certificate_verifier(
+                builder,
+                Some(accept_any_server_cert),
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        assert_ne!(config, null());
+        config
+    }
+
+    // A root store trusting only the CA that issued the test certificates.
+    pub(crate) fn make_root_store() -> *mut rustls_root_cert_store {
+        let store = rustls_root_cert_store::rustls_root_cert_store_new();
+        let pem = include_str!("../testdata/minica.pem");
+        let result = rustls_root_cert_store::rustls_root_cert_store_add_pem(
+            store,
+            pem.as_ptr(),
+            pem.len(),
+            true,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        store
+    }
+
+    // Trust the test CA on `builder` and build it.
+    pub(crate) fn build_verifying_client_config(
+        builder: *mut rustls_client_config_builder,
+    ) -> *const rustls_client_config {
+        let store = make_root_store();
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_use_roots(builder, store);
+        assert_eq!(result, rustls_result::Ok);
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        assert_ne!(config, null());
+        config
+    }
+
+    pub(crate) fn all_ciphersuites() -> Vec<*const rustls_supported_ciphersuite> {
+        (0..crate::cipher::rustls_all_ciphersuites_len())
+            .map(|i| crate::cipher::rustls_all_ciphersuites_get_entry(i))
+            .collect()
+    }
+
+    pub(crate) fn make_client_connection(
+        config: *const rustls_client_config,
+        server_name: &str,
+    ) -> *mut rustls_connection {
+        let server_name = format!("{}\0", server_name);
+        let mut conn: *mut rustls_connection = null_mut();
+        let result = rustls_client_config::rustls_client_connection_new(
+            config,
+            server_name.as_ptr() as *const c_char,
+            &mut conn,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        conn
+    }
+
+    pub(crate) fn make_server_connection(
+        config: *const rustls_server_config,
+    ) -> *mut rustls_connection {
+        let mut conn: *mut rustls_connection = null_mut();
+        let result = rustls_server_config::rustls_server_connection_new(config, &mut conn);
+        assert_eq!(result, rustls_result::Ok);
+        conn
+    }
+
+    // Move all pending TLS bytes from `from` to `to`, then process them.
+    // Returns the result of the last call to process_new_packets on `to`.
+    pub(crate) fn transfer(
+        from: *mut rustls_connection,
+        to: *mut rustls_connection,
+    ) -> rustls_result {
+        let mut buf = VecDeque::<u8>::new();
+        let mut n: usize = 0;
+        while rustls_connection::rustls_connection_wants_write(from) {
+            let result = rustls_connection::rustls_connection_write_tls(
+                from,
+                Some(vecdeque_write),
+                &mut buf as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        let mut result = rustls_result::Ok;
+        while !buf.is_empty() {
+            let io_result = rustls_connection::rustls_connection_read_tls(
+                to,
+                Some(vecdeque_read),
+                &mut buf as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(io_result, rustls_io_result(0));
+            result = rustls_connection::rustls_connection_process_new_packets(to);
+            if result != rustls_result::Ok {
+                break;
+            }
+        }
+        result
+    }
+
+    // Shuttle TLS bytes between `client` and `server` until neither has
+    // anything left to send. Returns the first error encountered.
+    pub(crate) fn do_handshake(
+        client: *mut rustls_connection,
+        server: *mut rustls_connection,
+    ) -> rustls_result {
+        for _ in 0..16 {
+            if !rustls_connection::rustls_connection_wants_write(client)
+                && !rustls_connection::rustls_connection_wants_write(server)
+            {
+                return rustls_result::Ok;
+            }
+            let result = transfer(client, server);
+            if result != rustls_result::Ok {
+                return result;
+            }
+            let result = transfer(server, client);
+            if result != rustls_result::Ok {
+                return result;
+            }
+        }
+        panic!("handshake did not complete");
+    }
+
+    fn alpn_slices(protocols: &[&'static [u8]]) -> Vec<rustls_slice_bytes<'static>> {
+        protocols.iter().map(|p| (*p).into()).collect()
+    }
+
+    // Returns the ALPN protocol negotiated on `conn`, if any.
+    fn negotiated_alpn(conn: *const rustls_connection) -> Option<Vec<u8>> {
+        let mut protocol: *const u8 = null();
+        let mut protocol_len: usize = 0;
+        let result = rustls_connection::rustls_connection_get_alpn_protocol_ex(
+            conn,
+            &mut protocol,
+            &mut protocol_len,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        if protocol.is_null() {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts(protocol, protocol_len) }.to_vec())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_alpn_negotiation() {
+        let client_builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let client_alpn = alpn_slices(&[b"h2", b"http/1.1"]);
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_alpn_protocols(
+            client_builder,
+            client_alpn.as_ptr(),
+            client_alpn.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config = build_client_config(client_builder);
+
+        let server_builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        let server_alpn = alpn_slices(&[b"h2"]);
+        let result = rustls_server_config_builder::rustls_server_config_builder_set_alpn_protocols(
+            server_builder,
+            server_alpn.as_ptr(),
+            server_alpn.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let server_config = build_server_config(server_builder);
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        // Mid-handshake, nothing has been negotiated yet.
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        assert!(rustls_connection::rustls_connection_is_handshaking(client));
+        assert_eq!(negotiated_alpn(client), None);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(negotiated_alpn(client), Some(b"h2".to_vec()));
+        assert_eq!(negotiated_alpn(server), Some(b"h2".to_vec()));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_alpn_protocol_none_negotiated() {
+        let client_builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let alpn = alpn_slices(&[b"h2"]);
+        rustls_client_config_builder::rustls_client_config_builder_set_alpn_protocols(
+            client_builder,
+            alpn.as_ptr(),
+            alpn.len(),
+        );
+        let client_config = build_client_config(client_builder);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(negotiated_alpn(client), None);
+        assert_eq!(negotiated_alpn(server), None);
+
+        let mut protocol: *const u8 = null();
+        assert_eq!(
+            rustls_connection::rustls_connection_get_alpn_protocol_ex(
+                client,
+                &mut protocol,
+                null_mut()
+            ),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_config_builder_clone() {
+        let base = rustls_client_config_builder::rustls_client_config_builder_new();
+        let store = make_root_store();
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_use_roots(base, store);
+        assert_eq!(result, rustls_result::Ok);
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
+        let alpn = alpn_slices(&[b"h2"]);
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_alpn_protocols(
+            base,
+            alpn.as_ptr(),
+            alpn.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let copy = rustls_client_config_builder::rustls_client_config_builder_clone(base);
+        assert_ne!(copy, null_mut());
+        let alpn = alpn_slices(&[b"http/1.1"]);
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_alpn_protocols(
+            copy,
+            alpn.as_ptr(),
+            alpn.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        // The copy keeps the roots it was never given directly, and building
+        // it leaves the base usable.
+        let copy_config = rustls_client_config_builder::rustls_client_config_builder_build(copy);
+        assert_ne!(copy_config, null());
+        let base_config = rustls_client_config_builder::rustls_client_config_builder_build(base);
+        assert_ne!(base_config, null());
+
+        let server_builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        let server_alpn = alpn_slices(&[b"h2", b"http/1.1"]);
+        let result = rustls_server_config_builder::rustls_server_config_builder_set_alpn_protocols(
+            server_builder,
+            server_alpn.as_ptr(),
+            server_alpn.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let server_config = build_server_config(server_builder);
+
+        for &(config, expected) in &[(base_config, b"h2".as_ref()), (copy_config, b"http/1.1")] {
+            let client = make_client_connection(config, "localhost");
+            let server = make_server_connection(server_config);
+            assert_eq!(do_handshake(client, server), rustls_result::Ok);
+            assert_eq!(negotiated_alpn(client), Some(expected.to_vec()));
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+        }
+
+        rustls_client_config::rustls_client_config_free(base_config);
+        rustls_client_config::rustls_client_config_free(copy_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_clone(null()),
+            null_mut()
+        );
+    }
+
+    #[test]
+    fn test_alpn_rejects_null_protocol() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let mut protocol = rustls_slice_bytes::default();
+        protocol.data = null();
+        protocol.len = 2;
+        let protocols = [protocol];
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_alpn_protocols(
+            builder,
+            protocols.as_ptr(),
+            protocols.len(),
+        );
+        assert_eq!(result, rustls_result::NullParameter);
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+    }
+
+    fn versioned_client_config(versions: &[u16]) -> *const rustls_client_config {
+        let suites = all_ciphersuites();
+        let mut builder: *mut rustls_client_config_builder = null_mut();
+        let result = rustls_client_config_builder::rustls_client_config_builder_new_custom(
+            suites.as_ptr(),
+            suites.len(),
+            versions.as_ptr(),
+            versions.len(),
+            &mut builder,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        build_client_config(builder)
+    }
+
+    fn versioned_server_config(versions: &[u16]) -> *const rustls_server_config {
+        let suites = all_ciphersuites();
+        let mut builder: *mut rustls_server_config_builder = null_mut();
+        let result = rustls_server_config_builder::rustls_server_config_builder_new_custom(
+            suites.as_ptr(),
+            suites.len(),
+            versions.as_ptr(),
+            versions.len(),
+            &mut builder,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        build_server_config(builder)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_protocol_version_restriction() {
+        let tls13 = rustls_tls_version::Tlsv1_3 as u16;
+        let tls12 = rustls_tls_version::Tlsv1_2 as u16;
+        let client_config = versioned_client_config(&[tls13]);
+        let tls12_server_config = versioned_server_config(&[tls12]);
+        let tls13_server_config = versioned_server_config(&[tls13]);
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(tls12_server_config);
+        assert_eq!(
+            do_handshake(client, server),
+            rustls_result::PeerIncompatibleError
+        );
+        assert_eq!(
+            transfer(server, client),
+            rustls_result::AlertProtocolVersion
+        );
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(tls13_server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(
+            rustls_connection::rustls_connection_get_protocol_version(client),
+            tls13
+        );
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(tls12_server_config);
+        rustls_server_config::rustls_server_config_free(tls13_server_config);
+    }
+
+    #[test]
+    fn test_protocol_version_rejects_unknown() {
+        let suites = all_ciphersuites();
+        let mut builder: *mut rustls_client_config_builder = null_mut();
+        for (versions, expected) in [
+            (&[][..], rustls_result::InvalidParameter),
+            (&[0x0302][..], rustls_result::UnsupportedProtocolVersion),
+        ] {
+            let result = rustls_client_config_builder::rustls_client_config_builder_new_custom(
+                suites.as_ptr(),
+                suites.len(),
+                versions.as_ptr(),
+                versions.len(),
+                &mut builder,
+            );
+            assert_eq!(result, expected);
+            assert_eq!(builder, null_mut());
+        }
+    }
+
+    fn ciphersuite_entry(id: u16) -> *const rustls_supported_ciphersuite {
+        *all_ciphersuites()
+            .iter()
+            .find(|&&cs| {
+                crate::cipher::rustls_supported_ciphersuite::rustls_supported_ciphersuite_get_suite(
+                    cs,
+                ) == id
+            })
+            .unwrap()
+    }
+
+    fn single_suite_server_config(id: u16) -> *const rustls_server_config {
+        let suites = [ciphersuite_entry(id)];
+        let mut builder: *mut rustls_server_config_builder = null_mut();
+        let result = rustls_server_config_builder::rustls_server_config_builder_new_custom(
+            suites.as_ptr(),
+            suites.len(),
+            crate::enums::RUSTLS_ALL_VERSIONS.as_ptr(),
+            crate::enums::RUSTLS_ALL_VERSIONS_LEN,
+            &mut builder,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        build_server_config(builder)
+    }
+
+    const TLS13_AES_128_GCM_SHA256: u16 = 0x1301;
+    const TLS13_AES_256_GCM_SHA384: u16 = 0x1302;
+    const TLS13_CHACHA20_POLY1305_SHA256: u16 = 0x1303;
+    const TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384: u16 = 0xc030;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_ciphersuite_restriction() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let ids = [
+            TLS13_AES_256_GCM_SHA384,
+            TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        ];
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites(
+            builder,
+            ids.as_ptr(),
+            ids.len(),
+            null_mut(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config = build_client_config(builder);
+
+        let excluded_server_config = single_suite_server_config(TLS13_CHACHA20_POLY1305_SHA256);
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(excluded_server_config);
+        // With no suite in common, the server can't find a usable signature
+        // scheme for its certificate and aborts the handshake.
+        assert_eq!(do_handshake(client, server), rustls_result::General);
+        assert_eq!(transfer(server, client), rustls_result::AlertAccessDenied);
+        assert_eq!(
+            rustls_connection::rustls_connection_get_negotiated_ciphersuite(client),
+            null()
+        );
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        let allowed_server_config = single_suite_server_config(TLS13_AES_256_GCM_SHA384);
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(allowed_server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(
+            rustls_connection::rustls_connection_get_negotiated_ciphersuite(client),
+            ciphersuite_entry(TLS13_AES_256_GCM_SHA384)
+        );
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(excluded_server_config);
+        rustls_server_config::rustls_server_config_free(allowed_server_config);
+    }
+
+    #[test]
+    fn test_ciphersuite_ids_rejected() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        // 0x000a is TLS_RSA_WITH_3DES_EDE_CBC_SHA, which rustls does not implement.
+        let ids = [TLS13_AES_128_GCM_SHA256, 0x000a];
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites(
+            builder,
+            ids.as_ptr(),
+            ids.len(),
+            null_mut(),
+        );
+        assert_eq!(result, rustls_result::UnsupportedCipherSuite);
+        let mut failed = usize::MAX;
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites(
+            builder,
+            ids.as_ptr(),
+            ids.len(),
+            &mut failed,
+        );
+        assert_eq!(result, rustls_result::UnsupportedCipherSuite);
+        assert_eq!(failed, 1);
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites(
+            builder,
+            ids.as_ptr(),
+            0,
+            &mut failed,
+        );
+        assert_eq!(result, rustls_result::InvalidParameter);
+        // Only a rejected identifier sets the index.
+        assert_eq!(failed, 1);
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+
+        // A TLS 1.2 suite can't be used by a TLS 1.3-only builder.
+        let suites = all_ciphersuites();
+        let versions = [rustls_tls_version::Tlsv1_3 as u16];
+        let mut builder: *mut rustls_client_config_builder = null_mut();
+        let result = rustls_client_config_builder::rustls_client_config_builder_new_custom(
+            suites.as_ptr(),
+            suites.len(),
+            versions.as_ptr(),
+            versions.len(),
+            &mut builder,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let ids = [TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384];
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites(
+            builder,
+            ids.as_ptr(),
+            ids.len(),
+            null_mut(),
+        );
+        assert_eq!(result, rustls_result::InvalidParameter);
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_ciphersuites_by_name() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let names = [
+            "TLS_CHACHA20_POLY1305_SHA256\0".as_ptr() as *const c_char,
+            "tls13_aes_128_gcm_sha256\0".as_ptr() as *const c_char,
+        ];
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites_by_name(
+                builder,
+                names.as_ptr(),
+                names.len(),
+                null_mut(),
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config = build_client_config(builder);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(
+            rustls_connection::rustls_connection_get_negotiated_ciphersuite(server),
+            ciphersuite_entry(TLS13_CHACHA20_POLY1305_SHA256)
+        );
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let names = [
+            "TLS_CHACHA20_POLY1305_SHA256\0".as_ptr() as *const c_char,
+            "TLS_RSA_WITH_RC4_128_MD5\0".as_ptr() as *const c_char,
+        ];
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites_by_name(
+                builder,
+                names.as_ptr(),
+                names.len(),
+                null_mut(),
+            );
+        assert_eq!(result, rustls_result::UnsupportedCipherSuite);
+        let mut failed = usize::MAX;
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites_by_name(
+                builder,
+                names.as_ptr(),
+                names.len(),
+                &mut failed,
+            );
+        assert_eq!(result, rustls_result::UnsupportedCipherSuite);
+        assert_eq!(failed, 1);
+        let names = [null()];
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites_by_name(
+                builder,
+                names.as_ptr(),
+                names.len(),
+                null_mut(),
+            );
+        assert_eq!(result, rustls_result::NullParameter);
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_negotiated_ciphersuite() {
+        const TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256: u16 = 0xc02f;
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config = single_suite_server_config(TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256);
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        for conn in [client, server] {
+            assert_eq!(
+                rustls_connection::rustls_connection_get_negotiated_ciphersuite(conn),
+                null()
+            );
+        }
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        for conn in [client, server] {
+            let negotiated = rustls_connection::rustls_connection_get_negotiated_ciphersuite(conn);
+            assert_ne!(negotiated, null());
+            assert_eq!(
+                crate::cipher::rustls_supported_ciphersuite::rustls_supported_ciphersuite_get_suite(
+                    negotiated
+                ),
+                TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+            );
+        }
+        assert_eq!(
+            rustls_connection::rustls_connection_get_negotiated_ciphersuite(null()),
+            null()
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_negotiated_protocol_version() {
+        let tls12 = rustls_tls_version::Tlsv1_2 as u16;
+        let client_config = versioned_client_config(&[tls12]);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(
+            rustls_connection::rustls_connection_get_protocol_version(client),
+            0
+        );
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(
+            rustls_connection::rustls_connection_get_protocol_version(client),
+            tls12
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_get_protocol_version(server),
+            tls12
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Run a handshake between a client built from `client_config` and a
+    // server using the test certificates, returning the client's result.
+    fn client_handshake_result(client_config: *const rustls_client_config) -> rustls_result {
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let mut result = rustls_result::Ok;
+        for _ in 0..16 {
+            if !rustls_connection::rustls_connection_wants_write(client)
+                && !rustls_connection::rustls_connection_wants_write(server)
+            {
+                break;
+            }
+            transfer(client, server);
+            result = transfer(server, client);
+            if result != rustls_result::Ok {
+                break;
+            }
+        }
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+        result
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_private_ca_roots() {
+        // A fresh builder trusts nothing.
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        assert_ne!(client_handshake_result(config), rustls_result::Ok);
+
+        // Neither does one that uses an empty root store.
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let empty_store = rustls_root_cert_store::rustls_root_cert_store_new();
+        rustls_client_config_builder::rustls_client_config_builder_use_roots(builder, empty_store);
+        rustls_root_cert_store::rustls_root_cert_store_free(empty_store);
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        assert_eq!(
+            client_handshake_result(config),
+            rustls_result::CertUnknownIssuer
+        );
+
+        // Adding the private CA is enough to connect.
+        let config = build_verifying_client_config(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        assert_eq!(client_handshake_result(config), rustls_result::Ok);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_shared_root_store() {
+        let store = make_root_store();
+        let configs: Vec<*const rustls_client_config> = (0..2)
+            .map(|_| {
+                let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+                let result = rustls_client_config_builder::rustls_client_config_builder_use_roots(
+                    builder, store,
+                );
+                assert_eq!(result, rustls_result::Ok);
+                rustls_client_config_builder::rustls_client_config_builder_build(builder)
+            })
+            .collect();
+        // Freeing the store doesn't affect configs that use it.
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
+        for config in configs {
+            assert_ne!(config, null());
+            assert_eq!(client_handshake_result(config), rustls_result::Ok);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_der_root() {
+        let der = include_bytes!("../testdata/minica.der");
+        let store = rustls_root_cert_store::rustls_root_cert_store_new();
+        let result =
+            rustls_root_cert_store::rustls_root_cert_store_add_der(store, der.as_ptr(), der.len());
+        assert_eq!(result, rustls_result::Ok);
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        rustls_client_config_builder::rustls_client_config_builder_use_roots(builder, store);
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
+        let config = rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        assert_eq!(client_handshake_result(config), rustls_result::Ok);
+    }
+
+    // A server config requiring client certificates issued by the test CA.
+    fn mtls_server_config() -> *const rustls_server_config {
+        let store = make_root_store();
+        let verifier = rustls_client_cert_verifier::rustls_client_cert_verifier_new(store);
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
+        let builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        rustls_server_config_builder::rustls_server_config_builder_set_client_verifier(
+            builder, verifier,
+        );
+        rustls_client_cert_verifier::rustls_client_cert_verifier_free(verifier);
+        build_server_config(builder)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_cert_pem() {
+        let cert = include_str!("../testdata/example.com/cert.pem");
+        let key = include_str!("../testdata/example.com/key.pem");
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_client_cert_pem(
+            builder,
+            cert.as_ptr(),
+            cert.len(),
+            key.as_ptr(),
+            key.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config = build_verifying_client_config(builder);
+        let server_config = mtls_server_config();
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // The server saw the chain the client was given.
+        let peer_cert = rustls_connection::rustls_connection_get_peer_certificate(server, 0);
+        assert_ne!(peer_cert, null());
+        let (mut der_data, mut der_len) = (null(), 0);
+        let result =
+            rustls_certificate::rustls_certificate_get_der(peer_cert, &mut der_data, &mut der_len);
+        assert_eq!(result, rustls_result::Ok);
+        let der = unsafe { slice::from_raw_parts(der_data, der_len) };
+        let expected = rustls_pemfile::certs(&mut cert.as_bytes()).unwrap();
+        assert_eq!(der, &expected[0][..]);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+
+        // Without a certificate the server refuses the client.
+        let client_config = build_verifying_client_config(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        let server_config = mtls_server_config();
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_ne!(do_handshake(client, server), rustls_result::Ok);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_cert_der() {
+        let chain: [&[u8]; 2] = [
+            include_bytes!("../testdata/ecdsa/cert.der"),
+            include_bytes!("../testdata/minica.der"),
+        ];
+        let key = include_bytes!("../testdata/ecdsa/key.der");
+        let ptrs: Vec<*const u8> = chain.iter().map(|c| c.as_ptr()).collect();
+        let lens: Vec<size_t> = chain.iter().map(|c| c.len()).collect();
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_client_cert_der(
+            builder,
+            ptrs.as_ptr(),
+            lens.as_ptr(),
+            chain.len(),
+            key.as_ptr(),
+            key.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config = build_verifying_client_config(builder);
+        let server_config = mtls_server_config();
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // The server received the chain in the order it was given.
+        for (i, expected) in chain.iter().enumerate() {
+            let peer_cert = rustls_connection::rustls_connection_get_peer_certificate(server, i);
+            let (mut der_data, mut der_len) = (null(), 0);
+            let result = rustls_certificate::rustls_certificate_get_der(
+                peer_cert,
+                &mut der_data,
+                &mut der_len,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            assert_eq!(
+                unsafe { slice::from_raw_parts(der_data, der_len) },
+                *expected
+            );
+        }
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    // Authenticate with whichever key the connection's userdata points to.
+    unsafe extern "C" fn key_from_userdata(
+        userdata: *mut c_void,
+        request: *const rustls_client_cert_request,
+    ) -> *const rustls_certified_key {
+        assert!(!(*request).signature_schemes.data.is_null());
+        userdata as *const rustls_certified_key
+    }
+
+    // A server config accepting connections with or without a client
+    // certificate issued by the test CA.
+    fn optional_mtls_server_config() -> *const rustls_server_config {
+        let store = make_root_store();
+        let verifier =
+            rustls_client_cert_verifier_optional::rustls_client_cert_verifier_optional_new(store);
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
+        let builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        rustls_server_config_builder::rustls_server_config_builder_set_client_verifier_optional(
+            builder, verifier,
+        );
+        rustls_client_cert_verifier_optional::rustls_client_cert_verifier_optional_free(verifier);
+        build_server_config(builder)
+    }
+
+    // Connect with the given userdata and return the leaf certificate the
+    // server received, if any. Each connection gets a new server config so
+    // that it can't resume an earlier session.
+    fn client_cert_seen_by_server(
+        client_config: *const rustls_client_config,
+        userdata: *const rustls_certified_key,
+    ) -> Option<Vec<u8>> {
+        let server_config = optional_mtls_server_config();
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        rustls_connection::rustls_connection_set_userdata(client, userdata as *mut c_void);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let der = peer_cert_der(server, 0);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        der
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_cert_resolver() {
+        let localhost_cert = include_str!("../testdata/localhost/cert.pem");
+        let example_cert = include_str!("../testdata/example.com/cert.pem");
+        let localhost_key = make_certified_key(
+            localhost_cert,
+            include_str!("../testdata/localhost/key.pem"),
+        );
+        let example_key = make_certified_key(
+            example_cert,
+            include_str!("../testdata/example.com/key.pem"),
+        );
+
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_set_client_cert_resolver(
+                builder, None
+            ),
+            rustls_result::NullParameter
+        );
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_set_client_cert_resolver(
+                builder,
+                Some(key_from_userdata),
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config = build_verifying_client_config(builder);
+
+        let der = |pem: &str| {
+            rustls_pemfile::certs(&mut pem.as_bytes())
+                .unwrap()
+                .remove(0)
+        };
+        assert_eq!(
+            client_cert_seen_by_server(client_config, localhost_key),
+            Some(der(localhost_cert))
+        );
+        assert_eq!(
+            client_cert_seen_by_server(client_config, example_key),
+            Some(der(example_cert))
+        );
+        assert_eq!(client_cert_seen_by_server(client_config, null()), None);
+
+        rustls_certified_key::rustls_certified_key_free(localhost_key);
+        rustls_certified_key::rustls_certified_key_free(example_key);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_shared_certified_key() {
+        let certified_key = make_certified_key(
+            include_str!("../testdata/localhost/cert.pem"),
+            include_str!("../testdata/localhost/key.pem"),
+        );
+        let server_configs: Vec<*const rustls_server_config> = (0..2)
+            .map(|_| {
+                let builder = rustls_server_config_builder::rustls_server_config_builder_new();
+                let result =
+                    rustls_server_config_builder::rustls_server_config_builder_set_certified_keys(
+                        builder,
+                        &certified_key,
+                        1,
+                    );
+                assert_eq!(result, rustls_result::Ok);
+                let config =
+                    rustls_server_config_builder::rustls_server_config_builder_build(builder);
+                assert_ne!(config, null());
+                config
+            })
+            .collect();
+        {
+            let key: Arc<CertifiedKey> = crate::try_arc_from(certified_key).unwrap();
+            // Our handle, the clone above, and one per server config.
+            assert_eq!(Arc::strong_count(&key), 4);
+        }
+        // The configs keep the key alive after the caller's handle is freed.
+        rustls_certified_key::rustls_certified_key_free(certified_key);
+
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let client_config = build_verifying_client_config(builder);
+        for server_config in server_configs {
+            let client = make_client_connection(client_config, "localhost");
+            let server = make_server_connection(server_config);
+            assert_eq!(do_handshake(client, server), rustls_result::Ok);
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+            rustls_server_config::rustls_server_config_free(server_config);
+        }
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    // The DER of the certificate at position `i` of `conn`'s peer chain.
+    fn peer_cert_der(conn: *const rustls_connection, i: usize) -> Option<Vec<u8>> {
+        let cert = rustls_connection::rustls_connection_get_peer_certificate(conn, i);
+        if cert.is_null() {
+            return None;
+        }
+        let (mut der_data, mut der_len) = (null(), 0);
+        let result =
+            rustls_certificate::rustls_certificate_get_der(cert, &mut der_data, &mut der_len);
+        assert_eq!(result, rustls_result::Ok);
+        Some(unsafe { slice::from_raw_parts(der_data, der_len) }.to_vec())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_dangerous_disable_cert_verification() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let alpn = alpn_slices(&[b"h2"]);
+        rustls_client_config_builder::rustls_client_config_builder_set_alpn_protocols(
+            builder,
+            alpn.as_ptr(),
+            alpn.len(),
+        );
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_dangerous_disable_cert_verification(
+                builder,
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        assert_ne!(client_config, null());
+
+        let server_builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        rustls_server_config_builder::rustls_server_config_builder_set_alpn_protocols(
+            server_builder,
+            alpn.as_ptr(),
+            alpn.len(),
+        );
+        let server_config = build_server_config(server_builder);
+
+        // The server's certificate is for localhost, and no roots are
+        // configured, but the handshake succeeds anyway.
+        let client = make_client_connection(client_config, "example.invalid");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(negotiated_alpn(client), Some(b"h2".to_vec()));
+
+        // What was accepted is still available.
+        let localhost_cert =
+            rustls_pemfile::certs(&mut include_bytes!("../testdata/localhost/cert.pem").as_ref())
+                .unwrap()
+                .remove(0);
+        assert_eq!(peer_cert_der(client, 0), Some(localhost_cert));
+
+        // Application data is encrypted on the wire.
+        let message = b"attack at dawn, attack at dawn";
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write(
+            client,
+            message.as_ptr(),
+            message.len(),
+            &mut n,
+        );
+        assert_eq!((result, n), (rustls_result::Ok, message.len()));
+        let mut wire = VecDeque::<u8>::new();
+        while rustls_connection::rustls_connection_wants_write(client) {
+            let result = rustls_connection::rustls_connection_write_tls(
+                client,
+                Some(vecdeque_write),
+                &mut wire as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        let wire_bytes: Vec<u8> = wire.iter().copied().collect();
+        assert!(wire_bytes.len() > message.len());
+        assert!(!wire_bytes.windows(6).any(|w| w == b"attack"));
+
+        while !wire.is_empty() {
+            let result = rustls_connection::rustls_connection_read_tls(
+                server,
+                Some(vecdeque_read),
+                &mut wire as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+            assert_eq!(
+                rustls_connection::rustls_connection_process_new_packets(server),
+                rustls_result::Ok
+            );
+        }
+        let mut received = [0u8; 64];
+        let result = rustls_connection::rustls_connection_read(
+            server,
+            received.as_mut_ptr(),
+            received.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(&received[..n], &message[..]);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // What a recording_verifier saw, and when it should say no.
+    #[derive(Default)]
+    struct VerifierLog {
+        reject_call: usize,
+        reject_with: u32,
+        server_names: Vec<String>,
+        chains: Vec<Vec<Vec<u8>>>,
+        ocsp_responses: Vec<Vec<u8>>,
+    }
+
+    // A verifier callback that records its input in the VerifierLog passed
+    // as userdata, and rejects the `reject_call`th certificate (counting
+    // from 1) with `reject_with`.
+    unsafe extern "C" fn recording_verifier(
+        userdata: *mut c_void,
+        params: *const crate::client::rustls_verify_server_cert_params,
+    ) -> u32 {
+        let log = &mut *(userdata as *mut VerifierLog);
+        let params = &*params;
+        let name =
+            slice::from_raw_parts(params.server_name.data as *const u8, params.server_name.len);
+        log.server_names
+            .push(String::from_utf8(name.to_vec()).unwrap());
+        let der = |s: &rustls_slice_bytes| slice::from_raw_parts(s.data, s.len).to_vec();
+        let mut chain = vec![der(&params.end_entity_cert_der)];
+        let intermediates: *const _ = params.intermediate_certs_der;
+        for i in 0..rustls_slice_slice_bytes_len(intermediates) {
+            chain.push(der(&rustls_slice_slice_bytes_get(intermediates, i)));
+        }
+        log.chains.push(chain);
+        log.ocsp_responses.push(der(&params.ocsp_response));
+        if log.server_names.len() == log.reject_call {
+            log.reject_with
+        } else {
+            rustls_result::Ok as u32
+        }
+    }
+
+    // Connect to a fresh server, so that no session is resumed, with `log`
+    // as the client's userdata.
+    fn handshake_with_log(
+        client_config: *const rustls_client_config,
+        log: &mut VerifierLog,
+    ) -> rustls_result {
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let result = handshake_with_server(client_config, server_config, log);
+        rustls_server_config::rustls_server_config_free(server_config);
+        result
+    }
+
+    fn handshake_with_server(
+        client_config: *const rustls_client_config,
+        server_config: *const rustls_server_config,
+        log: &mut VerifierLog,
+    ) -> rustls_result {
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        rustls_connection::rustls_connection_set_userdata(client, log as *mut _ as *mut c_void);
+        let result = do_handshake(client, server);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        result
+    }
+
+    fn recording_client_config() -> *const rustls_client_config {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_dangerous_set_certificate_verifier(
+                builder,
+                Some(recording_verifier),
+            );
+        assert_eq!(result, rustls_result::Ok);
+        rustls_client_config_builder::rustls_client_config_builder_build(builder)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_custom_verifier_accepts() {
+        let client_config = recording_client_config();
+        let mut log = VerifierLog::default();
+        assert_eq!(
+            handshake_with_log(client_config, &mut log),
+            rustls_result::Ok
+        );
+
+        let localhost_cert =
+            rustls_pemfile::certs(&mut include_bytes!("../testdata/localhost/cert.pem").as_ref())
+                .unwrap();
+        assert_eq!(log.server_names, vec!["localhost".to_string()]);
+        assert_eq!(log.chains, vec![localhost_cert]);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_custom_verifier_rejects() {
+        let client_config = recording_client_config();
+        let mut log = VerifierLog {
+            reject_call: 1,
+            reject_with: rustls_result::CertRevoked as u32,
+            ..Default::default()
+        };
+        assert_eq!(
+            handshake_with_log(client_config, &mut log),
+            rustls_result::CertRevoked
+        );
+
+        // Anything that isn't a certificate error still rejects the
+        // certificate.
+        for reject_with in [rustls_result::InvalidParameter as u32, 12345] {
+            let mut log = VerifierLog {
+                reject_call: 1,
+                reject_with,
+                ..Default::default()
+            };
+            assert_eq!(
+                handshake_with_log(client_config, &mut log),
+                rustls_result::CertApplicationVerificationFailure
+            );
+        }
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_custom_verifier_per_connection() {
+        let client_config = recording_client_config();
+        let mut log = VerifierLog {
+            reject_call: 2,
+            reject_with: rustls_result::CertUnknownIssuer as u32,
+            ..Default::default()
+        };
+        assert_eq!(
+            handshake_with_log(client_config, &mut log),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            handshake_with_log(client_config, &mut log),
+            rustls_result::CertUnknownIssuer
+        );
+        assert_eq!(
+            handshake_with_log(client_config, &mut log),
+            rustls_result::Ok
+        );
+        assert_eq!(log.server_names.len(), 3);
+
+        // Connections with their own userdata are unaffected.
+        let mut other_log = VerifierLog::default();
+        assert_eq!(
+            handshake_with_log(client_config, &mut other_log),
+            rustls_result::Ok
+        );
+        assert_eq!(other_log.server_names.len(), 1);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_custom_verifier_sees_ocsp_response() {
+        // An OCSPResponse with status "unauthorized"; rustls passes it on
+        // without parsing it.
+        let ocsp = [0x30, 0x03, 0x0a, 0x01, 0x06];
+        let certified_key = make_certified_key(
+            include_str!("../testdata/localhost/cert.pem"),
+            include_str!("../testdata/localhost/key.pem"),
+        );
+        let ocsp_slice: rustls_slice_bytes = (&ocsp[..]).into();
+        let mut stapled_key: *const rustls_certified_key = null();
+        let result = rustls_certified_key::rustls_certified_key_clone_with_ocsp(
+            certified_key,
+            &ocsp_slice,
+            &mut stapled_key,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        rustls_certified_key::rustls_certified_key_free(certified_key);
+
+        let client_config = recording_client_config();
+        let mut log = VerifierLog::default();
+        let suites = all_ciphersuites();
+        for version in [rustls_tls_version::Tlsv1_2, rustls_tls_version::Tlsv1_3] {
+            let versions = [version as u16];
+            let mut builder = null_mut();
+            let result = rustls_server_config_builder::rustls_server_config_builder_new_custom(
+                suites.as_ptr(),
+                suites.len(),
+                versions.as_ptr(),
+                versions.len(),
+                &mut builder,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            let result =
+                rustls_server_config_builder::rustls_server_config_builder_set_certified_keys(
+                    builder,
+                    &stapled_key,
+                    1,
+                );
+            assert_eq!(result, rustls_result::Ok);
+            let server_config =
+                rustls_server_config_builder::rustls_server_config_builder_build(builder);
+            assert_eq!(
+                handshake_with_server(client_config, server_config, &mut log),
+                rustls_result::Ok
+            );
+            rustls_server_config::rustls_server_config_free(server_config);
+        }
+        assert_eq!(log.ocsp_responses, vec![ocsp.to_vec(), ocsp.to_vec()]);
+
+        // Without a stapled response the callback sees an empty one.
+        assert_eq!(
+            handshake_with_log(client_config, &mut log),
+            rustls_result::Ok
+        );
+        assert_eq!(log.ocsp_responses[2], Vec::<u8>::new());
+
+        rustls_certified_key::rustls_certified_key_free(stapled_key);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    fn pem_certs(pem: &str) -> Vec<Vec<u8>> {
+        rustls_pemfile::certs(&mut pem.as_bytes()).unwrap()
+    }
+
+    fn spki_hash(der: &[u8]) -> [u8; 32] {
+        crate::cipher::spki_sha256(&rustls::Certificate(der.to_vec())).unwrap()
+    }
+
+    // Connect to a server presenting `chain_pem` with a client that trusts
+    // `root_pem` and pins `pins`.
+    fn pinned_handshake(
+        chain_pem: &str,
+        key_pem: &str,
+        root_pem: &str,
+        pins: &[[u8; 32]],
+        leaf_only: bool,
+    ) -> rustls_result {
+        let store = rustls_root_cert_store::rustls_root_cert_store_new();
+        let result = rustls_root_cert_store::rustls_root_cert_store_add_pem(
+            store,
+            root_pem.as_ptr(),
+            root_pem.len(),
+            true,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_set_pinned_spki_sha256(
+                builder,
+                pins.as_ptr(),
+                pins.len(),
+                leaf_only,
+            );
+        assert_eq!(result, rustls_result::Ok);
+        rustls_client_config_builder::rustls_client_config_builder_use_roots(builder, store);
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            chain_pem,
+            key_pem,
+        );
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let result = do_handshake(client, server);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+        result
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_pinned_spki_leaf() {
+        let cert = include_str!("../testdata/localhost/cert.pem");
+        let key = include_str!("../testdata/localhost/key.pem");
+        let root = include_str!("../testdata/minica.pem");
+        let leaf_pin = spki_hash(&pem_certs(cert)[0]);
+        let other_pin = spki_hash(&pem_certs(include_str!("../testdata/example.com/cert.pem"))[0]);
+        let root_pin = spki_hash(&pem_certs(root)[0]);
+
+        assert_eq!(
+            pinned_handshake(cert, key, root, &[leaf_pin], true),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            pinned_handshake(cert, key, root, &[other_pin, leaf_pin], false),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            pinned_handshake(cert, key, root, &[other_pin], false),
+            rustls_result::PinViolation
+        );
+        // The root the chain was verified up to counts too, unless only the
+        // leaf is checked.
+        assert_eq!(
+            pinned_handshake(cert, key, root, &[root_pin], false),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            pinned_handshake(cert, key, root, &[root_pin], true),
+            rustls_result::PinViolation
+        );
+        // Other trusted roots don't.
+        let other_root = include_str!("../testdata/intermediate/root.pem");
+        let other_root_pin = spki_hash(&pem_certs(other_root)[0]);
+        let roots = format!("{}{}", root, other_root);
+        assert_eq!(
+            pinned_handshake(cert, key, &roots, &[other_root_pin], false),
+            rustls_result::PinViolation
+        );
+        assert_eq!(
+            pinned_handshake(cert, key, &roots, &[other_root_pin, root_pin], false),
+            rustls_result::Ok
+        );
+        // Pinning doesn't replace verification.
+        let wrong_root = include_str!("../testdata/intermediate/root.pem");
+        assert_eq!(
+            pinned_handshake(cert, key, wrong_root, &[leaf_pin], true),
+            rustls_result::CertUnknownIssuer
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_pinned_spki_intermediate() {
+        let chain = include_str!("../testdata/intermediate/cert.pem");
+        let key = include_str!("../testdata/intermediate/key.pem");
+        let root = include_str!("../testdata/intermediate/root.pem");
+        let chain_der = pem_certs(chain);
+        let leaf_pin = spki_hash(&chain_der[0]);
+        let intermediate_pin = spki_hash(&chain_der[1]);
+
+        assert_eq!(
+            pinned_handshake(chain, key, root, &[intermediate_pin], false),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            pinned_handshake(chain, key, root, &[intermediate_pin], true),
+            rustls_result::PinViolation
+        );
+        assert_eq!(
+            pinned_handshake(chain, key, root, &[leaf_pin], true),
+            rustls_result::Ok
+        );
+
+        // A pinned certificate that the server sends but that isn't on the
+        // verified path doesn't satisfy the pin.
+        let unrelated = include_str!("../testdata/example.com/cert.pem");
+        let padded_chain = format!("{}{}", chain, unrelated);
+        let unrelated_pin = spki_hash(&pem_certs(unrelated)[0]);
+        assert_eq!(
+            pinned_handshake(&padded_chain, key, root, &[unrelated_pin], false),
+            rustls_result::PinViolation
+        );
+        assert_eq!(
+            pinned_handshake(
+                &padded_chain,
+                key,
+                root,
+                &[unrelated_pin, intermediate_pin],
+                false
+            ),
+            rustls_result::Ok
+        );
+    }
+
+    #[test]
+    fn test_pinned_spki_rejects_empty_set() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let pins = [[0u8; 32]];
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_set_pinned_spki_sha256(
+                builder,
+                pins.as_ptr(),
+                0,
+                false,
+            ),
+            rustls_result::InvalidParameter
+        );
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_set_pinned_spki_sha256(
+                builder,
+                null(),
+                1,
+                false,
+            ),
+            rustls_result::NullParameter
+        );
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+    }
+
+    // Connect to a server for `cert_pem` as "localhost", with a client
+    // pinned to `pinned_der`. The pinned buffer is wiped as soon as the
+    // builder has it, to check that the builder keeps its own copy.
+    fn pinned_cert_handshake(cert_pem: &str, key_pem: &str, pinned_der: &[u8]) -> rustls_result {
+        let mut pinned_der = pinned_der.to_vec();
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_pinned_cert_der(
+            builder,
+            pinned_der.as_ptr(),
+            pinned_der.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        pinned_der.iter_mut().for_each(|b| *b = 0);
+        drop(pinned_der);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            cert_pem,
+            key_pem,
+        );
+
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let result = do_handshake(client, server);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+        result
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_pinned_cert_der() {
+        // Self-signed, long expired, and issued for device.invalid.
+        let cert = include_str!("../testdata/expired/cert.pem");
+        let key = include_str!("../testdata/expired/key.pem");
+        let der = include_bytes!("../testdata/expired/cert.der");
+        assert_eq!(pinned_cert_handshake(cert, key, der), rustls_result::Ok);
+
+        let other = include_bytes!("../testdata/ecdsa/cert.der");
+        assert_eq!(
+            pinned_cert_handshake(cert, key, other),
+            rustls_result::PinnedCertMismatch
+        );
+    }
+
+    #[test]
+    fn test_pinned_cert_der_rejects_garbage() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let set_pinned = |der: &[u8]| {
+            rustls_client_config_builder::rustls_client_config_builder_set_pinned_cert_der(
+                builder,
+                der.as_ptr(),
+                der.len(),
+            )
+        };
+        assert_eq!(set_pinned(b""), rustls_result::CertificateParseError);
+        assert_eq!(
+            set_pinned(b"not a cert"),
+            rustls_result::CertificateParseError
+        );
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_set_pinned_cert_der(
+                builder,
+                null(),
+                0
+            ),
+            rustls_result::NullParameter
+        );
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+    }
+
+    // Connect as `server_name` to a server presenting `chain_pem`, with a
+    // client that has the TLSA `records` (usage, selector, matching type and
+    // hex data) and trusts `root_pem`, if given.
+    fn dane_handshake(
+        chain_pem: &str,
+        key_pem: &str,
+        root_pem: Option<&str>,
+        server_name: &str,
+        records: &[(u8, u8, u8, &str)],
+    ) -> rustls_result {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        if let Some(root_pem) = root_pem {
+            let store = rustls_root_cert_store::rustls_root_cert_store_new();
+            let result = rustls_root_cert_store::rustls_root_cert_store_add_pem(
+                store,
+                root_pem.as_ptr(),
+                root_pem.len(),
+                true,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            rustls_client_config_builder::rustls_client_config_builder_use_roots(builder, store);
+            rustls_root_cert_store::rustls_root_cert_store_free(store);
+        }
+        let data: Vec<Vec<u8>> = records.iter().map(|(_, _, _, hex)| unhex(hex)).collect();
+        let tlsa: Vec<rustls_tlsa_record> = records
+            .iter()
+            .zip(&data)
+            .map(
+                |(&(usage, selector, matching_type, _), data)| rustls_tlsa_record {
+                    usage,
+                    selector,
+                    matching_type,
+                    data: data.as_slice().into(),
+                },
+            )
+            .collect();
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_tlsa_records(
+            builder,
+            tlsa.as_ptr(),
+            tlsa.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        drop(tlsa);
+        drop(data);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            chain_pem,
+            key_pem,
+        );
+
+        let client = make_client_connection(client_config, server_name);
+        let server = make_server_connection(server_config);
+        let result = do_handshake(client, server);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+        result
+    }
+
+    // TLSA data for the testdata/intermediate chain, computed with openssl
+    // as in client's test_tlsa_record_matches.
+    const LEAF_SPKI_SHA256: &str =
+        "978e8a175f6a40ff7508c4fb428dfc0febe9c34a5e68abb843ddc1d64cf021a4";
+    const LEAF_CERT_SHA512: &str = "6d61d676eb0f15607ac0cf31020294c6acdf1939d815f40dce9067967035c328\
+                                    0793c28b090e8f00b2d01c30b53faf27bd3301a53ea9c8cf270f11630187948e";
+    const INTERMEDIATE_SPKI_SHA256: &str =
+        "c4f686ef36801b3bcb296ad5eefc703ae6a258324dc8f3861b97a7771dc1688f";
+    const INTERMEDIATE_CERT_SHA256: &str =
+        "760c8db71c17699c2f2c3504bff061df2cb1d5cce94128feecef042387144382";
+    const ROOT_SPKI_SHA256: &str =
+        "59346ae8309cc91b2e09ae4a6f3ed9a6685ffefd5b6f0cbfa9645323ed748830";
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_dane_ee() {
+        // Self-signed, long expired, and issued for device.invalid: none of
+        // that matters to a DANE-EE record.
+        let cert = include_str!("../testdata/expired/cert.pem");
+        let key = include_str!("../testdata/expired/key.pem");
+        let spki_sha256 = "01349458ffa68838af58d4b6ac57664b82a4e7e49319a2eced82871e64563866";
+        assert_eq!(
+            dane_handshake(cert, key, None, "localhost", &[(3, 1, 1, spki_sha256)]),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            dane_handshake(cert, key, None, "localhost", &[(3, 1, 1, LEAF_SPKI_SHA256)]),
+            rustls_result::TlsaMismatch
+        );
+
+        let chain = include_str!("../testdata/intermediate/cert.pem");
+        let key = include_str!("../testdata/intermediate/key.pem");
+        assert_eq!(
+            dane_handshake(
+                chain,
+                key,
+                None,
+                "example.com",
+                &[(3, 0, 2, LEAF_CERT_SHA512)]
+            ),
+            rustls_result::Ok
+        );
+        // Any one matching record is enough.
+        assert_eq!(
+            dane_handshake(
+                chain,
+                key,
+                None,
+                "localhost",
+                &[(3, 1, 1, spki_sha256), (3, 1, 1, LEAF_SPKI_SHA256)]
+            ),
+            rustls_result::Ok
+        );
+        // A DANE-EE record only ever matches the end-entity certificate.
+        assert_eq!(
+            dane_handshake(
+                chain,
+                key,
+                None,
+                "localhost",
+                &[(3, 1, 1, INTERMEDIATE_SPKI_SHA256)]
+            ),
+            rustls_result::TlsaMismatch
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_dane_ta() {
+        let chain = include_str!("../testdata/intermediate/cert.pem");
+        let key = include_str!("../testdata/intermediate/key.pem");
+        for record in [
+            (2, 1, 1, INTERMEDIATE_SPKI_SHA256),
+            (2, 0, 1, INTERMEDIATE_CERT_SHA256),
+        ] {
+            assert_eq!(
+                dane_handshake(chain, key, None, "localhost", &[record]),
+                rustls_result::Ok
+            );
+            // Unlike with DANE-EE, the server name is checked.
+            assert_eq!(
+                dane_handshake(chain, key, None, "example.com", &[record]),
+                rustls_result::CertNotValidForName
+            );
+        }
+        // The root isn't sent by the server, so it can't be the anchor.
+        assert_eq!(
+            dane_handshake(
+                chain,
+                key,
+                None,
+                "localhost",
+                &[(2, 1, 1, ROOT_SPKI_SHA256)]
+            ),
+            rustls_result::TlsaMismatch
+        );
+        // A certificate the server sends that the end-entity certificate
+        // doesn't chain to matches, but doesn't anchor the chain.
+        let unrelated = include_str!("../testdata/example.com/cert.pem");
+        let unrelated_spki: String = spki_hash(&pem_certs(unrelated)[0])
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let padded_chain = format!("{}{}", chain, unrelated);
+        assert_eq!(
+            dane_handshake(
+                &padded_chain,
+                key,
+                None,
+                "localhost",
+                &[(2, 1, 1, &unrelated_spki)]
+            ),
+            rustls_result::CertUnknownIssuer
+        );
+        assert_eq!(
+            dane_handshake(
+                &padded_chain,
+                key,
+                None,
+                "localhost",
+                &[
+                    (2, 1, 1, &unrelated_spki),
+                    (2, 1, 1, INTERMEDIATE_SPKI_SHA256)
+                ]
+            ),
+            rustls_result::Ok
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_dane_pkix() {
+        let chain = include_str!("../testdata/intermediate/cert.pem");
+        let key = include_str!("../testdata/intermediate/key.pem");
+        let root = include_str!("../testdata/intermediate/root.pem");
+        let wrong_root = include_str!("../testdata/minica.pem");
+        for record in [
+            (0, 1, 1, INTERMEDIATE_SPKI_SHA256),
+            (1, 1, 1, LEAF_SPKI_SHA256),
+            (1, 0, 2, LEAF_CERT_SHA512),
+        ] {
+            assert_eq!(
+                dane_handshake(chain, key, Some(root), "localhost", &[record]),
+                rustls_result::Ok
+            );
+            // PKIX records also need the usual verification to pass.
+            assert_eq!(
+                dane_handshake(chain, key, Some(wrong_root), "localhost", &[record]),
+                rustls_result::CertUnknownIssuer
+            );
+            assert_eq!(
+                dane_handshake(chain, key, None, "localhost", &[record]),
+                dane_handshake(chain, key, None, "localhost", &[])
+            );
+        }
+        // Trusted roots don't help a chain that matches no record.
+        assert_eq!(
+            dane_handshake(
+                chain,
+                key,
+                Some(root),
+                "localhost",
+                &[(1, 1, 1, INTERMEDIATE_SPKI_SHA256)]
+            ),
+            rustls_result::TlsaMismatch
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_dane_unusable_records() {
+        let chain = include_str!("../testdata/intermediate/cert.pem");
+        let key = include_str!("../testdata/intermediate/key.pem");
+        let root = include_str!("../testdata/intermediate/root.pem");
+        let unusable = [
+            (4, 1, 1, LEAF_SPKI_SHA256),
+            (3, 2, 1, LEAF_SPKI_SHA256),
+            (3, 1, 3, LEAF_SPKI_SHA256),
+            (3, 1, 2, LEAF_SPKI_SHA256),
+        ];
+        // With no usable records, verification goes on as usual.
+        assert_eq!(
+            dane_handshake(chain, key, Some(root), "localhost", &unusable),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            dane_handshake(chain, key, Some(root), "example.com", &unusable),
+            rustls_result::CertNotValidForName
+        );
+        // Unusable records are ignored next to usable ones.
+        let mut records = unusable.to_vec();
+        records.push((2, 1, 1, INTERMEDIATE_SPKI_SHA256));
+        assert_eq!(
+            dane_handshake(chain, key, None, "localhost", &records),
+            rustls_result::Ok
+        );
+        records.pop();
+        records.push((2, 1, 1, ROOT_SPKI_SHA256));
+        assert_eq!(
+            dane_handshake(chain, key, Some(root), "localhost", &records),
+            rustls_result::TlsaMismatch
+        );
+    }
+
+    #[test]
+    fn test_set_tlsa_records_null() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_set_tlsa_records(
+                builder,
+                null(),
+                1,
+            ),
+            rustls_result::NullParameter
+        );
+        let mut data = rustls_slice_bytes::default();
+        data.data = null();
+        data.len = 32;
+        let record = rustls_tlsa_record {
+            usage: 3,
+            selector: 1,
+            matching_type: 1,
+            data,
+        };
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_set_tlsa_records(
+                builder, &record, 1,
+            ),
+            rustls_result::NullParameter
+        );
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_set_tlsa_records(
+                builder, &record, 0,
+            ),
+            rustls_result::Ok
+        );
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+    }
+
+    fn verified_chain(conn: *const rustls_connection) -> Result<Vec<Vec<u8>>, rustls_result> {
+        let mut count = 0;
+        let result =
+            crate::client::rustls_client_connection_get_verified_chain_count(conn, &mut count);
+        if result != rustls_result::Ok {
+            return Err(result);
+        }
+        let mut chain = vec![];
+        for i in 0..count {
+            let mut der: *const u8 = null();
+            let mut len = 0;
+            let result = crate::client::rustls_client_connection_get_verified_chain(
+                conn, i, &mut der, &mut len,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            chain.push(unsafe { std::slice::from_raw_parts(der, len) }.to_vec());
+        }
+        let mut der: *const u8 = null();
+        let mut len = 0;
+        let result = crate::client::rustls_client_connection_get_verified_chain(
+            conn, count, &mut der, &mut len,
+        );
+        assert_eq!(result, rustls_result::NotFound);
+        Ok(chain)
+    }
+
+    // Connect to a server presenting `chain_pem` with `client_config`,
+    // returning the client's verified chain.
+    fn handshake_verified_chain(
+        client_config: *const rustls_client_config,
+        chain_pem: &str,
+        key_pem: &str,
+    ) -> Result<Vec<Vec<u8>>, rustls_result> {
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            chain_pem,
+            key_pem,
+        );
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(
+            verified_chain(client),
+            Err(rustls_result::HandshakeNotComplete)
+        );
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(verified_chain(server), Err(rustls_result::InvalidParameter));
+        let chain = verified_chain(client);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        chain
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_verified_chain() {
+        // The chain file sends the upper intermediate before the lower one
+        // that issued the leaf; an unrelated certificate goes on the end.
+        let chain = include_str!("../testdata/two-intermediates/chain.pem");
+        let key = include_str!("../testdata/two-intermediates/key.pem");
+        let root = include_str!("../testdata/two-intermediates/root.pem");
+        let unrelated = include_str!("../testdata/example.com/cert.pem");
+        let sent = format!("{}{}", chain, unrelated);
+        let sent_der = pem_certs(&sent);
+        let (leaf, upper, lower) = (&sent_der[0], &sent_der[1], &sent_der[2]);
+        // The root's subject Name, CN=rustls-ffi test root 2.
+        let mut root_subject = vec![
+            0x30, 0x21, 0x31, 0x1f, 0x30, 0x1d, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x16,
+        ];
+        root_subject.extend_from_slice(b"rustls-ffi test root 2");
+
+        let store = rustls_root_cert_store::rustls_root_cert_store_new();
+        let result = rustls_root_cert_store::rustls_root_cert_store_add_pem(
+            store,
+            root.as_ptr(),
+            root.len(),
+            true,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        rustls_client_config_builder::rustls_client_config_builder_use_roots(builder, store);
+        rustls_root_cert_store::rustls_root_cert_store_free(store);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        assert_eq!(
+            handshake_verified_chain(client_config, &sent, key),
+            Ok(vec![
+                leaf.clone(),
+                lower.clone(),
+                upper.clone(),
+                root_subject.clone()
+            ])
+        );
+        rustls_client_config::rustls_client_config_free(client_config);
+
+        // The roots from a file are recorded the same way.
+        let dir = std::env::temp_dir().join(format!("rustls-ffi-verified-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("root.pem");
+        std::fs::write(&path, root).unwrap();
+        let path = format!("{}\0", path.to_str().unwrap());
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_load_roots_from_file(
+                builder,
+                path.as_ptr() as *const c_char,
+            );
+        assert_eq!(result, rustls_result::Ok);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        assert_eq!(
+            handshake_verified_chain(client_config, chain, key),
+            Ok(vec![
+                leaf.clone(),
+                lower.clone(),
+                upper.clone(),
+                root_subject
+            ])
+        );
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    // The path through the roots is only worked out once it is asked for.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_verified_chain_lazy() {
+        let client_config = build_verifying_client_config(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let recorded = |conn: *mut rustls_connection| match &try_ref_from_ptr!(conn).verified_chain
+        {
+            Some(chain) => chain.path_known(),
+            None => panic!("no chain recorded"),
+        };
+        assert!(!recorded(client));
+        assert_eq!(verified_chain(client).map(|chain| chain.len()), Ok(2));
+        assert!(recorded(client));
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_verified_chain_dane_and_unavailable() {
+        let chain = include_str!("../testdata/intermediate/cert.pem");
+        let key = include_str!("../testdata/intermediate/key.pem");
+        let chain_der = pem_certs(chain);
+
+        // A DANE-TA anchor is a certificate the server sent.
+        let tlsa_config = |usage: u8, data: &str| {
+            let data = unhex(data);
+            let record = rustls_tlsa_record {
+                usage,
+                selector: 1,
+                matching_type: 1,
+                data: data.as_slice().into(),
+            };
+            let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+            let result =
+                rustls_client_config_builder::rustls_client_config_builder_set_tlsa_records(
+                    builder, &record, 1,
+                );
+            assert_eq!(result, rustls_result::Ok);
+            rustls_client_config_builder::rustls_client_config_builder_build(builder)
+        };
+        let client_config = tlsa_config(2, INTERMEDIATE_SPKI_SHA256);
+        assert_eq!(
+            handshake_verified_chain(client_config, chain, key),
+            Ok(chain_der.clone())
+        );
+        rustls_client_config::rustls_client_config_free(client_config);
+
+        // A DANE-EE record and a pinned certificate don't verify a chain.
+        let client_config = tlsa_config(3, LEAF_SPKI_SHA256);
+        assert_eq!(
+            handshake_verified_chain(client_config, chain, key),
+            Err(rustls_result::VerifiedChainUnavailable)
+        );
+        rustls_client_config::rustls_client_config_free(client_config);
+
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_pinned_cert_der(
+            builder,
+            chain_der[0].as_ptr(),
+            chain_der[0].len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        assert_eq!(
+            handshake_verified_chain(client_config, chain, key),
+            Err(rustls_result::VerifiedChainUnavailable)
+        );
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    // Connect as `server_name` to a server for example.com, trusting the test
+    // CA, optionally skipping hostname verification.
+    fn example_com_handshake(server_name: &str, skip_hostname: bool) -> rustls_result {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        if skip_hostname {
+            let result =
+                rustls_client_config_builder::rustls_client_config_builder_dangerous_skip_hostname_verification(
+                    builder,
+                );
+            assert_eq!(result, rustls_result::Ok);
+        }
+        let client_config = build_verifying_client_config(builder);
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            include_str!("../testdata/example.com/cert.pem"),
+            include_str!("../testdata/example.com/key.pem"),
+        );
+        let client = make_client_connection(client_config, server_name);
+        let server = make_server_connection(server_config);
+        let result = do_handshake(client, server);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+        result
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_skip_hostname_verification() {
+        assert_eq!(
+            example_com_handshake("example.com", false),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            example_com_handshake("localhost", false),
+            rustls_result::CertNotValidForName
+        );
+        assert_eq!(
+            example_com_handshake("127.0.0.1", false),
+            rustls_result::CertNotValidForName
+        );
+        assert_eq!(example_com_handshake("localhost", true), rustls_result::Ok);
+        assert_eq!(example_com_handshake("127.0.0.1", true), rustls_result::Ok);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_skip_hostname_verification_still_checks_chain() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        rustls_client_config_builder::rustls_client_config_builder_dangerous_skip_hostname_verification(
+            builder,
+        );
+        let client_config = build_verifying_client_config(builder);
+        let handshake = |cert_pem: &str, key_pem: &str| {
+            let server_config = build_server_config_with(
+                rustls_server_config_builder::rustls_server_config_builder_new(),
+                cert_pem,
+                key_pem,
+            );
+            let client = make_client_connection(client_config, "localhost");
+            let server = make_server_connection(server_config);
+            let result = do_handshake(client, server);
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+            rustls_server_config::rustls_server_config_free(server_config);
+            result
+        };
+        assert_eq!(
+            handshake(
+                include_str!("../testdata/expired/cert.pem"),
+                include_str!("../testdata/expired/key.pem"),
+            ),
+            rustls_result::CertExpired
+        );
+        // Issued by a CA the client doesn't trust.
+        assert_eq!(
+            handshake(
+                include_str!("../testdata/intermediate/cert.pem"),
+                include_str!("../testdata/intermediate/key.pem"),
+            ),
+            rustls_result::CertUnknownIssuer
+        );
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_verification_time() {
+        // Valid from 2023-01-01 until 2024-01-01.
+        let cert = include_str!("../testdata/expired/localhost-cert.pem");
+        let key = include_str!("../testdata/expired/localhost-key.pem");
+        // Set each of `times` in turn, then connect.
+        let handshake = |times: &[u64]| {
+            let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+            for &unix_seconds in times {
+                let result =
+                    rustls_client_config_builder::rustls_client_config_builder_set_verification_time(
+                        builder,
+                        unix_seconds,
+                    );
+                assert_eq!(result, rustls_result::Ok);
+            }
+            let client_config = build_verifying_client_config(builder);
+            let server_config = build_server_config_with(
+                rustls_server_config_builder::rustls_server_config_builder_new(),
+                cert,
+                key,
+            );
+            let client = make_client_connection(client_config, "localhost");
+            let server = make_server_connection(server_config);
+            let result = do_handshake(client, server);
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+            rustls_server_config::rustls_server_config_free(server_config);
+            rustls_client_config::rustls_client_config_free(client_config);
+            result
+        };
+        let mid_2023 = 1_688_169_600;
+        let mid_2022 = 1_656_633_600;
+        assert_eq!(handshake(&[]), rustls_result::CertExpired);
+        assert_eq!(handshake(&[mid_2023]), rustls_result::Ok);
+        assert_eq!(handshake(&[mid_2022]), rustls_result::CertNotYetValid);
+        // 0 goes back to the system clock.
+        assert_eq!(handshake(&[0]), rustls_result::CertExpired);
+        assert_eq!(handshake(&[mid_2023, 0]), rustls_result::CertExpired);
+    }
+
+    // Create a client connection with separate SNI and verification names.
+    fn make_client_connection_verifying(
+        config: *const rustls_client_config,
+        sni_hostname: &str,
+        verify_hostname: Option<&str>,
+        conn_out: &mut *mut rustls_connection,
+    ) -> rustls_result {
+        let sni_hostname = format!("{}\0", sni_hostname);
+        let verify_hostname = verify_hostname.map(|name| format!("{}\0", name));
+        rustls_client_config::rustls_client_connection_new_with_verify_hostname(
+            config,
+            sni_hostname.as_ptr() as *const c_char,
+            verify_hostname
+                .as_ref()
+                .map_or(null(), |name| name.as_ptr() as *const c_char),
+            conn_out,
+        )
+    }
+
+    // Connect to a server for example.com, sending `sni_hostname` and
+    // verifying against `verify_hostname`. Returns the handshake result and
+    // the SNI name the server saw.
+    fn verify_hostname_handshake(
+        sni_hostname: &str,
+        verify_hostname: Option<&str>,
+    ) -> (rustls_result, String) {
+        let client_config = build_verifying_client_config(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            include_str!("../testdata/example.com/cert.pem"),
+            include_str!("../testdata/example.com/key.pem"),
+        );
+        let mut client = null_mut();
+        let result = make_client_connection_verifying(
+            client_config,
+            sni_hostname,
+            verify_hostname,
+            &mut client,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let server = make_server_connection(server_config);
+        let result = do_handshake(client, server);
+        let mut sni = [0u8; 64];
+        let mut n = 0;
+        let sni_result = crate::server::rustls_server_connection_get_server_name(
+            server,
+            sni.as_mut_ptr(),
+            sni.len(),
+            &mut n,
+        );
+        assert_eq!(sni_result, rustls_result::Ok);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+        (result, String::from_utf8(sni[..n].to_vec()).unwrap())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_normalized_hostnames() {
+        assert_eq!(
+            verify_hostname_handshake("example.com.", None),
+            (rustls_result::Ok, "example.com".to_string())
+        );
+        assert_eq!(
+            verify_hostname_handshake("Example.COM", None),
+            (rustls_result::Ok, "example.com".to_string())
+        );
+        assert_eq!(
+            verify_hostname_handshake("B\u{fc}cher.example.", Some("example.com")),
+            (rustls_result::Ok, "xn--bcher-kva.example".to_string())
+        );
+        assert_eq!(
+            verify_hostname_handshake("B\u{fc}cher.example", None),
+            (
+                rustls_result::CertNotValidForName,
+                "xn--bcher-kva.example".to_string()
+            )
+        );
+
+        // Verifiers see the converted name too.
+        let client_config = recording_client_config();
+        let mut log = VerifierLog::default();
+        let client = make_client_connection(client_config, "b\u{fc}cher.example");
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let server = make_server_connection(server_config);
+        rustls_connection::rustls_connection_set_userdata(
+            client,
+            &mut log as *mut _ as *mut c_void,
+        );
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(log.server_names, vec!["xn--bcher-kva.example".to_string()]);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_verify_hostname() {
+        assert_eq!(
+            verify_hostname_handshake("localhost", Some("example.com")),
+            (rustls_result::Ok, "localhost".to_string())
+        );
+        assert_eq!(
+            verify_hostname_handshake("localhost", None),
+            (rustls_result::CertNotValidForName, "localhost".to_string())
+        );
+        assert_eq!(
+            verify_hostname_handshake("example.com", Some("localhost")),
+            (
+                rustls_result::CertNotValidForName,
+                "example.com".to_string()
+            )
+        );
+        assert_eq!(
+            verify_hostname_handshake("example.com", Some("example.com")),
+            (rustls_result::Ok, "example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_verify_hostname_rejects_invalid_names() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let mut conn = null_mut();
+        for (sni, verify) in [("localhost", "not a name"), ("not a name", "localhost")] {
+            let result =
+                make_client_connection_verifying(client_config, sni, Some(verify), &mut conn);
+            assert_eq!(result, rustls_result::InvalidDnsNameError);
+            assert!(conn.is_null());
+        }
+        let result = rustls_client_config::rustls_client_connection_new_with_verify_hostname(
+            client_config,
+            null(),
+            null(),
+            &mut conn,
+        );
+        assert_eq!(result, rustls_result::NullParameter);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_custom_verifier_sees_verify_hostname() {
+        let client_config = recording_client_config();
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let mut log = VerifierLog::default();
+        let mut client = null_mut();
+        let result = make_client_connection_verifying(
+            client_config,
+            "localhost",
+            Some("origin.example"),
+            &mut client,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let server = make_server_connection(server_config);
+        rustls_connection::rustls_connection_set_userdata(
+            client,
+            &mut log as *mut _ as *mut c_void,
+        );
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(log.server_names, vec!["origin.example".to_string()]);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_verify_hostname_resumption() {
+        let client_config = recording_client_config();
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let mut log = VerifierLog::default();
+        // The second connection resumes the first one's session; the other
+        // names have none of their own until the last.
+        for (verify_hostname, resumed) in [
+            (Some("origin.example"), false),
+            (Some("origin.example"), true),
+            (None, false),
+            (Some("other.example"), false),
+            (None, true),
+        ] {
+            let mut client = null_mut();
+            let result = make_client_connection_verifying(
+                client_config,
+                "localhost",
+                verify_hostname,
+                &mut client,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            let server = make_server_connection(server_config);
+            rustls_connection::rustls_connection_set_userdata(
+                client,
+                &mut log as *mut _ as *mut c_void,
+            );
+            assert_eq!(do_handshake(client, server), rustls_result::Ok);
+            assert_eq!(was_resumed(client), (rustls_result::Ok, resumed));
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+        }
+        assert_eq!(
+            log.server_names,
+            vec!["origin.example", "localhost", "other.example"]
+        );
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    // Connect twice to the same server with a client built from `builder`
+    // and the recording verifier, returning how often the verifier ran.
+    fn verifications_for_two_connections(builder: *mut rustls_client_config_builder) -> usize {
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_dangerous_set_certificate_verifier(
+                builder,
+                Some(recording_verifier),
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let mut log = VerifierLog::default();
+        for _ in 0..2 {
+            assert_eq!(
+                handshake_with_server(client_config, server_config, &mut log),
+                rustls_result::Ok
+            );
+        }
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+        log.server_names.len()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_disable_resumption() {
+        // A resumed session skips certificate verification.
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        assert_eq!(verifications_for_two_connections(builder), 1);
+
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_disable_resumption(builder);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(verifications_for_two_connections(builder), 2);
+
+        // Options set afterwards, including the verifier, keep resumption
+        // disabled.
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        rustls_client_config_builder::rustls_client_config_builder_disable_resumption(builder);
+        let suites = [0x1301];
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites(
+            builder,
+            suites.as_ptr(),
+            suites.len(),
+            null_mut(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        rustls_client_config_builder::rustls_client_config_builder_set_enable_sni(builder, false);
+        assert_eq!(verifications_for_two_connections(builder), 2);
+    }
+
+    // Connect to `server_names` in turn with a session cache of
+    // `cache_size` entries, returning the names of the servers whose
+    // certificates were verified, i.e. whose sessions were not resumed.
+    fn verified_with_session_cache(cache_size: usize, server_names: &[&str]) -> Vec<String> {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_set_session_cache_size(
+                builder, cache_size,
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_dangerous_set_certificate_verifier(
+                builder,
+                Some(recording_verifier),
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let mut log = VerifierLog::default();
+        for name in server_names {
+            let client = make_client_connection(client_config, name);
+            let server = make_server_connection(server_config);
+            rustls_connection::rustls_connection_set_userdata(
+                client,
+                &mut log as *mut _ as *mut c_void,
+            );
+            assert_eq!(do_handshake(client, server), rustls_result::Ok);
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+        }
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+        log.server_names
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_session_cache_size() {
+        let (a, b) = ("localhost", "example.com");
+        assert_eq!(verified_with_session_cache(1, &[a, a, a]), vec![a]);
+        // A second server pushes the first out of a single entry cache.
+        assert_eq!(verified_with_session_cache(1, &[a, b, a, a]), vec![a, b, a]);
+        assert_eq!(verified_with_session_cache(2, &[a, b, a, b]), vec![a, b]);
+        assert_eq!(verified_with_session_cache(0, &[a, a]), vec![a, a]);
+        assert_eq!(
+            verified_with_session_cache(usize::MAX, &[a, b, a]),
+            vec![a, b]
+        );
+    }
+
+    // Send the client's first flight to `server` and finish the handshake,
+    // returning its result and the ClientHello record.
+    fn handshake_capturing_hello(
+        client: *mut rustls_connection,
+        server: *mut rustls_connection,
+    ) -> (rustls_result, Vec<u8>) {
+        let mut buf = VecDeque::<u8>::new();
+        let mut n: usize = 0;
+        while rustls_connection::rustls_connection_wants_write(client) {
+            let result = rustls_connection::rustls_connection_write_tls(
+                client,
+                Some(vecdeque_write),
+                &mut buf as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        let hello: Vec<u8> = buf.iter().copied().collect();
+        while !buf.is_empty() {
+            let result = rustls_connection::rustls_connection_read_tls(
+                server,
+                Some(vecdeque_read),
+                &mut buf as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+            let result = rustls_connection::rustls_connection_process_new_packets(server);
+            if result != rustls_result::Ok {
+                return (result, hello);
+            }
+        }
+        (do_handshake(client, server), hello)
+    }
+
+    // The extension types in a ClientHello record, in order.
+    fn client_hello_extensions(record: &[u8]) -> Vec<u16> {
+        let u16_at = |i: usize| u16::from_be_bytes([record[i], record[i + 1]]) as usize;
+        // Record header, handshake header, version and random.
+        let mut i = 5 + 4 + 2 + 32;
+        // Session ID.
+        i += 1 + record[i] as usize;
+        // Cipher suites.
+        i += 2 + u16_at(i);
+        // Compression methods.
+        i += 1 + record[i] as usize;
+        let end = i + 2 + u16_at(i);
+        i += 2;
+        let mut extensions = vec![];
+        while i < end {
+            extensions.push(u16_at(i) as u16);
+            i += 4 + u16_at(i + 2);
+        }
+        extensions
+    }
+
+    // The localhost certificate chain and key, for building rustls server
+    // configs directly.
+    fn localhost_chain_and_key() -> (Vec<rustls::Certificate>, rustls::PrivateKey) {
+        let certs =
+            rustls_pemfile::certs(&mut include_bytes!("../testdata/localhost/cert.pem").as_ref())
+                .unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(
+            &mut include_bytes!("../testdata/localhost/key-pkcs8.pem").as_ref(),
+        )
+        .unwrap();
+        (
+            certs.into_iter().map(rustls::Certificate).collect(),
+            rustls::PrivateKey(key[0].clone()),
+        )
+    }
+
+    // A TLS 1.2-only server for localhost that issues session tickets and
+    // keeps a session ID cache.
+    fn ticketing_tls12_server_config() -> *const rustls_server_config {
+        let config = versioned_server_config(&[rustls_tls_version::Tlsv1_2 as u16]);
+        modified_server_config(config, |config| {
+            config.ticketer = rustls::Ticketer::new().unwrap();
+        })
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_enable_tickets() {
+        const SESSION_TICKET: u16 = 35;
+        // Connect twice, returning both ClientHellos' extensions and how
+        // many times the server's certificate was verified.
+        let connect_twice = |enable_tickets: bool| {
+            let suites = all_ciphersuites();
+            let tls12 = [rustls_tls_version::Tlsv1_2 as u16];
+            let mut builder = null_mut();
+            let result = rustls_client_config_builder::rustls_client_config_builder_new_custom(
+                suites.as_ptr(),
+                suites.len(),
+                tls12.as_ptr(),
+                tls12.len(),
+                &mut builder,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            rustls_client_config_builder::rustls_client_config_builder_set_enable_tickets(
+                builder,
+                enable_tickets,
+            );
+            let result =
+                rustls_client_config_builder::rustls_client_config_builder_dangerous_set_certificate_verifier(
+                    builder,
+                    Some(recording_verifier),
+                );
+            assert_eq!(result, rustls_result::Ok);
+            let client_config =
+                rustls_client_config_builder::rustls_client_config_builder_build(builder);
+            let server_config = ticketing_tls12_server_config();
+            let mut log = VerifierLog::default();
+            let mut extensions = vec![];
+            for _ in 0..2 {
+                let client = make_client_connection(client_config, "localhost");
+                let server = make_server_connection(server_config);
+                rustls_connection::rustls_connection_set_userdata(
+                    client,
+                    &mut log as *mut _ as *mut c_void,
+                );
+                let (result, hello) = handshake_capturing_hello(client, server);
+                assert_eq!(result, rustls_result::Ok);
+                assert_eq!(
+                    rustls_connection::rustls_connection_get_protocol_version(client),
+                    rustls_tls_version::Tlsv1_2 as u16
+                );
+                extensions.push(client_hello_extensions(&hello));
+                rustls_connection::rustls_connection_free(client);
+                rustls_connection::rustls_connection_free(server);
+            }
+            rustls_server_config::rustls_server_config_free(server_config);
+            rustls_client_config::rustls_client_config_free(client_config);
+            (extensions, log.server_names.len())
+        };
+
+        let (extensions, verified) = connect_twice(true);
+        assert!(extensions[0].contains(&SESSION_TICKET));
+        assert!(extensions[1].contains(&SESSION_TICKET));
+        assert_eq!(verified, 1);
+
+        // Without tickets the second connection resumes by session ID.
+        let (extensions, verified) = connect_twice(false);
+        assert!(!extensions[0].contains(&SESSION_TICKET));
+        assert!(!extensions[1].contains(&SESSION_TICKET));
+        assert_eq!(verified, 1);
+    }
+
+    // A TLS 1.3 server for localhost that accepts early data.
+    fn early_data_server_config() -> *const rustls_server_config {
+        let config = versioned_server_config(&[rustls_tls_version::Tlsv1_3 as u16]);
+        modified_server_config(config, |config| config.max_early_data_size = 1024)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_enable_early_data() {
+        const EARLY_DATA: u16 = 42;
+        // Connect twice, returning both ClientHellos' extensions.
+        let connect_twice = |enable_early_data: bool| {
+            let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+            rustls_client_config_builder::rustls_client_config_builder_set_enable_early_data(
+                builder,
+                enable_early_data,
+            );
+            let client_config = build_client_config(builder);
+            let server_config = early_data_server_config();
+            let mut extensions = vec![];
+            for _ in 0..2 {
+                let client = make_client_connection(client_config, "localhost");
+                let server = make_server_connection(server_config);
+                let (result, hello) = handshake_capturing_hello(client, server);
+                assert_eq!(result, rustls_result::Ok);
+                extensions.push(client_hello_extensions(&hello));
+                rustls_connection::rustls_connection_free(client);
+                rustls_connection::rustls_connection_free(server);
+            }
+            rustls_server_config::rustls_server_config_free(server_config);
+            rustls_client_config::rustls_client_config_free(client_config);
+            extensions
+        };
+
+        // Early data is only offered when resuming.
+        let extensions = connect_twice(true);
+        assert!(!extensions[0].contains(&EARLY_DATA));
+        assert!(extensions[1].contains(&EARLY_DATA));
+
+        let extensions = connect_twice(false);
+        assert!(!extensions[0].contains(&EARLY_DATA));
+        assert!(!extensions[1].contains(&EARLY_DATA));
+    }
+
+    // Write `data` as early data on `client`, returning the result.
+    fn write_early_data(client: *mut rustls_connection, data: &[u8]) -> rustls_result {
+        let mut n = 0;
+        let result = crate::client::rustls_client_connection_write_early_data(
+            client,
+            data.as_ptr(),
+            data.len(),
+            &mut n,
+        );
+        if result == rustls_result::Ok {
+            assert_eq!(n, data.len());
+        }
+        result
+    }
+
+    fn early_data_accepted(client: *const rustls_connection) -> bool {
+        let mut accepted = true;
+        let result =
+            crate::client::rustls_client_connection_is_early_data_accepted(client, &mut accepted);
+        assert_eq!(result, rustls_result::Ok);
+        accepted
+    }
+
+    // The early data `server` received.
+    fn server_early_data(server: *mut rustls_connection) -> Option<Vec<u8>> {
+        let server: &mut Connection = crate::try_from_mut(server).unwrap();
+        let mut early_data = server.as_server_mut().unwrap().early_data()?;
+        let mut received = vec![];
+        early_data.read_to_end(&mut received).unwrap();
+        Some(received)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_write_early_data() {
+        let message = b"GET / HTTP/1.1\r\n\r\n";
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        rustls_client_config_builder::rustls_client_config_builder_set_enable_early_data(
+            builder, true,
+        );
+        let client_config = build_client_config(builder);
+        let server_config = early_data_server_config();
+
+        // There is no session to resume yet.
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(
+            write_early_data(client, message),
+            rustls_result::EarlyDataUnavailable
+        );
+        assert_eq!(
+            write_early_data(server, message),
+            rustls_result::InvalidParameter
+        );
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert!(!early_data_accepted(client));
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        // Resuming, the server accepts the early data.
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(write_early_data(client, message), rustls_result::Ok);
+        assert!(!early_data_accepted(client));
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert!(early_data_accepted(client));
+        assert_eq!(server_early_data(server), Some(message.to_vec()));
+        // Too late for more.
+        assert_eq!(
+            write_early_data(client, message),
+            rustls_result::EarlyDataUnavailable
+        );
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        // A server that has forgotten the session rejects the early data,
+        // so it has to be written again.
+        let other_server_config = early_data_server_config();
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(other_server_config);
+        assert_eq!(write_early_data(client, message), rustls_result::Ok);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert!(!early_data_accepted(client));
+        assert_eq!(server_early_data(server), None);
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write(
+            client,
+            message.as_ptr(),
+            message.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        let mut received = [0u8; 64];
+        let result = rustls_connection::rustls_connection_read(
+            server,
+            received.as_mut_ptr(),
+            received.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(&received[..n], &message[..]);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        rustls_server_config::rustls_server_config_free(other_server_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_max_fragment_size() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        for size in [1, 31, 16390, usize::MAX] {
+            assert_eq!(
+                rustls_client_config_builder::rustls_client_config_builder_set_max_fragment_size(
+                    builder, size,
+                ),
+                rustls_result::InvalidParameter
+            );
+        }
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_set_max_fragment_size(
+                builder, 1200,
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config = build_client_config(builder);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        let message = vec![0x42u8; 10 * 1024];
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write(
+            client,
+            message.as_ptr(),
+            message.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(n, message.len());
+        let mut buf = VecDeque::<u8>::new();
+        while rustls_connection::rustls_connection_wants_write(client) {
+            let result = rustls_connection::rustls_connection_write_tls(
+                client,
+                Some(vecdeque_write),
+                &mut buf as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        // Each record gains a content type byte and a 16-byte tag when
+        // encrypted with TLS 1.3.
+        let tls = Vec::from(buf);
+        let mut records = 0;
+        let mut i = 0;
+        while i < tls.len() {
+            let len = 5 + u16::from_be_bytes([tls[i + 3], tls[i + 4]]) as usize;
+            assert!(len <= 1200 + 17, "record of {} bytes", len);
+            i += len;
+            records += 1;
+        }
+        assert_eq!(i, tls.len());
+        assert!(records >= 9);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    // Do a handshake with a client config built from `builder`.
+    fn handshake_with_builder(builder: *mut rustls_client_config_builder) -> rustls_result {
+        let client_config = build_client_config(builder);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let result = do_handshake(client, server);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_client_config::rustls_client_config_free(client_config);
+        result
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_keylog_file() {
+        let dir = std::env::temp_dir().join(format!("rustls-ffi-keylog-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let env_path = dir.join("env.log");
+        let explicit_path = dir.join("explicit.log");
+
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        std::env::remove_var("SSLKEYLOGFILE");
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_enable_keylog_file(builder),
+            rustls_result::EnvironmentNotSet
+        );
+        std::env::set_var("SSLKEYLOGFILE", dir.join("missing").join("key.log"));
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_enable_keylog_file(builder),
+            rustls_result::Io
+        );
+        std::env::set_var("SSLKEYLOGFILE", &env_path);
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_enable_keylog_file(builder),
+            rustls_result::Ok
+        );
+        std::env::remove_var("SSLKEYLOGFILE");
+        assert_eq!(handshake_with_builder(builder), rustls_result::Ok);
+
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let filename = format!("{}\0", explicit_path.to_str().unwrap());
+        assert_eq!(
+            rustls_client_config_builder::rustls_client_config_builder_set_keylog_file(
+                builder,
+                filename.as_ptr() as *const c_char,
+            ),
+            rustls_result::Ok
+        );
+        assert_eq!(handshake_with_builder(builder), rustls_result::Ok);
+
+        for path in [&env_path, &explicit_path] {
+            let log = std::fs::read_to_string(path).unwrap();
+            let labels: Vec<&str> = log
+                .lines()
+                .map(|line| line.split(' ').next().unwrap())
+                .collect();
+            assert!(labels.contains(&"CLIENT_HANDSHAKE_TRAFFIC_SECRET"));
+            assert!(labels.contains(&"CLIENT_TRAFFIC_SECRET_0"));
+            for line in log.lines() {
+                let fields: Vec<&str> = line.split(' ').collect();
+                assert_eq!(fields.len(), 3);
+                // The client random is 32 bytes.
+                assert_eq!(fields[1].len(), 64);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Record the label of each secret logged, passing a `Vec<String>` as
+    // userdata.
+    unsafe extern "C" fn record_keylog_label(
+        userdata: *mut c_void,
+        label: crate::rslice::rustls_str,
+        client_random: *const u8,
+        client_random_len: size_t,
+        _secret: *const u8,
+        secret_len: size_t,
+    ) {
+        let labels = &mut *(userdata as *mut Vec<String>);
+        let label = slice::from_raw_parts(label.data as *const u8, label.len);
+        assert_eq!(
+            slice::from_raw_parts(client_random, client_random_len).len(),
+            32
+        );
+        assert!(secret_len > 0);
+        labels.push(String::from_utf8(label.to_vec()).unwrap());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_keylog_callback() {
+        let mut labels: Vec<String> = vec![];
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_keylog_callback(
+            builder,
+            Some(record_keylog_label),
+            &mut labels as *mut _ as *mut c_void,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(handshake_with_builder(builder), rustls_result::Ok);
+        labels.sort();
+        assert_eq!(
+            labels,
+            vec![
+                "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+                "CLIENT_TRAFFIC_SECRET_0",
+                "EXPORTER_SECRET",
+                "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+                "SERVER_TRAFFIC_SECRET_0",
+            ]
+        );
+
+        // Removing the callback again logs nothing.
+        labels.clear();
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        rustls_client_config_builder::rustls_client_config_builder_set_keylog_callback(
+            builder,
+            Some(record_keylog_label),
+            &mut labels as *mut _ as *mut c_void,
+        );
+        rustls_client_config_builder::rustls_client_config_builder_set_keylog_callback(
+            builder,
+            None,
+            null_mut(),
+        );
+        assert_eq!(handshake_with_builder(builder), rustls_result::Ok);
+        assert!(labels.is_empty());
+    }
+
+    // A CT log with a fresh ECDSA P-256 key. Returns the key and the log's
+    // SubjectPublicKeyInfo.
+    fn make_ct_log() -> (ring::signature::EcdsaKeyPair, Vec<u8>) {
+        use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+        const P256_SPKI_PREFIX: &[u8] = &[
+            0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06,
+            0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+        ];
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+        let mut spki = P256_SPKI_PREFIX.to_vec();
+        spki.extend_from_slice(key.public_key().as_ref());
+        (key, spki)
+    }
+
+    // An SCT for `cert`, signed by the log with `key` and `spki`.
+    fn make_sct(
+        key: &ring::signature::EcdsaKeyPair,
+        spki: &[u8],
+        cert: &[u8],
+        timestamp_ms: u64,
+    ) -> Vec<u8> {
+        let mut signed = vec![0, 0];
+        signed.extend_from_slice(&timestamp_ms.to_be_bytes());
+        signed.extend_from_slice(&[0, 0]);
+        signed.extend_from_slice(&(cert.len() as u32).to_be_bytes()[1..]);
+        signed.extend_from_slice(cert);
+        signed.extend_from_slice(&[0, 0]);
+        let rng = ring::rand::SystemRandom::new();
+        let signature = key.sign(&rng, &signed).unwrap();
+
+        let mut sct = vec![0];
+        sct.extend_from_slice(ring::digest::digest(&ring::digest::SHA256, spki).as_ref());
+        sct.extend_from_slice(&timestamp_ms.to_be_bytes());
+        sct.extend_from_slice(&[0, 0, 0x04, 0x03]);
+        sct.extend_from_slice(&(signature.as_ref().len() as u16).to_be_bytes());
+        sct.extend_from_slice(signature.as_ref());
+        sct
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_ct_logs() {
+        let (log_key, log_spki) = make_ct_log();
+        let (other_log_key, other_log_spki) = make_ct_log();
+        let (chain, key) = localhost_chain_and_key();
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let next_year = now_ms / 1000 + 365 * 24 * 60 * 60;
+        let a_year_ago = now_ms / 1000 - 365 * 24 * 60 * 60;
+
+        // Connect to a server sending `scts`, trusting `logs` until `deadline`.
+        let handshake = |scts: &[&Vec<u8>], logs: &[&[u8]], deadline: u64| {
+            let mut sct_list = vec![];
+            for sct in scts {
+                sct_list.extend_from_slice(&(sct.len() as u16).to_be_bytes());
+                sct_list.extend_from_slice(sct);
+            }
+            // An empty list is malformed; send no SCT extension instead.
+            let mut encoded = vec![];
+            if !scts.is_empty() {
+                encoded.extend_from_slice(&(sct_list.len() as u16).to_be_bytes());
+                encoded.extend_from_slice(&sct_list);
+            }
+            let server_config = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert_with_ocsp_and_sct(chain.clone(), key.clone(), vec![], encoded)
+                .unwrap();
+            let server_config = rustls_server_config::to_const_ptr(server_config);
+
+            let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+            let logs: Vec<rustls_slice_bytes> = logs.iter().map(|&log| log.into()).collect();
+            let result = rustls_client_config_builder::rustls_client_config_builder_set_ct_logs(
+                builder,
+                logs.as_ptr(),
+                logs.len(),
+                deadline,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            let client_config = build_verifying_client_config(builder);
+            let client = make_client_connection(client_config, "localhost");
+            let server = make_server_connection(server_config);
+            let result = do_handshake(client, server);
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+            rustls_server_config::rustls_server_config_free(server_config);
+            rustls_client_config::rustls_client_config_free(client_config);
+            result
+        };
+
+        let leaf = &chain[0].0;
+        let good = make_sct(&log_key, &log_spki, leaf, now_ms - 1000);
+        let from_other_log = make_sct(&other_log_key, &other_log_spki, leaf, now_ms - 1000);
+        let future = make_sct(&log_key, &log_spki, leaf, now_ms + 3_600_000);
+        let mut bad_signature = good.clone();
+        *bad_signature.last_mut().unwrap() ^= 1;
+        let logs: &[&[u8]] = &[&log_spki];
+
+        assert_eq!(handshake(&[&good], logs, next_year), rustls_result::Ok);
+        assert_eq!(
+            handshake(&[&from_other_log, &good], logs, next_year),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            handshake(&[&from_other_log], logs, next_year),
+            rustls_result::CertSCTUnknownLog
+        );
+        assert_eq!(
+            handshake(&[&from_other_log], &[&log_spki, &other_log_spki], next_year),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            handshake(&[&bad_signature], logs, next_year),
+            rustls_result::CertSCTInvalidSignature
+        );
+        assert_eq!(
+            handshake(&[&future], logs, next_year),
+            rustls_result::CertSCTTimestampInFuture
+        );
+        // Once the logs have expired, CT can't be enforced, so verification
+        // fails whatever the server sends rather than silently passing.
+        for scts in [&[&good][..], &[&bad_signature], &[]] {
+            let result = handshake(scts, logs, a_year_ago);
+            assert_eq!(result, rustls_result::CtLogsExpired);
+            assert!(rustls_result::rustls_result_is_cert_error(result as u32));
+        }
+    }
+
+    #[test]
+    fn test_ct_logs_rejects_bad_keys() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let logs: Vec<rustls_slice_bytes> = vec![b"not a key".as_ref().into()];
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_ct_logs(
+            builder,
+            logs.as_ptr(),
+            logs.len(),
+            u64::MAX,
+        );
+        assert_eq!(result, rustls_result::InvalidParameter);
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_ct_logs(
+            builder,
+            logs.as_ptr(),
+            0,
+            0,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        rustls_client_config_builder::rustls_client_config_builder_free(builder);
+    }
+
+    fn config_userdata(conn: *const rustls_connection) -> *mut c_void {
+        let mut userdata: *mut c_void = null_mut();
+        let result =
+            crate::client::rustls_client_connection_get_config_userdata(conn, &mut userdata);
+        assert_eq!(result, rustls_result::Ok);
+        userdata
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_config_userdata() {
+        let mut first = 1u8;
+        let mut second = 2u8;
+        let configs: Vec<(*const rustls_client_config, *mut c_void)> = vec![
+            null_mut(),
+            &mut first as *mut u8 as *mut c_void,
+            &mut second as *mut u8 as *mut c_void,
+        ]
+        .into_iter()
+        .map(|userdata| {
+            let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+            if !userdata.is_null() {
+                rustls_client_config_builder::rustls_client_config_builder_set_userdata(
+                    builder, userdata,
+                );
+            }
+            (build_client_config(builder), userdata)
+        })
+        .collect();
+        for &(config, userdata) in &configs {
+            let conn = make_client_connection(config, "localhost");
+            assert_eq!(config_userdata(conn), userdata);
+            // The connection's own userdata is separate.
+            rustls_connection::rustls_connection_set_userdata(conn, null_mut());
+            assert_eq!(config_userdata(conn), userdata);
+            assert_eq!(
+                crate::client::rustls_client_connection_get_config_userdata(conn, null_mut()),
+                rustls_result::NullParameter
+            );
+            rustls_connection::rustls_connection_free(conn);
+            rustls_client_config::rustls_client_config_free(config);
+        }
+
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let server = make_server_connection(server_config);
+        let mut userdata: *mut c_void = null_mut();
+        assert_eq!(
+            crate::client::rustls_client_connection_get_config_userdata(server, &mut userdata),
+            rustls_result::InvalidParameter
+        );
+        rustls_connection::rustls_connection_free(server);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_config_userdata_passed_to_callbacks() {
+        let mut config_log = VerifierLog::default();
+        let mut conn_log = VerifierLog::default();
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        rustls_client_config_builder::rustls_client_config_builder_set_userdata(
+            builder,
+            &mut config_log as *mut _ as *mut c_void,
+        );
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_dangerous_set_certificate_verifier(
+                builder,
+                Some(recording_verifier),
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+
+        // Without userdata of its own, the connection passes the config's.
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        assert_eq!(config_log.server_names, vec!["localhost"]);
+
+        // The connection's own userdata takes precedence.
+        let server_config_2 =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        assert_eq!(
+            handshake_with_server(client_config, server_config_2, &mut conn_log),
+            rustls_result::Ok
+        );
+        assert_eq!(config_log.server_names.len(), 1);
+        assert_eq!(conn_log.server_names, vec!["localhost"]);
+        rustls_server_config::rustls_server_config_free(server_config);
+        rustls_server_config::rustls_server_config_free(server_config_2);
+        rustls_client_config::rustls_client_config_free(client_config);
+
+        // So does a key log callback's userdata; without any, the config's
+        // is used.
+        let mut config_labels: Vec<String> = vec![];
+        let mut callback_labels: Vec<String> = vec![];
+        for &callback_userdata in &[null_mut(), &mut callback_labels as *mut _ as *mut c_void] {
+            let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+            rustls_client_config_builder::rustls_client_config_builder_set_keylog_callback(
+                builder,
+                Some(record_keylog_label),
+                callback_userdata,
+            );
+            rustls_client_config_builder::rustls_client_config_builder_set_userdata(
+                builder,
+                &mut config_labels as *mut _ as *mut c_void,
+            );
+            assert_eq!(handshake_with_builder(builder), rustls_result::Ok);
+        }
+        assert_eq!(config_labels.len(), 5);
+        assert_eq!(callback_labels.len(), 5);
+    }
+
+    fn peer_certificate_count(conn: *const rustls_connection) -> usize {
+        let mut count = usize::MAX;
+        let result =
+            rustls_connection::rustls_connection_get_peer_certificate_count(conn, &mut count);
+        assert_eq!(result, rustls_result::Ok);
+        count
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_peer_certificate_der() {
+        // With TLS 1.2, the client has the server's certificate a round trip
+        // before the handshake is done.
+        let client_config = versioned_client_config(&[0x0303]);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(peer_certificate_count(client), 0);
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert!(rustls_connection::rustls_connection_is_handshaking(client));
+        assert_eq!(peer_certificate_count(client), 0);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        assert_eq!(peer_certificate_count(client), 1);
+        assert_eq!(peer_certificate_count(server), 0);
+        let mut der: *const u8 = null();
+        let mut len: usize = 0;
+        let result = rustls_connection::rustls_connection_get_peer_certificate_der(
+            client, 0, &mut der, &mut len,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let expected =
+            rustls_pemfile::certs(&mut include_bytes!("../testdata/localhost/cert.pem").as_ref())
+                .unwrap();
+        assert_eq!(unsafe { slice::from_raw_parts(der, len) }, &expected[0][..]);
+        let result = rustls_connection::rustls_connection_get_peer_certificate_der(
+            client, 1, &mut der, &mut len,
+        );
+        assert_eq!(result, rustls_result::NotFound);
+        let result = rustls_connection::rustls_connection_get_peer_certificate_der(
+            client,
+            0,
+            null_mut(),
+            &mut len,
+        );
+        assert_eq!(result, rustls_result::NullParameter);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn peer_chain_pem(conn: *const rustls_connection) -> Vec<u8> {
+        let mut buffer: *mut rustls_buffer = null_mut();
+        let result = rustls_connection::rustls_connection_get_peer_chain_pem(conn, &mut buffer);
+        assert_eq!(result, rustls_result::Ok);
+        let data = rustls_buffer::rustls_buffer_data(buffer);
+        let len = rustls_buffer::rustls_buffer_len(buffer);
+        let pem = unsafe { slice::from_raw_parts(data, len) }.to_vec();
+        rustls_buffer::rustls_buffer_free(buffer);
+        pem
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_peer_chain_pem() {
+        let chain = include_str!("../testdata/intermediate/cert.pem");
+        let key = include_str!("../testdata/intermediate/key.pem");
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            chain,
+            key,
+        );
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert!(peer_chain_pem(client).is_empty());
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // The chain comes back exactly as the OpenSSL-written fixture.
+        assert_eq!(peer_chain_pem(client), chain.as_bytes());
+        // The client sent no certificate.
+        assert!(peer_chain_pem(server).is_empty());
+
+        let result = rustls_connection::rustls_connection_get_peer_chain_pem(client, null_mut());
+        assert_eq!(result, rustls_result::NullParameter);
+        let mut buffer: *mut rustls_buffer = null_mut();
+        let result = rustls_connection::rustls_connection_get_peer_chain_pem(null(), &mut buffer);
+        assert_eq!(result, rustls_result::NullParameter);
+        assert!(buffer.is_null());
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Export 32 bytes labelled "EXPORTER-test" from `conn`.
+    fn export(conn: *const rustls_connection, context: Option<&[u8]>) -> [u8; 32] {
+        let label = b"EXPORTER-test";
+        let mut out = [0u8; 32];
+        let (context_ptr, context_len) = match context {
+            Some(c) => (c.as_ptr(), c.len()),
+            None => (null(), 0),
+        };
+        let result = rustls_connection::rustls_connection_export_keying_material(
+            conn,
+            out.as_mut_ptr(),
+            out.len(),
+            label.as_ptr(),
+            label.len(),
+            context_ptr,
+            context_len,
+            context.is_some(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        out
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_export_keying_material() {
+        for version in [0x0303, 0x0304] {
+            let client_config = versioned_client_config(&[version]);
+            let server_config = build_server_config(
+                rustls_server_config_builder::rustls_server_config_builder_new(),
+            );
+            let client = make_client_connection(client_config, "localhost");
+            let server = make_server_connection(server_config);
+
+            let label = b"EXPORTER-test";
+            let mut out = [0xaau8; 16];
+            let result = rustls_connection::rustls_connection_export_keying_material(
+                client,
+                out.as_mut_ptr(),
+                out.len(),
+                label.as_ptr(),
+                label.len(),
+                null(),
+                0,
+                false,
+            );
+            assert_eq!(result, rustls_result::HandshakeNotComplete);
+            assert_eq!(out, [0xaa; 16]);
+
+            assert_eq!(do_handshake(client, server), rustls_result::Ok);
+            let no_context = export(client, None);
+            assert_eq!(no_context, export(server, None));
+            let empty_context = export(client, Some(b""));
+            assert_eq!(empty_context, export(server, Some(b"")));
+            let context = export(client, Some(b"context"));
+            assert_eq!(context, export(server, Some(b"context")));
+            // RFC 8446 section 7.5 treats no context as an empty one.
+            if version == 0x0304 {
+                assert_eq!(no_context, empty_context);
+            } else {
+                assert_ne!(no_context, empty_context);
+            }
+            assert_ne!(no_context, context);
+            assert_ne!(empty_context, context);
+
+            // A context pointer is only required when has_context is true.
+            let result = rustls_connection::rustls_connection_export_keying_material(
+                client,
+                out.as_mut_ptr(),
+                out.len(),
+                label.as_ptr(),
+                label.len(),
+                null(),
+                0,
+                true,
+            );
+            assert_eq!(result, rustls_result::NullParameter);
+
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+            rustls_client_config::rustls_client_config_free(client_config);
+            rustls_server_config::rustls_server_config_free(server_config);
+        }
+    }
+
+    // Read plaintext from `conn` into `buf`, returning the result and count.
+    fn read_plaintext(conn: *mut rustls_connection, buf: &mut [u8]) -> (rustls_result, usize) {
+        let mut n = usize::MAX;
+        let result =
+            rustls_connection::rustls_connection_read(conn, buf.as_mut_ptr(), buf.len(), &mut n);
+        (result, n)
+    }
+
+    // Tell `conn` the transport has reached EOF.
+    fn read_tls_eof(conn: *mut rustls_connection) {
+        let mut empty = VecDeque::<u8>::new();
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_read_tls(
+            conn,
+            Some(vecdeque_read),
+            &mut empty as *mut _ as *mut _,
+            &mut n,
+        );
+        assert_eq!(result, rustls_io_result(0));
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_send_close_notify() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let mut buf = [0u8; 32];
+
+        // Without close_notify, EOF on the transport is a truncation.
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        read_tls_eof(server);
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(server),
+            rustls_result::Ok
+        );
+        assert_eq!(
+            read_plaintext(server, &mut buf).0,
+            rustls_result::UnexpectedEof
+        );
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        // With it, the server reads the remaining data and then a clean EOF.
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write(client, b"bye".as_ptr(), 3, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        rustls_connection::rustls_connection_send_close_notify(client);
+        assert!(rustls_connection::rustls_connection_wants_write(client));
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        // A second call doesn't queue another alert.
+        rustls_connection::rustls_connection_send_close_notify(client);
+        assert!(!rustls_connection::rustls_connection_wants_write(client));
+        read_tls_eof(server);
+        assert_eq!(read_plaintext(server, &mut buf), (rustls_result::Ok, 3));
+        assert_eq!(&buf[..3], b"bye");
+        assert_eq!(read_plaintext(server, &mut buf), (rustls_result::Ok, 0));
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        rustls_client_config::rustls_client_config_free(client_config);
+
+        // Mid-handshake, the alert is queued after the ClientHello. A TLS 1.2
+        // server reads it as a clean close. A TLS 1.3 server has switched to
+        // handshake keys by then, so the unprotected alert fails to decrypt.
+        for (version, expected) in [
+            (0x0303, rustls_result::Ok),
+            (0x0304, rustls_result::DecryptError),
+        ] {
+            let client_config = versioned_client_config(&[version]);
+            let client = make_client_connection(client_config, "localhost");
+            let server = make_server_connection(server_config);
+            rustls_connection::rustls_connection_send_close_notify(client);
+            assert_eq!(transfer(client, server), expected);
+            assert!(rustls_connection::rustls_connection_is_handshaking(server));
+            if expected == rustls_result::Ok {
+                assert_eq!(read_plaintext(server, &mut buf), (rustls_result::Ok, 0));
+            }
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+            rustls_client_config::rustls_client_config_free(client_config);
+        }
+
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Write as much of `data` as `conn` accepts, returning the count.
+    fn write_all_accepted(conn: *mut rustls_connection, data: &[u8]) -> usize {
+        let mut total = 0;
+        loop {
+            let mut n = usize::MAX;
+            let result = rustls_connection::rustls_connection_write(
+                conn,
+                data[total..].as_ptr(),
+                data.len() - total,
+                &mut n,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            if n == 0 {
+                return total;
+            }
+            total += n;
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_buffer_limit() {
+        const LIMIT: usize = 4096;
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        rustls_connection::rustls_connection_set_buffer_limit(client, LIMIT);
+        let data = vec![0x42u8; 1 << 20];
+
+        // Before the handshake, plaintext is buffered up to the limit.
+        let early = write_all_accepted(client, &data);
+        assert_eq!(early, LIMIT);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // Afterwards, the limit bounds the encrypted records waiting for
+        // write_tls. Sending them makes room to write more.
+        let mut sent = early;
+        let mut received = Vec::new();
+        while sent < data.len() {
+            let n = write_all_accepted(client, &data[sent..]);
+            assert!(n > 0);
+            assert!(n <= LIMIT);
+            sent += n;
+            assert_eq!(transfer(client, server), rustls_result::Ok);
+            drain_plaintext(server, &mut received);
+        }
+        assert_eq!(received, data);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Client and server share every plaintext path, so moving data both
+    // ways at once, under a buffer limit, exercises them identically.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_full_duplex() {
+        const LIMIT: usize = 16384;
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let mut buf = [0u8; 1024];
+        for conn in [client, server] {
+            rustls_connection::rustls_connection_set_buffer_limit(conn, LIMIT);
+            // No application data has arrived yet.
+            assert_eq!(
+                read_plaintext(conn, &mut buf).0,
+                rustls_result::PlaintextEmpty
+            );
+        }
+
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        let upload: Vec<u8> = (0..3 << 20).map(|_| rng.below(256) as u8).collect();
+        let download: Vec<u8> = (0..3 << 20).map(|_| rng.below(256) as u8).collect();
+        let (mut uploaded, mut downloaded) = (0, 0);
+        let (mut upload_received, mut download_received) = (Vec::new(), Vec::new());
+        while uploaded < upload.len() || downloaded < download.len() {
+            let n = write_all_accepted(client, &upload[uploaded..]);
+            assert!(n <= LIMIT);
+            uploaded += n;
+            let n = write_all_accepted(server, &download[downloaded..]);
+            assert!(n <= LIMIT);
+            downloaded += n;
+            assert_eq!(transfer(client, server), rustls_result::Ok);
+            assert_eq!(transfer(server, client), rustls_result::Ok);
+            drain_plaintext(server, &mut upload_received);
+            drain_plaintext(client, &mut download_received);
+        }
+        assert!(upload_received == upload);
+        assert!(download_received == download);
+
+        // close_notify ends each direction cleanly, the same way on both sides.
+        for (from, to) in [(client, server), (server, client)] {
+            rustls_connection::rustls_connection_send_close_notify(from);
+            assert_eq!(transfer(from, to), rustls_result::Ok);
+            assert_eq!(read_plaintext(to, &mut buf), (rustls_result::Ok, 0));
+        }
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    unsafe extern "C" fn count_handshake_done(userdata: *mut c_void, conn: *mut rustls_connection) {
+        assert!(!rustls_connection::rustls_connection_is_handshaking(conn));
+        *(userdata as *mut u32) += 1;
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_handshake_done_callback() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let mut client_calls = 0u32;
+        let mut server_calls = 0u32;
+        for (conn, calls) in [(client, &mut client_calls), (server, &mut server_calls)] {
+            let result = rustls_connection::rustls_connection_set_handshake_done_callback(
+                conn,
+                Some(count_handshake_done),
+                calls as *mut u32 as *mut c_void,
+            );
+            assert_eq!(result, rustls_result::Ok);
+        }
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        // The client finishes first, in the call that processes the server's
+        // flight.
+        assert_eq!((client_calls, server_calls), (1, 0));
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!((client_calls, server_calls), (1, 1));
+
+        // More traffic doesn't call the callbacks again.
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write(client, b"hi".as_ptr(), 2, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        assert_eq!((client_calls, server_calls), (1, 1));
+
+        // Registering once the handshake is done is an error.
+        let result = rustls_connection::rustls_connection_set_handshake_done_callback(
+            client,
+            Some(count_handshake_done),
+            &mut client_calls as *mut u32 as *mut c_void,
+        );
+        assert_eq!(result, rustls_result::HandshakeAlreadyComplete);
+        assert_eq!(client_calls, 1);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_handshake_done_callback_not_called_on_failure() {
+        let client_config = build_verifying_client_config(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            include_str!("../testdata/expired/cert.pem"),
+            include_str!("../testdata/expired/key.pem"),
+        );
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let mut calls = 0u32;
+        let result = rustls_connection::rustls_connection_set_handshake_done_callback(
+            client,
+            Some(count_handshake_done),
+            &mut calls as *mut u32 as *mut c_void,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(do_handshake(client, server), rustls_result::CertExpired);
+        assert_eq!(calls, 0);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn handshake_info(conn: *const rustls_connection) -> (rustls_result, rustls_handshake_info) {
+        let mut info = rustls_handshake_info {
+            version: 0,
+            ciphersuite: 0,
+            alpn: [0xff; 32],
+            alpn_len: 0,
+            resumed: true,
+        };
+        let result = rustls_client_connection_get_handshake_info(conn, &mut info);
+        (result, info)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_handshake_info() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let alpn = alpn_slices(&[b"h2", b"http/1.1"]);
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_alpn_protocols(
+            builder,
+            alpn.as_ptr(),
+            alpn.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let suites = [0x1301];
+        let result = rustls_client_config_builder::rustls_client_config_builder_set_ciphersuites(
+            builder,
+            suites.as_ptr(),
+            suites.len(),
+            null_mut(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config = build_client_config(builder);
+        let builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        let alpn = alpn_slices(&[b"h2"]);
+        let result = rustls_server_config_builder::rustls_server_config_builder_set_alpn_protocols(
+            builder,
+            alpn.as_ptr(),
+            alpn.len(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let server_config = build_server_config(builder);
+
+        for resumed in [false, true] {
+            let client = make_client_connection(client_config, "localhost");
+            let server = make_server_connection(server_config);
+            let (result, info) = handshake_info(client);
+            assert_eq!(result, rustls_result::HandshakeNotComplete);
+            assert_eq!(info.version, 0);
+            assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+            let (result, info) = handshake_info(client);
+            assert_eq!(result, rustls_result::Ok);
+            assert_eq!(info.version, 0x0304);
+            assert_eq!(info.ciphersuite, 0x1301);
+            assert_eq!(&info.alpn[..info.alpn_len], b"h2");
+            assert_eq!(info.alpn_len, 2);
+            assert_eq!(info.alpn[2..], [0; 30]);
+            assert_eq!(info.resumed, resumed);
+
+            assert_eq!(handshake_info(server).0, rustls_result::InvalidParameter);
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+        }
+
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Append all the plaintext `conn` has available to `out`.
+    fn drain_plaintext(conn: *mut rustls_connection, out: &mut Vec<u8>) {
+        let mut buf = [0u8; 16384];
+        loop {
+            match read_plaintext(conn, &mut buf) {
+                (rustls_result::Ok, n) => out.extend_from_slice(&buf[..n]),
+                (rustls_result::PlaintextEmpty, _) => return,
+                (result, _) => panic!("unexpected {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_write_all() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+
+        let mut n = usize::MAX;
+        let result =
+            rustls_connection::rustls_connection_write_all(client, data.as_ptr(), 10_000, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(n, 10_000);
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        let mut received = Vec::new();
+        drain_plaintext(server, &mut received);
+
+        // With a limit in place, the rest only goes in a piece at a time.
+        rustls_connection::rustls_connection_set_buffer_limit(client, 8192);
+        let mut sent = 10_000;
+        let mut short_writes = 0;
+        while sent < data.len() {
+            let result = rustls_connection::rustls_connection_write_all(
+                client,
+                data[sent..].as_ptr(),
+                data.len() - sent,
+                &mut n,
+            );
+            if result == rustls_result::BufferLimitReached {
+                assert!(n > 0);
+                assert!(n < data.len() - sent);
+                short_writes += 1;
+            } else {
+                assert_eq!(result, rustls_result::Ok);
+                assert_eq!(n, data.len() - sent);
+            }
+            sent += n;
+            assert_eq!(transfer(client, server), rustls_result::Ok);
+            drain_plaintext(server, &mut received);
+        }
+        assert!(short_writes > 0);
+
+        // Once the buffer is full, nothing more is accepted.
+        let result = rustls_connection::rustls_connection_write_all(
+            client,
+            data.as_ptr(),
+            data.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::BufferLimitReached);
+        let mut expected = data.clone();
+        expected.extend_from_slice(&data[..n]);
+        let result =
+            rustls_connection::rustls_connection_write_all(client, data.as_ptr(), 1, &mut n);
+        assert_eq!(result, rustls_result::BufferLimitReached);
+        assert_eq!(n, 0);
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        drain_plaintext(server, &mut received);
+        assert_eq!(received, expected);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_flush() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(
+            rustls_connection::rustls_connection_flush(null_mut()),
+            NullParameter
+        );
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert!(!rustls_connection::rustls_connection_wants_write(client));
+
+        let mut n = 0;
+        let result =
+            rustls_connection::rustls_connection_write(client, b"GET /".as_ptr(), 5, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(
+            rustls_connection::rustls_connection_flush(client),
+            rustls_result::Ok
+        );
+        assert!(rustls_connection::rustls_connection_wants_write(client));
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        let mut received = Vec::new();
+        drain_plaintext(server, &mut received);
+        assert_eq!(received, b"GET /");
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_eof_and_empty() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let mut buf = [0u8; 32];
+
+        // Nothing has arrived yet, before or after the handshake.
+        assert_eq!(
+            read_plaintext(client, &mut buf).0,
+            rustls_result::PlaintextEmpty
+        );
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(
+            read_plaintext(client, &mut buf).0,
+            rustls_result::PlaintextEmpty
+        );
+
+        // The server sends a response and closes.
+        let mut n = 0;
+        let result =
+            rustls_connection::rustls_connection_write(server, b"reply".as_ptr(), 5, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 5));
+        assert_eq!(&buf[..5], b"reply");
+        assert_eq!(
+            read_plaintext(client, &mut buf).0,
+            rustls_result::PlaintextEmpty
+        );
+        rustls_connection::rustls_connection_send_close_notify(server);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 0));
+        // End of stream is sticky.
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 0));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_read_after_server_close() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let mut buf = [0u8; 32];
+
+        // A clean shutdown is end of stream, even once the transport closes.
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        rustls_connection::rustls_connection_send_close_notify(server);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        read_tls_eof(client);
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(client),
+            rustls_result::Ok
+        );
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 0));
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        // A transport EOF with no close_notify is a truncation, not EOF.
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let mut n = 0;
+        let result =
+            rustls_connection::rustls_connection_write(server, b"partial".as_ptr(), 7, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        read_tls_eof(client);
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 7));
+        assert_eq!(
+            read_plaintext(client, &mut buf).0,
+            rustls_result::UnexpectedEof
+        );
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_connection_userdata() {
+        let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+        let result =
+            rustls_client_config_builder::rustls_client_config_builder_dangerous_set_certificate_verifier(
+                builder,
+                Some(recording_verifier),
+            );
+        assert_eq!(result, rustls_result::Ok);
+        let client_config =
+            rustls_client_config_builder::rustls_client_config_builder_build(builder);
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+
+        let mut logs = [VerifierLog::default(), VerifierLog::default()];
+        let mut done_calls = [0u32, 0u32];
+        let names = ["localhost", "example.com"];
+        let mut pairs = Vec::new();
+        for i in 0..2 {
+            let client = make_client_connection(client_config, names[i]);
+            let server = make_server_connection(server_config);
+            let mut userdata: *mut c_void = null_mut();
+            let result = rustls_connection::rustls_connection_get_userdata(client, &mut userdata);
+            assert_eq!(result, rustls_result::Ok);
+            assert!(userdata.is_null());
+            let log = &mut logs[i] as *mut VerifierLog as *mut c_void;
+            rustls_connection::rustls_connection_set_userdata(client, log);
+            let result = rustls_connection::rustls_connection_get_userdata(client, &mut userdata);
+            assert_eq!(result, rustls_result::Ok);
+            assert_eq!(userdata, log);
+            let result = rustls_connection::rustls_connection_set_handshake_done_callback(
+                client,
+                Some(count_handshake_done),
+                &mut done_calls[i] as *mut u32 as *mut c_void,
+            );
+            assert_eq!(result, rustls_result::Ok);
+            pairs.push((client, server));
+        }
+        // Interleave the two handshakes.
+        for (client, server) in &pairs {
+            assert_eq!(transfer(*client, *server), rustls_result::Ok);
+        }
+        for (client, server) in &pairs {
+            assert_eq!(do_handshake(*client, *server), rustls_result::Ok);
+        }
+        assert_eq!(logs[0].server_names, vec!["localhost"]);
+        assert_eq!(logs[1].server_names, vec!["example.com"]);
+        assert_eq!(done_calls, [1, 1]);
+
+        let result = rustls_connection::rustls_connection_get_userdata(pairs[0].0, null_mut());
+        assert_eq!(result, rustls_result::NullParameter);
+        for (client, server) in pairs {
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+        }
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    unsafe extern "C" fn record_handshake_done_userdata(
+        userdata: *mut c_void,
+        _conn: *mut rustls_connection,
+    ) {
+        *(userdata as *mut bool) = true;
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_handshake_done_callback_falls_back_to_connection_userdata() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let mut called = false;
+        rustls_connection::rustls_connection_set_userdata(
+            client,
+            &mut called as *mut bool as *mut c_void,
+        );
+        let result = rustls_connection::rustls_connection_set_handshake_done_callback(
+            client,
+            Some(record_handshake_done_userdata),
+            null_mut(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert!(called);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_leaves_rest_of_buffer_alone() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write(server, b"abc".as_ptr(), 3, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+
+        let mut buf = vec![0xee; 256 * 1024];
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 3));
+        assert_eq!(&buf[..3], b"abc");
+        assert!(buf[3..].iter().all(|&b| b == 0xee));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Collects what a vectored write callback is given, accepting at most
+    // `max_per_call` bytes per call.
+    struct VectoredSink {
+        out: Vec<u8>,
+        max_per_call: usize,
+        iov_counts: Vec<usize>,
+    }
+
+    unsafe extern "C" fn vectored_sink_write(
+        userdata: *mut c_void,
+        iov: *const crate::io::rustls_iovec,
+        count: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_io_result {
+        let sink = &mut *(userdata as *mut VectoredSink);
+        let iov = slice::from_raw_parts(iov as *const std::io::IoSlice, count);
+        sink.iov_counts.push(count);
+        let mut n = 0;
+        for slice in iov {
+            let take = min(slice.len(), sink.max_per_call - n);
+            sink.out.extend_from_slice(&slice[..take]);
+            n += take;
+        }
+        *out_n = n;
+        rustls_io_result(0)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_write_tls_vectored() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // Three writes make three records, handed over as three iovecs.
+        for chunk in [&b"one"[..], b"two", b"three"] {
+            let mut n = 0;
+            let result = rustls_connection::rustls_connection_write(
+                client,
+                chunk.as_ptr(),
+                chunk.len(),
+                &mut n,
+            );
+            assert_eq!(result, rustls_result::Ok);
+        }
+        // Each call takes 30 bytes, so writes stop part way through records.
+        let mut sink = VectoredSink {
+            out: Vec::new(),
+            max_per_call: 30,
+            iov_counts: Vec::new(),
+        };
+        let mut total = 0;
+        while rustls_connection::rustls_connection_wants_write(client) {
+            let mut n = 0;
+            let result = rustls_connection::rustls_connection_write_tls_vectored(
+                client,
+                Some(vectored_sink_write),
+                &mut sink as *mut VectoredSink as *mut c_void,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+            assert!(n > 0 && n <= 30);
+            total += n;
+        }
+        assert_eq!(sink.iov_counts[0], 3);
+        assert!(sink.iov_counts.len() > 1);
+        assert_eq!(total, sink.out.len());
+
+        // The concatenation is a valid TLS stream carrying all three writes.
+        let mut input = VecDeque::from(sink.out);
+        while !input.is_empty() {
+            let mut n = 0;
+            let result = rustls_connection::rustls_connection_read_tls(
+                server,
+                Some(vecdeque_read),
+                &mut input as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+            assert_eq!(
+                rustls_connection::rustls_connection_process_new_packets(server),
+                rustls_result::Ok
+            );
+        }
+        let mut received = Vec::new();
+        drain_plaintext(server, &mut received);
+        assert_eq!(received, b"onetwothree");
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn read_vectored(
+        conn: *mut rustls_connection,
+        bufs: &mut [&mut [u8]],
+    ) -> (rustls_result, usize) {
+        let mut iov: Vec<IoSliceMut> = bufs.iter_mut().map(|b| IoSliceMut::new(b)).collect();
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_read_vectored(
+            conn,
+            iov.as_mut_ptr() as *mut rustls_iovec_mut,
+            iov.len(),
+            &mut n,
+        );
+        (result, n)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_vectored() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let mut tail = [0u8; 4];
+        let mut head = [0u8; 8];
+        assert_eq!(
+            read_vectored(client, &mut [&mut tail, &mut head]).0,
+            rustls_result::PlaintextEmpty
+        );
+
+        // Two records' worth of plaintext spans the end of the ring buffer.
+        for chunk in [&b"abc"[..], b"defgh"] {
+            let mut n = 0;
+            let result = rustls_connection::rustls_connection_write(
+                server,
+                chunk.as_ptr(),
+                chunk.len(),
+                &mut n,
+            );
+            assert_eq!(result, rustls_result::Ok);
+        }
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(
+            read_vectored(client, &mut [&mut tail, &mut [], &mut head]),
+            (rustls_result::Ok, 8)
+        );
+        assert_eq!(&tail, b"abcd");
+        assert_eq!(&head[..4], b"efgh");
+        assert_eq!(head[4..], [0; 4]);
+
+        // Buffers that run out first leave the rest for the next read.
+        let mut n = 0;
+        let result =
+            rustls_connection::rustls_connection_write(server, b"0123456789".as_ptr(), 10, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(
+            read_vectored(client, &mut [&mut tail, &mut head]),
+            (rustls_result::Ok, 10)
+        );
+        assert_eq!(&tail, b"0123");
+        assert_eq!(&head[..6], b"456789");
+        let mut small = [0u8; 2];
+        let result = rustls_connection::rustls_connection_write(server, b"xyz".as_ptr(), 3, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(
+            read_vectored(client, &mut [&mut small]),
+            (rustls_result::Ok, 2)
+        );
+        assert_eq!(read_plaintext(client, &mut head), (rustls_result::Ok, 1));
+        assert_eq!(head[0], b'z');
+
+        // A clean close reads as end of stream.
+        rustls_connection::rustls_connection_send_close_notify(server);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(
+            read_vectored(client, &mut [&mut tail, &mut head]),
+            (rustls_result::Ok, 0)
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // A write callback that takes at most `max_per_call` bytes per call into
+    // `out`, failing with `fail_with` on call number `fail_call`.
+    struct TrickleSink {
+        out: VecDeque<u8>,
+        max_per_call: usize,
+        calls: usize,
+        fail_call: usize,
+        fail_with: i32,
+        overreport: bool,
+    }
+
+    impl TrickleSink {
+        fn new(max_per_call: usize) -> Self {
+            TrickleSink {
+                out: VecDeque::new(),
+                max_per_call,
+                calls: 0,
+                fail_call: 0,
+                fail_with: 0,
+                overreport: false,
+            }
+        }
+    }
+
+    unsafe extern "C" fn trickle_write(
+        userdata: *mut c_void,
+        buf: *const u8,
+        n: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_io_result {
+        let sink = &mut *(userdata as *mut TrickleSink);
+        sink.calls += 1;
+        if sink.calls == sink.fail_call {
+            return rustls_io_result(sink.fail_with);
+        }
+        if sink.overreport {
+            *out_n = n + 1;
+            return rustls_io_result(0);
+        }
+        let n = min(n, sink.max_per_call);
+        sink.out.extend(slice::from_raw_parts(buf, n));
+        *out_n = n;
+        rustls_io_result(0)
+    }
+
+    fn write_tls_to(
+        conn: *mut rustls_connection,
+        sink: &mut TrickleSink,
+    ) -> (rustls_io_result, usize) {
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_write_tls(
+            conn,
+            Some(trickle_write),
+            sink as *mut TrickleSink as *mut c_void,
+            &mut n,
+        );
+        (result, n)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_write_tls_callback_partial_writes() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+
+        // The ClientHello goes out 10 bytes at a time. A callback error in
+        // the middle is passed through and loses nothing.
+        let mut sink = TrickleSink::new(10);
+        sink.fail_call = 3;
+        sink.fail_with = libc::EAGAIN;
+        let mut calls = 0;
+        while rustls_connection::rustls_connection_wants_write(client) {
+            let (result, n) = write_tls_to(client, &mut sink);
+            calls += 1;
+            if calls == 3 {
+                assert_eq!(result, rustls_io_result(libc::EAGAIN));
+            } else {
+                assert_eq!(result, rustls_io_result(0));
+                assert!(n > 0 && n <= 10);
+            }
+        }
+        assert!(calls > 3);
+        while !sink.out.is_empty() {
+            let mut n = 0;
+            let result = rustls_connection::rustls_connection_read_tls(
+                server,
+                Some(vecdeque_read),
+                &mut sink.out as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(server),
+            rustls_result::Ok
+        );
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // A callback claiming to have written more than it was given is an
+        // error, and the pending bytes are kept.
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write(client, b"hi".as_ptr(), 2, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        let mut liar = TrickleSink::new(usize::MAX);
+        liar.overreport = true;
+        assert_eq!(
+            write_tls_to(client, &mut liar).0,
+            rustls_io_result(libc::EIO)
+        );
+        assert!(rustls_connection::rustls_connection_wants_write(client));
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        let mut received = Vec::new();
+        drain_plaintext(server, &mut received);
+        assert_eq!(received, b"hi");
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // A read callback that hands over one byte of `input` per call, failing
+    // with `fail_with` on call number `fail_call`.
+    struct TrickleSource {
+        input: VecDeque<u8>,
+        calls: usize,
+        fail_call: usize,
+        fail_with: i32,
+        overreport: bool,
+    }
+
+    unsafe extern "C" fn trickle_read(
+        userdata: *mut c_void,
+        buf: *mut u8,
+        n: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_io_result {
+        let source = &mut *(userdata as *mut TrickleSource);
+        source.calls += 1;
+        if source.calls == source.fail_call {
+            return rustls_io_result(source.fail_with);
+        }
+        if source.overreport {
+            *out_n = n + 1;
+            return rustls_io_result(0);
+        }
+        *out_n = 0;
+        if n > 0 {
+            if let Some(b) = source.input.pop_front() {
+                *buf = b;
+                *out_n = 1;
+            }
+        }
+        rustls_io_result(0)
+    }
+
+    fn read_tls_from(
+        conn: *mut rustls_connection,
+        source: &mut TrickleSource,
+    ) -> (rustls_io_result, usize) {
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_read_tls(
+            conn,
+            Some(trickle_read),
+            source as *mut TrickleSource as *mut c_void,
+            &mut n,
+        );
+        (result, n)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_tls_callback_one_byte_at_a_time() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+
+        let mut hello = VecDeque::new();
+        let mut n = 0;
+        while rustls_connection::rustls_connection_wants_write(client) {
+            let result = rustls_connection::rustls_connection_write_tls(
+                client,
+                Some(vecdeque_write),
+                &mut hello as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        let hello_len = hello.len();
+        let mut source = TrickleSource {
+            input: hello,
+            calls: 0,
+            fail_call: 5,
+            fail_with: libc::EAGAIN,
+            overreport: false,
+        };
+        // Each call reads one byte; the error on the fifth call consumes
+        // nothing, and processing partial records as they arrive is fine.
+        let mut total = 0;
+        while !source.input.is_empty() {
+            let (result, n) = read_tls_from(server, &mut source);
+            if source.calls == 5 {
+                assert_eq!(result, rustls_io_result(libc::EAGAIN));
+                continue;
+            }
+            assert_eq!(result, rustls_io_result(0));
+            assert_eq!(n, 1);
+            total += n;
+            assert_eq!(
+                rustls_connection::rustls_connection_process_new_packets(server),
+                rustls_result::Ok
+            );
+        }
+        assert_eq!(total, hello_len);
+        assert!(rustls_connection::rustls_connection_wants_write(server));
+
+        // Out of input, the callback reports EOF.
+        assert_eq!(read_tls_from(server, &mut source), (rustls_io_result(0), 0));
+
+        // Claiming to have read more than the buffer holds is an error.
+        let mut liar = TrickleSource {
+            input: VecDeque::new(),
+            calls: 0,
+            fail_call: 0,
+            fail_with: 0,
+            overreport: true,
+        };
+        let client2 = make_client_connection(client_config, "localhost");
+        assert_eq!(
+            read_tls_from(client2, &mut liar).0,
+            rustls_io_result(libc::EIO)
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(client2);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_tls_from_fd() {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        theirs.set_nonblocking(true).unwrap();
+        let fd = theirs.as_raw_fd();
+        let mut n = usize::MAX;
+
+        // Nothing to read yet.
+        let result = rustls_connection::rustls_connection_read_tls_from_fd(server, fd, &mut n);
+        assert_eq!(result, rustls_io_result(libc::EAGAIN));
+
+        let mut hello = VecDeque::new();
+        while rustls_connection::rustls_connection_wants_write(client) {
+            let result = rustls_connection::rustls_connection_write_tls(
+                client,
+                Some(vecdeque_write),
+                &mut hello as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        let hello: Vec<u8> = hello.into();
+        ours.write_all(&hello).unwrap();
+        let mut total = 0;
+        while total < hello.len() {
+            let result = rustls_connection::rustls_connection_read_tls_from_fd(server, fd, &mut n);
+            assert_eq!(result, rustls_io_result(0));
+            assert!(n > 0);
+            total += n;
+        }
+        assert_eq!(total, hello.len());
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(server),
+            rustls_result::Ok
+        );
+        assert!(rustls_connection::rustls_connection_wants_write(server));
+
+        // EOF once the other end closes, and the fd itself stays open.
+        drop(ours);
+        let result = rustls_connection::rustls_connection_read_tls_from_fd(server, fd, &mut n);
+        assert_eq!(result, rustls_io_result(0));
+        assert_eq!(n, 0);
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+
+        let result = rustls_connection::rustls_connection_read_tls_from_fd(server, -1, &mut n);
+        assert_eq!(result, rustls_io_result(libc::EBADF));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[cfg(unix)]
+    fn set_send_buffer(fd: c_int, size: c_int) {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                &size as *const c_int as *const c_void,
+                std::mem::size_of::<c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(result, 0);
+    }
+
+    /// Feed everything waiting on the non-blocking `source` to `server`,
+    /// collecting the plaintext it yields.
+    #[cfg(unix)]
+    fn pump_to_server(
+        source: &mut std::os::unix::net::UnixStream,
+        server: *mut rustls_connection,
+        received: &mut Vec<u8>,
+    ) {
+        use std::io::Read;
+
+        let mut buf = [0u8; 8192];
+        loop {
+            let read = match source.read(&mut buf) {
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(e) => panic!("{}", e),
+            };
+            let mut queue = VecDeque::from(buf[..read].to_vec());
+            while !queue.is_empty() {
+                let mut n = 0;
+                let result = rustls_connection::rustls_connection_read_tls(
+                    server,
+                    Some(vecdeque_read),
+                    &mut queue as *mut _ as *mut _,
+                    &mut n,
+                );
+                assert_eq!(result, rustls_io_result(0));
+                assert_eq!(
+                    rustls_connection::rustls_connection_process_new_packets(server),
+                    rustls_result::Ok
+                );
+                drain_plaintext(server, received);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_write_tls_to_fd() {
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        theirs.set_nonblocking(true).unwrap();
+        let fd = ours.as_raw_fd();
+        // The kernel rounds this up to its minimum, which is still well
+        // short of what we queue below.
+        set_send_buffer(fd, 1);
+
+        let plaintext: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write(
+            client,
+            plaintext.as_ptr(),
+            plaintext.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(n, plaintext.len());
+
+        let mut received = Vec::new();
+        let mut saw_eagain = false;
+        while rustls_connection::rustls_connection_wants_write(client) {
+            let result = rustls_connection::rustls_connection_write_tls_to_fd(client, fd, &mut n);
+            if result == rustls_io_result(libc::EAGAIN) {
+                // The rest is still queued; make room and carry on.
+                saw_eagain = true;
+                pump_to_server(&mut theirs, server, &mut received);
+                continue;
+            }
+            assert_eq!(result, rustls_io_result(0));
+            assert!(n > 0);
+        }
+        assert!(saw_eagain, "the socket buffer never filled up");
+        pump_to_server(&mut theirs, server, &mut received);
+        assert_eq!(received, plaintext);
+
+        // Once the peer is gone, writes fail with EPIPE instead of raising
+        // SIGPIPE, the bytes stay queued, and the fd stays open.
+        drop(theirs);
+        rustls_connection::rustls_connection_send_close_notify(client);
+        let result = rustls_connection::rustls_connection_write_tls_to_fd(client, fd, &mut n);
+        assert_eq!(result, rustls_io_result(libc::EPIPE));
+        assert!(rustls_connection::rustls_connection_wants_write(client));
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_complete_io_fd() {
+        use std::io::{Read, Write};
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+        use std::sync::Arc;
+
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let server_config = build_server_config(
+                rustls_server_config_builder::rustls_server_config_builder_new(),
+            );
+            let config: Arc<rustls::ServerConfig> = crate::try_arc_from_ptr!(server_config);
+            rustls_server_config::rustls_server_config_free(server_config);
+            let mut conn = rustls::ServerConnection::new(config).unwrap();
+            let mut stream = rustls::Stream::new(&mut conn, &mut theirs);
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request, b"ping");
+            stream.write_all(b"pong").unwrap();
+        });
+
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let fd = ours.as_raw_fd();
+        let (mut total_read, mut total_written) = (0, 0);
+        let mut read = usize::MAX;
+        let mut written = usize::MAX;
+        assert_eq!(
+            rustls_connection::rustls_connection_complete_io_fd(
+                client,
+                fd,
+                &mut read,
+                &mut written
+            ),
+            rustls_result::Ok
+        );
+        assert!(!rustls_connection::rustls_connection_is_handshaking(client));
+        assert!(read > 0 && written > 0);
+        total_read += read;
+        total_written += written;
+
+        let mut n = 0;
+        let result =
+            rustls_connection::rustls_connection_write(client, b"ping".as_ptr(), 4, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        while rustls_connection::rustls_connection_wants_write(client) {
+            assert_eq!(
+                rustls_connection::rustls_connection_complete_io_fd(
+                    client,
+                    fd,
+                    &mut read,
+                    &mut written
+                ),
+                rustls_result::Ok
+            );
+            total_written += written;
+        }
+
+        let mut response = Vec::new();
+        while response.len() < 4 {
+            assert_eq!(
+                rustls_connection::rustls_connection_complete_io_fd(
+                    client,
+                    fd,
+                    &mut read,
+                    null_mut()
+                ),
+                rustls_result::Ok
+            );
+            total_read += read;
+            drain_plaintext(client, &mut response);
+        }
+        assert_eq!(response, b"pong");
+        server.join().unwrap();
+
+        assert!(total_read > read && total_written > written);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_complete_io_fd_would_block() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+
+        // The ClientHello goes out, then there is nothing to read.
+        let mut read = usize::MAX;
+        let mut written = 0;
+        assert_eq!(
+            rustls_connection::rustls_connection_complete_io_fd(
+                client,
+                ours.as_raw_fd(),
+                &mut read,
+                &mut written
+            ),
+            rustls_result::WouldBlock
+        );
+        assert_eq!(read, 0);
+        assert!(written > 0);
+        assert!(rustls_connection::rustls_connection_is_handshaking(client));
+
+        // A transport closed mid-handshake is an unexpected EOF, not a retry.
+        // Take the ClientHello first, or the close is a reset instead.
+        let mut hello = vec![0u8; written];
+        theirs.read_exact(&mut hello).unwrap();
+        drop(theirs);
+        assert_eq!(
+            rustls_connection::rustls_connection_complete_io_fd(
+                client,
+                ours.as_raw_fd(),
+                &mut read,
+                &mut written
+            ),
+            rustls_result::UnexpectedEof
+        );
+        assert_eq!((read, written), (0, 0));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_complete_io_fd_would_block_on_write() {
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        theirs.set_nonblocking(true).unwrap();
+        set_send_buffer(ours.as_raw_fd(), 4096);
+
+        // Whenever the socket fills up, complete_io_fd says so, and the rest
+        // goes out on a later call: nothing is lost or sent twice.
+        let data: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+        let mut sent = 0;
+        let mut would_block = 0;
+        let mut received = Vec::new();
+        while sent < data.len() || rustls_connection::rustls_connection_wants_write(client) {
+            sent += write_all_accepted(client, &data[sent..]);
+            let mut written = usize::MAX;
+            match rustls_connection::rustls_connection_complete_io_fd(
+                client,
+                ours.as_raw_fd(),
+                null_mut(),
+                &mut written,
+            ) {
+                rustls_result::Ok => {}
+                rustls_result::WouldBlock => {
+                    would_block += 1;
+                    assert!(rustls_connection::rustls_connection_wants_write(client));
+                    assert_eq!(last_error(client), "");
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+            assert_ne!(written, usize::MAX);
+            pump_to_server(&mut theirs, server, &mut received);
+        }
+        assert!(would_block > 0);
+        assert_eq!(received, data);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn plaintext_bytes_to_read(conn: *const rustls_connection) -> usize {
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_plaintext_bytes_to_read(conn, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        n
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_plaintext_bytes_to_read() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(plaintext_bytes_to_read(client), 0);
+
+        // Several records' worth, all processed before any is read.
+        let data: Vec<u8> = (0..12345u32).map(|i| i as u8).collect();
+        let mut n = 0;
+        let result =
+            rustls_connection::rustls_connection_write(server, data.as_ptr(), data.len(), &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(plaintext_bytes_to_read(client), 12345);
+
+        let mut buf = vec![0u8; 1000];
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 1000));
+        assert_eq!(plaintext_bytes_to_read(client), 11345);
+
+        let (mut first, mut second) = ([0u8; 300], [0u8; 45]);
+        let result = read_vectored(client, &mut [&mut first[..], &mut second[..]]);
+        assert_eq!(result, (rustls_result::Ok, 345));
+        assert_eq!(plaintext_bytes_to_read(client), 11000);
+
+        let mut rest = vec![0u8; 20000];
+        assert_eq!(
+            read_plaintext(client, &mut rest),
+            (rustls_result::Ok, 11000)
+        );
+        assert_eq!(plaintext_bytes_to_read(client), 0);
+        assert_eq!(
+            read_plaintext(client, &mut rest).0,
+            rustls_result::PlaintextEmpty
+        );
+
+        assert_eq!(
+            rustls_connection::rustls_connection_plaintext_bytes_to_read(client, null_mut()),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn tls_bytes_to_write(conn: *mut rustls_connection) -> usize {
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_tls_bytes_to_write(conn, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        n
+    }
+
+    fn write_plaintext(conn: *mut rustls_connection, data: &[u8]) -> usize {
+        let mut n = usize::MAX;
+        let result =
+            rustls_connection::rustls_connection_write(conn, data.as_ptr(), data.len(), &mut n);
+        assert_eq!(result, rustls_result::Ok);
+        n
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_tls_bytes_to_write() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        let hello = tls_bytes_to_write(client);
+        assert!(hello > 0);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(tls_bytes_to_write(client), 0);
+        assert!(!rustls_connection::rustls_connection_wants_write(client));
+
+        // Two records, half of which go out in small writes.
+        assert_eq!(write_plaintext(client, &[b'a'; 100]), 100);
+        assert_eq!(write_plaintext(client, &[b'b'; 200]), 200);
+        let queued = tls_bytes_to_write(client);
+        assert!(queued > 300);
+        let mut sink = TrickleSink::new(50);
+        while sink.out.len() < queued / 2 {
+            assert_eq!(write_tls_to(client, &mut sink).0, rustls_io_result(0));
+            assert_eq!(tls_bytes_to_write(client), queued - sink.out.len());
+        }
+        assert!(rustls_connection::rustls_connection_wants_write(client));
+
+        // The bytes held back still count against the buffer limit.
+        let remaining = tls_bytes_to_write(client);
+        rustls_connection::rustls_connection_set_buffer_limit(client, remaining);
+        assert_eq!(write_plaintext(client, &[b'c'; 300]), 0);
+        rustls_connection::rustls_connection_set_buffer_limit(client, 64 * 1024);
+
+        // A record written now goes out after the ones already counted.
+        assert_eq!(write_plaintext(client, &[b'c'; 300]), 300);
+        assert!(tls_bytes_to_write(client) > remaining + 300);
+        sink.max_per_call = usize::MAX;
+        while rustls_connection::rustls_connection_wants_write(client) {
+            assert_eq!(write_tls_to(client, &mut sink).0, rustls_io_result(0));
+        }
+        assert_eq!(tls_bytes_to_write(client), 0);
+
+        let mut n = 0;
+        while !sink.out.is_empty() {
+            let result = rustls_connection::rustls_connection_read_tls(
+                server,
+                Some(vecdeque_read),
+                &mut sink.out as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(server),
+            rustls_result::Ok
+        );
+        let mut received = Vec::new();
+        drain_plaintext(server, &mut received);
+        let mut expected = vec![b'a'; 100];
+        expected.extend_from_slice(&[b'b'; 200]);
+        expected.extend_from_slice(&[b'c'; 300]);
+        assert_eq!(received, expected);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn last_error(conn: *const rustls_connection) -> String {
+        let mut buf = [0xffu8; 512];
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_get_last_error(
+            conn,
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(buf[n], 0);
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_last_error() {
+        // The server's certificate is for example.com, not localhost.
+        let client_config = build_verifying_client_config(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            include_str!("../testdata/example.com/cert.pem"),
+            include_str!("../testdata/example.com/key.pem"),
+        );
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(last_error(client), "");
+        assert_eq!(
+            do_handshake(client, server),
+            rustls_result::CertNotValidForName
+        );
+        let message = last_error(client);
+        assert!(message.contains("NotValidForName"), "{}", message);
+
+        // Too small a buffer gets nothing, not a truncated message.
+        let mut buf = vec![0u8; message.len()];
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_get_last_error(
+            client,
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::InsufficientSize);
+        assert_eq!(n, 0);
+
+        // A failing callback's errno is described too, and a later success
+        // clears the message.
+        let mut source = TrickleSource {
+            input: VecDeque::new(),
+            calls: 0,
+            fail_call: 1,
+            fail_with: libc::ECONNRESET,
+            overreport: false,
+        };
+        assert_eq!(
+            read_tls_from(client, &mut source).0,
+            rustls_io_result(libc::ECONNRESET)
+        );
+        let message = last_error(client);
+        assert!(
+            message.contains(&format!("os error {}", libc::ECONNRESET)),
+            "{}",
+            message
+        );
+        assert_eq!(read_tls_from(client, &mut source), (rustls_io_result(0), 0));
+        assert_eq!(last_error(client), "");
+
+        // Try-again results leave it alone.
+        source.fail_call = 3;
+        source.fail_with = libc::ECONNRESET;
+        assert_eq!(
+            read_tls_from(client, &mut source).0,
+            rustls_io_result(libc::ECONNRESET)
+        );
+        source.fail_call = 4;
+        source.fail_with = libc::EAGAIN;
+        assert_eq!(
+            read_tls_from(client, &mut source).0,
+            rustls_io_result(libc::EAGAIN)
+        );
+        assert_ne!(last_error(client), "");
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Call each data-plane function on `conn` that should refuse to work
+    // once it has failed, and return what they returned.
+    fn data_plane_results(conn: *mut rustls_connection) -> Vec<rustls_result> {
+        let mut buf = [0u8; 64];
+        let mut n = 0;
+        let mut ptr = null();
+        let mut state = rustls_io_state {
+            bytes_to_read: 0,
+            bytes_to_write: 0,
+            handshaking: false,
+            peer_closed: false,
+        };
+        #[cfg_attr(not(unix), allow(unused_mut))]
+        let mut results = vec![
+            rustls_connection::rustls_connection_process_new_packets(conn),
+            rustls_connection::rustls_connection_process_new_packets_ex(conn, &mut state),
+            rustls_connection::rustls_connection_write(conn, b"hi".as_ptr(), 2, &mut n),
+            rustls_connection::rustls_connection_write_all(conn, b"hi".as_ptr(), 2, &mut n),
+            rustls_connection::rustls_connection_flush(conn),
+            read_plaintext(conn, &mut buf).0,
+            read_vectored(conn, &mut [&mut buf[..]]).0,
+            rustls_connection::rustls_connection_peek_plaintext(conn, &mut ptr, &mut n),
+            rustls_connection::rustls_connection_consume_plaintext(conn, 0),
+            pump(conn, &[], &mut buf).0,
+            shutdown(conn, 64).0,
+            crate::client::rustls_client_connection_write_early_data(
+                conn,
+                b"hi".as_ptr(),
+                2,
+                &mut n,
+            ),
+        ];
+        #[cfg(unix)]
+        results.push(rustls_connection::rustls_connection_complete_io_fd(
+            conn,
+            -1,
+            null_mut(),
+            null_mut(),
+        ));
+        results
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_fatal_error_is_sticky() {
+        // The server's certificate is for example.com, not localhost.
+        let client_config = build_verifying_client_config(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        let server_config = build_server_config_with(
+            rustls_server_config_builder::rustls_server_config_builder_new(),
+            include_str!("../testdata/example.com/cert.pem"),
+            include_str!("../testdata/example.com/key.pem"),
+        );
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        assert_eq!(transfer(server, client), rustls_result::CertNotValidForName);
+        let message = last_error(client);
+
+        // Everything reports the original error, and leaves the message.
+        for result in data_plane_results(client) {
+            assert_eq!(result, rustls_result::CertNotValidForName);
+        }
+        assert_eq!(last_error(client), message);
+        let dump = debug_dump(client);
+        assert!(dump.contains("fatal_error=CertNotValidForName"), "{}", dump);
+
+        // The alert can still be sent, and kills the server in turn.
+        assert!(rustls_connection::rustls_connection_wants_write(client));
+        let result = transfer(client, server);
+        assert_ne!(result, rustls_result::Ok);
+        // Even early data, which a server would refuse as InvalidParameter.
+        for r in data_plane_results(server) {
+            assert_eq!(r, result);
+        }
+        assert!(!rustls_connection::rustls_connection_wants_write(client));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Move all of `from`'s pending TLS bytes into `to`, without processing them.
+    fn deliver_tls(from: *mut rustls_connection, to: *mut rustls_connection) {
+        let mut buf = VecDeque::<u8>::new();
+        let mut n = 0;
+        while rustls_connection::rustls_connection_wants_write(from) {
+            let result = rustls_connection::rustls_connection_write_tls(
+                from,
+                Some(vecdeque_write),
+                &mut buf as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        while !buf.is_empty() {
+            let result = rustls_connection::rustls_connection_read_tls(
+                to,
+                Some(vecdeque_read),
+                &mut buf as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+    }
+
+    // (bytes_to_read, bytes_to_write, handshaking, peer_closed)
+    fn process_ex(conn: *mut rustls_connection) -> (usize, usize, bool, bool) {
+        let mut state = rustls_io_state {
+            bytes_to_read: usize::MAX,
+            bytes_to_write: usize::MAX,
+            handshaking: false,
+            peer_closed: true,
+        };
+        let result = rustls_connection::rustls_connection_process_new_packets_ex(conn, &mut state);
+        assert_eq!(result, rustls_result::Ok);
+        (
+            state.bytes_to_read,
+            state.bytes_to_write,
+            state.handshaking,
+            state.peer_closed,
+        )
+    }
+
+    unsafe extern "C" fn write_hello_on_handshake_done(
+        _userdata: *mut c_void,
+        conn: *mut rustls_connection,
+    ) {
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write(conn, b"hello".as_ptr(), 5, &mut n);
+        assert_eq!(result, rustls_result::Ok);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_process_new_packets_ex() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+
+        // Handshaking: the server has its first flight to send.
+        deliver_tls(client, server);
+        let (to_read, to_write, handshaking, closed) = process_ex(server);
+        assert_eq!(to_read, 0);
+        assert!(to_write > 0);
+        assert_eq!(to_write, tls_bytes_to_write(server));
+        assert!(handshaking);
+        assert!(!closed);
+
+        // The client finishes the handshake, and the callback's write is
+        // counted.
+        let result = rustls_connection::rustls_connection_set_handshake_done_callback(
+            client,
+            Some(write_hello_on_handshake_done),
+            null_mut(),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        deliver_tls(server, client);
+        let (to_read, to_write, handshaking, closed) = process_ex(client);
+        assert_eq!(to_read, 0);
+        assert_eq!(to_write, tls_bytes_to_write(client));
+        assert!(!handshaking);
+        assert!(!closed);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let mut received = Vec::new();
+        drain_plaintext(server, &mut received);
+        assert_eq!(received, b"hello");
+
+        // Steady state: data arrives and nothing needs writing.
+        assert_eq!(write_plaintext(server, &[b'x'; 500]), 500);
+        deliver_tls(server, client);
+        assert_eq!(process_ex(client), (500, 0, false, false));
+
+        // After close_notify, the unread data is still there.
+        rustls_connection::rustls_connection_send_close_notify(server);
+        deliver_tls(server, client);
+        assert_eq!(process_ex(client), (500, 0, false, true));
+        let mut buf = [0u8; 200];
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 200));
+        assert_eq!(process_ex(client), (300, 0, false, true));
+
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets_ex(client, null_mut()),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn was_resumed(conn: *const rustls_connection) -> (rustls_result, bool) {
+        let mut resumed = false;
+        let result = crate::client::rustls_client_connection_was_resumed(conn, &mut resumed);
+        (result, resumed)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_was_resumed() {
+        // A fresh client for each server, since a client with a TLS 1.3
+        // ticket for localhost offers that in preference to a TLS 1.2 session.
+        let servers = [
+            // TLS 1.3 PSK
+            versioned_server_config(&[rustls_tls_version::Tlsv1_3 as u16]),
+            // TLS 1.2 session ID
+            versioned_server_config(&[rustls_tls_version::Tlsv1_2 as u16]),
+            // TLS 1.2 ticket
+            ticketing_tls12_server_config(),
+        ];
+        for server_config in servers {
+            let client_config = build_client_config(
+                rustls_client_config_builder::rustls_client_config_builder_new(),
+            );
+            for resumed in [false, true] {
+                let client = make_client_connection(client_config, "localhost");
+                let server = make_server_connection(server_config);
+                assert_eq!(was_resumed(client).0, rustls_result::HandshakeNotComplete);
+                assert_eq!(do_handshake(client, server), rustls_result::Ok);
+                assert_eq!(was_resumed(client), (rustls_result::Ok, resumed));
+                assert_eq!(was_resumed(server).0, rustls_result::InvalidParameter);
+                rustls_connection::rustls_connection_free(client);
+                rustls_connection::rustls_connection_free(server);
+            }
+            rustls_client_config::rustls_client_config_free(client_config);
+            rustls_server_config::rustls_server_config_free(server_config);
+        }
+
+        assert_eq!(
+            crate::client::rustls_client_connection_was_resumed(null(), null_mut()),
+            rustls_result::NullParameter
+        );
+    }
+
+    fn handshake_duration(conn: *const rustls_connection) -> (rustls_result, u64) {
+        let mut micros = u64::MAX;
+        let result = rustls_connection::rustls_connection_handshake_duration_us(conn, &mut micros);
+        (result, micros)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_handshake_duration() {
+        let server_config = versioned_server_config(&[rustls_tls_version::Tlsv1_3 as u16]);
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        for resumed in [false, true] {
+            let client = make_client_connection(client_config, "localhost");
+            let server = make_server_connection(server_config);
+            for conn in [client, server] {
+                assert_eq!(
+                    handshake_duration(conn),
+                    (rustls_result::HandshakeNotComplete, u64::MAX)
+                );
+            }
+            assert_eq!(do_handshake(client, server), rustls_result::Ok);
+            assert_eq!(was_resumed(client), (rustls_result::Ok, resumed));
+            for conn in [client, server] {
+                let (result, micros) = handshake_duration(conn);
+                assert_eq!(result, rustls_result::Ok);
+                assert!(micros > 0 && micros != u64::MAX);
+                // Fixed once the handshake is over.
+                assert_eq!(handshake_duration(conn), (result, micros));
+            }
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+        }
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+
+        let mut micros = 0;
+        assert_eq!(
+            rustls_connection::rustls_connection_handshake_duration_us(null(), &mut micros),
+            rustls_result::NullParameter
+        );
+    }
+
+    fn peek_tls_output(conn: *mut rustls_connection) -> Vec<u8> {
+        let mut ptr = null();
+        let mut len = usize::MAX;
+        let result = rustls_connection::rustls_connection_peek_tls_output(conn, &mut ptr, &mut len);
+        assert_eq!(result, rustls_result::Ok);
+        assert!(!ptr.is_null());
+        unsafe { slice::from_raw_parts(ptr, len) }.to_vec()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_peek_tls_output() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert!(peek_tls_output(client).is_empty());
+
+        // Two records, peeked and sent in pieces.
+        assert_eq!(write_plaintext(client, &[b'a'; 100]), 100);
+        assert_eq!(write_plaintext(client, &[b'b'; 200]), 200);
+        let pending = peek_tls_output(client);
+        assert_eq!(pending.len(), tls_bytes_to_write(client));
+        assert_eq!(peek_tls_output(client), pending);
+        let half = pending.len() / 2;
+        assert_eq!(
+            rustls_connection::rustls_connection_consume_tls_output(client, half),
+            rustls_result::Ok
+        );
+        let rest = peek_tls_output(client);
+        assert_eq!(rest, pending[half..]);
+        assert_eq!(
+            rustls_connection::rustls_connection_consume_tls_output(client, rest.len() + 1),
+            rustls_result::InvalidParameter
+        );
+        assert_eq!(peek_tls_output(client), rest);
+
+        // What's left goes out first through write_tls, ahead of a newer record.
+        assert_eq!(write_plaintext(client, &[b'c'; 300]), 300);
+        let mut sink = TrickleSink::new(10);
+        assert_eq!(write_tls_to(client, &mut sink), (rustls_io_result(0), 10));
+        let mut sent: Vec<u8> = pending[..half].to_vec();
+        sent.extend(sink.out.drain(..));
+        sent.extend(peek_tls_output(client));
+        let len = tls_bytes_to_write(client);
+        assert_eq!(
+            rustls_connection::rustls_connection_consume_tls_output(client, len),
+            rustls_result::Ok
+        );
+        assert!(!rustls_connection::rustls_connection_wants_write(client));
+        assert!(peek_tls_output(client).is_empty());
+
+        let mut queue = VecDeque::from(sent);
+        let mut n = 0;
+        while !queue.is_empty() {
+            let result = rustls_connection::rustls_connection_read_tls(
+                server,
+                Some(vecdeque_read),
+                &mut queue as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(server),
+            rustls_result::Ok
+        );
+        let mut received = Vec::new();
+        drain_plaintext(server, &mut received);
+        let mut expected = vec![b'a'; 100];
+        expected.extend_from_slice(&[b'b'; 200]);
+        expected.extend_from_slice(&[b'c'; 300]);
+        assert_eq!(received, expected);
+
+        let mut len = 0;
+        assert_eq!(
+            rustls_connection::rustls_connection_peek_tls_output(client, null_mut(), &mut len),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn peek_plaintext(conn: *mut rustls_connection) -> (rustls_result, Vec<u8>) {
+        let mut ptr = null();
+        let mut len = usize::MAX;
+        let result = rustls_connection::rustls_connection_peek_plaintext(conn, &mut ptr, &mut len);
+        assert!(!ptr.is_null());
+        (result, unsafe { slice::from_raw_parts(ptr, len) }.to_vec())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_peek_plaintext() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(
+            peek_plaintext(client),
+            (rustls_result::PlaintextEmpty, vec![])
+        );
+
+        assert_eq!(write_plaintext(server, b"hello "), 6);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(
+            peek_plaintext(client),
+            (rustls_result::Ok, b"hello ".to_vec())
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_consume_plaintext(client, 2),
+            rustls_result::Ok
+        );
+        assert_eq!(plaintext_bytes_to_read(client), 4);
+
+        // New TLS bytes don't show up until they are processed, and then
+        // follow on from what was left.
+        assert_eq!(write_plaintext(server, b"world"), 5);
+        deliver_tls(server, client);
+        assert_eq!(
+            peek_plaintext(client),
+            (rustls_result::Ok, b"llo ".to_vec())
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(client),
+            rustls_result::Ok
+        );
+        assert_eq!(plaintext_bytes_to_read(client), 9);
+        assert_eq!(
+            peek_plaintext(client),
+            (rustls_result::Ok, b"llo world".to_vec())
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_consume_plaintext(client, 10),
+            rustls_result::InvalidParameter
+        );
+
+        // Reads take peeked bytes first, then carry on into rustls's.
+        assert_eq!(write_plaintext(server, b"!"), 1);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        let mut buf = [0u8; 3];
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 3));
+        assert_eq!(&buf, b"llo");
+        let mut buf = [0u8; 20];
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 7));
+        assert_eq!(&buf[..7], b" world!");
+        assert_eq!(plaintext_bytes_to_read(client), 0);
+
+        // After close_notify, an empty peek is a clean end of stream.
+        assert_eq!(write_plaintext(server, b"bye"), 3);
+        rustls_connection::rustls_connection_send_close_notify(server);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(peek_plaintext(client), (rustls_result::Ok, b"bye".to_vec()));
+        assert_eq!(
+            rustls_connection::rustls_connection_consume_plaintext(client, 3),
+            rustls_result::Ok
+        );
+        assert_eq!(peek_plaintext(client), (rustls_result::Ok, vec![]));
+        assert_eq!(read_plaintext(client, &mut buf), (rustls_result::Ok, 0));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Take all of `conn`'s pending TLS bytes.
+    fn take_tls(conn: *mut rustls_connection) -> Vec<u8> {
+        let mut buf = VecDeque::<u8>::new();
+        let mut n = 0;
+        while rustls_connection::rustls_connection_wants_write(conn) {
+            let result = rustls_connection::rustls_connection_write_tls(
+                conn,
+                Some(vecdeque_write),
+                &mut buf as *mut _ as *mut _,
+                &mut n,
+            );
+            assert_eq!(result, rustls_io_result(0));
+        }
+        buf.into()
+    }
+
+    fn pump(
+        conn: *mut rustls_connection,
+        tls_in: &[u8],
+        plain_out: &mut [u8],
+    ) -> (rustls_result, usize, usize) {
+        let (mut consumed, mut written) = (usize::MAX, usize::MAX);
+        let result = rustls_connection::rustls_connection_pump(
+            conn,
+            tls_in.as_ptr(),
+            tls_in.len(),
+            &mut consumed,
+            plain_out.as_mut_ptr(),
+            plain_out.len(),
+            &mut written,
+        );
+        (result, consumed, written)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_pump() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+
+        // The handshake can be pumped through with no room for plaintext.
+        while rustls_connection::rustls_connection_is_handshaking(client) {
+            let input = take_tls(client);
+            if !input.is_empty() {
+                assert_eq!(
+                    pump(server, &input, &mut []),
+                    (rustls_result::Ok, input.len(), 0)
+                );
+            }
+            let input = take_tls(server);
+            assert_eq!(
+                pump(client, &input, &mut []),
+                (rustls_result::Ok, input.len(), 0)
+            );
+        }
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // Several records in one go.
+        for chunk in [&b"one "[..], b"two ", b"three"] {
+            assert_eq!(write_plaintext(server, chunk), chunk.len());
+        }
+        let input = take_tls(server);
+        let mut out = [0u8; 64];
+        assert_eq!(
+            pump(client, &input, &mut out),
+            (rustls_result::Ok, input.len(), 13)
+        );
+        assert_eq!(&out[..13], b"one two three");
+
+        // With a small plain_out, feeding stops until the plaintext is read.
+        for chunk in [&[b'a'; 100][..], &[b'b'; 100], &[b'c'; 100]] {
+            assert_eq!(write_plaintext(server, chunk), 100);
+        }
+        let input = take_tls(server);
+        let mut out = [0u8; 64];
+        assert_eq!(
+            pump(client, &input, &mut out),
+            (rustls_result::Ok, input.len(), 64)
+        );
+        let mut received = out.to_vec();
+        assert_eq!(write_plaintext(server, &[b'd'; 100]), 100);
+        let input = take_tls(server);
+        assert_eq!(pump(client, &input, &mut out), (rustls_result::Ok, 0, 64));
+        received.extend_from_slice(&out);
+        let mut offset = 0;
+        while offset < input.len() {
+            let (result, consumed, written) = pump(client, &input[offset..], &mut out);
+            assert_eq!(result, rustls_result::Ok);
+            assert!(consumed > 0 || written > 0);
+            offset += consumed;
+            received.extend_from_slice(&out[..written]);
+        }
+        drain_plaintext(client, &mut received);
+        let mut expected = vec![b'a'; 100];
+        expected.extend_from_slice(&[b'b'; 100]);
+        expected.extend_from_slice(&[b'c'; 100]);
+        expected.extend_from_slice(&[b'd'; 100]);
+        assert_eq!(received, expected);
+
+        // A corrupted record: the good one before it still comes through.
+        assert_eq!(write_plaintext(server, b"good"), 4);
+        let mut input = take_tls(server);
+        let good_len = input.len();
+        assert_eq!(write_plaintext(server, b"evil"), 4);
+        input.extend(take_tls(server));
+        let last = input.len() - 1;
+        input[last] ^= 1;
+        input.extend_from_slice(&[0x17, 0x03, 0x03]);
+        let mut out = [0u8; 64];
+        let (result, consumed, written) = pump(client, &input, &mut out);
+        assert_eq!(result, rustls_result::DecryptError);
+        assert!(consumed > good_len);
+        assert_eq!(written, 4);
+        assert_eq!(&out[..4], b"good");
+
+        let mut n = 0;
+        assert_eq!(
+            rustls_connection::rustls_connection_pump(
+                client,
+                input.as_ptr(),
+                input.len(),
+                null_mut(),
+                out.as_mut_ptr(),
+                out.len(),
+                &mut n,
+            ),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_pump_after_close() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // A clean close ends the reading without an error; read reports it.
+        assert_eq!(write_plaintext(server, b"bye"), 3);
+        rustls_connection::rustls_connection_send_close_notify(server);
+        let input = take_tls(server);
+        let mut out = [0u8; 64];
+        assert_eq!(
+            pump(client, &input, &mut out),
+            (rustls_result::Ok, input.len(), 3)
+        );
+        assert_eq!(&out[..3], b"bye");
+        assert_eq!(read_plaintext(client, &mut out), (rustls_result::Ok, 0));
+        assert_eq!(pump(client, &[], &mut out), (rustls_result::Ok, 0, 0));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // One step of a transport made of two rings: `from` writes into
+    // `out_ring`, an arbitrary number of bytes cross to `in_ring`, and `to`
+    // reads and processes what has arrived.
+    fn ring_step(
+        from: *mut rustls_connection,
+        out_ring: *mut rustls_ring_buffer,
+        in_ring: *mut rustls_ring_buffer,
+        in_limit: usize,
+        to: *mut rustls_connection,
+        rng: &mut XorShift,
+    ) {
+        let mut n = 0;
+        let result = rustls_connection::rustls_connection_write_tls_to_ring(from, out_ring, &mut n);
+        assert!(result == rustls_io_result(0) || result == rustls_io_result(EAGAIN));
+
+        let room = in_limit - rustls_ring_buffer::rustls_ring_buffer_len(in_ring);
+        let mut chunk = vec![0u8; rng.below(room + 1)];
+        let result = rustls_ring_buffer::rustls_ring_buffer_pull(
+            out_ring,
+            chunk.as_mut_ptr(),
+            chunk.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let mut pushed = 0;
+        let result =
+            rustls_ring_buffer::rustls_ring_buffer_push(in_ring, chunk.as_ptr(), n, &mut pushed);
+        assert_eq!((result, pushed), (rustls_result::Ok, n));
+
+        let result = rustls_connection::rustls_connection_read_tls_from_ring(to, in_ring, &mut n);
+        assert!(result == rustls_io_result(0) || result == rustls_io_result(EAGAIN));
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(to),
+            rustls_result::Ok
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_ring_buffer_loopback() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        // Small limits on purpose, so that the rings fill and wrap often.
+        let limits = [1500, 700, 900, 3000];
+        let rings: Vec<*mut rustls_ring_buffer> = limits
+            .iter()
+            .map(|&limit| rustls_ring_buffer::rustls_ring_buffer_new(limit))
+            .collect();
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+
+        let upload: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let download: Vec<u8> = (0..50_000u32).map(|i| (i % 241) as u8).collect();
+        let (mut sent_up, mut sent_down) = (0, 0);
+        let (mut received_up, mut received_down) = (Vec::new(), Vec::new());
+        for _ in 0..100_000 {
+            if received_up.len() == upload.len() && received_down.len() == download.len() {
+                break;
+            }
+            if !rustls_connection::rustls_connection_is_handshaking(client) {
+                let end = upload.len().min(sent_up + rng.below(5000));
+                sent_up += write_plaintext(client, &upload[sent_up..end]);
+            }
+            if !rustls_connection::rustls_connection_is_handshaking(server) {
+                let end = download.len().min(sent_down + rng.below(5000));
+                sent_down += write_plaintext(server, &download[sent_down..end]);
+            }
+            ring_step(client, rings[0], rings[1], limits[1], server, &mut rng);
+            ring_step(server, rings[2], rings[3], limits[3], client, &mut rng);
+            drain_plaintext(server, &mut received_up);
+            drain_plaintext(client, &mut received_down);
+        }
+        assert_eq!(received_up, upload);
+        assert_eq!(received_down, download);
+        for ring in rings {
+            assert_eq!(rustls_ring_buffer::rustls_ring_buffer_len(ring), 0);
+            rustls_ring_buffer::rustls_ring_buffer_free(ring);
+        }
+
+        let ring = rustls_ring_buffer::rustls_ring_buffer_new(10);
+        let mut n = usize::MAX;
+        assert_eq!(
+            rustls_connection::rustls_connection_read_tls_from_ring(client, ring, &mut n),
+            rustls_io_result(EAGAIN)
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_write_tls_to_ring(client, ring, null_mut()),
+            rustls_io_result(EINVAL)
+        );
+        rustls_ring_buffer::rustls_ring_buffer_free(ring);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn debug_dump(conn: *mut rustls_connection) -> String {
+        let mut buf = [0xffu8; 512];
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_debug_dump(
+            conn,
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            &mut n,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(buf[n], 0);
+        let dump = String::from_utf8(buf[..n].to_vec()).unwrap();
+        assert!(!dump.contains('\n'));
+        dump
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_debug_dump() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+
+        let dump = debug_dump(client);
+        assert!(dump.starts_with("client handshaking=true"), "{}", dump);
+        assert!(dump.contains("wants_write=true"), "{}", dump);
+        assert!(
+            dump.contains("version=none suite=none resumed=unknown"),
+            "{}",
+            dump
+        );
+        // Counting the ClientHello doesn't lose it.
+        assert!(!dump.contains("tls_to_write=0 "), "{}", dump);
+
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(write_plaintext(server, b"hello"), 5);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        let dump = debug_dump(client);
+        assert!(dump.contains("handshaking=false"), "{}", dump);
+        assert!(
+            dump.contains("plaintext_to_read=5 tls_to_write=0"),
+            "{}",
+            dump
+        );
+        assert!(dump.contains("version=TLSv1_3 suite=TLS13_"), "{}", dump);
+        assert!(dump.contains("resumed=no"), "{}", dump);
+        assert!(dump.contains("close_notify_sent=false close_notify_received=false"));
+        assert!(debug_dump(server).starts_with("server "));
+
+        rustls_connection::rustls_connection_send_close_notify(server);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        rustls_connection::rustls_connection_send_close_notify(client);
+        let dump = debug_dump(client);
+        assert!(
+            dump.contains("close_notify_sent=true close_notify_received=true"),
+            "{}",
+            dump
+        );
+        assert!(!dump.contains("fatal_error"), "{}", dump);
+
+        // Too small a buffer gets nothing.
+        let mut buf = [0u8; 16];
+        let mut n = usize::MAX;
+        let result = rustls_connection::rustls_connection_debug_dump(
+            client,
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            &mut n,
+        );
+        assert_eq!((result, n), (rustls_result::InsufficientSize, 0));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn tls_server_end_point(conn: *const rustls_connection) -> (rustls_result, Vec<u8>) {
+        let mut buf = [0u8; 64];
+        let mut n = usize::MAX;
+        let result =
+            crate::client::rustls_client_connection_get_channel_binding_tls_server_end_point(
+                conn,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut n,
+            );
+        (result, buf[..n.min(buf.len())].to_vec())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_channel_binding_tls_server_end_point() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        // The expected hashes were computed with
+        //   openssl x509 -in testdata/localhost/cert.pem -outform der | openssl dgst -sha256
+        //   openssl x509 -in testdata/sha384/cert.pem -outform der | openssl dgst -sha384
+        // since the first certificate is signed with SHA-256 and the second
+        // with SHA-384.
+        for (cert, key, expected) in [
+            (
+                include_str!("../testdata/localhost/cert.pem"),
+                include_str!("../testdata/localhost/key.pem"),
+                "120b1d2f61671ac7db558366da18e85a9b2b8c0f8982583b541ec1eeeae0160c",
+            ),
+            (
+                include_str!("../testdata/sha384/cert.pem"),
+                include_str!("../testdata/sha384/key.pem"),
+                "b23fbfabef563f35010bbf495b68583f476b6ad4f3edf6b3\
+                 cabad22f082fa32a254bfe10da85cfdbb52cd9d16a3691d6",
+            ),
+        ] {
+            let server_config = build_server_config_with(
+                rustls_server_config_builder::rustls_server_config_builder_new(),
+                cert,
+                key,
+            );
+            let client = make_client_connection(client_config, "localhost");
+            let server = make_server_connection(server_config);
+            assert_eq!(
+                tls_server_end_point(client).0,
+                rustls_result::HandshakeNotComplete
+            );
+            assert_eq!(do_handshake(client, server), rustls_result::Ok);
+            let (result, hash) = tls_server_end_point(client);
+            assert_eq!(result, rustls_result::Ok);
+            assert_eq!(hash, unhex(expected));
+            assert_eq!(
+                tls_server_end_point(server).0,
+                rustls_result::InvalidParameter
+            );
+
+            let mut buf = [0u8; 31];
+            let mut n = usize::MAX;
+            let result =
+                crate::client::rustls_client_connection_get_channel_binding_tls_server_end_point(
+                    client,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut n,
+                );
+            assert_eq!((result, n), (rustls_result::InsufficientSize, 0));
+
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+            rustls_server_config::rustls_server_config_free(server_config);
+        }
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    fn peer_closed(conn: *const rustls_connection) -> bool {
+        let mut closed = false;
+        let result = rustls_connection::rustls_connection_peer_closed(conn, &mut closed);
+        assert_eq!(result, rustls_result::Ok);
+        closed
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_peer_closed() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert!(!peer_closed(client));
+
+        // Data and close_notify in one flight: closed while the data is
+        // still there to read.
+        assert_eq!(write_plaintext(server, b"last words"), 10);
+        rustls_connection::rustls_connection_send_close_notify(server);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert!(peer_closed(client));
+        assert!(!peer_closed(server));
+        let mut out = [0u8; 64];
+        assert_eq!(read_plaintext(client, &mut out), (rustls_result::Ok, 10));
+        assert_eq!(&out[..10], b"last words");
+        assert!(peer_closed(client));
+
+        // The client can still send.
+        assert_eq!(write_plaintext(client, b"ack"), 3);
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        assert_eq!(read_plaintext(server, &mut out), (rustls_result::Ok, 3));
+        assert!(!peer_closed(server));
+
+        let mut closed = false;
+        assert_eq!(
+            rustls_connection::rustls_connection_peer_closed(client, null_mut()),
+            rustls_result::NullParameter
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_peer_closed(null(), &mut closed),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_connection_reset() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let mut marker = 0u8;
+        let userdata = &mut marker as *mut u8 as *mut c_void;
+        rustls_connection::rustls_connection_set_userdata(client, userdata);
+
+        // Connect, leave some plaintext unread, and close.
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(write_plaintext(server, b"unread"), 6);
+        rustls_connection::rustls_connection_send_close_notify(server);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert!(peer_closed(client));
+        rustls_connection::rustls_connection_send_close_notify(client);
+        assert_eq!(transfer(client, server), rustls_result::Ok);
+        rustls_connection::rustls_connection_free(server);
+        let before = counters(client);
+
+        let result = rustls_client_config::rustls_client_connection_reset(
+            client,
+            client_config,
+            "localhost\0".as_ptr() as *const c_char,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        assert!(rustls_connection::rustls_connection_is_handshaking(client));
+        assert!(!peer_closed(client));
+        assert_eq!(
+            handshake_duration(client).0,
+            rustls_result::HandshakeNotComplete
+        );
+        let mut out = [0u8; 64];
+        assert_eq!(
+            read_plaintext(client, &mut out).0,
+            rustls_result::PlaintextEmpty
+        );
+
+        // The same pointer connects again, keeping its userdata, and its
+        // counters keep running.
+        let mut calls = 0u32;
+        let result = rustls_connection::rustls_connection_set_handshake_done_callback(
+            client,
+            Some(count_handshake_done),
+            &mut calls as *mut u32 as *mut c_void,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(calls, 1);
+        assert_eq!(write_plaintext(server, b"again"), 5);
+        assert_eq!(transfer(server, client), rustls_result::Ok);
+        assert_eq!(read_plaintext(client, &mut out), (rustls_result::Ok, 5));
+        assert_eq!(&out[..5], b"again");
+        let mut got = null_mut();
+        assert_eq!(
+            rustls_connection::rustls_connection_get_userdata(client, &mut got),
+            rustls_result::Ok
+        );
+        assert_eq!(got, userdata);
+        let after = counters(client);
+        assert!(after.tls_read > before.tls_read && after.tls_written > before.tls_written);
+        assert_eq!(after.plaintext_read, before.plaintext_read + 5);
+
+        // Only client connections can be reset, and a bad name changes
+        // nothing.
+        for (conn, name, expected) in [
+            (server, "localhost\0", rustls_result::InvalidParameter),
+            (
+                client,
+                "not a hostname\0",
+                rustls_result::InvalidDnsNameError,
+            ),
+        ] {
+            let result = rustls_client_config::rustls_client_connection_reset(
+                conn,
+                client_config,
+                name.as_ptr() as *const c_char,
+            );
+            assert_eq!(result, expected);
+        }
+        assert!(!rustls_connection::rustls_connection_is_handshaking(client));
+        assert_eq!(
+            rustls_client_config::rustls_client_connection_reset(client, client_config, null()),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn shutdown(conn: *mut rustls_connection, out_len: usize) -> (rustls_result, Vec<u8>, bool) {
+        let mut buf = vec![0u8; out_len];
+        let (mut n, mut complete) = (usize::MAX, false);
+        let result = rustls_connection::rustls_connection_shutdown(
+            conn,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut n,
+            &mut complete,
+        );
+        buf.truncate(n);
+        (result, buf, complete)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_shutdown() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // Both sides shut down through a small buffer, one still having
+        // plaintext to send, then hand each other what came out.
+        assert_eq!(write_plaintext(client, b"last words"), 10);
+        let mut to_server = Vec::new();
+        let mut to_client = Vec::new();
+        for (conn, out) in [(client, &mut to_server), (server, &mut to_client)] {
+            loop {
+                let (result, bytes, complete) = shutdown(conn, 7);
+                assert_eq!(result, rustls_result::Ok);
+                assert!(bytes.len() <= 7);
+                out.extend_from_slice(&bytes);
+                if complete {
+                    break;
+                }
+                assert_eq!(bytes.len(), 7);
+            }
+            assert!(!rustls_connection::rustls_connection_wants_write(conn));
+            // Further calls have nothing left to do.
+            assert_eq!(shutdown(conn, 7), (rustls_result::Ok, vec![], true));
+        }
+
+        let mut out = [0u8; 64];
+        assert_eq!(
+            pump(server, &to_server, &mut out),
+            (rustls_result::Ok, to_server.len(), 10)
+        );
+        assert_eq!(&out[..10], b"last words");
+        assert_eq!(read_plaintext(server, &mut out), (rustls_result::Ok, 0));
+        assert_eq!(
+            pump(client, &to_client, &mut out),
+            (rustls_result::Ok, to_client.len(), 0)
+        );
+        assert_eq!(read_plaintext(client, &mut out), (rustls_result::Ok, 0));
+
+        let (mut n, mut complete) = (0, false);
+        assert_eq!(
+            rustls_connection::rustls_connection_shutdown(
+                client,
+                null_mut(),
+                0,
+                &mut n,
+                &mut complete
+            ),
+            rustls_result::NullParameter
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_shutdown(
+                client,
+                out.as_mut_ptr(),
+                out.len(),
+                &mut n,
+                null_mut()
+            ),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_shutdown_after_send_close_notify() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // The alert already queued is the only one sent.
+        rustls_connection::rustls_connection_send_close_notify(client);
+        let queued = tls_bytes_to_write(client);
+        let (result, bytes, complete) = shutdown(client, 1024);
+        assert_eq!(
+            (result, bytes.len(), complete),
+            (rustls_result::Ok, queued, true)
+        );
+
+        let mut out = [0u8; 64];
+        assert_eq!(
+            pump(server, &bytes, &mut out),
+            (rustls_result::Ok, queued, 0)
+        );
+        assert_eq!(read_plaintext(server, &mut out), (rustls_result::Ok, 0));
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn make_client_connection_with_name(
+        config: *const rustls_client_config,
+        server_name: &str,
+    ) -> *mut rustls_connection {
+        let server_name = format!("{}\0", server_name);
+        let mut name: *const rustls_server_name = null();
+        let result = rustls_server_name::rustls_server_name_new(
+            server_name.as_ptr() as *const c_char,
+            &mut name,
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let mut conn: *mut rustls_connection = null_mut();
+        let result =
+            rustls_client_config::rustls_client_connection_new_with_name(config, name, &mut conn);
+        assert_eq!(result, rustls_result::Ok);
+        rustls_server_name::rustls_server_name_free(name);
+        conn
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_connection_new_with_name() {
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+
+        // The verifier sees the same name and certificate either way.
+        let mut logs = Vec::new();
+        for with_name in [false, true] {
+            let client_config = recording_client_config();
+            let client = if with_name {
+                make_client_connection_with_name(client_config, "localhost")
+            } else {
+                make_client_connection(client_config, "localhost")
+            };
+            let server = make_server_connection(server_config);
+            let mut log = VerifierLog::default();
+            rustls_connection::rustls_connection_set_userdata(
+                client,
+                &mut log as *mut _ as *mut c_void,
+            );
+            assert_eq!(do_handshake(client, server), rustls_result::Ok);
+            logs.push((log.server_names, log.chains));
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+            rustls_client_config::rustls_client_config_free(client_config);
+        }
+        assert_eq!(logs[0].0, vec!["localhost".to_string()]);
+        assert_eq!(logs[0], logs[1]);
+
+        // And the built-in verifier reaches the same verdict.
+        for (name, expected) in [
+            ("localhost", rustls_result::Ok),
+            ("example.com", rustls_result::CertNotValidForName),
+        ] {
+            for with_name in [false, true] {
+                let client_config = build_verifying_client_config(
+                    rustls_client_config_builder::rustls_client_config_builder_new(),
+                );
+                let client = if with_name {
+                    make_client_connection_with_name(client_config, name)
+                } else {
+                    make_client_connection(client_config, name)
+                };
+                let server = make_server_connection(server_config);
+                assert_eq!(do_handshake(client, server), expected);
+                rustls_connection::rustls_connection_free(client);
+                rustls_connection::rustls_connection_free(server);
+                rustls_client_config::rustls_client_config_free(client_config);
+            }
+        }
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // A sink that takes at most `capacity` bytes in total, then reports
+    // `full_with` (or takes 0 bytes, if that is 0).
+    struct CappedSink {
+        out: Vec<u8>,
+        capacity: usize,
+        full_with: i32,
+        calls: usize,
+    }
+
+    unsafe extern "C" fn capped_write(
+        userdata: *mut c_void,
+        buf: *const u8,
+        n: size_t,
+        out_n: *mut size_t,
+    ) -> rustls_io_result {
+        let sink = &mut *(userdata as *mut CappedSink);
+        sink.calls += 1;
+        let n = min(n, sink.capacity - sink.out.len());
+        if n == 0 && sink.full_with != 0 {
+            return rustls_io_result(sink.full_with);
+        }
+        sink.out.extend_from_slice(slice::from_raw_parts(buf, n));
+        *out_n = n;
+        rustls_io_result(0)
+    }
+
+    fn write_tls_drain(
+        conn: *mut rustls_connection,
+        sink: &mut CappedSink,
+    ) -> (rustls_io_result, usize) {
+        let mut total = usize::MAX;
+        let result = rustls_connection::rustls_connection_write_tls_drain(
+            conn,
+            Some(capped_write),
+            sink as *mut CappedSink as *mut c_void,
+            &mut total,
+        );
+        (result, total)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_write_tls_drain() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+
+        // Several records go out through one call, in as many callbacks as
+        // needed.
+        let mut expected = Vec::new();
+        for chunk in [&[b'a'; 3000][..], &[b'b'; 3000], &[b'c'; 3000]] {
+            assert_eq!(write_plaintext(server, chunk), chunk.len());
+            expected.extend_from_slice(chunk);
+        }
+        let queued = tls_bytes_to_write(server);
+        let mut sink = CappedSink {
+            out: Vec::new(),
+            capacity: usize::MAX,
+            full_with: EAGAIN,
+            calls: 0,
+        };
+        assert_eq!(
+            write_tls_drain(server, &mut sink),
+            (rustls_io_result(0), queued)
+        );
+        assert_eq!(sink.out.len(), queued);
+        assert!(!rustls_connection::rustls_connection_wants_write(server));
+
+        // Nothing pending: nothing to do.
+        let calls = sink.calls;
+        assert_eq!(write_tls_drain(server, &mut sink), (rustls_io_result(0), 0));
+        assert_eq!(sink.calls, calls);
+
+        // A full callback, whether it says EAGAIN or takes nothing, stops the
+        // drain with EAGAIN; what's left goes on the next call.
+        for full_with in [EAGAIN, 0] {
+            for chunk in [&[b'd'; 3000][..], &[b'e'; 3000]] {
+                assert_eq!(write_plaintext(server, chunk), chunk.len());
+                expected.extend_from_slice(chunk);
+            }
+            let queued = tls_bytes_to_write(server);
+            sink.full_with = full_with;
+            sink.capacity = sink.out.len() + queued / 3;
+            assert_eq!(
+                write_tls_drain(server, &mut sink),
+                (rustls_io_result(EAGAIN), queued / 3)
+            );
+            assert_eq!(tls_bytes_to_write(server), queued - queued / 3);
+            sink.capacity = usize::MAX;
+            assert_eq!(
+                write_tls_drain(server, &mut sink),
+                (rustls_io_result(0), queued - queued / 3)
+            );
+        }
+
+        // Other errors are passed through, with the count so far.
+        assert_eq!(write_plaintext(server, b"more"), 4);
+        expected.extend_from_slice(b"more");
+        sink.capacity = sink.out.len() + 10;
+        sink.full_with = libc::EPIPE;
+        assert_eq!(
+            write_tls_drain(server, &mut sink),
+            (rustls_io_result(libc::EPIPE), 10)
+        );
+        sink.capacity = usize::MAX;
+        assert_eq!(write_tls_drain(server, &mut sink).0, rustls_io_result(0));
+
+        // The client gets everything, in order.
+        let mut out = vec![0u8; expected.len() + 64];
+        let mut received = Vec::new();
+        let mut offset = 0;
+        while offset < sink.out.len() {
+            let (result, consumed, written) = pump(client, &sink.out[offset..], &mut out);
+            assert_eq!(result, rustls_result::Ok);
+            offset += consumed;
+            received.extend_from_slice(&out[..written]);
+        }
+        drain_plaintext(client, &mut received);
+        assert_eq!(received, expected);
+
+        let mut total = 0;
+        assert_eq!(
+            rustls_connection::rustls_connection_write_tls_drain(
+                server,
+                Some(capped_write),
+                &mut sink as *mut CappedSink as *mut c_void,
+                null_mut(),
+            ),
+            rustls_io_result(EINVAL)
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_write_tls_drain(
+                server,
+                None,
+                null_mut(),
+                &mut total,
+            ),
+            rustls_io_result(EINVAL)
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    const READ: u32 = rustls_interest::Read as u32;
+    const WRITE: u32 = rustls_interest::Write as u32;
+    const CLOSED: u32 = rustls_interest::Closed as u32;
+
+    // The interest flags for `conn`, checked against the predicates they
+    // summarize.
+    fn interest(conn: *const rustls_connection) -> u32 {
+        let mut flags = u32::MAX;
+        let result = rustls_connection::rustls_connection_poll_interest(conn, &mut flags);
+        assert_eq!(result, rustls_result::Ok);
+        assert_eq!(
+            flags & READ != 0,
+            rustls_connection::rustls_connection_wants_read(conn)
+        );
+        assert_eq!(
+            flags & WRITE != 0,
+            rustls_connection::rustls_connection_wants_write(conn)
+        );
+        flags
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_poll_interest() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+
+        // Handshake: each side alternates between wanting to write its
+        // flight and waiting for the other's.
+        assert_eq!(interest(client), WRITE);
+        assert_eq!(interest(server), READ);
+        while rustls_connection::rustls_connection_is_handshaking(client)
+            || rustls_connection::rustls_connection_is_handshaking(server)
+        {
+            for (from, to) in [(client, server), (server, client)] {
+                deliver_tls(from, to);
+                assert_eq!(interest(from) & (WRITE | CLOSED), 0);
+                assert_eq!(
+                    rustls_connection::rustls_connection_process_new_packets(to),
+                    rustls_result::Ok
+                );
+                assert_eq!(interest(to) & CLOSED, 0);
+            }
+        }
+
+        // Steady state: waiting for data, and wanting to write it out once
+        // there is some.
+        deliver_tls(client, server);
+        assert_eq!(interest(client), READ);
+        assert_eq!(interest(server), READ);
+        assert_eq!(write_plaintext(server, b"hello"), 5);
+        assert_eq!(interest(server), READ | WRITE);
+        deliver_tls(server, client);
+        assert_eq!(interest(server), READ);
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(client),
+            rustls_result::Ok
+        );
+        // Buffered plaintext holds off reading more.
+        assert_eq!(interest(client), 0);
+        let mut out = [0u8; 16];
+        assert_eq!(read_plaintext(client, &mut out), (rustls_result::Ok, 5));
+        assert_eq!(interest(client), READ);
+
+        // The server closes first: it has the alert to write, and the client
+        // reads nothing more once it has seen it.
+        rustls_connection::rustls_connection_send_close_notify(server);
+        assert_eq!(interest(server), READ | WRITE);
+        deliver_tls(server, client);
+        assert_eq!(interest(server), READ);
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(client),
+            rustls_result::Ok
+        );
+        assert_eq!(interest(client), CLOSED);
+
+        // Then the client: once its alert is out, it has nothing left to do.
+        rustls_connection::rustls_connection_send_close_notify(client);
+        assert_eq!(interest(client), WRITE | CLOSED);
+        deliver_tls(client, server);
+        assert_eq!(interest(client), CLOSED);
+        assert_eq!(
+            rustls_connection::rustls_connection_process_new_packets(server),
+            rustls_result::Ok
+        );
+        assert_eq!(interest(server), CLOSED);
+
+        let mut flags = 0;
+        assert_eq!(
+            rustls_connection::rustls_connection_poll_interest(client, null_mut()),
+            rustls_result::NullParameter
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_poll_interest(null(), &mut flags),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_poll_interest_closed_by_eof_or_error() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+
+        // The transport ending counts, with or without close_notify.
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(interest(client) & CLOSED, 0);
+        read_tls_eof(client);
+        assert_ne!(interest(client) & CLOSED, 0);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        // So does a failure to process what arrived.
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        assert_eq!(write_plaintext(server, b"hello"), 5);
+        let mut input = take_tls(server);
+        let last = input.len() - 1;
+        input[last] ^= 1;
+        let mut out = [0u8; 16];
+        assert_eq!(
+            pump(client, &input, &mut out).0,
+            rustls_result::DecryptError
+        );
+        assert_ne!(interest(client) & CLOSED, 0);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    fn counters(conn: *const rustls_connection) -> rustls_counters {
+        let mut counters = rustls_counters {
+            plaintext_written: u64::MAX,
+            plaintext_read: u64::MAX,
+            tls_written: u64::MAX,
+            tls_read: u64::MAX,
+        };
+        let result = rustls_connection::rustls_connection_get_counters(conn, &mut counters);
+        assert_eq!(result, rustls_result::Ok);
+        counters
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_counters() {
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(counters(client), rustls_counters::default());
+
+        // The handshake is all overhead.
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let (c, s) = (counters(client), counters(server));
+        assert!(c.tls_written > 0 && s.tls_written > 0);
+        assert_eq!((c.tls_written, c.tls_read), (s.tls_read, s.tls_written));
+        assert_eq!((c.plaintext_written, c.plaintext_read), (0, 0));
+        assert_eq!((s.plaintext_written, s.plaintext_read), (0, 0));
+
+        // A known transfer, sent by peeking and consuming, read back in
+        // pieces.
+        let data = vec![7u8; 40000];
+        assert_eq!(write_all_accepted(client, &data), data.len());
+        let output = peek_tls_output(client);
+        assert!(output.len() > data.len());
+        assert_eq!(
+            rustls_connection::rustls_connection_consume_tls_output(client, output.len()),
+            rustls_result::Ok
+        );
+        let mut out = [0u8; 1000];
+        let mut received = 0;
+        let mut offset = 0;
+        while received < data.len() {
+            let (result, consumed, written) = pump(server, &output[offset..], &mut out);
+            assert_eq!(result, rustls_result::Ok);
+            offset += consumed;
+            received += written;
+        }
+        let (c2, s2) = (counters(client), counters(server));
+        assert_eq!(c2.plaintext_written, data.len() as u64);
+        assert_eq!(s2.plaintext_read, data.len() as u64);
+        assert_eq!(c2.tls_written - c.tls_written, output.len() as u64);
+        assert_eq!(s2.tls_read - s.tls_read, output.len() as u64);
+        assert!(c2.tls_written - c.tls_written > c2.plaintext_written);
+        assert_eq!((c2.tls_read, c2.plaintext_read), (c.tls_read, 0));
+
+        // Calls that move nothing count nothing.
+        assert_eq!(
+            read_plaintext(server, &mut out).0,
+            rustls_result::PlaintextEmpty
+        );
+        let mut sink = CappedSink {
+            out: Vec::new(),
+            capacity: 0,
+            full_with: EAGAIN,
+            calls: 0,
+        };
+        assert_eq!(write_plaintext(server, b"pong"), 4);
+        assert_eq!(
+            write_tls_drain(server, &mut sink),
+            (rustls_io_result(EAGAIN), 0)
+        );
+        let s3 = counters(server);
+        assert_eq!(
+            (s3.tls_written, s3.plaintext_read),
+            (s2.tls_written, s2.plaintext_read)
+        );
+        assert_eq!(s3.plaintext_written, 4);
+
+        let mut c = rustls_counters::default();
+        assert_eq!(
+            rustls_connection::rustls_connection_get_counters(client, null_mut()),
+            rustls_result::NullParameter
+        );
+        assert_eq!(
+            rustls_connection::rustls_connection_get_counters(null(), &mut c),
+            rustls_result::NullParameter
+        );
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_counters_across_would_block() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let client_config =
+            build_client_config(rustls_client_config_builder::rustls_client_config_builder_new());
+        let server_config =
+            build_server_config(rustls_server_config_builder::rustls_server_config_builder_new());
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        let before = counters(client);
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        theirs.set_nonblocking(true).unwrap();
+        set_send_buffer(ours.as_raw_fd(), 4096);
+
+        // Retried writes, through every fd-based path, add up to exactly
+        // what reached the socket.
+        let mut would_block = 0;
+        let mut on_socket = 0;
+        let mut buf = [0u8; 8192];
+        let data = vec![1u8; 100 * 1024];
+        let mut sent = write_all_accepted(client, &data);
+        for round in 0.. {
+            if sent == data.len() && !rustls_connection::rustls_connection_wants_write(client) {
+                break;
+            }
+            if round % 2 == 0 {
+                let mut n = 0;
+                let result = rustls_connection::rustls_connection_write_tls_to_fd(
+                    client,
+                    ours.as_raw_fd(),
+                    &mut n,
+                );
+                if result == rustls_io_result(EAGAIN) {
+                    would_block += 1;
+                }
+            } else if rustls_connection::rustls_connection_complete_io_fd(
+                client,
+                ours.as_raw_fd(),
+                null_mut(),
+                null_mut(),
+            ) == rustls_result::WouldBlock
+            {
+                would_block += 1;
+            }
+            loop {
+                match theirs.read(&mut buf) {
+                    Ok(n) => on_socket += n,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            sent += write_all_accepted(client, &data[sent..]);
+        }
+        assert!(would_block > 0);
+        let c = counters(client);
+        assert_eq!(c.tls_written - before.tls_written, on_socket as u64);
+        assert_eq!(c.plaintext_written, data.len() as u64);
+
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+}
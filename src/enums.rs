@@ -1,3 +1,7 @@
+use rustls::SupportedProtocolVersion;
+
+use crate::rustls_result;
+
 #[repr(C)]
 #[allow(dead_code)]
 /// Definitions of known TLS protocol versions.
@@ -34,6 +38,28 @@ pub static RUSTLS_DEFAULT_VERSIONS: [u16; 2] = [
 #[no_mangle]
 pub static RUSTLS_DEFAULT_VERSIONS_LEN: usize = RUSTLS_DEFAULT_VERSIONS.len();
 
+/// Map a list of TLS protocol version numbers onto rustls' supported
+/// versions, keeping the caller's order. An empty list is rejected with
+/// InvalidParameter, since no handshake could succeed with it; a version
+/// rustls does not implement is rejected with UnsupportedProtocolVersion.
+pub(crate) fn protocol_versions_from_u16s(
+    versions: &[u16],
+) -> Result<Vec<&'static SupportedProtocolVersion>, rustls_result> {
+    if versions.is_empty() {
+        return Err(rustls_result::InvalidParameter);
+    }
+    versions
+        .iter()
+        .map(|&v| {
+            rustls::ALL_VERSIONS
+                .iter()
+                .find(|supported| supported.version.get_u16() == v)
+                .copied()
+                .ok_or(rustls_result::UnsupportedProtocolVersion)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -55,4 +81,27 @@ mod tests {
             assert_eq!(original.version.get_u16(), *ffi);
         }
     }
+
+    #[test]
+    fn protocol_versions_from_u16s_strict() {
+        let tls13 = rustls_tls_version::Tlsv1_3 as u16;
+        let tls12 = rustls_tls_version::Tlsv1_2 as u16;
+        let versions = protocol_versions_from_u16s(&[tls12, tls13]).unwrap();
+        assert_eq!(
+            versions,
+            vec![&rustls::version::TLS12, &rustls::version::TLS13]
+        );
+        assert_eq!(
+            protocol_versions_from_u16s(&[]),
+            Err(rustls_result::InvalidParameter)
+        );
+        assert_eq!(
+            protocol_versions_from_u16s(&[tls13, rustls_tls_version::Tlsv1_1 as u16]),
+            Err(rustls_result::UnsupportedProtocolVersion)
+        );
+        assert_eq!(
+            protocol_versions_from_u16s(&[0x1234]),
+            Err(rustls_result::UnsupportedProtocolVersion)
+        );
+    }
 }
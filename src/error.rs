@@ -63,6 +63,10 @@ impl rustls_result {
                 | CertInvalidPurpose
                 | CertApplicationVerificationFailure
                 | CertOtherError
+                | PinViolation
+                | PinnedCertMismatch
+                | TlsaMismatch
+                | CtLogsExpired
                 | CertSCTMalformed
                 | CertSCTInvalidSignature
                 | CertSCTTimestampInFuture
@@ -72,6 +76,26 @@ impl rustls_result {
     }
 }
 
+/// A certificate error specific to this library, such as a pin violation.
+/// Verifiers report these through `CertificateError::Other`, and map_error
+/// turns them back into the rustls_result they carry.
+#[derive(Debug)]
+pub(crate) struct LocalCertError(pub(crate) rustls_result);
+
+impl LocalCertError {
+    pub(crate) fn error(result: rustls_result) -> rustls::Error {
+        Error::InvalidCertificate(CertificateError::Other(Arc::new(LocalCertError(result))))
+    }
+}
+
+impl Display for LocalCertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for LocalCertError {}
+
 /// For cert-related rustls_results, turn them into a rustls::Error. For other
 /// inputs, including Ok, return rustls::Error::General.
 pub(crate) fn cert_result_to_error(result: rustls_result) -> rustls::Error {
@@ -93,6 +117,9 @@ pub(crate) fn cert_result_to_error(result: rustls_result) -> rustls::Error {
             InvalidCertificate(CertificateError::ApplicationVerificationFailure)
         }
         CertOtherError => InvalidCertificate(CertificateError::Other(Arc::from(Box::from("")))),
+        PinViolation | PinnedCertMismatch | TlsaMismatch | CtLogsExpired => {
+            LocalCertError::error(result)
+        }
         CertSCTMalformed => InvalidSct(sct::Error::MalformedSct),
         CertSCTInvalidSignature => InvalidSct(sct::Error::InvalidSignature),
         CertSCTTimestampInFuture => InvalidSct(sct::Error::TimestampInFuture),
@@ -153,6 +180,25 @@ pub enum rustls_result {
     PlaintextEmpty = 7011,
     AcceptorNotReady = 7012,
     AlreadyUsed = 7013,
+    UnsupportedProtocolVersion = 7014,
+    UnsupportedCipherSuite = 7015,
+    NoCertificatesFound = 7016,
+    Unsupported = 7017,
+    EnvironmentNotSet = 7018,
+    CertKeyMismatch = 7019,
+    UnsupportedPrivateKeyType = 7020,
+    NoPrivateKeyFound = 7021,
+    PinViolation = 7022,
+    PinnedCertMismatch = 7023,
+    EarlyDataUnavailable = 7024,
+    LoggerAlreadySet = 7025,
+    HandshakeAlreadyComplete = 7026,
+    BufferLimitReached = 7027,
+    WouldBlock = 7028,
+    TlsaMismatch = 7029,
+    VerifiedChainUnavailable = 7030,
+    NoCertificateConfigured = 7031,
+    CtLogsExpired = 7032,
 
     // From https://docs.rs/rustls/latest/rustls/enum.Error.html
     NoCertificatesPresented = 7101,
@@ -323,6 +369,10 @@ pub(crate) fn map_error(input: rustls::Error) -> rustls_result {
             CertificateError::NotValidForName => CertNotValidForName,
             CertificateError::InvalidPurpose => CertInvalidPurpose,
             CertificateError::ApplicationVerificationFailure => CertApplicationVerificationFailure,
+            CertificateError::Other(e) => match e.downcast_ref::<LocalCertError>() {
+                Some(LocalCertError(result)) => *result,
+                None => CertOtherError,
+            },
             _ => CertOtherError,
         },
 
@@ -414,6 +464,33 @@ impl Display for rustls_result {
                 f,
                 "tried to use a rustls struct after it had been converted to another struct"
             ),
+            UnsupportedProtocolVersion => {
+                write!(f, "TLS protocol version is unknown or not supported")
+            }
+            UnsupportedCipherSuite => write!(f, "cipher suite is unknown or not supported"),
+            NoCertificatesFound => write!(f, "no certificates found in input"),
+            Unsupported => write!(f, "operation not supported by this build of rustls-ffi"),
+            EnvironmentNotSet => write!(f, "required environment variable is not set"),
+            CertKeyMismatch => write!(f, "private key does not match the certificate"),
+            UnsupportedPrivateKeyType => write!(f, "private key algorithm is not supported"),
+            NoPrivateKeyFound => write!(f, "no private key found in PEM input"),
+            PinViolation => write!(f, "server certificate chain does not match any pinned key"),
+            PinnedCertMismatch => write!(f, "server certificate is not the pinned certificate"),
+            EarlyDataUnavailable => write!(f, "early data cannot be written on this connection"),
+            LoggerAlreadySet => write!(f, "a different logger is already registered"),
+            HandshakeAlreadyComplete => write!(f, "the handshake has already completed"),
+            BufferLimitReached => write!(f, "the connection's buffer limit was reached"),
+            WouldBlock => write!(f, "the operation would block; try again once ready"),
+            TlsaMismatch => write!(f, "server certificate chain does not match any TLSA record"),
+            VerifiedChainUnavailable => write!(
+                f,
+                "the verifier in use does not record the verified certificate chain"
+            ),
+            NoCertificateConfigured => write!(f, "no server certificate configured"),
+            CtLogsExpired => write!(
+                f,
+                "the certificate transparency logs are past their validation deadline"
+            ),
 
             CertEncodingBad => Error::InvalidCertificate(CertificateError::BadEncoding).fmt(f),
             CertExpired => Error::InvalidCertificate(CertificateError::Expired).fmt(f),
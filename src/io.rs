@@ -1,15 +1,29 @@
-use std::io::{Error, IoSlice, Read, Result, Write};
+use std::io::{Error, ErrorKind, IoSlice, Read, Result, Write};
 
-use libc::{c_void, size_t};
+use libc::{c_int, c_void, size_t};
 
 use crate::error::rustls_io_result;
 
+/// Check the count a callback reported against the size of the buffer it was
+/// given. Believing a larger count would make rustls consume bytes that were
+/// never transferred.
+fn check_count(out_n: usize, len: usize) -> Result<usize> {
+    if out_n > len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "callback reported more bytes than the buffer holds",
+        ));
+    }
+    Ok(out_n)
+}
+
 /// A callback for rustls_connection_read_tls.
 /// An implementation of this callback should attempt to read up to n bytes from the
 /// network, storing them in `buf`. If any bytes were stored, the implementation should
-/// set out_n to the number of bytes stored and return 0. If there was an error,
-/// the implementation should return a nonzero rustls_io_result, which will be
-/// passed through to the caller. On POSIX systems, returning `errno` is convenient.
+/// set out_n to the number of bytes stored and return 0. Setting out_n to 0 and
+/// returning 0 signals EOF. Setting out_n to more than n is treated as an error.
+/// If there was an error, the implementation should return a nonzero
+/// rustls_io_result, which will be passed through to the caller. On POSIX systems, returning `errno` is convenient.
 /// On other systems, any appropriate error code works.
 /// It's best to make one read attempt to the network per call. Additional reads will
 /// be triggered by subsequent calls to one of the `_read_tls` methods.
@@ -43,7 +57,7 @@ impl Read for CallbackReader {
         let cb = self.callback;
         let result = unsafe { cb(self.userdata, buf.as_mut_ptr(), buf.len(), &mut out_n) };
         match result.0 {
-            0 => Ok(out_n),
+            0 => check_count(out_n, buf.len()),
             e => Err(Error::from_raw_os_error(e)),
         }
     }
@@ -88,7 +102,7 @@ impl Write for CallbackWriter {
         let cb = self.callback;
         let result = unsafe { cb(self.userdata, buf.as_ptr(), buf.len(), &mut out_n) };
         match result.0 {
-            0 => Ok(out_n),
+            0 => check_count(out_n, buf.len()),
             e => Err(Error::from_raw_os_error(e)),
         }
     }
@@ -106,6 +120,15 @@ pub struct rustls_iovec {
     _private: [u8; 0],
 }
 
+/// An alias for `struct iovec` from uio.h (on Unix) or `WSABUF` on Windows, like
+/// rustls_iovec, but for buffers that rustls writes into. Cast `struct iovec *` to
+/// `struct rustls_iovec_mut *` on Unix, or `LPWSABUF` on Windows. See
+/// [`std::io::IoSliceMut`] for details on interoperability with platform
+/// specific vectored IO.
+pub struct rustls_iovec_mut {
+    _private: [u8; 0],
+}
+
 /// A callback for rustls_connection_write_tls_vectored.
 /// An implementation of this callback should attempt to write the bytes in
 /// the given `count` iovecs to the network. If any bytes were written,
@@ -162,8 +185,105 @@ impl Write for VectoredCallbackWriter {
             )
         };
         match result.0 {
-            0 => Ok(out_n),
+            0 => check_count(out_n, bufs.iter().map(|b| b.len()).sum()),
             e => Err(Error::from_raw_os_error(e)),
         }
     }
 }
+
+/// Reads from and writes to a file descriptor that the caller owns. It is
+/// never closed or duplicated. Where file descriptors aren't available, every operation fails
+/// with ENOSYS.
+pub(crate) struct FdStream {
+    pub fd: c_int,
+}
+
+impl Read for FdStream {
+    #[cfg(unix)]
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    #[cfg(not(unix))]
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize> {
+        Err(Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+impl Write for FdStream {
+    #[cfg(unix)]
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // Writing to a socket whose peer has gone away raises SIGPIPE, which
+        // kills the process unless the application handles it. Where we can,
+        // ask for EPIPE instead; send() refuses non-sockets, so fall back to
+        // write() for pipes and the like.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let n = unsafe {
+                libc::send(
+                    self.fd,
+                    buf.as_ptr() as *const c_void,
+                    buf.len(),
+                    libc::MSG_NOSIGNAL,
+                )
+            };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let err = Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ENOTSOCK) {
+                return Err(err);
+            }
+        }
+        let n = unsafe { libc::write(self.fd, buf.as_ptr() as *const c_void, buf.len()) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    #[cfg(not(unix))]
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Wraps a stream, counting the bytes that pass through it in each direction
+/// and noting when reading reaches EOF.
+pub(crate) struct CountingStream<T> {
+    pub inner: T,
+    pub read: usize,
+    pub written: usize,
+    pub eof: bool,
+}
+
+impl<T: Read> Read for CountingStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n;
+        if n == 0 && !buf.is_empty() {
+            self.eof = true;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for CountingStream<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
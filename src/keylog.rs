@@ -0,0 +1,135 @@
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use libc::{c_void, size_t};
+use rustls::KeyLog;
+
+use crate::rslice::rustls_str;
+
+/// The environment variable naming the file key logs are appended to, as
+/// understood by Wireshark and other TLS implementations.
+pub(crate) const SSLKEYLOGFILE: &str = "SSLKEYLOGFILE";
+
+/// Appends secrets to a file in the NSS key log format. Unlike
+/// `rustls::KeyLogFile`, the file is opened up front, so failing to open it
+/// can be reported to the caller.
+pub(crate) struct FileKeyLog {
+    file: Mutex<File>,
+}
+
+impl FileKeyLog {
+    pub(crate) fn open(path: &OsStr) -> io::Result<FileKeyLog> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(FileKeyLog {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl KeyLog for FileKeyLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line =
+            String::with_capacity(label.len() + 2 * (client_random.len() + secret.len()) + 3);
+        line.push_str(label);
+        line.push(' ');
+        for b in client_random {
+            line.push_str(&format!("{:02x}", b));
+        }
+        line.push(' ');
+        for b in secret {
+            line.push_str(&format!("{:02x}", b));
+        }
+        line.push('\n');
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::warn!("error writing to key log file: {}", e);
+        }
+    }
+}
+
+/// A callback that receives the secrets of a TLS session, for example to
+/// hand them to a debugger. `label` is the NSS key log label, such as
+/// CLIENT_HANDSHAKE_TRAFFIC_SECRET, and `client_random` and `secret` point at
+/// `client_random_len` and `secret_len` bytes.
+///
+/// NOTE:
+/// - `label`, `client_random` and `secret` are only valid during the callback
+///   invocation and must not be retained.
+/// - the callback is invoked from whichever thread is driving the handshake,
+///   so it must be safe to call concurrently with the same userdata unless
+///   only one connection uses the config at a time.
+/// - the callback cannot fail the handshake.
+pub type rustls_keylog_log_callback = Option<
+    unsafe extern "C" fn(
+        userdata: *mut c_void,
+        label: rustls_str,
+        client_random: *const u8,
+        client_random_len: size_t,
+        secret: *const u8,
+        secret_len: size_t,
+    ),
+>;
+
+// This is the same as a rustls_keylog_log_callback after unwrapping the
+// Option (which is equivalent to checking for null).
+type KeyLogLogCallback = unsafe extern "C" fn(
+    userdata: *mut c_void,
+    label: rustls_str,
+    client_random: *const u8,
+    client_random_len: size_t,
+    secret: *const u8,
+    secret_len: size_t,
+);
+
+/// Passes secrets to a C callback, along with the userdata it was registered
+/// with.
+#[derive(Clone)]
+pub(crate) struct CallbackKeyLog {
+    callback: KeyLogLogCallback,
+    userdata: *mut c_void,
+}
+
+impl CallbackKeyLog {
+    pub(crate) fn new(callback: KeyLogLogCallback, userdata: *mut c_void) -> CallbackKeyLog {
+        CallbackKeyLog { callback, userdata }
+    }
+
+    /// Use `userdata` if the callback was registered without any.
+    pub(crate) fn or_userdata(self, userdata: *mut c_void) -> CallbackKeyLog {
+        if self.userdata.is_null() {
+            CallbackKeyLog { userdata, ..self }
+        } else {
+            self
+        }
+    }
+}
+
+impl KeyLog for CallbackKeyLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let label: rustls_str = match label.try_into() {
+            Ok(label) => label,
+            Err(_) => return,
+        };
+        unsafe {
+            (self.callback)(
+                self.userdata,
+                label,
+                client_random.as_ptr(),
+                client_random.len(),
+                secret.as_ptr(),
+                secret.len(),
+            );
+        }
+    }
+}
+
+/// This struct can be considered thread safe, as long as the registered
+/// callback is thread safe. This is documented as a requirement in the API.
+unsafe impl Sync for CallbackKeyLog {}
+unsafe impl Send for CallbackKeyLog {}
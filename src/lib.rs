@@ -12,21 +12,27 @@
 //!
 //! [You may also want to read the rustls-ffi README](https://github.com/rustls/rustls-ffi#rustls-ffi-bindings).
 
+use crate::cipher::VerifiedChain;
 use crate::rslice::rustls_str;
 use libc::c_void;
 use std::cell::RefCell;
+use std::ffi::OsStr;
 use std::mem;
 use std::sync::Arc;
 
 pub mod acceptor;
+pub mod buffer;
 pub mod cipher;
+pub mod cipher_string;
 pub mod client;
 pub mod connection;
 pub mod enums;
 mod error;
 pub mod io;
+pub mod keylog;
 pub mod log;
 mod panic;
+mod pem;
 pub mod rslice;
 pub mod server;
 pub mod session;
@@ -66,6 +72,9 @@ thread_local! {
 pub(crate) struct Userdata {
     userdata: *mut c_void,
     log_callback: rustls_log_callback,
+    verify_name: Option<rustls::ServerName>,
+    cert_verified: bool,
+    verified_chain: Option<VerifiedChain>,
 }
 
 /// UserdataGuard pops an entry off the USERDATA stack, restoring the
@@ -88,6 +97,9 @@ impl UserdataGuard {
             data: Some(Userdata {
                 userdata: u,
                 log_callback: None,
+                verify_name: None,
+                cert_verified: false,
+                verified_chain: None,
             }),
         }
     }
@@ -150,6 +162,7 @@ pub(crate) enum UserdataError {
 pub(crate) fn userdata_push(
     u: *mut c_void,
     cb: rustls_log_callback,
+    verify_name: Option<rustls::ServerName>,
 ) -> Result<UserdataGuard, UserdataError> {
     USERDATA
         .try_with(|userdata| {
@@ -159,6 +172,9 @@ pub(crate) fn userdata_push(
                     v.push(Userdata {
                         userdata: u,
                         log_callback: cb,
+                        verify_name,
+                        cert_verified: false,
+                        verified_chain: None,
                     });
                     Ok(())
                 },
@@ -168,13 +184,15 @@ pub(crate) fn userdata_push(
     Ok(UserdataGuard::new(u))
 }
 
-pub(crate) fn userdata_get() -> Result<*mut c_void, UserdataError> {
+/// Apply `f` to the innermost item of this thread's userdata stack, the one
+/// for the connection currently being processed.
+fn with_top_userdata<R>(f: impl FnOnce(&mut Userdata) -> R) -> Result<R, UserdataError> {
     USERDATA
         .try_with(|userdata| {
             userdata.try_borrow_mut().map_or_else(
                 |_| Err(UserdataError::AlreadyBorrowed),
-                |v| match v.last() {
-                    Some(u) => Ok(u.userdata),
+                |mut v| match v.last_mut() {
+                    Some(u) => Ok(f(u)),
                     None => Err(UserdataError::EmptyStack),
                 },
             )
@@ -182,18 +200,41 @@ pub(crate) fn userdata_get() -> Result<*mut c_void, UserdataError> {
         .unwrap_or(Err(UserdataError::AccessError))
 }
 
+pub(crate) fn userdata_get() -> Result<*mut c_void, UserdataError> {
+    with_top_userdata(|u| u.userdata)
+}
+
 pub(crate) fn log_callback_get() -> Result<(rustls_log_callback, *mut c_void), UserdataError> {
-    USERDATA
-        .try_with(|userdata| {
-            userdata.try_borrow_mut().map_or_else(
-                |_| Err(UserdataError::AlreadyBorrowed),
-                |v| match v.last() {
-                    Some(u) => Ok((u.log_callback, u.userdata)),
-                    None => Err(UserdataError::EmptyStack),
-                },
-            )
-        })
-        .unwrap_or(Err(UserdataError::AccessError))
+    with_top_userdata(|u| (u.log_callback, u.userdata))
+}
+
+/// Return the name the current connection's server certificate should be
+/// verified against, if it differs from the SNI name.
+pub(crate) fn verify_name_get() -> Result<Option<rustls::ServerName>, UserdataError> {
+    with_top_userdata(|u| u.verify_name.clone())
+}
+
+/// Record that the current connection's server certificate went through
+/// verification, which happens only in full handshakes.
+pub(crate) fn cert_verified_set() -> Result<(), UserdataError> {
+    with_top_userdata(|u| u.cert_verified = true)
+}
+
+/// Return whether cert_verified_set was called for the current connection.
+pub(crate) fn cert_verified_get() -> Result<bool, UserdataError> {
+    with_top_userdata(|u| u.cert_verified)
+}
+
+/// Record the chain the current connection's server certificate was
+/// verified along.
+pub(crate) fn verified_chain_set(chain: VerifiedChain) -> Result<(), UserdataError> {
+    with_top_userdata(|u| u.verified_chain = Some(chain))
+}
+
+/// Take the chain recorded with verified_chain_set for the current
+/// connection, if any.
+pub(crate) fn verified_chain_take() -> Result<Option<VerifiedChain>, UserdataError> {
+    with_top_userdata(|u| u.verified_chain.take())
 }
 
 #[cfg(test)]
@@ -205,7 +246,7 @@ mod tests {
     fn guard_try_pop() {
         let data = "hello";
         let data_ptr: *mut c_void = data as *const _ as _;
-        let mut guard = userdata_push(data_ptr, None).unwrap();
+        let mut guard = userdata_push(data_ptr, None, None).unwrap();
         assert_eq!(userdata_get().unwrap(), data_ptr);
         guard.try_pop().unwrap();
         assert!(matches!(guard.try_pop(), Err(_)));
@@ -215,7 +256,7 @@ mod tests {
     fn guard_try_drop() {
         let data = "hello";
         let data_ptr: *mut c_void = data as *const _ as _;
-        let guard = userdata_push(data_ptr, None).unwrap();
+        let guard = userdata_push(data_ptr, None, None).unwrap();
         assert_eq!(userdata_get().unwrap(), data_ptr);
         guard.try_drop().unwrap();
         assert!(matches!(userdata_get(), Err(_)));
@@ -226,7 +267,7 @@ mod tests {
         let data = "hello";
         let data_ptr: *mut c_void = data as *const _ as _;
         {
-            let _guard = userdata_push(data_ptr, None).unwrap();
+            let _guard = userdata_push(data_ptr, None, None).unwrap();
             assert_eq!(userdata_get().unwrap(), data_ptr);
         }
         assert!(matches!(userdata_get(), Err(_)));
@@ -237,12 +278,12 @@ mod tests {
         let hello = "hello";
         let hello_ptr: *mut c_void = hello as *const _ as _;
         {
-            let guard = userdata_push(hello_ptr, None).unwrap();
+            let guard = userdata_push(hello_ptr, None, None).unwrap();
             assert_eq!(userdata_get().unwrap(), hello_ptr);
             {
                 let yo = "yo";
                 let yo_ptr: *mut c_void = yo as *const _ as _;
-                let guard2 = userdata_push(yo_ptr, None).unwrap();
+                let guard2 = userdata_push(yo_ptr, None, None).unwrap();
                 assert_eq!(userdata_get().unwrap(), yo_ptr);
                 guard2.try_drop().unwrap();
             }
@@ -256,12 +297,12 @@ mod tests {
     fn out_of_order_drop() {
         let hello = "hello";
         let hello_ptr: *mut c_void = hello as *const _ as _;
-        let guard = userdata_push(hello_ptr, None).unwrap();
+        let guard = userdata_push(hello_ptr, None, None).unwrap();
         assert_eq!(userdata_get().unwrap(), hello_ptr);
 
         let yo = "yo";
         let yo_ptr: *mut c_void = yo as *const _ as _;
-        let guard2 = userdata_push(yo_ptr, None).unwrap();
+        let guard2 = userdata_push(yo_ptr, None, None).unwrap();
         assert_eq!(userdata_get().unwrap(), yo_ptr);
 
         assert!(matches!(guard.try_drop(), Err(UserdataError::WrongData)));
@@ -272,19 +313,19 @@ mod tests {
     fn userdata_multi_threads() {
         let hello = "hello";
         let hello_ptr: *mut c_void = hello as *const _ as _;
-        let guard = userdata_push(hello_ptr, None).unwrap();
+        let guard = userdata_push(hello_ptr, None, None).unwrap();
         assert_eq!(userdata_get().unwrap(), hello_ptr);
 
         let thread1 = thread::spawn(|| {
             let yo = "yo";
             let yo_ptr: *mut c_void = yo as *const _ as _;
-            let guard2 = userdata_push(yo_ptr, None).unwrap();
+            let guard2 = userdata_push(yo_ptr, None, None).unwrap();
             assert_eq!(userdata_get().unwrap(), yo_ptr);
 
             let greetz = "greetz";
             let greetz_ptr: *mut c_void = greetz as *const _ as _;
 
-            let guard3 = userdata_push(greetz_ptr, None).unwrap();
+            let guard3 = userdata_push(greetz_ptr, None, None).unwrap();
 
             assert_eq!(userdata_get().unwrap(), greetz_ptr);
             guard3.try_drop().unwrap();
@@ -401,6 +442,24 @@ pub(crate) trait ArcCastPtr: CastConstPtr + Sized {
         drop(unsafe { Arc::from_raw(rs_typed) });
     }
 
+    /// Take another reference to the Arc behind `ptr` on behalf of C code,
+    /// which releases it with free() like the original one. This is what
+    /// to_arc does, minus the Arc it hands to Rust.
+    ///
+    /// Does nothing when passed null.
+    ///
+    /// Unsafety:
+    ///
+    /// If non-null, ptr must be a pointer that resulted from previously calling `Arc::into_raw`,
+    /// and C must still hold at least one reference.
+    fn incref(ptr: *const Self) {
+        if ptr.is_null() {
+            return;
+        }
+        let rs_typed = Self::cast_const_ptr(ptr);
+        unsafe { Arc::increment_strong_count(rs_typed) };
+    }
+
     fn to_const_ptr(src: Self::RustType) -> *const Self {
         Arc::into_raw(Arc::new(src)) as *const _
     }
@@ -465,6 +524,19 @@ where
     F::to_arc(from)
 }
 
+/// Interpret the bytes of a C string as a filesystem path. On Unix any bytes
+/// are allowed; elsewhere they must be valid UTF-8.
+#[cfg(unix)]
+pub(crate) fn os_str_from_bytes(bytes: &[u8]) -> Option<&OsStr> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn os_str_from_bytes(bytes: &[u8]) -> Option<&OsStr> {
+    std::str::from_utf8(bytes).ok().map(OsStr::new)
+}
+
 /// If the provided pointer is non-null, convert it to a reference.
 /// Otherwise, return NullParameter, or an appropriate default (false, 0, NULL)
 /// based on the context;
@@ -1,12 +1,34 @@
 use std::convert::TryInto;
+use std::ffi::CStr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
 
-use libc::c_void;
-use log::Level;
+use libc::{c_char, c_void};
+use log::{Level, LevelFilter};
 
-use crate::{log_callback_get, rslice::rustls_str};
+use crate::error::rustls_result;
+use crate::{ffi_panic_boundary, log_callback_get, rslice::rustls_str};
 
 struct Logger {}
 
+static LOGGER: Logger = Logger {};
+static REGISTER_LOGGER: Once = Once::new();
+static LOGGER_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Make our logger the global logger unless another one already is, and
+/// return whether ours is the one in place. Only the first registration sets
+/// the log level, so a level chosen with rustls_init_logging is kept.
+fn register_logger() -> bool {
+    REGISTER_LOGGER.call_once(|| {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(LevelFilter::Debug);
+            LOGGER_REGISTERED.store(true, Ordering::SeqCst);
+        }
+    });
+    LOGGER_REGISTERED.load(Ordering::SeqCst)
+}
+
 impl log::Log for Logger {
     fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
         true
@@ -35,8 +57,47 @@ pub(crate) fn ensure_log_registered() {}
 
 #[cfg(not(feature = "no_log_capture"))]
 pub(crate) fn ensure_log_registered() {
-    log::set_logger(&Logger {}).ok();
-    log::set_max_level(log::LevelFilter::Debug)
+    register_logger();
+}
+
+/// Register this library's logger, which passes log messages to the
+/// callbacks set with rustls_connection_set_log_callback, and set the most
+/// verbose level that is logged. `level_filter` is a NUL-terminated string:
+/// one of "off", "error", "warn", "info", "debug" or "trace", in any case.
+///
+/// There is no process-wide callback: a message is only delivered while a
+/// connection with a log callback is being processed, and messages logged
+/// outside any connection, e.g. while building a config, are dropped.
+///
+/// The logger is otherwise registered, at level "debug", when the first log
+/// callback is set. This may be called any number of times, from any thread;
+/// later calls just change the level. Returns InvalidParameter if
+/// `level_filter` isn't a level, and LoggerAlreadySet if something else in
+/// the process has already registered a Rust logger. This never panics.
+///
+/// If rustls-ffi was built with the no_log_capture feature, the logger is
+/// never registered, the level is left alone for the application's own
+/// logger, and this returns Unsupported.
+#[no_mangle]
+pub extern "C" fn rustls_init_logging(level_filter: *const c_char) -> rustls_result {
+    ffi_panic_boundary! {
+        if level_filter.is_null() {
+            return rustls_result::NullParameter;
+        }
+        let level_filter: &CStr = unsafe { CStr::from_ptr(level_filter) };
+        let level_filter = match level_filter.to_str().map(LevelFilter::from_str) {
+            Ok(Ok(level_filter)) => level_filter,
+            _ => return rustls_result::InvalidParameter,
+        };
+        if cfg!(feature = "no_log_capture") {
+            return rustls_result::Unsupported;
+        }
+        if !register_logger() {
+            return rustls_result::LoggerAlreadySet;
+        }
+        log::set_max_level(level_filter);
+        rustls_result::Ok
+    }
 }
 
 pub type rustls_log_level = usize;
@@ -64,3 +125,39 @@ pub struct rustls_log_params<'a> {
 #[allow(non_camel_case_types)]
 pub type rustls_log_callback =
     Option<unsafe extern "C" fn(userdata: *mut c_void, params: *const rustls_log_params)>;
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::null;
+
+    use super::*;
+
+    #[test]
+    #[cfg(not(feature = "no_log_capture"))]
+    fn test_init_logging() {
+        let level = b"DEBUG\0".as_ptr() as *const c_char;
+        assert_eq!(rustls_init_logging(level), rustls_result::Ok);
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+        // Registering again, including implicitly, is harmless.
+        ensure_log_registered();
+        assert_eq!(rustls_init_logging(level), rustls_result::Ok);
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+
+        let bogus = b"loud\0".as_ptr() as *const c_char;
+        assert_eq!(rustls_init_logging(bogus), rustls_result::InvalidParameter);
+        assert_eq!(rustls_init_logging(null()), rustls_result::NullParameter);
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    #[cfg(feature = "no_log_capture")]
+    fn test_init_logging_without_capture() {
+        let level = b"trace\0".as_ptr() as *const c_char;
+        let before = log::max_level();
+        assert_eq!(rustls_init_logging(level), rustls_result::Unsupported);
+        assert_eq!(log::max_level(), before);
+        let bogus = b"loud\0".as_ptr() as *const c_char;
+        assert_eq!(rustls_init_logging(bogus), rustls_result::InvalidParameter);
+        assert_eq!(rustls_init_logging(null()), rustls_result::NullParameter);
+    }
+}
@@ -0,0 +1,123 @@
+use rustls::Certificate;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Lines of base64 in PEM output are wrapped at this many characters, as
+/// OpenSSL does and RFC 7468 requires.
+const LINE_LENGTH: usize = 64;
+
+/// Append the padded, unwrapped base64 (RFC 4648) encoding of `input` to `out`.
+fn base64_encode(input: &[u8], out: &mut Vec<u8>) {
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize]);
+            } else {
+                out.push(b'=');
+            }
+        }
+    }
+}
+
+/// Append a PEM block with the given label to `out`.
+fn encode_block(label: &str, der: &[u8], out: &mut Vec<u8>) {
+    let mut encoded = Vec::with_capacity((der.len() + 2) / 3 * 4);
+    base64_encode(der, &mut encoded);
+    out.extend_from_slice(b"-----BEGIN ");
+    out.extend_from_slice(label.as_bytes());
+    out.extend_from_slice(b"-----\n");
+    for line in encoded.chunks(LINE_LENGTH) {
+        out.extend_from_slice(line);
+        out.push(b'\n');
+    }
+    out.extend_from_slice(b"-----END ");
+    out.extend_from_slice(label.as_bytes());
+    out.extend_from_slice(b"-----\n");
+}
+
+/// PEM-encode `certs` in order, one CERTIFICATE block each. An empty slice
+/// produces empty output.
+pub(crate) fn encode_certificates(certs: &[Certificate]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for cert in certs {
+        encode_block("CERTIFICATE", &cert.0, &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base64(input: &[u8]) -> String {
+        let mut out = Vec::new();
+        base64_encode(input, &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_base64_rfc4648_vectors() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xfb, 0xff, 0xbf]), "+/+/");
+    }
+
+    #[test]
+    fn test_empty_chain() {
+        assert!(encode_certificates(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_line_wrapping() {
+        // 48 bytes encode to exactly one full line, 49 spill onto a second.
+        let one_line = encode_certificates(&[Certificate(vec![0; 48])]);
+        let one_line = String::from_utf8(one_line).unwrap();
+        let lines: Vec<&str> = one_line.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "-----BEGIN CERTIFICATE-----",
+                &"A".repeat(64),
+                "-----END CERTIFICATE-----",
+            ]
+        );
+        let two_lines = encode_certificates(&[Certificate(vec![0; 49])]);
+        let two_lines = String::from_utf8(two_lines).unwrap();
+        let lines: Vec<&str> = two_lines.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1].len(), 64);
+        assert_eq!(lines[2], "AA==");
+        assert!(two_lines.ends_with("-----END CERTIFICATE-----\n"));
+    }
+
+    #[test]
+    fn test_matches_openssl_output() {
+        // These fixtures were written by OpenSSL, so re-encoding the parsed
+        // certificates must reproduce them byte for byte.
+        for fixture in [
+            &include_bytes!("../testdata/localhost/cert.pem")[..],
+            &include_bytes!("../testdata/intermediate/cert.pem")[..],
+            &include_bytes!("../testdata/minica.pem")[..],
+        ] {
+            let certs: Vec<Certificate> = rustls_pemfile::certs(&mut &fixture[..])
+                .unwrap()
+                .into_iter()
+                .map(Certificate)
+                .collect();
+            assert!(!certs.is_empty());
+            assert_eq!(encode_certificates(&certs), fixture);
+        }
+    }
+}
@@ -7,6 +7,25 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+/**
+ * Flags for rustls_connection_poll_interest, to be combined with bitwise OR.
+ */
+typedef enum rustls_interest {
+  /**
+   * rustls_connection_wants_read is true: read TLS bytes when readable.
+   */
+  RUSTLS_INTEREST_READ = 1,
+  /**
+   * rustls_connection_wants_write is true: write TLS bytes when writable.
+   */
+  RUSTLS_INTEREST_WRITE = 2,
+  /**
+   * No more TLS bytes will come from the peer: it sent close_notify, the
+   * transport reached EOF, or processing failed.
+   */
+  RUSTLS_INTEREST_CLOSED = 4,
+} rustls_interest;
+
 enum rustls_result {
   RUSTLS_RESULT_OK = 7000,
   RUSTLS_RESULT_IO = 7001,
@@ -22,6 +41,25 @@ enum rustls_result {
   RUSTLS_RESULT_PLAINTEXT_EMPTY = 7011,
   RUSTLS_RESULT_ACCEPTOR_NOT_READY = 7012,
   RUSTLS_RESULT_ALREADY_USED = 7013,
+  RUSTLS_RESULT_UNSUPPORTED_PROTOCOL_VERSION = 7014,
+  RUSTLS_RESULT_UNSUPPORTED_CIPHER_SUITE = 7015,
+  RUSTLS_RESULT_NO_CERTIFICATES_FOUND = 7016,
+  RUSTLS_RESULT_UNSUPPORTED = 7017,
+  RUSTLS_RESULT_ENVIRONMENT_NOT_SET = 7018,
+  RUSTLS_RESULT_CERT_KEY_MISMATCH = 7019,
+  RUSTLS_RESULT_UNSUPPORTED_PRIVATE_KEY_TYPE = 7020,
+  RUSTLS_RESULT_NO_PRIVATE_KEY_FOUND = 7021,
+  RUSTLS_RESULT_PIN_VIOLATION = 7022,
+  RUSTLS_RESULT_PINNED_CERT_MISMATCH = 7023,
+  RUSTLS_RESULT_EARLY_DATA_UNAVAILABLE = 7024,
+  RUSTLS_RESULT_LOGGER_ALREADY_SET = 7025,
+  RUSTLS_RESULT_HANDSHAKE_ALREADY_COMPLETE = 7026,
+  RUSTLS_RESULT_BUFFER_LIMIT_REACHED = 7027,
+  RUSTLS_RESULT_WOULD_BLOCK = 7028,
+  RUSTLS_RESULT_TLSA_MISMATCH = 7029,
+  RUSTLS_RESULT_VERIFIED_CHAIN_UNAVAILABLE = 7030,
+  RUSTLS_RESULT_NO_CERTIFICATE_CONFIGURED = 7031,
+  RUSTLS_RESULT_CT_LOGS_EXPIRED = 7032,
   RUSTLS_RESULT_NO_CERTIFICATES_PRESENTED = 7101,
   RUSTLS_RESULT_DECRYPT_ERROR = 7102,
   RUSTLS_RESULT_FAILED_TO_GET_CURRENT_TIME = 7103,
@@ -158,6 +196,14 @@ typedef struct rustls_accepted rustls_accepted;
  */
 typedef struct rustls_acceptor rustls_acceptor;
 
+/**
+ * A heap-allocated sequence of bytes, owned by rustls and handed to C. Read
+ * it with rustls_buffer_data and rustls_buffer_len, and free it with
+ * rustls_buffer_free when done. The contents never change, so the data
+ * pointer stays valid until the buffer is freed.
+ */
+typedef struct rustls_buffer rustls_buffer;
+
 /**
  * An X.509 certificate, as used in rustls.
  * Corresponds to `Certificate` in the Rust API.
@@ -194,7 +240,8 @@ typedef struct rustls_client_cert_verifier_optional rustls_client_cert_verifier_
 
 /**
  * A client config that is done being constructed and is now read-only.
- * Under the hood, this object corresponds to an `Arc<ClientConfig>`.
+ * Under the hood, this object corresponds to an `Arc` holding an
+ * `Arc<ClientConfig>` and the config userdata.
  * <https://docs.rs/rustls/latest/rustls/struct.ClientConfig.html>
  */
 typedef struct rustls_client_config rustls_client_config;
@@ -205,7 +252,7 @@ typedef struct rustls_client_config rustls_client_config;
  * done configuring settings, call rustls_client_config_builder_build
  * to turn it into a *rustls_client_config. This object is not safe
  * for concurrent mutation. Under the hood, it corresponds to a
- * `Box<ClientConfig>`.
+ * `Box` holding the settings until they are built into a `ClientConfig`.
  * <https://docs.rs/rustls/latest/rustls/struct.ConfigBuilder.html>
  */
 typedef struct rustls_client_config_builder rustls_client_config_builder;
@@ -220,8 +267,35 @@ typedef struct rustls_connection rustls_connection;
  */
 typedef struct rustls_iovec rustls_iovec;
 
+/**
+ * An alias for `struct iovec` from uio.h (on Unix) or `WSABUF` on Windows, like
+ * rustls_iovec, but for buffers that rustls writes into. Cast `struct iovec *` to
+ * `struct rustls_iovec_mut *` on Unix, or `LPWSABUF` on Windows. See
+ * [`std::io::IoSliceMut`] for details on interoperability with platform
+ * specific vectored IO.
+ */
+typedef struct rustls_iovec_mut rustls_iovec_mut;
+
+/**
+ * A byte queue between a socket and a rustls_connection, owned by rustls and
+ * handed to C. Bytes pushed in come out of rustls_ring_buffer_pull in the
+ * same order. Its storage grows as needed, up to the capacity limit given
+ * to rustls_ring_buffer_new, and wraps around internally, so C never has to
+ * shuffle or resize it.
+ *
+ * rustls_connection_read_tls_from_ring and
+ * rustls_connection_write_tls_to_ring move TLS bytes between a ring and a
+ * connection directly. This object is not safe for concurrent use.
+ */
+typedef struct rustls_ring_buffer rustls_ring_buffer;
+
 /**
  * A root certificate store.
+ *
+ * Client configs that use a store share a single copy of its roots, made
+ * the first time the store is used. Adding certificates afterwards doesn't
+ * affect configs that already use the store; only configs that use it
+ * after that point see the new certificates.
  * <https://docs.rs/rustls/latest/rustls/struct.RootCertStore.html>
  */
 typedef struct rustls_root_cert_store rustls_root_cert_store;
@@ -243,6 +317,13 @@ typedef struct rustls_server_config rustls_server_config;
  */
 typedef struct rustls_server_config_builder rustls_server_config_builder;
 
+/**
+ * A hostname or IP address that has already been parsed, for making many
+ * client connections to the same server.
+ * <https://docs.rs/rustls/latest/rustls/enum.ServerName.html>
+ */
+typedef struct rustls_server_name rustls_server_name;
+
 /**
  * A read-only view of a slice of Rust byte slices.
  *
@@ -314,9 +395,10 @@ typedef int rustls_io_result;
  * A callback for rustls_connection_read_tls.
  * An implementation of this callback should attempt to read up to n bytes from the
  * network, storing them in `buf`. If any bytes were stored, the implementation should
- * set out_n to the number of bytes stored and return 0. If there was an error,
- * the implementation should return a nonzero rustls_io_result, which will be
- * passed through to the caller. On POSIX systems, returning `errno` is convenient.
+ * set out_n to the number of bytes stored and return 0. Setting out_n to 0 and
+ * returning 0 signals EOF. Setting out_n to more than n is treated as an error.
+ * If there was an error, the implementation should return a nonzero
+ * rustls_io_result, which will be passed through to the caller. On POSIX systems, returning `errno` is convenient.
  * On other systems, any appropriate error code works.
  * It's best to make one read attempt to the network per call. Additional reads will
  * be triggered by subsequent calls to one of the `_read_tls` methods.
@@ -356,6 +438,9 @@ typedef void *rustls_verify_server_cert_user_data;
  *
  * server_name can contain a hostname, an IPv4 address in textual form, or an
  * IPv6 address in textual form.
+ *
+ * ocsp_response holds the DER-encoded OCSPResponse the server stapled,
+ * exactly as received; it is empty (len 0) if the server didn't staple one.
  */
 typedef struct rustls_verify_server_cert_params {
   struct rustls_slice_bytes end_entity_cert_der;
@@ -366,6 +451,145 @@ typedef struct rustls_verify_server_cert_params {
 
 typedef uint32_t (*rustls_verify_server_cert_callback)(rustls_verify_server_cert_user_data userdata, const struct rustls_verify_server_cert_params *params);
 
+/**
+ * A TLSA record (RFC 6698) for the server, as found in DNS. See
+ * rustls_client_config_builder_set_tlsa_records().
+ *
+ * `usage`, `selector` and `matching_type` are the record's first three
+ * fields, and `data` holds its certificate association data as raw bytes,
+ * not hex.
+ */
+typedef struct rustls_tlsa_record {
+  uint8_t usage;
+  uint8_t selector;
+  uint8_t matching_type;
+  struct rustls_slice_bytes data;
+} rustls_tlsa_record;
+
+/**
+ * A callback that receives the secrets of a TLS session, for example to
+ * hand them to a debugger. `label` is the NSS key log label, such as
+ * CLIENT_HANDSHAKE_TRAFFIC_SECRET, and `client_random` and `secret` point at
+ * `client_random_len` and `secret_len` bytes.
+ *
+ * NOTE:
+ * - `label`, `client_random` and `secret` are only valid during the callback
+ *   invocation and must not be retained.
+ * - the callback is invoked from whichever thread is driving the handshake,
+ *   so it must be safe to call concurrently with the same userdata unless
+ *   only one connection uses the config at a time.
+ * - the callback cannot fail the handshake.
+ */
+typedef void (*rustls_keylog_log_callback)(void *userdata, struct rustls_str label, const uint8_t *client_random, size_t client_random_len, const uint8_t *secret, size_t secret_len);
+
+/**
+ * Any context information the callback will receive when invoked.
+ */
+typedef void *rustls_client_cert_resolver_userdata;
+
+/**
+ * A read-only view on a Rust slice of 16-bit integers in platform endianness.
+ *
+ * This is used to pass data from rustls-ffi to callback functions provided
+ * by the user of the API.
+ * `len` indicates the number of bytes than can be safely read.
+ *
+ * The memory exposed is available as specified by the function
+ * using this in its signature. For instance, when this is a parameter to a
+ * callback, the lifetime will usually be the duration of the callback.
+ * Functions that receive one of these must not dereference the data pointer
+ * beyond the allowed lifetime.
+ */
+typedef struct rustls_slice_u16 {
+  const uint16_t *data;
+  size_t len;
+} rustls_slice_u16;
+
+/**
+ * A server's request for a client certificate, as passed to a
+ * rustls_client_cert_resolver_callback.
+ *
+ * `acceptable_issuers` holds the DER-encoded distinguished names of the
+ * certificate authorities the server will accept, and may be empty.
+ * `signature_schemes` holds the TLS signature schemes the server supports.
+ *
+ * The memory exposed is only available for the duration of the callback.
+ */
+typedef struct rustls_client_cert_request {
+  const struct rustls_slice_slice_bytes *acceptable_issuers;
+  struct rustls_slice_u16 signature_schemes;
+} rustls_client_cert_request;
+
+/**
+ * Prototype of a callback that chooses the client certificate to send when a
+ * server asks for one. `userdata` will be set based on
+ * rustls_connection_set_userdata.
+ *
+ * The callback returns a `rustls_certified_key` to authenticate with, or
+ * NULL to continue the handshake without a client certificate. The
+ * connection takes its own reference to a returned key, so the callback may
+ * free it at any time afterwards.
+ *
+ * NOTE:
+ * - the passed in `request` and all its values are only available during the
+ *   callback invocation.
+ * - the callback runs inside rustls_connection_process_new_packets, on the
+ *   thread that called it. It must not call back into rustls-ffi functions
+ *   on the same connection.
+ * - the passed callback function must be safe to call multiple times concurrently
+ *   with the same userdata, unless there is only a single config and connection
+ *   where it is installed.
+ */
+typedef const struct rustls_certified_key *(*rustls_client_cert_resolver_callback)(rustls_client_cert_resolver_userdata userdata, const struct rustls_client_cert_request *request);
+
+/**
+ * Settings for rustls_client_connection_new_ex. Zero-initialize this, so
+ * that any field you don't set gets its default, then fill in:
+ *
+ * - `struct_size`: `sizeof(rustls_client_connection_options)`, as seen by
+ *   the caller. New fields are only ever added at the end, so this tells
+ *   rustls-ffi which fields the caller knows about; the others get their
+ *   defaults. It must be a size some version of rustls-ffi gave this
+ *   struct: currently the whole struct, or the part up to and including
+ *   `userdata`.
+ * - `server_name` and `server_name_len`: the server's name, as for
+ *   rustls_client_connection_new_slice. Required.
+ * - `userdata`: passed to callbacks, as if set with
+ *   rustls_connection_set_userdata right away. Default NULL.
+ * - `verify_hostname` and `verify_hostname_len`: a name to verify the
+ *   server's certificate against instead of `server_name`, as for
+ *   rustls_client_connection_new_with_verify_hostname. Default NULL, which
+ *   verifies against `server_name`. Added after `userdata`.
+ */
+typedef struct rustls_client_connection_options {
+  size_t struct_size;
+  const char *server_name;
+  size_t server_name_len;
+  void *userdata;
+  const char *verify_hostname;
+  size_t verify_hostname_len;
+} rustls_client_connection_options;
+
+/**
+ * A summary of a completed handshake, filled in by
+ * rustls_client_connection_get_handshake_info. This is a plain struct that C
+ * allocates; the C declaration in rustls.h has the same layout:
+ *
+ * - `version`: the TLS protocol version, e.g. 0x0304 for TLS 1.3.
+ * - `ciphersuite`: the IANA ID of the negotiated cipher suite.
+ * - `alpn`, `alpn_len`: the negotiated ALPN protocol, in the first
+ *   `alpn_len` bytes of `alpn`. `alpn_len` is 0 if none was negotiated.
+ * - `resumed`: whether an earlier session was resumed, rather than the
+ *   server's certificate being verified afresh.
+ */
+typedef struct rustls_handshake_info {
+  uint16_t version;
+  uint16_t ciphersuite;
+  uint8_t alpn[32];
+  size_t alpn_len;
+  bool resumed;
+} rustls_handshake_info;
+
 typedef size_t rustls_log_level;
 
 typedef struct rustls_log_params {
@@ -408,27 +632,56 @@ typedef rustls_io_result (*rustls_write_callback)(void *userdata, const uint8_t
 typedef rustls_io_result (*rustls_write_vectored_callback)(void *userdata, const struct rustls_iovec *iov, size_t count, size_t *out_n);
 
 /**
- * Any context information the callback will receive when invoked.
+ * A callback for rustls_connection_set_handshake_done_callback. It is called
+ * once, with the userdata given when it was registered, when the handshake
+ * on `conn` completes successfully. The callback may use `conn`, for
+ * instance to start writing plaintext, but must not free it.
  */
-typedef void *rustls_client_hello_userdata;
+typedef void (*rustls_handshake_done_callback)(void *userdata, struct rustls_connection *conn);
 
 /**
- * A read-only view on a Rust slice of 16-bit integers in platform endianness.
+ * What a connection can do next, filled in by
+ * rustls_connection_process_new_packets_ex. This is a plain struct that C
+ * allocates:
  *
- * This is used to pass data from rustls-ffi to callback functions provided
- * by the user of the API.
- * `len` indicates the number of bytes than can be safely read.
+ * - `bytes_to_read`: plaintext bytes waiting for rustls_connection_read.
+ * - `bytes_to_write`: TLS bytes waiting for rustls_connection_write_tls.
+ * - `handshaking`: whether the handshake is still in progress.
+ * - `peer_closed`: whether the peer has sent close_notify. Once the
+ *   `bytes_to_read` have been read, no more plaintext will arrive.
+ */
+typedef struct rustls_io_state {
+  size_t bytes_to_read;
+  size_t bytes_to_write;
+  bool handshaking;
+  bool peer_closed;
+} rustls_io_state;
+
+/**
+ * Running totals of the bytes that have passed through a connection, filled
+ * in by rustls_connection_get_counters. This is a plain struct that C
+ * allocates:
  *
- * The memory exposed is available as specified by the function
- * using this in its signature. For instance, when this is a parameter to a
- * callback, the lifetime will usually be the duration of the callback.
- * Functions that receive one of these must not dereference the data pointer
- * beyond the allowed lifetime.
+ * - `plaintext_written`: plaintext accepted by rustls_connection_write,
+ *   rustls_connection_write_all and, for clients,
+ *   rustls_client_connection_write_early_data.
+ * - `plaintext_read`: plaintext handed to the application by
+ *   rustls_connection_read and the other reading functions.
+ * - `tls_written`: TLS bytes handed to the network, however they were sent.
+ *   Handshake messages, alerts and record overhead are all included.
+ * - `tls_read`: TLS bytes taken from the network, however they arrived.
  */
-typedef struct rustls_slice_u16 {
-  const uint16_t *data;
-  size_t len;
-} rustls_slice_u16;
+typedef struct rustls_counters {
+  uint64_t plaintext_written;
+  uint64_t plaintext_read;
+  uint64_t tls_written;
+  uint64_t tls_read;
+} rustls_counters;
+
+/**
+ * Any context information the callback will receive when invoked.
+ */
+typedef void *rustls_client_hello_userdata;
 
 /**
  * The TLS Client Hello information provided to a ClientHelloCallback function.
@@ -783,6 +1036,70 @@ rustls_result rustls_accepted_into_connection(struct rustls_accepted *accepted,
  */
 void rustls_accepted_free(struct rustls_accepted *accepted);
 
+/**
+ * Return a pointer to the first byte of the buffer. If the buffer is
+ * empty, the pointer is non-NULL but must not be dereferenced. Returns
+ * NULL if `buffer` is NULL.
+ */
+const uint8_t *rustls_buffer_data(const struct rustls_buffer *buffer);
+
+/**
+ * Return the number of bytes in the buffer, or 0 if `buffer` is NULL.
+ */
+size_t rustls_buffer_len(const struct rustls_buffer *buffer);
+
+/**
+ * Free a rustls_buffer. Calling with NULL is fine.
+ * Must not be called twice with the same value.
+ */
+void rustls_buffer_free(struct rustls_buffer *buffer);
+
+/**
+ * Create an empty ring buffer that will hold at most `limit` bytes. It
+ * starts with no storage, and allocates more as bytes are pushed.
+ * Free it with rustls_ring_buffer_free.
+ */
+struct rustls_ring_buffer *rustls_ring_buffer_new(size_t limit);
+
+/**
+ * Append up to `len` bytes from `buf` to the ring, and store how many
+ * were taken in *out_n. Bytes go in as long as there is room under the
+ * limit, so fewer than `len` may be taken. If none fit because the ring
+ * is full, returns RUSTLS_RESULT_WOULD_BLOCK with *out_n set to 0: pull
+ * some bytes out, then try again.
+ */
+rustls_result rustls_ring_buffer_push(struct rustls_ring_buffer *ring,
+                                      const uint8_t *buf,
+                                      size_t len,
+                                      size_t *out_n);
+
+/**
+ * Move up to `len` bytes from the front of the ring into `buf`, and
+ * store how many were moved in *out_n. That is 0 if the ring is empty.
+ */
+rustls_result rustls_ring_buffer_pull(struct rustls_ring_buffer *ring,
+                                      uint8_t *buf,
+                                      size_t len,
+                                      size_t *out_n);
+
+/**
+ * Return the number of bytes in the ring, or 0 if `ring` is NULL.
+ */
+size_t rustls_ring_buffer_len(const struct rustls_ring_buffer *ring);
+
+/**
+ * Return how many bytes the ring can hold before it has to allocate
+ * more storage. This grows as bytes are pushed, but never past the
+ * limit given to rustls_ring_buffer_new. Returns 0 if `ring` is NULL.
+ */
+size_t rustls_ring_buffer_capacity(const struct rustls_ring_buffer *ring);
+
+/**
+ * Free a rustls_ring_buffer, along with any bytes still in it. Calling
+ * with NULL is fine. Must not be called twice with the same value.
+ */
+void rustls_ring_buffer_free(struct rustls_ring_buffer *ring);
+
 /**
  * Get the DER data of the certificate itself.
  * The data is owned by the certificate and has the same lifetime.
@@ -798,6 +1115,22 @@ rustls_result rustls_certificate_get_der(const struct rustls_certificate *cert,
  */
 uint16_t rustls_supported_ciphersuite_get_suite(const struct rustls_supported_ciphersuite *supported_ciphersuite);
 
+/**
+ * Return the TLS protocol version this cipher suite is used with: 0x0303
+ * for TLS 1.2 or 0x0304 for TLS 1.3. These are defined in
+ * `rustls_tls_version`. Returns 0 if `supported_ciphersuite` is NULL.
+ */
+uint16_t rustls_supported_ciphersuite_get_protocol_version(const struct rustls_supported_ciphersuite *supported_ciphersuite);
+
+/**
+ * Look up the cipher suite with the given 16-bit IANA identifier in
+ * rustls' list of supported cipher suites, for instance to describe the
+ * result of rustls_supported_ciphersuite_get_suite. Returns NULL if rustls
+ * does not implement that cipher suite.
+ * The returned pointer is valid for the lifetime of the program.
+ */
+const struct rustls_supported_ciphersuite *rustls_supported_ciphersuite_get_by_id(uint16_t id);
+
 /**
  * Returns the name of the ciphersuite as a `rustls_str`. If the provided
  * ciphersuite is invalid, the rustls_str will contain the empty string. The
@@ -806,6 +1139,27 @@ uint16_t rustls_supported_ciphersuite_get_suite(const struct rustls_supported_ci
  */
 struct rustls_str rustls_supported_ciphersuite_get_name(const struct rustls_supported_ciphersuite *supported_ciphersuite);
 
+/**
+ * Write the name of the cipher suite with the given 16-bit IANA identifier,
+ * as rustls_supported_ciphersuite_get_name would return it, into `buf`,
+ * followed by a NUL. If the name and its NUL don't fit in `len` bytes, the
+ * name is truncated to `len - 1` bytes; `buf` is NUL-terminated whenever
+ * `len` is nonzero. *out_n is set to the number of bytes written, not
+ * counting the NUL.
+ * Returns RUSTLS_RESULT_NOT_FOUND, writing nothing, if rustls does not
+ * implement that cipher suite.
+ */
+rustls_result rustls_ciphersuite_get_name(uint16_t id, char *buf, size_t len, size_t *out_n);
+
+/**
+ * Store the TLS protocol version used with the cipher suite with the given
+ * 16-bit IANA identifier in *out_tls_version: 0x0303 for TLS 1.2 or 0x0304
+ * for TLS 1.3, as defined in `rustls_tls_version`.
+ * Returns RUSTLS_RESULT_NOT_FOUND, leaving *out_tls_version alone, if rustls
+ * does not implement that cipher suite.
+ */
+rustls_result rustls_ciphersuite_get_version(uint16_t id, uint16_t *out_tls_version);
+
 /**
  * Return the length of rustls' list of supported cipher suites.
  */
@@ -813,7 +1167,8 @@ size_t rustls_all_ciphersuites_len(void);
 
 /**
  * Get a pointer to a member of rustls' list of supported cipher suites. This will return non-NULL
- * for i < rustls_all_ciphersuites_len().
+ * for i < rustls_all_ciphersuites_len(), and NULL for any larger index.
+ * The order of the list is fixed for the lifetime of the program, so callers may cache it.
  * The returned pointer is valid for the lifetime of the program and may be used directly when
  * building a ClientConfig or ServerConfig.
  */
@@ -839,7 +1194,16 @@ const struct rustls_supported_ciphersuite *rustls_default_ciphersuites_get_entry
  * certificate first.
  *
  * `private_key` must point to a buffer of `private_key_len` bytes, containing
- * a PEM-encoded private key in either PKCS#1 or PKCS#8 format.
+ * a PEM-encoded private key in PKCS#8 ("BEGIN PRIVATE KEY"), PKCS#1
+ * ("BEGIN RSA PRIVATE KEY") or SEC1 ("BEGIN EC PRIVATE KEY") format. Keys
+ * are tried in that order of format, and the first one rustls can use is
+ * taken; any others are ignored. Use rustls_certified_key_build_ex to
+ * learn whether there were others.
+ *
+ * Returns `NoPrivateKeyFound` if `private_key` has no private key
+ * sections, `UnsupportedPrivateKeyType` if the key uses an algorithm
+ * rustls can't sign with, and `PrivateKeyParseError` if it can't be
+ * parsed.
  *
  * On success, this writes a pointer to the newly created
  * `rustls_certified_key` in `certified_key_out`. That pointer must later
@@ -866,6 +1230,19 @@ rustls_result rustls_certified_key_build(const uint8_t *cert_chain,
                                          size_t private_key_len,
                                          const struct rustls_certified_key **certified_key_out);
 
+/**
+ * Build a `rustls_certified_key` as rustls_certified_key_build does, and
+ * store in *out_ignored_keys the number of private keys in `private_key`
+ * other than the one that was used. A caller that expects exactly one
+ * key can treat a nonzero count as a configuration error.
+ */
+rustls_result rustls_certified_key_build_ex(const uint8_t *cert_chain,
+                                            size_t cert_chain_len,
+                                            const uint8_t *private_key,
+                                            size_t private_key_len,
+                                            size_t *out_ignored_keys,
+                                            const struct rustls_certified_key **certified_key_out);
+
 /**
  * Return the i-th rustls_certificate in the rustls_certified_key. 0 gives the
  * end-entity certificate. 1 and higher give certificates from the chain.
@@ -887,6 +1264,43 @@ rustls_result rustls_certified_key_clone_with_ocsp(const struct rustls_certified
                                                    const struct rustls_slice_bytes *ocsp_response,
                                                    const struct rustls_certified_key **cloned_key_out);
 
+/**
+ * Build a `rustls_certified_key` from DER-encoded certificates and a
+ * DER-encoded private key.
+ *
+ * `certs_der` and `cert_lens` are parallel arrays of `cert_count`
+ * entries: `certs_der[i]` points to `cert_lens[i]` bytes holding one
+ * DER-encoded certificate, with the end-entity (leaf) certificate first.
+ * `private_key` points to `private_key_len` bytes holding the key as
+ * DER-encoded PKCS#8, PKCS#1 (RSA) or SEC1 (EC).
+ *
+ * Returns `InvalidParameter` if `cert_count` is zero or any certificate
+ * is empty, `UnsupportedPrivateKeyType` if the key is well-formed but
+ * uses an algorithm rustls can't sign with, and `PrivateKeyParseError`
+ * if it can't be parsed at all. Otherwise this behaves like
+ * rustls_certified_key_build, including how the result must be freed.
+ */
+rustls_result rustls_certified_key_build_der(const uint8_t *const *certs_der,
+                                             const size_t *cert_lens,
+                                             size_t cert_count,
+                                             const uint8_t *private_key,
+                                             size_t private_key_len,
+                                             const struct rustls_certified_key **certified_key_out);
+
+/**
+ * Check that the private key in `key` belongs to its end-entity (leaf)
+ * certificate, by signing a test message with the key and verifying the
+ * signature with the certificate's public key. This works for RSA, ECDSA
+ * and Ed25519 keys, and is cheap enough to run when loading
+ * configuration, so that a certificate deployed with the wrong key can
+ * be reported before any connection fails.
+ *
+ * Returns `Ok` if the key matches, `CertKeyMismatch` if it doesn't, and
+ * `CertificateParseError` if `key` has no leaf certificate or it can't
+ * be parsed.
+ */
+rustls_result rustls_certified_key_matches(const struct rustls_certified_key *key);
+
 /**
  * "Free" a certified_key previously returned from
  * rustls_certified_key_build. Since certified_key is actually an
@@ -907,6 +1321,7 @@ struct rustls_root_cert_store *rustls_root_cert_store_new(void);
 
 /**
  * Add one or more certificates to the root cert store using PEM encoded data.
+ * `pem` may contain several concatenated certificates.
  *
  * When `strict` is true an error will return a `CertificateParseError`
  * result. So will an attempt to parse data that has zero certificates.
@@ -914,12 +1329,123 @@ struct rustls_root_cert_store *rustls_root_cert_store_new(void);
  * When `strict` is false, unparseable root certificates will be ignored.
  * This may be useful on systems that have syntactically invalid root
  * certificates.
+ *
+ * Malformed PEM (as opposed to a well-formed PEM block holding an
+ * unparseable certificate) always returns `CertificateParseError`.
+ * Whenever an error is returned, none of the certificates in `pem` are
+ * added, including any that preceded the error. To find out how many
+ * certificates were added or skipped, use
+ * rustls_root_cert_store_add_pem_ex.
  */
 rustls_result rustls_root_cert_store_add_pem(struct rustls_root_cert_store *store,
                                              const uint8_t *pem,
                                              size_t pem_len,
                                              bool strict);
 
+/**
+ * Add certificates to the root cert store as
+ * rustls_root_cert_store_add_pem does, and count them.
+ *
+ * The number of certificates parsed and the number skipped because
+ * rustls couldn't parse them are written to `*out_parsed` and
+ * `*out_skipped`. Either pointer may be NULL if the caller doesn't need
+ * that count. The counts are written even when strict mode turns a
+ * skipped certificate into an error.
+ */
+rustls_result rustls_root_cert_store_add_pem_ex(struct rustls_root_cert_store *store,
+                                                const uint8_t *pem,
+                                                size_t pem_len,
+                                                bool strict,
+                                                size_t *out_parsed,
+                                                size_t *out_skipped);
+
+/**
+ * Add a single DER-encoded certificate to the root cert store.
+ *
+ * Returns `InvalidParameter` if `der_len` is zero and
+ * `CertificateParseError` if `der` isn't a certificate rustls can use as
+ * a trust anchor, in which case the store is left unchanged.
+ * <https://docs.rs/rustls/latest/rustls/struct.RootCertStore.html#method.add>
+ */
+rustls_result rustls_root_cert_store_add_der(struct rustls_root_cert_store *store,
+                                             const uint8_t *der,
+                                             size_t der_len);
+
+/**
+ * Add the trust anchors from the platform's certificate store: the
+ * OpenSSL certificate file and directory on Linux and other Unix systems
+ * (honoring SSL_CERT_FILE and SSL_CERT_DIR), the Keychain on macOS, and
+ * the system certificate store on Windows.
+ *
+ * On success, the number of certificates added is written to `*out_added`.
+ * Certificates in the platform store that rustls cannot parse are
+ * skipped. If the platform store can't be read, returns `Io`; if it
+ * yields no usable certificates, returns `NoCertificatesFound`. In both
+ * cases `store` is left unchanged.
+ *
+ * This requires rustls-ffi to be built with the `native_roots` cargo
+ * feature. Otherwise it always returns `Unsupported`.
+ * <https://docs.rs/rustls-native-certs/latest/rustls_native_certs/fn.load_native_certs.html>
+ */
+rustls_result rustls_root_cert_store_add_native_roots(struct rustls_root_cert_store *store,
+                                                      size_t *out_added);
+
+/**
+ * Add the trust anchors named by the SSL_CERT_FILE and SSL_CERT_DIR
+ * environment variables, as OpenSSL-based tools do.
+ *
+ * SSL_CERT_FILE names a PEM file, which may hold several certificates.
+ * SSL_CERT_DIR names one or more directories, separated by `:` (`;` on
+ * Windows); every `.pem` or `.crt` file directly inside them is loaded
+ * as by rustls_root_cert_store_add_from_dir. Either variable or both may
+ * be set. An empty variable counts as unset.
+ *
+ * The number of certificates parsed is written to `*out_parsed`, and the
+ * number of certificates and directory entries skipped to
+ * `*out_skipped`; either may be NULL. When `strict` is true, skipping
+ * anything returns `CertificateParseError`.
+ *
+ * If neither variable is set, returns `EnvironmentNotSet`. If the file or
+ * any of the directories can't be read, returns `Io`; if the file holds
+ * malformed PEM, returns `CertificateParseError`; if no usable
+ * certificates are found at all, returns `NoCertificatesFound`. On any
+ * error `store` is left unchanged.
+ */
+rustls_result rustls_root_cert_store_add_from_env(struct rustls_root_cert_store *store,
+                                                  bool strict,
+                                                  size_t *out_parsed,
+                                                  size_t *out_skipped);
+
+/**
+ * Add the trust anchors from a directory of PEM files, such as the
+ * one-CA-per-file layout of /etc/ssl/certs on Debian-style systems.
+ *
+ * Every `.pem` or `.crt` file directly inside `dir` is loaded. Symlinks
+ * are followed; subdirectories are not descended into. Loading is best
+ * effort: a file that can't be read, is malformed, or holds no usable
+ * certificate is skipped rather than failing the whole load, unless
+ * `strict` is true.
+ *
+ * On Unix, `dir` is used as raw bytes and need not be valid UTF-8.
+ * Elsewhere it must be UTF-8.
+ *
+ * The number of certificates parsed is written to `*out_parsed`. Each
+ * skipped file, and each certificate rustls couldn't parse in a file that
+ * was otherwise loaded, adds one to `*out_skipped`. Either pointer may be
+ * NULL. The counts are written whenever the directory could be read,
+ * even if an error is then returned.
+ *
+ * Returns `Io` if `dir` itself can't be read and `NoCertificatesFound` if
+ * nothing usable was found in it. When `strict` is true, skipping
+ * anything returns `CertificateParseError`. On any error `store` is left
+ * unchanged.
+ */
+rustls_result rustls_root_cert_store_add_from_dir(struct rustls_root_cert_store *store,
+                                                  const char *dir,
+                                                  bool strict,
+                                                  size_t *out_parsed,
+                                                  size_t *out_skipped);
+
 /**
  * Free a rustls_root_cert_store previously returned from rustls_root_cert_store_builder_build.
  * Calling with NULL is fine. Must not be called twice with the same value.
@@ -966,15 +1492,46 @@ const struct rustls_client_cert_verifier_optional *rustls_client_cert_verifier_o
  */
 void rustls_client_cert_verifier_optional_free(const struct rustls_client_cert_verifier_optional *verifier);
 
+/**
+ * Translate an OpenSSL cipher string, as accepted by
+ * `SSL_CTX_set_cipher_list`, into the IANA identifiers of the rustls cipher
+ * suites it selects, and write them to `out_ids` in preference order.
+ * *out_n is set to the number of identifiers written.
+ *
+ * Elements are separated by ':' (or ',', ';' or spaces). An element is a
+ * cipher name like "ECDHE-RSA-AES128-GCM-SHA256", an alias like "HIGH",
+ * "aRSA" or "AESGCM", or several of those joined with '+' to select only
+ * suites matching all of them. A plain element adds the suites it selects,
+ * a '-' prefix removes them (later elements may add them back), '!' removes
+ * them for good, and '+' moves them to the end. "@STRENGTH" sorts suites by
+ * key length. As in OpenSSL, unknown names and aliases select nothing and
+ * are otherwise ignored, as are other '@' commands.
+ *
+ * As in OpenSSL, the cipher string only chooses among TLS 1.2 suites. All
+ * TLS 1.3 suites come first in the output, since OpenSSL enables them
+ * regardless of the cipher string.
+ *
+ * Returns RUSTLS_RESULT_INVALID_PARAMETER if `spec` is not UTF-8 or selects
+ * no TLS 1.2 suite, and RUSTLS_RESULT_INSUFFICIENT_SIZE if more than `max`
+ * suites are selected. On error, *out_n is set to 0.
+ */
+rustls_result rustls_ciphersuites_from_openssl_string(const char *spec,
+                                                      uint16_t *out_ids,
+                                                      size_t max,
+                                                      size_t *out_n);
+
 /**
  * Create a rustls_client_config_builder. Caller owns the memory and must
  * eventually call rustls_client_config_builder_build, then free the
  * resulting rustls_client_config.
  * This uses rustls safe default values
  * for the cipher suites, key exchange groups and protocol versions.
- * This starts out with no trusted roots.
- * Caller must add roots with rustls_client_config_builder_load_roots_from_file
- * or provide a custom verifier.
+ * This starts out with no trusted roots: no built-in or system roots are
+ * ever added implicitly, so a config can trust exactly the anchors the
+ * caller chooses. Caller must add roots with
+ * rustls_client_config_builder_use_roots or
+ * rustls_client_config_builder_load_roots_from_file, or provide a custom
+ * verifier.
  */
 struct rustls_client_config_builder *rustls_client_config_builder_new(void);
 
@@ -995,6 +1552,8 @@ struct rustls_client_config_builder *rustls_client_config_builder_new(void);
  *
  * `versions` will only be used during the call and the application retains
  * ownership. `len` is the number of consecutive `uint16_t` pointed to by `versions`.
+ * An empty list returns RUSTLS_RESULT_INVALID_PARAMETER, and a version
+ * rustls does not implement returns RUSTLS_RESULT_UNSUPPORTED_PROTOCOL_VERSION.
  */
 rustls_result rustls_client_config_builder_new_custom(const struct rustls_supported_ciphersuite *const *cipher_suites,
                                                       size_t cipher_suites_len,
@@ -1002,14 +1561,106 @@ rustls_result rustls_client_config_builder_new_custom(const struct rustls_suppor
                                                       size_t tls_versions_len,
                                                       struct rustls_client_config_builder **builder_out);
 
+/**
+ * Set the config userdata: an application pointer that every connection
+ * made with the built config can retrieve with
+ * rustls_client_connection_get_config_userdata. It is also the userdata
+ * passed to the config's callbacks, like a custom certificate verifier
+ * or client certificate resolver, for connections that have no userdata
+ * of their own (see rustls_connection_set_userdata), and to a key log
+ * callback registered without userdata.
+ *
+ * The pointer is never dereferenced by this library. The caller must
+ * keep what it points to valid for as long as the config or any
+ * connection made with it might use it.
+ */
+void rustls_client_config_builder_set_userdata(struct rustls_client_config_builder *builder,
+                                               void *userdata);
+
+/**
+ * Create a copy of `builder` with all the same settings. The caller owns
+ * the copy, and can go on configuring and build it independently of the
+ * original. This is useful for deriving several configs that differ in
+ * only a few settings, like ALPN protocols or client certificates, from
+ * one base builder.
+ *
+ * Objects the builder holds by reference, like root stores, certified
+ * keys and verifiers, are shared between the copies rather than copied.
+ * So is the session cache: call
+ * rustls_client_config_builder_set_session_cache_size on the copy to give
+ * it a cache of its own, e.g. if the copies use different client
+ * certificates. Returns NULL if `builder` is NULL.
+ */
+struct rustls_client_config_builder *rustls_client_config_builder_clone(const struct rustls_client_config_builder *builder);
+
+/**
+ * Replace the cipher suites used by this builder. `suite_ids` must point
+ * to an array of `len` 16-bit IANA cipher suite identifiers (see
+ * rustls_supported_ciphersuite_get_suite), in preference order. The
+ * order is preserved and honored during negotiation.
+ *
+ * Returns RUSTLS_RESULT_UNSUPPORTED_CIPHER_SUITE if any identifier is not
+ * implemented by rustls, and stores the index of the first such
+ * identifier in *out_failed_index unless `out_failed_index` is NULL.
+ * Returns RUSTLS_RESULT_INVALID_PARAMETER if the list is empty or has no
+ * suite usable with the builder's protocol versions. On error, the
+ * builder is unchanged.
+ */
+rustls_result rustls_client_config_builder_set_ciphersuites(struct rustls_client_config_builder *builder,
+                                                            const uint16_t *suite_ids,
+                                                            size_t len,
+                                                            size_t *out_failed_index);
+
+/**
+ * Replace the cipher suites used by this builder, looking them up by
+ * name. `names` must point to an array of `count` NUL-terminated strings,
+ * in preference order. Names are matched case-insensitively, ignoring
+ * surrounding whitespace, and may be given either in rustls' spelling
+ * ("TLS13_AES_128_GCM_SHA256") or IANA's ("TLS_AES_128_GCM_SHA256").
+ *
+ * Returns RUSTLS_RESULT_UNSUPPORTED_CIPHER_SUITE if any name does not
+ * match a cipher suite implemented by rustls, and stores the index of the
+ * first such name in *out_failed_index unless `out_failed_index` is NULL.
+ * Otherwise behaves like rustls_client_config_builder_set_ciphersuites.
+ */
+rustls_result rustls_client_config_builder_set_ciphersuites_by_name(struct rustls_client_config_builder *builder,
+                                                                    const char *const *names,
+                                                                    size_t count,
+                                                                    size_t *out_failed_index);
+
+/**
+ * Replace the cipher suites used by this builder with those selected by
+ * an OpenSSL cipher string like "ECDHE+AESGCM:!aNULL", in the order
+ * rustls_ciphersuites_from_openssl_string produces them. TLS 1.3 suites
+ * are always included, since OpenSSL cipher strings don't apply to them.
+ *
+ * Returns RUSTLS_RESULT_INVALID_PARAMETER if `spec` selects no TLS 1.2
+ * suite, or no suite usable with the builder's protocol versions. On
+ * error, the builder is unchanged.
+ */
+rustls_result rustls_client_config_builder_set_openssl_cipher_string(struct rustls_client_config_builder *builder,
+                                                                     const char *spec);
+
 /**
  * Set a custom server certificate verifier.
  *
- * The callback must not capture any of the pointers in its
- * rustls_verify_server_cert_params.
+ * The callback replaces certificate verification against trusted roots:
+ * it is called once per full handshake with the server's certificate
+ * chain as DER (the end-entity certificate, then any intermediates in
+ * the order the server sent them), the server name the connection was
+ * created with, and any stapled OCSP response. Handshakes that resume an
+ * earlier session don't call it again.
+ *
+ * The callback runs on the thread that called
+ * rustls_connection_process_new_packets, from inside that call. The
+ * pointers in its rustls_verify_server_cert_params are only valid until
+ * it returns, so it must copy anything it wants to keep.
  * If `userdata` has been set with rustls_connection_set_userdata, it
  * will be passed to the callback. Otherwise the userdata param passed to
- * the callback will be NULL.
+ * the callback will be NULL. This is the place for per-connection state.
+ *
+ * The callback must return normally: unwinding out of it (for instance
+ * a C++ exception) or leaving it with longjmp is undefined behavior.
  *
  * The callback must be safe to call on any thread at any time, including
  * multiple concurrent calls. So, for instance, if the callback mutates
@@ -1024,27 +1675,231 @@ rustls_result rustls_client_config_builder_new_custom(const struct rustls_suppor
  * If the custom verifier accepts the certificate, it should return
  * RUSTLS_RESULT_OK. Otherwise, it may return any other rustls_result error.
  * Feel free to use an appropriate error from the RUSTLS_RESULT_CERT_*
- * section.
+ * section. Any value other than RUSTLS_RESULT_OK fails the handshake;
+ * codes outside the RUSTLS_RESULT_CERT_* section, and values that
+ * aren't a rustls_result at all, are reported as
+ * RUSTLS_RESULT_CERT_APPLICATION_VERIFICATION_FAILURE.
  *
  * <https://docs.rs/rustls/latest/rustls/client/struct.DangerousClientConfig.html#method.set_certificate_verifier>
  */
 rustls_result rustls_client_config_builder_dangerous_set_certificate_verifier(struct rustls_client_config_builder *config_builder,
                                                                               rustls_verify_server_cert_callback callback);
 
+/**
+ * Accept any certificate the server presents, for any server name,
+ * without checking who issued it, whether it has expired, or whether it
+ * has been revoked.
+ *
+ * DANGER: this offers no protection against an attacker who can
+ * intercept the connection, since any certificate they present is
+ * accepted. It is meant for testing, and for reaching devices whose
+ * self-signed certificates can't be verified any other way.
+ *
+ * Everything else about the connection is unchanged: traffic is still
+ * encrypted, the server must still prove it holds the private key for
+ * the certificate it presented, and ALPN and session resumption work as
+ * usual. The accepted certificates can be inspected afterwards with
+ * rustls_connection_get_peer_certificate, for instance to log them.
+ *
+ * This replaces any trusted roots or custom verifier configured before.
+ */
+rustls_result rustls_client_config_builder_dangerous_disable_cert_verification(struct rustls_client_config_builder *config_builder);
+
+/**
+ * Pin the server's public key: in addition to the usual verification,
+ * require the verified certificate chain to include a certificate whose
+ * SubjectPublicKeyInfo has one of `count` SHA-256 hashes.
+ *
+ * `hashes` points to `count` 32-byte hashes, each the SHA-256 digest of
+ * a DER-encoded SubjectPublicKeyInfo, as in HTTP Public Key Pinning.
+ * They are copied; the caller may free them once this returns.
+ *
+ * If `pin_leaf_only` is true, only the end-entity certificate is
+ * checked against the pins. Otherwise an intermediate certificate sent
+ * by the server may match too, but only if it is on a verified path
+ * from the end-entity certificate; certificates the server sends that
+ * aren't part of the chain don't count. So may the trusted root the
+ * chain was verified up to, when verifying against a
+ * rustls_root_cert_store or roots loaded from a file.
+ *
+ * Pinning is applied on top of whatever verification the builder ends
+ * up with, whichever order the functions are called in; a chain that
+ * fails that verification is reported with the usual certificate error.
+ * A chain that verifies but doesn't match a pin fails the handshake with
+ * RUSTLS_RESULT_PIN_VIOLATION.
+ *
+ * Returns `InvalidParameter` if `count` is zero. Calling this again
+ * replaces the pins set before.
+ */
+rustls_result rustls_client_config_builder_set_pinned_spki_sha256(struct rustls_client_config_builder *config_builder,
+                                                                  const uint8_t (*hashes)[32],
+                                                                  size_t count,
+                                                                  bool pin_leaf_only);
+
+/**
+ * Accept only the given end-entity certificate: the certificate the
+ * server presents must be byte-for-byte identical to the `len` bytes of
+ * DER at `der`. This is meant for devices with long-lived self-signed
+ * certificates.
+ *
+ * DANGER: nothing else about the certificate is checked. No chain is
+ * built, so trusted roots are ignored; the server name isn't compared
+ * with the certificate; and the certificate may have expired. The
+ * server must still prove that it holds the certificate's private key.
+ *
+ * The certificate is copied; the caller may free `der` once this
+ * returns. A server presenting any other certificate fails the
+ * handshake with RUSTLS_RESULT_PINNED_CERT_MISMATCH.
+ *
+ * Returns `CertificateParseError` if `der` isn't a certificate. This
+ * replaces any trusted roots or custom verifier configured before.
+ */
+rustls_result rustls_client_config_builder_set_pinned_cert_der(struct rustls_client_config_builder *config_builder,
+                                                               const uint8_t *der,
+                                                               size_t len);
+
+/**
+ * Verify the server's certificate chain as usual, but don't check that
+ * the certificate is valid for the server name the connection was
+ * created with. This is for reaching servers by an address their
+ * certificate doesn't name, like OpenSSL's verification without
+ * X509_check_host.
+ *
+ * DANGER: any server with a certificate from a trusted root can
+ * impersonate any other. Unlike
+ * rustls_client_config_builder_dangerous_disable_cert_verification, the
+ * chain is still checked, so untrusted, expired or otherwise invalid
+ * certificates are rejected with the usual errors.
+ *
+ * This applies to whichever trusted roots or custom verifier the builder
+ * ends up with, whichever order the functions are called in. With a
+ * custom verifier, only a RUSTLS_RESULT_CERT_NOT_VALID_FOR_NAME result is
+ * ignored. The server name is still sent in the SNI extension.
+ */
+rustls_result rustls_client_config_builder_dangerous_skip_hostname_verification(struct rustls_client_config_builder *config_builder);
+
+/**
+ * Check the validity periods of server certificates as if the current
+ * time were `unix_seconds` seconds after the Unix epoch, instead of
+ * reading the system clock. This is for tests with fixed or expired
+ * certificates, and for machines whose clock can't be trusted. Passing 0
+ * goes back to using the system clock.
+ *
+ * Only certificate verification sees this time; everything else, such
+ * as session ticket lifetimes, still uses the system clock. With a
+ * custom verifier, it is the only time the verifier is given.
+ */
+rustls_result rustls_client_config_builder_set_verification_time(struct rustls_client_config_builder *config_builder,
+                                                                 uint64_t unix_seconds);
+
+/**
+ * Check the signed certificate timestamps (SCTs) servers send against
+ * the given certificate transparency logs. `log_keys` points to `count`
+ * DER-encoded SubjectPublicKeyInfo structures, one per log; each log is
+ * identified by the SHA-256 hash of its key, per RFC 6962. To use the
+ * list bundled with rustls-ffi instead, see
+ * rustls_client_config_builder_set_bundled_ct_logs.
+ *
+ * If the server sends SCTs, at least one must be valid and issued by
+ * one of these logs, or the handshake fails with one of the
+ * RUSTLS_RESULT_CERT_SCT_* errors. As in rustls, certificates without
+ * SCTs are accepted, and only SCTs delivered in the TLS handshake are
+ * checked, not ones embedded in the certificate.
+ *
+ * CT logs come and go, so after `validation_deadline` (in seconds since
+ * the Unix epoch) the logs can't be relied on, and every handshake fails
+ * with RUSTLS_RESULT_CT_LOGS_EXPIRED until the logs are replaced. Pass a
+ * deadline shortly after the next time you expect to update the log
+ * list.
+ *
+ * Passing a `count` of 0 turns CT verification back off. Returns
+ * InvalidParameter if any log key can't be parsed or
+ * `validation_deadline` is out of range.
+ */
+rustls_result rustls_client_config_builder_set_ct_logs(struct rustls_client_config_builder *builder,
+                                                       const struct rustls_slice_bytes *log_keys,
+                                                       size_t count,
+                                                       uint64_t validation_deadline);
+
+/**
+ * Check the signed certificate timestamps servers send against the
+ * certificate transparency logs bundled with rustls-ffi, from the
+ * ct-logs crate, as rustls_client_config_builder_set_ct_logs does for
+ * logs the caller provides. This replaces any logs set earlier.
+ *
+ * The bundled list only changes when rustls-ffi is rebuilt, so logs
+ * started since then are unknown and SCTs from them are rejected. Use
+ * rustls_bundled_ct_logs_timestamp to decide whether the list is recent
+ * enough, and pick `validation_deadline` accordingly.
+ *
+ * This requires rustls-ffi to be built with the `bundled_ct_logs` cargo
+ * feature. Otherwise it always returns Unsupported and leaves the
+ * builder unchanged.
+ */
+rustls_result rustls_client_config_builder_set_bundled_ct_logs(struct rustls_client_config_builder *builder,
+                                                               uint64_t validation_deadline);
+
+/**
+ * Authenticate servers with the `count` TLSA records at `records`
+ * (DANE, RFC 6698 and RFC 7671). The caller is responsible for looking
+ * the records up and validating them with DNSSEC; since they belong to
+ * one server's name and port, a config with TLSA records should only be
+ * used for connections to that server. The records are copied; the
+ * caller may free them once this returns.
+ *
+ * The server is accepted if any record matches:
+ *
+ * - DANE-EE (3): the end-entity certificate matches. Nothing else is
+ *   checked, not even the server name or the validity period.
+ * - DANE-TA (2): a certificate the server sent matches, and the
+ *   end-entity certificate verifies with it as the only trust anchor,
+ *   including the server name and validity checks.
+ * - PKIX-EE (1) and PKIX-TA (0): as DANE-EE and DANE-TA, and the chain
+ *   also passes the builder's usual verification, against its trusted
+ *   roots or custom verifier.
+ *
+ * DANE-EE and DANE-TA records replace the usual verification; with
+ * only those, no trusted roots are needed. If no record matches, the
+ * handshake fails with RUSTLS_RESULT_TLSA_MISMATCH. If a record matches
+ * but the chain then fails verification, that failure is reported
+ * instead, with the usual certificate error.
+ *
+ * Records with an unknown usage, selector or matching type, or with a
+ * digest of the wrong length, are unusable and ignored. If no record is
+ * usable, or `count` is 0, servers are verified as if this had never
+ * been called, as RFC 6698 section 4.1 requires. Calling this again
+ * replaces the records set before.
+ */
+rustls_result rustls_client_config_builder_set_tlsa_records(struct rustls_client_config_builder *config_builder,
+                                                            const struct rustls_tlsa_record *records,
+                                                            size_t count);
+
 /**
  * Use the trusted root certificates from the provided store.
  *
- * This replaces any trusted roots already configured with copies
- * from `roots`. This adds 1 to the refcount for `roots`. When you
- * call rustls_client_config_free or rustls_client_config_builder_free,
- * those will subtract 1 from the refcount for `roots`.
+ * This replaces any trusted roots already configured. Builders and
+ * configs that use the same store share one copy of its roots instead
+ * of each copying them. Certificates added to `roots` later are not
+ * seen by this builder. `roots` may be freed as soon as this returns.
  */
 rustls_result rustls_client_config_builder_use_roots(struct rustls_client_config_builder *config_builder,
                                                      const struct rustls_root_cert_store *roots);
 
 /**
  * Add trusted root certificates from the named file, which should contain
- * PEM-formatted certificates.
+ * PEM-formatted certificates. This replaces any trusted roots already
+ * configured.
+ *
+ * On Unix, `filename` is used as raw bytes and need not be valid UTF-8.
+ * Elsewhere it must be UTF-8.
+ *
+ * Returns RUSTLS_RESULT_IO if the file can't be opened or read,
+ * RUSTLS_RESULT_NO_CERTIFICATES_FOUND if it contains no certificates,
+ * and RUSTLS_RESULT_CERTIFICATE_PARSE_ERROR if it is malformed or any of
+ * the certificates in it could not be parsed. Since no certificate is
+ * ever silently skipped, this behaves like the `strict` mode of the
+ * rustls_root_cert_store loaders; to load the usable certificates from a
+ * file and count the rest, use rustls_root_cert_store_add_pem.
  */
 rustls_result rustls_client_config_builder_load_roots_from_file(struct rustls_client_config_builder *config_builder,
                                                                 const char *filename);
@@ -1059,6 +1914,8 @@ rustls_result rustls_client_config_builder_load_roots_from_file(struct rustls_cl
  *
  * This function makes a copy of the data in `protocols` and does not retain
  * any pointers, so the caller can free the pointed-to memory after calling.
+ * If any element has a NULL `data` field, this returns
+ * RUSTLS_RESULT_NULL_PARAMETER and leaves the configured list unchanged.
  *
  * <https://docs.rs/rustls/latest/rustls/client/struct.ClientConfig.html#structfield.alpn_protocols>
  */
@@ -1067,17 +1924,208 @@ rustls_result rustls_client_config_builder_set_alpn_protocols(struct rustls_clie
                                                               size_t len);
 
 /**
- * Enable or disable SNI.
+ * Enable or disable SNI. SNI is enabled by default.
+ *
+ * When disabled, the ClientHello carries no server_name extension, so a
+ * server that picks its certificate by SNI will present its default
+ * certificate. The name passed to rustls_client_connection_new is still
+ * required, and the server's certificate is still verified against it.
  * <https://docs.rs/rustls/latest/rustls/struct.ClientConfig.html#structfield.enable_sni>
  */
 void rustls_client_config_builder_set_enable_sni(struct rustls_client_config_builder *config,
                                                  bool enable);
 
 /**
- * Provide the configuration a list of certificates where the connection
- * will select the first one that is compatible with the server's signature
- * verification capabilities. Clients that want to support both ECDSA and
- * RSA certificates will want the ECSDA to go first in the list.
+ * Never resume TLS sessions: connections made with the built config
+ * neither store nor offer session IDs or tickets, so every connection
+ * performs a full handshake.
+ * <https://docs.rs/rustls/latest/rustls/client/struct.Resumption.html#method.disabled>
+ */
+rustls_result rustls_client_config_builder_disable_resumption(struct rustls_client_config_builder *builder);
+
+/**
+ * Remember TLS sessions for up to `max_entries` servers, so that later
+ * connections to them can resume. When the cache is full, the server
+ * added to it longest ago is forgotten. Memory is only allocated as
+ * servers are added, so very large values are fine. Passing 0 is the same
+ * as rustls_client_config_builder_disable_resumption.
+ *
+ * This replaces the default cache, rustls's in-memory cache of 256
+ * entries, where each TLS 1.3 ticket, TLS 1.2 session and key exchange
+ * hint takes one entry; it also replaces any earlier call to this
+ * function or rustls_client_config_builder_disable_resumption.
+ */
+rustls_result rustls_client_config_builder_set_session_cache_size(struct rustls_client_config_builder *builder,
+                                                                  size_t max_entries);
+
+/**
+ * Enable or disable offering early data (0-RTT) to servers. Early data
+ * is disabled by default.
+ *
+ * Early data can only be sent when resuming a TLS 1.3 session with a
+ * server that allowed it, so the first connection to a server never
+ * offers it. Enabling this only makes the client offer early data;
+ * nothing is sent as early data unless the application writes it with
+ * rustls_client_connection_write_early_data.
+ *
+ * Early data is not protected against replay, so it should only carry
+ * requests that are safe to repeat.
+ * <https://docs.rs/rustls/latest/rustls/client/struct.ClientConfig.html#structfield.enable_early_data>
+ */
+void rustls_client_config_builder_set_enable_early_data(struct rustls_client_config_builder *builder,
+                                                        bool enable);
+
+/**
+ * Limit the size of the TLS records this client emits to `max_fragment_size`
+ * bytes, including the 5-byte record header. Encryption adds its own
+ * overhead on top of this, so records may be a few dozen bytes larger on
+ * the wire. Pass 0 to remove the limit, which is the default.
+ *
+ * Returns InvalidParameter if `max_fragment_size` is not between 32 and
+ * 16389, the range rustls supports.
+ * <https://docs.rs/rustls/latest/rustls/client/struct.ClientConfig.html#structfield.max_fragment_size>
+ */
+rustls_result rustls_client_config_builder_set_max_fragment_size(struct rustls_client_config_builder *builder,
+                                                                 size_t max_fragment_size);
+
+/**
+ * Append the secrets of every TLS session made with this config to the
+ * file named by the SSLKEYLOGFILE environment variable, in the NSS key
+ * log format understood by Wireshark.
+ *
+ * WARNING: this writes secrets to disk. Anyone who can read the file can
+ * decrypt the traffic of every logged session. Only use it for debugging.
+ *
+ * Returns EnvironmentNotSet if SSLKEYLOGFILE is not set, and Io if the
+ * file cannot be opened for appending. Either way no key log is
+ * installed.
+ */
+rustls_result rustls_client_config_builder_enable_keylog_file(struct rustls_client_config_builder *builder);
+
+/**
+ * Like rustls_client_config_builder_enable_keylog_file, but append to
+ * the file at `filename` regardless of SSLKEYLOGFILE.
+ *
+ * WARNING: this writes secrets to disk. Anyone who can read the file can
+ * decrypt the traffic of every logged session. Only use it for debugging.
+ *
+ * Returns Io if the file cannot be opened for appending, in which case
+ * no key log is installed.
+ */
+rustls_result rustls_client_config_builder_set_keylog_file(struct rustls_client_config_builder *builder,
+                                                           const char *filename);
+
+/**
+ * Pass the secrets of every TLS session made with this config to
+ * `callback`, along with `userdata`, or the config userdata (see
+ * rustls_client_config_builder_set_userdata) if `userdata` is NULL. See
+ * rustls_keylog_log_callback for the requirements on the callback.
+ * Passing NULL for `callback` removes any key log installed on the
+ * builder.
+ *
+ * WARNING: anything that sees these secrets can decrypt the traffic of
+ * the sessions they belong to. Only use this for debugging.
+ *
+ * Any key log set by rustls_client_config_builder_enable_keylog_file or
+ * rustls_client_config_builder_set_keylog_file is replaced.
+ */
+rustls_result rustls_client_config_builder_set_keylog_callback(struct rustls_client_config_builder *builder,
+                                                               rustls_keylog_log_callback callback,
+                                                               void *userdata);
+
+/**
+ * Enable or disable TLS 1.2 session tickets (RFC 5077). Tickets are
+ * enabled by default. When disabled, TLS 1.2 sessions can still be
+ * resumed by session ID, provided the server keeps a session cache.
+ *
+ * This only affects TLS 1.2. TLS 1.3 always resumes with tickets, and is
+ * unaffected by this setting. Note that while TLS 1.3 is enabled, the
+ * ClientHello still asks for a ticket, since it may be answered in TLS
+ * 1.3; use rustls_client_config_builder_new_custom to allow only TLS 1.2
+ * if the extension must not be sent at all.
+ * <https://docs.rs/rustls/latest/rustls/client/enum.Tls12Resumption.html>
+ */
+void rustls_client_config_builder_set_enable_tickets(struct rustls_client_config_builder *builder,
+                                                     bool enable);
+
+/**
+ * Present a client certificate to servers that ask for one.
+ *
+ * `cert_chain_pem` must point to `chain_len` bytes of PEM-encoded
+ * certificates, with the end-entity (leaf) certificate first, and
+ * `key_pem` to `key_len` bytes holding the PEM-encoded private key for
+ * the leaf, in PKCS#8, PKCS#1 (RSA) or SEC1 (EC) format; see
+ * rustls_certified_key_build for how the key is chosen. Both are
+ * copied; the caller may free them once this returns.
+ *
+ * Returns `CertificateParseError` if the chain can't be parsed or is
+ * empty, `NoPrivateKeyFound`, `UnsupportedPrivateKeyType` or
+ * `PrivateKeyParseError` if there's no usable key, and
+ * `CertKeyMismatch` if the key doesn't belong to the leaf certificate.
+ * On error the builder is unchanged.
+ *
+ * This replaces any certified keys or client authentication callback
+ * configured before.
+ */
+rustls_result rustls_client_config_builder_set_client_cert_pem(struct rustls_client_config_builder *builder,
+                                                               const uint8_t *cert_chain_pem,
+                                                               size_t chain_len,
+                                                               const uint8_t *key_pem,
+                                                               size_t key_len);
+
+/**
+ * Present a client certificate to servers that ask for one, from DER.
+ *
+ * `certs_der` and `cert_lens` are parallel arrays of `cert_count`
+ * entries: `certs_der[i]` points to `cert_lens[i]` bytes holding one
+ * DER-encoded certificate. The chain is sent in the order given, so the
+ * end-entity (leaf) certificate must come first. `key_der` points to
+ * `key_len` bytes holding the leaf's private key as DER-encoded PKCS#8,
+ * PKCS#1 (RSA) or SEC1 (EC). Everything is copied; the caller may free
+ * the buffers once this returns. The copy of the key bytes is zeroed
+ * once the key has been parsed; the parsed key itself is held by the
+ * crypto library and isn't zeroed when the config is freed.
+ *
+ * Returns `InvalidParameter` if `cert_count` is zero or any certificate
+ * is empty, `CertificateParseError` if the leaf can't be parsed,
+ * `UnsupportedPrivateKeyType` if the key uses an algorithm rustls can't
+ * sign with, `PrivateKeyParseError` if the key can't be parsed, and
+ * `CertKeyMismatch` if the key doesn't belong to the leaf. On error
+ * the builder is unchanged.
+ *
+ * This replaces any certified keys or client authentication callback
+ * configured before.
+ */
+rustls_result rustls_client_config_builder_set_client_cert_der(struct rustls_client_config_builder *builder,
+                                                               const uint8_t *const *certs_der,
+                                                               const size_t *cert_lens,
+                                                               size_t cert_count,
+                                                               const uint8_t *key_der,
+                                                               size_t key_len);
+
+/**
+ * Register a callback that chooses the client certificate to send each
+ * time a server asks for one, for instance to present a different
+ * identity to each backend. If `userdata` has been set with
+ * rustls_connection_set_userdata, it will be passed to the callback.
+ * Otherwise the userdata param passed to the callback will be NULL.
+ *
+ * If the callback returns NULL the handshake continues without a client
+ * certificate; whether that succeeds is up to the server. See
+ * rustls_client_cert_resolver_callback for the rules the callback must
+ * follow. It is not permitted to pass a NULL value for `callback`.
+ *
+ * This replaces any certified keys or client certificate configured
+ * before, and vice versa.
+ */
+rustls_result rustls_client_config_builder_set_client_cert_resolver(struct rustls_client_config_builder *builder,
+                                                                    rustls_client_cert_resolver_callback callback);
+
+/**
+ * Provide the configuration a list of certificates where the connection
+ * will select the first one that is compatible with the server's signature
+ * verification capabilities. Clients that want to support both ECDSA and
+ * RSA certificates will want the ECSDA to go first in the list.
  *
  * The built configuration will keep a reference to all certified keys
  * provided. The client may `rustls_certified_key_free()` afterwards
@@ -1093,10 +2141,31 @@ rustls_result rustls_client_config_builder_set_certified_key(struct rustls_clien
 
 /**
  * Turn a *rustls_client_config_builder (mutable) into a const *rustls_client_config
- * (read-only).
+ * (read-only). Returns NULL if the builder's settings can't be combined
+ * into a valid configuration; use rustls_client_config_builder_build_ex
+ * to find out why.
+ *
+ * This consumes the builder: it is freed whether or not building
+ * succeeds, and must not be passed to any other function afterwards. The
+ * resulting config can't be changed, so it is safe to share between
+ * threads and connections.
  */
 const struct rustls_client_config *rustls_client_config_builder_build(struct rustls_client_config_builder *builder);
 
+/**
+ * Build a rustls_client_config as rustls_client_config_builder_build
+ * does, reporting why that failed. Returns InvalidParameter if the
+ * builder's cipher suites and protocol versions can't be combined. On
+ * success the memory pointed to by `config_out` is set to the new
+ * config, which the caller frees with rustls_client_config_free;
+ * otherwise it is left unchanged.
+ *
+ * Like rustls_client_config_builder_build, this consumes the builder
+ * whether or not building succeeds, unless `config_out` is NULL.
+ */
+rustls_result rustls_client_config_builder_build_ex(struct rustls_client_config_builder *builder,
+                                                    const struct rustls_client_config **config_out);
+
 /**
  * "Free" a client_config_builder without building it into a rustls_client_config.
  * Normally builders are built into rustls_client_config via `rustls_client_config_builder_build`
@@ -1107,15 +2176,94 @@ const struct rustls_client_config *rustls_client_config_builder_build(struct rus
 void rustls_client_config_builder_free(struct rustls_client_config_builder *config);
 
 /**
- * "Free" a rustls_client_config previously returned from
- * rustls_client_config_builder_build. Since rustls_client_config is actually an
+ * When the certificate transparency log list used by
+ * rustls_client_config_builder_set_bundled_ct_logs was published, in
+ * seconds since the Unix epoch, or 0 if rustls-ffi was built without the
+ * `bundled_ct_logs` cargo feature.
+ */
+uint64_t rustls_bundled_ct_logs_timestamp(void);
+
+/**
+ * Take another reference to `config`, so that it stays usable until a
+ * matching rustls_client_config_free. This suits bindings whose wrapper
+ * objects can share a config and be released independently: each takes
+ * its own reference and frees it when done. The same pointer is used for
+ * every reference. Calling with NULL is fine.
+ */
+void rustls_client_config_incref(const struct rustls_client_config *config);
+
+/**
+ * Release one reference to a rustls_client_config: the one returned from
+ * rustls_client_config_builder_build, or one taken with
+ * rustls_client_config_incref. Since rustls_client_config is actually an
  * atomically reference-counted pointer, extant client connections may still
- * hold an internal reference to the Rust object. However, C code must
- * consider this pointer unusable after "free"ing it.
- * Calling with NULL is fine. Must not be called twice with the same value.
+ * hold an internal reference to the Rust object, which is dropped once
+ * the last reference goes. C code must consider the pointer unusable
+ * once it has released every reference it took.
+ * Calling with NULL is fine. Releasing more references than were taken
+ * is undefined behavior. Debug builds of rustls-ffi check for it, and
+ * panic, with the panic caught and nothing freed, rather than release a
+ * reference that C does not hold.
  */
 void rustls_client_config_free(const struct rustls_client_config *config);
 
+/**
+ * Write the TLS protocol versions enabled in `config` to `versions_out`,
+ * as the integers defined in rustls_tls_version, and store how many
+ * were written in *out_n. Returns RUSTLS_RESULT_INSUFFICIENT_SIZE, with
+ * *out_n set to 0, if there are more than `count` of them.
+ */
+rustls_result rustls_client_config_get_versions(const struct rustls_client_config *config,
+                                                uint16_t *versions_out,
+                                                size_t count,
+                                                size_t *out_n);
+
+/**
+ * Return the number of ALPN protocols `config` offers, or 0 if `config`
+ * is NULL.
+ */
+size_t rustls_client_config_get_alpn_count(const struct rustls_client_config *config);
+
+/**
+ * Write the ALPN protocol at position `index` in `config`'s preference
+ * order to `buf`, and store its length in *out_n. Returns
+ * RUSTLS_RESULT_NOT_FOUND if `index` is not less than
+ * rustls_client_config_get_alpn_count, and
+ * RUSTLS_RESULT_INSUFFICIENT_SIZE if the protocol is longer than `count`.
+ * On error, *out_n is set to 0.
+ */
+rustls_result rustls_client_config_get_alpn(const struct rustls_client_config *config,
+                                            size_t index,
+                                            uint8_t *buf,
+                                            size_t count,
+                                            size_t *out_n);
+
+/**
+ * Return the number of cipher suites `config` offers, or 0 if `config`
+ * is NULL. Suites that none of the enabled protocol versions can use
+ * are not counted.
+ */
+size_t rustls_client_config_get_ciphersuite_count(const struct rustls_client_config *config);
+
+/**
+ * Return the cipher suite at position `index` in `config`'s preference
+ * order, or NULL if `index` is not less than
+ * rustls_client_config_get_ciphersuite_count. Like the result of
+ * rustls_all_ciphersuites_get_entry, the returned pointer is valid for
+ * the lifetime of the program.
+ */
+const struct rustls_supported_ciphersuite *rustls_client_config_get_ciphersuite(const struct rustls_client_config *config,
+                                                                                size_t index);
+
+/**
+ * Return the number of root certificates `config` trusts, as configured
+ * with rustls_client_config_builder_use_roots or
+ * rustls_client_config_builder_load_roots_from_file. This is 0 if
+ * `config` is NULL, or if it verifies servers some other way, such as
+ * with a custom verifier or a pinned certificate.
+ */
+size_t rustls_client_config_root_cert_count(const struct rustls_client_config *config);
+
 /**
  * Create a new rustls_connection containing a client connection and return
  * it in the output parameter `out`. If this returns an error code, the
@@ -1126,19 +2274,289 @@ void rustls_client_config_free(const struct rustls_client_config *config);
  *
  * The server_name parameter can contain a hostname or an IP address in
  * textual form (IPv4 or IPv6). This function will return an error if it
- * cannot be parsed as one of those types.
+ * cannot be parsed as one of those types. A hostname may end in a single
+ * dot, which is dropped, and may contain Unicode labels, which are
+ * converted to their ASCII (punycode) form as IDNA specifies; names that
+ * fail that conversion get InvalidDnsNameError too. It is sent to the server in
+ * the SNI extension (unless SNI was disabled with
+ * rustls_client_config_builder_set_enable_sni) and the server's
+ * certificate is verified against it.
  */
 rustls_result rustls_client_connection_new(const struct rustls_client_config *config,
                                            const char *server_name,
                                            struct rustls_connection **conn_out);
 
+/**
+ * Like rustls_client_connection_new, but `server_name` is given as a
+ * pointer and length rather than a NUL-terminated string, so a name
+ * inside a larger buffer can be used without copying it. The
+ * `server_name_len` bytes must be ASCII (or UTF-8) text; a NUL byte
+ * among them makes the name invalid, and InvalidDnsNameError is
+ * returned.
+ */
+rustls_result rustls_client_connection_new_slice(const struct rustls_client_config *config,
+                                                 const char *server_name,
+                                                 size_t server_name_len,
+                                                 struct rustls_connection **conn_out);
+
+/**
+ * Like rustls_client_connection_new, but verify the server's certificate
+ * against `verify_hostname` instead of `sni_hostname`. `sni_hostname` is
+ * still sent in the ClientHello (if SNI is enabled). This is useful when
+ * the name a server is reached by differs from the name on its
+ * certificate, e.g. when connecting through a fronting proxy.
+ *
+ * Both names are parsed the same way as `server_name` in
+ * rustls_client_connection_new, and InvalidDnsNameError is returned if
+ * either cannot be parsed. If `verify_hostname` is NULL, or names the
+ * same host as `sni_hostname`, this behaves exactly like
+ * rustls_client_connection_new.
+ *
+ * Any custom verifier set with
+ * rustls_client_config_builder_dangerous_set_certificate_verifier sees
+ * `verify_hostname` as the server_name in its parameters.
+ *
+ * Sessions are cached under `verify_hostname` rather than `sni_hostname`,
+ * so a connection only resumes a session whose certificate was verified
+ * against the name it expects.
+ */
+rustls_result rustls_client_connection_new_with_verify_hostname(const struct rustls_client_config *config,
+                                                                const char *sni_hostname,
+                                                                const char *verify_hostname,
+                                                                struct rustls_connection **conn_out);
+
+/**
+ * Create a new client connection as rustls_client_connection_new does,
+ * with the settings in `options`. This takes the place of a separate
+ * constructor for each combination of per-connection settings; see
+ * rustls_client_connection_options for how a caller built against an
+ * older rustls-ffi keeps working with a newer one.
+ *
+ * Returns InvalidParameter if `options->struct_size` isn't a size this
+ * version knows, including one from a newer version with fields it can't
+ * honor. Returns InvalidDnsNameError if a name can't be parsed. On
+ * success the memory pointed to by `conn_out` is set to the new
+ * connection, which the caller frees with rustls_connection_free;
+ * otherwise it is left unchanged.
+ */
+rustls_result rustls_client_connection_new_ex(const struct rustls_client_config *config,
+                                              const struct rustls_client_connection_options *options,
+                                              struct rustls_connection **conn_out);
+
+/**
+ * Like rustls_client_connection_new, but take the server name from a
+ * rustls_server_name made earlier with rustls_server_name_new, so it
+ * isn't parsed again for every connection. `name` is only borrowed:
+ * the application still frees it when it is done making connections.
+ */
+rustls_result rustls_client_connection_new_with_name(const struct rustls_client_config *config,
+                                                     const struct rustls_server_name *name,
+                                                     struct rustls_connection **conn_out);
+
+/**
+ * Reuse the client connection `conn` for a new connection to
+ * `server_name`, made from `config` as rustls_client_connection_new
+ * would, instead of freeing it and making another. The old TLS session
+ * is dropped without sending anything more, so send close_notify first
+ * if the peer should see a clean close.
+ *
+ * The connection's userdata, log callback, buffer limit and
+ * rustls_connection_get_counters totals carry over. So does a
+ * handshake-done callback that hasn't run yet; one that ran for the old
+ * handshake is gone and must be set again. Everything else starts
+ * afresh, including any name given to
+ * rustls_client_connection_new_with_verify_hostname, buffered TLS output
+ * and unread plaintext. `config` need not be the one `conn` was made
+ * with.
+ *
+ * Pointers returned earlier by rustls_connection_peek_tls_output,
+ * rustls_connection_peek_plaintext and the like point into buffers
+ * this reuses; dereferencing one after the reset is undefined behavior.
+ *
+ * Returns InvalidParameter if `conn` is a server connection and
+ * InvalidDnsNameError if `server_name` can't be parsed; `conn` is left
+ * unchanged in either case.
+ */
+rustls_result rustls_client_connection_reset(struct rustls_connection *conn,
+                                             const struct rustls_client_config *config,
+                                             const char *server_name);
+
+/**
+ * Parse `name`, a NUL-terminated hostname or textual IP address, the way
+ * rustls_client_connection_new would, and store the result in *name_out.
+ * Returns InvalidDnsNameError if it can't be parsed, leaving *name_out
+ * unchanged. On success the caller owns the rustls_server_name and must
+ * free it with rustls_server_name_free; it can be used by any number of
+ * connections, from any thread, in the meantime.
+ */
+rustls_result rustls_server_name_new(const char *name, const struct rustls_server_name **name_out);
+
+/**
+ * Check whether `name`, `name_len` bytes long and not necessarily
+ * NUL-terminated, would be accepted as a server name by
+ * rustls_client_connection_new and the other connection constructors,
+ * after the same normalization. Returns RUSTLS_RESULT_OK if so, and
+ * InvalidDnsNameError if not. Useful for checking configuration up front.
+ * Nothing is allocated or logged unless the name has Unicode labels.
+ */
+rustls_result rustls_server_name_validate(const char *name, size_t name_len);
+
+/**
+ * Free a rustls_server_name made by rustls_server_name_new. Connections
+ * made with it are unaffected. Calling with NULL is fine. Must not be
+ * called twice with the same value.
+ */
+void rustls_server_name_free(const struct rustls_server_name *name);
+
+/**
+ * Write up to `count` bytes from `buf` as early data (0-RTT), to be sent
+ * along with the ClientHello. On success, store the number of bytes actually
+ * written in *out_n; this may be less than `count` if the server limits how
+ * much early data it takes. Returns EarlyDataUnavailable if early data can't
+ * be written on this connection: early data was not enabled with
+ * rustls_client_config_builder_set_enable_early_data, no resumable TLS 1.3
+ * session allowing early data was found for the server, or the ClientHello
+ * has already been sent. Returns InvalidParameter for server connections.
+ *
+ * Early data has to be written after creating the connection and before the
+ * first call to rustls_connection_write_tls. The server may reject it, in
+ * which case the bytes are discarded. Once rustls_connection_is_handshaking
+ * returns false, call rustls_client_connection_is_early_data_accepted: if it
+ * reports false, the application must write the same bytes again with
+ * rustls_connection_write.
+ * <https://docs.rs/rustls/latest/rustls/client/struct.ClientConnection.html#method.early_data>
+ */
+rustls_result rustls_client_connection_write_early_data(struct rustls_connection *conn,
+                                                        const uint8_t *buf,
+                                                        size_t count,
+                                                        size_t *out_n);
+
+/**
+ * Store in *out whether the server accepted the early data written with
+ * rustls_client_connection_write_early_data. This is only meaningful once
+ * the handshake has completed; before then, *out is always false. If it is
+ * false after the handshake, any early data was discarded by the server and
+ * must be written again with rustls_connection_write. Returns
+ * InvalidParameter for server connections.
+ * <https://docs.rs/rustls/latest/rustls/client/struct.ClientConnection.html#method.is_early_data_accepted>
+ */
+rustls_result rustls_client_connection_is_early_data_accepted(const struct rustls_connection *conn,
+                                                              bool *out);
+
+/**
+ * Store in *out the config userdata of the rustls_client_config `conn` was
+ * made with, as set by rustls_client_config_builder_set_userdata, or NULL if
+ * none was set. Returns InvalidParameter for server connections.
+ */
+rustls_result rustls_client_connection_get_config_userdata(const struct rustls_connection *conn,
+                                                           void **out);
+
+/**
+ * Fill in *out with a summary of the handshake on client connection `conn`.
+ * Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE while still handshaking,
+ * InvalidParameter for server connections, and InsufficientSize if the
+ * negotiated ALPN protocol is longer than 32 bytes. *out is only written on
+ * success.
+ */
+rustls_result rustls_client_connection_get_handshake_info(const struct rustls_connection *conn,
+                                                          struct rustls_handshake_info *out);
+
+/**
+ * Store in *out whether the completed handshake on client connection `conn`
+ * resumed an earlier session, by session ID, ticket or TLS 1.3 PSK, rather
+ * than doing a full handshake. The same as the `resumed` field filled in by
+ * rustls_client_connection_get_handshake_info.
+ *
+ * rustls doesn't report this directly. A resumed handshake never asks for
+ * the server's certificate to be verified, so that is what rustls-ffi
+ * watches for. Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE while still
+ * handshaking, and InvalidParameter for server connections.
+ */
+rustls_result rustls_client_connection_was_resumed(const struct rustls_connection *conn, bool *out);
+
+/**
+ * Copy the `tls-server-end-point` channel binding (RFC 5929) for the
+ * connection into `buf`, storing its length in *out_n. This is the hash of
+ * the server's end-entity certificate, by the digest of the certificate's
+ * signature algorithm, or SHA-256 if that is MD5 or SHA-1. SCRAM-SHA-256-PLUS,
+ * as PostgreSQL uses, needs it to tie authentication to the TLS connection.
+ * 64 bytes is always enough.
+ *
+ * Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE until the handshake has
+ * completed, RUSTLS_RESULT_INVALID_PARAMETER if `conn` is a server
+ * connection, RUSTLS_RESULT_UNSUPPORTED if the certificate's signature
+ * algorithm has no single digest, as with Ed25519, or can't be parsed, and
+ * RUSTLS_RESULT_INSUFFICIENT_SIZE, with *out_n set to 0, if `len` is too
+ * small.
+ */
+rustls_result rustls_client_connection_get_channel_binding_tls_server_end_point(const struct rustls_connection *conn,
+                                                                                uint8_t *buf,
+                                                                                size_t len,
+                                                                                size_t *out_n);
+
+/**
+ * Store in *out the number of entries in the chain the server's certificate
+ * was verified along. See rustls_client_connection_get_verified_chain.
+ *
+ * Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE until the handshake has
+ * completed, RUSTLS_RESULT_INVALID_PARAMETER if `conn` is a server
+ * connection, and RUSTLS_RESULT_VERIFIED_CHAIN_UNAVAILABLE if no chain was
+ * recorded.
+ */
+rustls_result rustls_client_connection_get_verified_chain_count(const struct rustls_connection *conn,
+                                                                size_t *out);
+
+/**
+ * Store a pointer to the DER encoding of the entry at `index` in the chain
+ * the server's certificate was verified along in *out_der, and its length
+ * in *out_len.
+ *
+ * Unlike the certificates from rustls_connection_get_peer_certificate_der,
+ * which are what the server sent, this is the path verification took:
+ * index 0 is the end-entity certificate, followed by the intermediates that
+ * were needed, each the issuer of the one before, whatever order the server
+ * sent them in. Certificates the server sent that aren't on the path are
+ * left out. The last entry is the trust anchor. Trusted roots only keep the
+ * subject of their certificate, so for a root the entry is its DER-encoded
+ * subject Name rather than a certificate. For a DANE-TA record (see
+ * rustls_client_config_builder_set_tlsa_records) the anchor is a full
+ * certificate the server sent.
+ *
+ * The chain is only recorded when verifying against trusted roots or with
+ * DANE-TA, PKIX-TA or PKIX-EE records. A custom verifier, a pinned
+ * certificate, a DANE-EE record or disabled verification don't say how
+ * they accepted the certificate, and resumed handshakes don't verify it
+ * again, so for those this returns RUSTLS_RESULT_VERIFIED_CHAIN_UNAVAILABLE.
+ * It also returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE until the handshake
+ * has completed, RUSTLS_RESULT_INVALID_PARAMETER if `conn` is a server
+ * connection, and RUSTLS_RESULT_NOT_FOUND if `index` is not less than the
+ * count from rustls_client_connection_get_verified_chain_count.
+ *
+ * With trusted roots, the path is worked out the first time either of these
+ * functions is called for a connection, so handshakes don't pay for it
+ * unless it is used. The returned pointer is valid until the connection is
+ * freed or reset.
+ */
+rustls_result rustls_client_connection_get_verified_chain(const struct rustls_connection *conn,
+                                                          size_t index,
+                                                          const uint8_t **out_der,
+                                                          size_t *out_len);
+
 /**
  * Set the userdata pointer associated with this connection. This will be passed
  * to any callbacks invoked by the connection, if you've set up callbacks in the config.
- * The pointed-to data must outlive the connection.
+ * The pointed-to data must outlive the connection. Until this is set, client
+ * connections pass the config userdata instead, if
+ * rustls_client_config_builder_set_userdata was used.
  */
 void rustls_connection_set_userdata(struct rustls_connection *conn, void *userdata);
 
+/**
+ * Store in *out the userdata pointer set with rustls_connection_set_userdata,
+ * or NULL if none was set. Rust never dereferences it.
+ */
+rustls_result rustls_connection_get_userdata(const struct rustls_connection *conn, void **out);
+
 /**
  * Set the logging callback for this connection. The log callback will be invoked
  * with the userdata parameter previously set by rustls_connection_set_userdata, or
@@ -1163,6 +2581,50 @@ rustls_io_result rustls_connection_read_tls(struct rustls_connection *conn,
                                             void *userdata,
                                             size_t *out_n);
 
+/**
+ * Read some TLS bytes from the file descriptor `fd` into internal buffers,
+ * like rustls_connection_read_tls with a callback that calls `read(2)` once.
+ * `fd` is typically a socket, blocking or not. It is never closed.
+ *
+ * Returns 0 for success, with *out_n set to the number of bytes read; 0
+ * bytes means the peer has closed the transport (EOF). Otherwise returns
+ * the errno from `read(2)`: EAGAIN or EWOULDBLOCK for a non-blocking `fd`
+ * with nothing to read, in which case try again once it is readable.
+ * Returns ENOSYS on platforms without file descriptors, such as Windows.
+ */
+rustls_io_result rustls_connection_read_tls_from_fd(struct rustls_connection *conn,
+                                                    int fd,
+                                                    size_t *out_n);
+
+/**
+ * Move TLS bytes from `ring` into the connection's internal buffers, as
+ * rustls_connection_read_tls would from the network, and store how many
+ * were taken in *out_n. The bytes are taken straight from the ring's
+ * storage. Call rustls_connection_process_new_packets afterwards as
+ * usual.
+ *
+ * Returns EAGAIN if the ring is empty: an empty ring never means EOF,
+ * so report that with rustls_connection_read_tls. Otherwise returns 0,
+ * or EIO if rustls can't take any more bytes until it has processed the
+ * ones it has.
+ */
+rustls_io_result rustls_connection_read_tls_from_ring(struct rustls_connection *conn,
+                                                      struct rustls_ring_buffer *ring,
+                                                      size_t *out_n);
+
+/**
+ * Move pending TLS bytes from the connection into `ring`, as many as fit
+ * under its limit, and store how many were moved in *out_n. This
+ * replaces a loop over rustls_connection_write_tls with a callback that
+ * copies into a buffer of your own.
+ *
+ * Returns 0 once everything pending has been moved, or EAGAIN if the
+ * ring filled first: pull bytes out of it, then call this again.
+ */
+rustls_io_result rustls_connection_write_tls_to_ring(struct rustls_connection *conn,
+                                                     struct rustls_ring_buffer *ring,
+                                                     size_t *out_n);
+
 /**
  * Write some TLS bytes to the network. The actual network I/O is performed by
  * `callback`, which you provide. Rustls will invoke your callback with a
@@ -1180,11 +2642,78 @@ rustls_io_result rustls_connection_write_tls(struct rustls_connection *conn,
                                              void *userdata,
                                              size_t *out_n);
 
+/**
+ * Write all pending TLS bytes to the network through `callback`, calling
+ * it as many times as it takes. `callback` and `userdata` are as for
+ * rustls_connection_write_tls. *out_total is set to the number of bytes
+ * the callback took, however this returns.
+ *
+ * Returns 0 once nothing is left to write, as
+ * rustls_connection_wants_write would report. If the callback can't take
+ * any more, by returning EAGAIN or EWOULDBLOCK or by accepting 0 bytes,
+ * this stops and returns EAGAIN: the rest stays queued, so call again
+ * once the network is ready. Any other error from the callback is
+ * returned as is, and the bytes it didn't take stay queued too.
+ */
+rustls_io_result rustls_connection_write_tls_drain(struct rustls_connection *conn,
+                                                   rustls_write_callback callback,
+                                                   void *userdata,
+                                                   size_t *out_total);
+
+/**
+ * Write some pending TLS bytes to the file descriptor `fd`, like
+ * rustls_connection_write_tls with a callback that calls `write(2)` once.
+ * `fd` is typically a socket, blocking or not. It is never closed.
+ *
+ * Returns 0 for success, with *out_n set to the number of bytes written.
+ * That may be fewer than are pending; anything not written stays queued
+ * in the connection for the next call, so keep calling while
+ * rustls_connection_wants_write returns true. Otherwise returns the errno
+ * from the write: EAGAIN or EWOULDBLOCK for a non-blocking `fd` that is
+ * full, in which case try again once it is writable, or an error such as
+ * EPIPE or ECONNRESET if the peer has gone away. On Linux and Android,
+ * writing to a closed socket returns EPIPE rather than raising SIGPIPE.
+ * Returns ENOSYS on platforms without file descriptors, such as Windows.
+ */
+rustls_io_result rustls_connection_write_tls_to_fd(struct rustls_connection *conn,
+                                                   int fd,
+                                                   size_t *out_n);
+
+/**
+ * Do TLS I/O on the file descriptor `fd` until there is progress to report,
+ * blocking as `fd` does. This is rustls's `complete_io`, for simple programs
+ * that would rather not drive rustls_connection_wants_read and
+ * rustls_connection_wants_write themselves. `fd` is never closed.
+ *
+ * While handshaking, reads and writes until the handshake completes.
+ * Afterwards, writes out any pending TLS bytes if there are some, and
+ * otherwise reads and processes at least one batch of TLS bytes, after
+ * which rustls_connection_read may have plaintext for you. Packets are
+ * processed as by rustls_connection_process_new_packets, including any
+ * callbacks.
+ *
+ * *out_read and *out_written are set to the number of TLS bytes read
+ * from and written to `fd`, whatever the result; either may be NULL.
+ *
+ * Returns RUSTLS_RESULT_OK on success, or the TLS error if processing
+ * failed. If `fd` is non-blocking and not ready, returns
+ * RUSTLS_RESULT_WOULD_BLOCK; that is not fatal, so call again once it is.
+ * RUSTLS_RESULT_UNEXPECTED_EOF means the peer closed the transport
+ * mid-handshake, and RUSTLS_RESULT_IO is any other I/O error.
+ * <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.complete_io>
+ */
+rustls_result rustls_connection_complete_io_fd(struct rustls_connection *conn,
+                                               int fd,
+                                               size_t *out_read,
+                                               size_t *out_written);
+
 /**
  * Write all available TLS bytes to the network. The actual network I/O is performed by
  * `callback`, which you provide. Rustls will invoke your callback with an array
- * of rustls_slice_bytes, each containing a buffer with TLS bytes to send.
- * You don't have to write them all, just as many as you are willing.
+ * of rustls_iovec, each containing a buffer with TLS bytes to send, so that it can
+ * pass them straight to `writev` without copying them into one buffer first.
+ * You don't have to write them all, just as many as you are willing; a write
+ * may end part way through an iovec, and the next call resumes from there.
  * The `userdata` parameter is passed through directly to `callback`. Note that
  * this is distinct from the `userdata` parameter set with
  * `rustls_connection_set_userdata`.
@@ -1197,14 +2726,153 @@ rustls_io_result rustls_connection_write_tls_vectored(struct rustls_connection *
                                                       void *userdata,
                                                       size_t *out_n);
 
+/**
+ * Register `callback` to be called, with `userdata`, from inside the
+ * rustls_connection_process_new_packets call that completes the
+ * handshake. It is called at most once, and not at all if the handshake
+ * fails; errors are reported through the result code as usual. A later
+ * registration replaces an earlier one, and NULL removes it. If
+ * `userdata` is NULL, the callback gets the same userdata as the
+ * connection's other callbacks instead.
+ *
+ * Returns RUSTLS_RESULT_HANDSHAKE_ALREADY_COMPLETE, without calling
+ * `callback`, if the handshake has already completed, so check
+ * rustls_connection_is_handshaking instead in that case.
+ */
+rustls_result rustls_connection_set_handshake_done_callback(struct rustls_connection *conn,
+                                                            rustls_handshake_done_callback callback,
+                                                            void *userdata);
+
 /**
  * Decrypt any available ciphertext from the internal buffer and put it
  * into the internal plaintext buffer, potentially making bytes available
- * for rustls_connection_read().
+ * for rustls_connection_read(). If this completes the handshake, the
+ * callback set with rustls_connection_set_handshake_done_callback is
+ * called before returning.
+ *
+ * An error here is fatal: rustls may have queued an alert for the peer,
+ * and the connection is good for nothing else. From then on this and the
+ * other data-plane calls, such as rustls_connection_read,
+ * rustls_connection_write, rustls_connection_pump and
+ * rustls_connection_complete_io_fd, return the same error without doing
+ * anything. Only rustls_connection_write_tls and the other ways of
+ * sending TLS bytes keep working, so that the alert can still be flushed
+ * to the peer. (rustls_connection_read_tls still takes bytes, for
+ * callers that read before they look at results, but they are never
+ * processed.)
+ *
+ * rustls_connection_process_new_packets_ex does the same and also reports
+ * the connection's state, saving further calls to find out what to do next.
  * <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.process_new_packets>
  */
 rustls_result rustls_connection_process_new_packets(struct rustls_connection *conn);
 
+/**
+ * Process new packets like rustls_connection_process_new_packets, and on
+ * success fill in *out with what the caller can do next. This is the fast
+ * path for event loops: one call replaces separate ones to
+ * rustls_connection_is_handshaking, rustls_connection_wants_write and
+ * friends. *out reflects any handshake-done callback that ran, and is only
+ * written on success.
+ * <https://docs.rs/rustls/latest/rustls/struct.IoState.html>
+ */
+rustls_result rustls_connection_process_new_packets_ex(struct rustls_connection *conn,
+                                                       struct rustls_io_state *out);
+
+/**
+ * Copy a description of the most recent failure on this connection into
+ * `buf`, as UTF-8 followed by a NUL, and store its length, not counting
+ * the NUL, in *out_n. This carries the detail a rustls_result can't, such
+ * as which certificate problem failed the handshake or which errno a
+ * callback returned.
+ *
+ * Failures of rustls_connection_read_tls, rustls_connection_write_tls and
+ * their variants, rustls_connection_process_new_packets,
+ * rustls_connection_complete_io_fd, rustls_connection_read,
+ * rustls_connection_read_vectored and rustls_connection_peek_plaintext
+ * are recorded, including those caused by
+ * callbacks. A successful call to any of them clears the message, so an
+ * empty string means the last one succeeded. "Try again" outcomes, such
+ * as RUSTLS_RESULT_PLAINTEXT_EMPTY or EAGAIN, leave it alone.
+ *
+ * If `len` is too small for the message and its NUL, returns
+ * RUSTLS_RESULT_INSUFFICIENT_SIZE with *out_n set to 0.
+ */
+rustls_result rustls_connection_get_last_error(const struct rustls_connection *conn,
+                                               char *buf,
+                                               size_t len,
+                                               size_t *out_n);
+
+/**
+ * Copy a one-line, human-readable snapshot of the connection's state
+ * into `buf`, as UTF-8 followed by a NUL, and store its length, not
+ * counting the NUL, in *out_n. It covers whether the connection is
+ * handshaking, whether it wants to read or write, how much plaintext is
+ * waiting to be read and how many TLS bytes to be written, the
+ * negotiated protocol version and cipher suite, whether a client's
+ * session was resumed, whether close_notify has been sent and received,
+ * and any fatal error. This is meant for logs and bug reports when a
+ * connection seems stuck: the format may change between releases, so
+ * don't parse it. It never includes keys or other secrets.
+ *
+ * Counting the TLS bytes moves them out of rustls, as
+ * rustls_connection_tls_bytes_to_write does, which is why `conn` isn't
+ * const; nothing observable changes. If `len` is too small for the
+ * snapshot and its NUL, returns RUSTLS_RESULT_INSUFFICIENT_SIZE with
+ * *out_n set to 0. 256 bytes is plenty.
+ */
+rustls_result rustls_connection_debug_dump(struct rustls_connection *conn,
+                                           char *buf,
+                                           size_t len,
+                                           size_t *out_n);
+
+/**
+ * Store in *out_flags what an event loop should wait for on `conn`'s
+ * transport, as a combination of rustls_interest flags:
+ * RUSTLS_INTEREST_READ exactly when rustls_connection_wants_read
+ * returns true, RUSTLS_INTEREST_WRITE exactly when
+ * rustls_connection_wants_write does, and RUSTLS_INTEREST_CLOSED once
+ * nothing more will come from the peer. CLOSED is set when
+ * rustls_connection_process_new_packets sees the peer's close_notify or
+ * fails, or when reading TLS bytes hits EOF.
+ *
+ * A connection with CLOSED set and WRITE clear has no more I/O to do:
+ * read out any plaintext it holds, then stop watching its transport.
+ */
+rustls_result rustls_connection_poll_interest(const struct rustls_connection *conn,
+                                              uint32_t *out_flags);
+
+/**
+ * Copy the connection's running byte counts, described at
+ * rustls_counters, into *out. The counts only grow. A call that transfers
+ * nothing, such as one returning RUSTLS_RESULT_WOULD_BLOCK or EAGAIN,
+ * adds nothing, so retrying never counts a byte twice.
+ */
+rustls_result rustls_connection_get_counters(const struct rustls_connection *conn,
+                                             struct rustls_counters *out);
+
+/**
+ * Store in *out whether the peer's close_notify alert has been processed,
+ * meaning it will send nothing more. This says nothing about plaintext
+ * still waiting to be read: it becomes true as soon as
+ * rustls_connection_process_new_packets sees the alert, even if data
+ * sent before it has not been read yet. An EOF or error on the
+ * transport does not set it.
+ */
+rustls_result rustls_connection_peer_closed(const struct rustls_connection *conn, bool *out);
+
+/**
+ * Store in *out_micros how long the handshake took, in microseconds:
+ * the time from creating the connection to the packet processing that
+ * completed the handshake, full or resumed. The clock is monotonic, so
+ * changes to the system time don't affect it.
+ *
+ * Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE, leaving *out_micros
+ * untouched, if the handshake has not completed yet.
+ */
+rustls_result rustls_connection_handshake_duration_us(const struct rustls_connection *conn,
+                                                      uint64_t *out_micros);
+
 /**
  * <https://docs.rs/rustls/latest/rustls/struct.CommonState.html#method.wants_read>
  */
@@ -1215,6 +2883,46 @@ bool rustls_connection_wants_read(const struct rustls_connection *conn);
  */
 bool rustls_connection_wants_write(const struct rustls_connection *conn);
 
+/**
+ * Store in *out the exact number of TLS bytes waiting to be sent, as
+ * rustls_connection_write_tls would write them if the callback accepted
+ * everything. It is non-zero exactly when rustls_connection_wants_write
+ * returns true, and goes down by however many bytes each
+ * rustls_connection_write_tls call writes.
+ *
+ * To count the bytes, rustls-ffi moves them out of rustls into a buffer
+ * of its own; they still count against rustls_connection_set_buffer_limit.
+ */
+rustls_result rustls_connection_tls_bytes_to_write(struct rustls_connection *conn, size_t *out);
+
+/**
+ * Borrow the TLS bytes waiting to be sent, so they can be handed to the
+ * network without first copying them into a buffer of your own. Stores
+ * a pointer to them in *out_ptr and their length in *out_len; that is all
+ * the pending bytes, in one contiguous run. When nothing is pending,
+ * *out_len is 0 and *out_ptr must not be dereferenced.
+ *
+ * Send as many of the bytes as you can, then call
+ * rustls_connection_consume_tls_output to say how many went. Until then,
+ * they stay queued, and rustls_connection_write_tls would send them too.
+ *
+ * The pointer is only valid until the next call on this connection,
+ * other than rustls_connection_peek_tls_output itself. Any other call,
+ * including rustls_connection_consume_tls_output and
+ * rustls_connection_free, may move or free the bytes. Peek again
+ * afterwards rather than reusing it.
+ */
+rustls_result rustls_connection_peek_tls_output(struct rustls_connection *conn,
+                                                const uint8_t **out_ptr,
+                                                size_t *out_len);
+
+/**
+ * Mark the first `n` bytes returned by rustls_connection_peek_tls_output
+ * as sent, removing them from the queue. Returns InvalidParameter, and
+ * consumes nothing, if `n` is more than the last peek returned.
+ */
+rustls_result rustls_connection_consume_tls_output(struct rustls_connection *conn, size_t n);
+
 /**
  * <https://docs.rs/rustls/latest/rustls/struct.CommonState.html#method.is_handshaking>
  */
@@ -1222,19 +2930,57 @@ bool rustls_connection_is_handshaking(const struct rustls_connection *conn);
 
 /**
  * Sets a limit on the internal buffers used to buffer unsent plaintext (prior
- * to completing the TLS handshake) and unsent TLS records. By default, there
- * is no limit. The limit can be set at any time, even if the current buffer
+ * to completing the TLS handshake) and unsent TLS records. By default, the
+ * limit is 64 KiB. The limit can be set at any time, even if the current buffer
  * use is higher.
+ *
+ * Once the limit is reached, rustls_connection_write accepts fewer bytes
+ * than offered, and returns RUSTLS_RESULT_OK with *out_n set to 0 when the
+ * buffers are full. That means "call rustls_connection_write_tls to send
+ * what is buffered, then retry", not an error.
  * <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.set_buffer_limit>
  */
 void rustls_connection_set_buffer_limit(struct rustls_connection *conn, size_t n);
 
 /**
- * Queues a close_notify fatal alert to be sent in the next write_tls call.
+ * Queues a close_notify alert to be sent in the next write_tls call, telling
+ * the peer that no more data will be sent, so that it sees a clean EOF
+ * rather than a truncated connection. Plaintext already written is sent
+ * first.
+ *
+ * Only the first call queues the alert; later calls do nothing. It may also
+ * be called while handshaking, to abandon the handshake: the alert is
+ * queued after any pending handshake messages. In that case the peer may
+ * report an error rather than a clean EOF, since the alert can't always
+ * be protected the way it expects.
  * <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.send_close_notify>
  */
 void rustls_connection_send_close_notify(struct rustls_connection *conn);
 
+/**
+ * Shut down the sending side of the connection, copying the TLS bytes
+ * that have to go out into `tls_out` rather than writing them through a
+ * callback. It does what rustls_connection_send_close_notify followed by
+ * draining rustls_connection_write_tls would: the close_notify is queued on
+ * the first call (or not at all, if it was already sent) and then up to
+ * `out_len` pending bytes are copied into `tls_out`, anything queued
+ * before the alert first. *out_n is set to the number of bytes copied.
+ *
+ * *out_complete is set to true once every pending byte, the close_notify
+ * included, has been handed over; send those `*out_n` bytes and you are
+ * done. Until then, send what you got and call again. Calling it after
+ * completion is harmless: it copies nothing and reports complete again.
+ *
+ * This only closes the sending side. To wait for the peer's close_notify
+ * as well, keep reading until rustls_connection_read returns
+ * RUSTLS_RESULT_OK with 0 bytes.
+ */
+rustls_result rustls_connection_shutdown(struct rustls_connection *conn,
+                                         uint8_t *tls_out,
+                                         size_t out_len,
+                                         size_t *out_n,
+                                         bool *out_complete);
+
 /**
  * Return the i-th certificate provided by the peer.
  * Index 0 is the end entity certificate. Higher indexes are certificates
@@ -1249,6 +2995,41 @@ void rustls_connection_send_close_notify(struct rustls_connection *conn);
 const struct rustls_certificate *rustls_connection_get_peer_certificate(const struct rustls_connection *conn,
                                                                         size_t i);
 
+/**
+ * Store in *out the number of certificates the peer presented: the end
+ * entity certificate followed by any intermediates. This is 0 until the
+ * handshake has completed, and also if the peer sent no certificates,
+ * as clients usually don't.
+ * <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.peer_certificates>
+ */
+rustls_result rustls_connection_get_peer_certificate_count(const struct rustls_connection *conn,
+                                                           size_t *out);
+
+/**
+ * Store a pointer to the DER encoding of the peer's certificate at
+ * `index` in *out_der, and its length in *out_len. Index 0 is the end
+ * entity certificate. Returns RUSTLS_RESULT_NOT_FOUND if `index` is not
+ * less than the count from rustls_connection_get_peer_certificate_count.
+ *
+ * The certificates don't change once the handshake is complete, so the
+ * returned pointer is valid until the connection is freed.
+ */
+rustls_result rustls_connection_get_peer_certificate_der(const struct rustls_connection *conn,
+                                                         size_t index,
+                                                         const uint8_t **out_der,
+                                                         size_t *out_len);
+
+/**
+ * PEM-encode the certificate chain the peer presented, end entity first,
+ * and store it in *out as a newly allocated rustls_buffer, which the
+ * caller must free with rustls_buffer_free. Before the handshake has
+ * completed, or if the peer sent no certificates, the buffer is empty.
+ * The output is the same as `openssl x509` would write for each
+ * certificate, so it can be passed to tools that read PEM bundles.
+ */
+rustls_result rustls_connection_get_peer_chain_pem(const struct rustls_connection *conn,
+                                                   struct rustls_buffer **out);
+
 /**
  * Get the ALPN protocol that was negotiated, if any. Stores a pointer to a
  * borrowed buffer of bytes, and that buffer's len, in the output parameters.
@@ -1266,10 +3047,22 @@ void rustls_connection_get_alpn_protocol(const struct rustls_connection *conn,
                                          const uint8_t **protocol_out,
                                          size_t *protocol_out_len);
 
+/**
+ * Like rustls_connection_get_alpn_protocol, but returns a rustls_result,
+ * so that "no ALPN" can be told apart from an error. If the connection
+ * is still handshaking, or no ALPN protocol was negotiated, stores NULL
+ * and 0 in the output parameters and returns RUSTLS_RESULT_OK.
+ * Returns RUSTLS_RESULT_NULL_PARAMETER if any parameter is NULL.
+ */
+rustls_result rustls_connection_get_alpn_protocol_ex(const struct rustls_connection *conn,
+                                                     const uint8_t **protocol_out,
+                                                     size_t *protocol_out_len);
+
 /**
  * Return the TLS protocol version that has been negotiated. Before this
- * has been decided during the handshake, this will return 0. Otherwise,
- * the u16 version number as defined in the relevant RFC is returned.
+ * has been decided during the handshake, this will return 0, which is
+ * never a valid version number. Otherwise, the u16 version number as
+ * defined in the relevant RFC is returned (see `rustls_tls_version`).
  * <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.protocol_version>
  * <https://docs.rs/rustls/latest/rustls/internal/msgs/enums/enum.ProtocolVersion.html>
  */
@@ -1283,12 +3076,36 @@ uint16_t rustls_connection_get_protocol_version(const struct rustls_connection *
  */
 const struct rustls_supported_ciphersuite *rustls_connection_get_negotiated_ciphersuite(const struct rustls_connection *conn);
 
+/**
+ * Derive `out_len` bytes of keying material from the connection's secrets
+ * into `out`, as defined by RFC 5705 (TLS 1.2) and RFC 8446 section 7.5
+ * (TLS 1.3). Both peers get the same bytes for the same `label` and
+ * context.
+ *
+ * If `has_context` is false, no context is used and `context` may be NULL.
+ * With TLS 1.2 this gives different output than an empty context; with
+ * TLS 1.3 the two are equivalent.
+ *
+ * Returns RUSTLS_RESULT_HANDSHAKE_NOT_COMPLETE, leaving `out` untouched,
+ * if the handshake has not completed yet.
+ * <https://docs.rs/rustls/latest/rustls/enum.Connection.html#method.export_keying_material>
+ */
+rustls_result rustls_connection_export_keying_material(const struct rustls_connection *conn,
+                                                       uint8_t *out,
+                                                       size_t out_len,
+                                                       const uint8_t *label,
+                                                       size_t label_len,
+                                                       const uint8_t *context,
+                                                       size_t context_len,
+                                                       bool has_context);
+
 /**
  * Write up to `count` plaintext bytes from `buf` into the `rustls_connection`.
  * This will increase the number of output bytes available to
  * `rustls_connection_write_tls`.
  * On success, store the number of bytes actually written in *out_n
- * (this may be less than `count`).
+ * (this may be less than `count`, and is 0 if the limit set by
+ * rustls_connection_set_buffer_limit has been reached).
  * <https://docs.rs/rustls/latest/rustls/struct.Writer.html#method.write>
  */
 rustls_result rustls_connection_write(struct rustls_connection *conn,
@@ -1296,18 +3113,70 @@ rustls_result rustls_connection_write(struct rustls_connection *conn,
                                       size_t count,
                                       size_t *out_n);
 
+/**
+ * Flush plaintext written with rustls_connection_write into TLS records
+ * ready for rustls_connection_write_tls.
+ *
+ * Once the handshake is complete, rustls encrypts plaintext as soon as it
+ * is written, so there is nothing left for this to do and it always
+ * succeeds. Plaintext written before that is held until the handshake
+ * completes, whether or not this is called.
+ * <https://docs.rs/rustls/latest/rustls/struct.Writer.html#method.flush>
+ */
+rustls_result rustls_connection_flush(struct rustls_connection *conn);
+
+/**
+ * Write all `count` plaintext bytes from `buf` into the `rustls_connection`,
+ * storing the number of bytes accepted in *out_n. Like
+ * rustls_connection_write, this only buffers: no network I/O happens, and
+ * the caller still has to send the resulting TLS bytes with
+ * rustls_connection_write_tls.
+ *
+ * Fewer than `count` bytes are only accepted if the limit set with
+ * rustls_connection_set_buffer_limit is reached. In that case this returns
+ * RUSTLS_RESULT_BUFFER_LIMIT_REACHED, with *out_n set to the number of
+ * bytes that were accepted; send the pending TLS bytes and write the rest.
+ */
+rustls_result rustls_connection_write_all(struct rustls_connection *conn,
+                                          const uint8_t *buf,
+                                          size_t count,
+                                          size_t *out_n);
+
+/**
+ * Store in *out the number of plaintext bytes received and waiting to be
+ * read with rustls_connection_read. The count is exact: it is what the
+ * last successful rustls_connection_process_new_packets (or
+ * rustls_connection_complete_io_fd) left buffered, less whatever has
+ * been read since. Plaintext only arrives when packets are processed, so
+ * calls to rustls_connection_read_tls alone don't change it.
+ * <https://docs.rs/rustls/latest/rustls/struct.IoState.html#method.plaintext_bytes_to_read>
+ */
+rustls_result rustls_connection_plaintext_bytes_to_read(const struct rustls_connection *conn,
+                                                        size_t *out);
+
 /**
  * Read up to `count` plaintext bytes from the `rustls_connection` into `buf`.
  * On success, store the number of bytes read in *out_n (this may be less
- * than `count`). A success with *out_n set to 0 means "all bytes currently
- * available have been read, but more bytes may become available after
- * subsequent calls to rustls_connection_read_tls and
- * rustls_connection_process_new_packets."
+ * than `count`).
+ *
+ * Once the plaintext received so far has been read, the result tells the
+ * caller why there is no more:
+ *  - RUSTLS_RESULT_PLAINTEXT_EMPTY: nothing is available right now. More
+ *    may arrive after further calls to rustls_connection_read_tls and
+ *    rustls_connection_process_new_packets.
+ *  - RUSTLS_RESULT_OK with *out_n set to 0 (and `count` non-zero): the peer
+ *    sent close_notify. This is a clean end of stream; no more data will
+ *    arrive.
+ *  - RUSTLS_RESULT_UNEXPECTED_EOF: the transport reached EOF without a
+ *    close_notify, so the data may have been truncated.
  *
  * Subtle note: Even though this function only writes to `buf` and does not
  * read from it, the memory in `buf` must be initialized before the call (for
  * Rust-internal reasons). Initializing a buffer once and then using it
- * multiple times without zeroizing before each call is fine.
+ * multiple times without zeroizing before each call is fine. This function
+ * never clears `buf` itself: it copies in the *out_n bytes it reads and
+ * leaves the rest of `buf` as it was, so the cost of a call doesn't
+ * depend on `count`.
  * <https://docs.rs/rustls/latest/rustls/struct.Reader.html#method.read>
  */
 rustls_result rustls_connection_read(struct rustls_connection *conn,
@@ -1315,14 +3184,106 @@ rustls_result rustls_connection_read(struct rustls_connection *conn,
                                      size_t count,
                                      size_t *out_n);
 
+/**
+ * Read plaintext from the `rustls_connection` into the `count` buffers in
+ * `iov`, filling each in turn before moving on to the next, and store the
+ * total number of bytes read in *out_n. This stops when either the
+ * buffers or the available plaintext run out, so a single call can fill
+ * both halves of a ring buffer that wraps around.
+ *
+ * When no bytes could be read, the result means the same as for
+ * rustls_connection_read: RUSTLS_RESULT_PLAINTEXT_EMPTY if nothing is
+ * available yet, RUSTLS_RESULT_OK with *out_n set to 0 for a clean end of
+ * stream, and RUSTLS_RESULT_UNEXPECTED_EOF for a truncated one. As with
+ * rustls_connection_read, the buffers must be initialized memory.
+ */
+rustls_result rustls_connection_read_vectored(struct rustls_connection *conn,
+                                              struct rustls_iovec_mut *iov,
+                                              size_t count,
+                                              size_t *out_n);
+
+/**
+ * Borrow the plaintext received so far, so it can be parsed where it is
+ * rather than copied out with rustls_connection_read. Stores a pointer to
+ * it in *out_ptr and its length in *out_len; that is all the plaintext
+ * available, in one contiguous run, including any left over from an
+ * earlier peek. Call rustls_connection_consume_plaintext when done with
+ * some or all of it. Bytes not consumed are still there for the next
+ * peek or read.
+ *
+ * When no plaintext is available, *out_len is 0, *out_ptr must not be
+ * dereferenced, and the result means the same as for
+ * rustls_connection_read: RUSTLS_RESULT_PLAINTEXT_EMPTY if more may still
+ * arrive, RUSTLS_RESULT_OK for a clean end of stream, and
+ * RUSTLS_RESULT_UNEXPECTED_EOF for a truncated one.
+ *
+ * The pointer is only valid until the next call on this connection,
+ * other than rustls_connection_peek_plaintext itself. Any other call,
+ * including rustls_connection_consume_plaintext,
+ * rustls_connection_read_tls and rustls_connection_free, may move or free
+ * the bytes. Peek again afterwards rather than reusing it.
+ */
+rustls_result rustls_connection_peek_plaintext(struct rustls_connection *conn,
+                                               const uint8_t **out_ptr,
+                                               size_t *out_len);
+
+/**
+ * Mark the first `n` bytes returned by rustls_connection_peek_plaintext
+ * as read, removing them. Returns InvalidParameter, and consumes nothing,
+ * if `n` is more than the last peek returned.
+ */
+rustls_result rustls_connection_consume_plaintext(struct rustls_connection *conn, size_t n);
+
+/**
+ * Feed TLS bytes from `tls_in`, process them, and read the resulting
+ * plaintext into `plain_out`, all in one call: the equivalent of
+ * alternating rustls_connection_read_tls,
+ * rustls_connection_process_new_packets and rustls_connection_read until
+ * `tls_in` runs out. Callbacks run as they would for those functions.
+ *
+ * *out_tls_consumed is set to the number of bytes of `tls_in` that were
+ * fed to rustls, and *out_plain_written to the number of plaintext bytes
+ * written to `plain_out`, whatever the result. Bytes beyond
+ * *out_tls_consumed were not looked at; pass them again next time.
+ *
+ * Feeding stops early, with RUSTLS_RESULT_OK, once `plain_out` is full
+ * and decrypted plaintext is still waiting. Read it (or pump again with
+ * a fresh `plain_out`) before offering more input.
+ *
+ * If processing fails, returns that error. Every byte fed before the
+ * failure counts as consumed, and plaintext from records before the bad
+ * one is still written to `plain_out`; the connection is unusable after
+ * that, as with rustls_connection_process_new_packets. If rustls can't
+ * take the input, returns RUSTLS_RESULT_IO.
+ *
+ * Running out of plaintext isn't an error here: a clean close, a
+ * truncated stream and "nothing yet" all just end the reading, and a
+ * later rustls_connection_read tells them apart.
+ */
+rustls_result rustls_connection_pump(struct rustls_connection *conn,
+                                     const uint8_t *tls_in,
+                                     size_t tls_in_len,
+                                     size_t *out_tls_consumed,
+                                     uint8_t *plain_out,
+                                     size_t plain_out_len,
+                                     size_t *out_plain_written);
+
 #if defined(DEFINE_READ_BUF)
 /**
  * Read up to `count` plaintext bytes from the `rustls_connection` into `buf`.
  * On success, store the number of bytes read in *out_n (this may be less
- * than `count`). A success with *out_n set to 0 means "all bytes currently
- * available have been read, but more bytes may become available after
- * subsequent calls to rustls_connection_read_tls and
- * rustls_connection_process_new_packets."
+ * than `count`).
+ *
+ * Once the plaintext received so far has been read, the result tells the
+ * caller why there is no more:
+ *  - RUSTLS_RESULT_PLAINTEXT_EMPTY: nothing is available right now. More
+ *    may arrive after further calls to rustls_connection_read_tls and
+ *    rustls_connection_process_new_packets.
+ *  - RUSTLS_RESULT_OK with *out_n set to 0 (and `count` non-zero): the peer
+ *    sent close_notify. This is a clean end of stream; no more data will
+ *    arrive.
+ *  - RUSTLS_RESULT_UNEXPECTED_EOF: the transport reached EOF without a
+ *    close_notify, so the data may have been truncated.
  *
  * This experimental API is only available when using a nightly Rust compiler
  * and enabling the `read_buf` Cargo feature. It will be deprecated and later
@@ -1353,6 +3314,28 @@ void rustls_error(unsigned int result, char *buf, size_t len, size_t *out_n);
 
 bool rustls_result_is_cert_error(unsigned int result);
 
+/**
+ * Register this library's logger, which passes log messages to the
+ * callbacks set with rustls_connection_set_log_callback, and set the most
+ * verbose level that is logged. `level_filter` is a NUL-terminated string:
+ * one of "off", "error", "warn", "info", "debug" or "trace", in any case.
+ *
+ * There is no process-wide callback: a message is only delivered while a
+ * connection with a log callback is being processed, and messages logged
+ * outside any connection, e.g. while building a config, are dropped.
+ *
+ * The logger is otherwise registered, at level "debug", when the first log
+ * callback is set. This may be called any number of times, from any thread;
+ * later calls just change the level. Returns InvalidParameter if
+ * `level_filter` isn't a level, and LoggerAlreadySet if something else in
+ * the process has already registered a Rust logger. This never panics.
+ *
+ * If rustls-ffi was built with the no_log_capture feature, the logger is
+ * never registered, the level is left alone for the application's own
+ * logger, and this returns Unsupported.
+ */
+rustls_result rustls_init_logging(const char *level_filter);
+
 /**
  * Return a rustls_str containing the stringified version of a log level.
  */
@@ -1408,6 +3391,8 @@ struct rustls_server_config_builder *rustls_server_config_builder_new(void);
  *
  * `versions` will only be used during the call and the application retains
  * ownership. `len` is the number of consecutive `uint16_t` pointed to by `versions`.
+ * An empty list returns RUSTLS_RESULT_INVALID_PARAMETER, and a version
+ * rustls does not implement returns RUSTLS_RESULT_UNSUPPORTED_PROTOCOL_VERSION.
  */
 rustls_result rustls_server_config_builder_new_custom(const struct rustls_supported_ciphersuite *const *cipher_suites,
                                                       size_t cipher_suites_len,
@@ -1476,7 +3461,8 @@ rustls_result rustls_server_config_builder_set_alpn_protocols(struct rustls_serv
  * The built configuration will keep a reference to all certified keys
  * provided. The client may `rustls_certified_key_free()` afterwards
  * without the configuration losing them. The same certified key may also
- * be used in multiple configs.
+ * be used in multiple configs. Returns RUSTLS_RESULT_INVALID_PARAMETER,
+ * leaving the builder unchanged, if `certified_keys_len` is 0.
  *
  * EXPERIMENTAL: installing a client_hello callback will replace any
  * configured certified keys and vice versa.
@@ -1487,10 +3473,51 @@ rustls_result rustls_server_config_builder_set_certified_keys(struct rustls_serv
 
 /**
  * Turn a *rustls_server_config_builder (mutable) into a const *rustls_server_config
- * (read-only).
+ * (read-only). Returns NULL if no certificate was configured, with
+ * rustls_server_config_builder_set_certified_keys or
+ * rustls_server_config_builder_set_hello_callback; use
+ * rustls_server_config_builder_build_ex to tell that apart from other
+ * failures.
  */
 const struct rustls_server_config *rustls_server_config_builder_build(struct rustls_server_config_builder *builder);
 
+/**
+ * Build a rustls_server_config as rustls_server_config_builder_build
+ * does, reporting why that failed. Returns NoCertificateConfigured if
+ * the builder has no certified keys or hello callback to pick a
+ * certificate with. On success the memory pointed to by `config_out` is
+ * set to the new config, which the caller frees with
+ * rustls_server_config_free; otherwise it is left unchanged.
+ *
+ * Like rustls_server_config_builder_build, this consumes the builder
+ * whether or not building succeeds, unless `config_out` is NULL.
+ */
+rustls_result rustls_server_config_builder_build_ex(struct rustls_server_config_builder *builder,
+                                                    const struct rustls_server_config **config_out);
+
+/**
+ * Build a server config that presents one certificate chain, with the
+ * defaults from rustls_server_config_builder_new and no client
+ * authentication. `cert_chain` holds `cert_chain_len` bytes of
+ * PEM-encoded certificates, end entity first, and `private_key` the
+ * end-entity certificate's PEM-encoded private key. For anything else,
+ * use a rustls_server_config_builder.
+ *
+ * Returns `NoCertificatesFound` if `cert_chain` has no certificates,
+ * `CertificateParseError` if it can't be parsed, the errors of
+ * rustls_certified_key_build for an unusable `private_key`, and
+ * `CertKeyMismatch` if the key isn't the end-entity certificate's.
+ *
+ * On success, this stores the new config in *config_out; free it with
+ * rustls_server_config_free. The inputs are copied and may be freed
+ * once this returns.
+ */
+rustls_result rustls_server_config_new(const uint8_t *cert_chain,
+                                       size_t cert_chain_len,
+                                       const uint8_t *private_key,
+                                       size_t private_key_len,
+                                       const struct rustls_server_config **config_out);
+
 /**
  * "Free" a rustls_server_config previously returned from
  * rustls_server_config_builder_build. Since rustls_server_config is actually an
@@ -11,15 +11,14 @@ use rustls::server::{
     StoresServerSessions,
 };
 use rustls::sign::CertifiedKey;
-use rustls::{
-    ProtocolVersion, SignatureScheme, SupportedCipherSuite, WantsVerifier, ALL_CIPHER_SUITES,
-};
+use rustls::{SignatureScheme, SupportedCipherSuite, WantsVerifier, ALL_CIPHER_SUITES};
 
 use crate::cipher::{
-    rustls_certified_key, rustls_client_cert_verifier, rustls_client_cert_verifier_optional,
-    rustls_supported_ciphersuite,
+    certified_key_matches, rustls_certified_key, rustls_client_cert_verifier,
+    rustls_client_cert_verifier_optional, rustls_supported_ciphersuite,
 };
 use crate::connection::{rustls_connection, Connection};
+use crate::enums::protocol_versions_from_u16s;
 use crate::error::rustls_result::{InvalidParameter, NullParameter};
 use crate::error::{map_error, rustls_result};
 use crate::rslice::{rustls_slice_bytes, rustls_slice_slice_bytes, rustls_slice_u16, rustls_str};
@@ -110,6 +109,8 @@ impl rustls_server_config_builder {
     ///
     /// `versions` will only be used during the call and the application retains
     /// ownership. `len` is the number of consecutive `uint16_t` pointed to by `versions`.
+    /// An empty list returns RUSTLS_RESULT_INVALID_PARAMETER, and a version
+    /// rustls does not implement returns RUSTLS_RESULT_UNSUPPORTED_PROTOCOL_VERSION.
     #[no_mangle]
     pub extern "C" fn rustls_server_config_builder_new_custom(
         cipher_suites: *const *const rustls_supported_ciphersuite,
@@ -130,15 +131,10 @@ impl rustls_server_config_builder {
             }
 
             let tls_versions: &[u16] = try_slice!(tls_versions, tls_versions_len);
-            let mut versions = vec![];
-            for version_number in tls_versions {
-                let proto = ProtocolVersion::from(*version_number);
-                if proto == rustls::version::TLS12.version {
-                    versions.push(&rustls::version::TLS12);
-                } else if proto == rustls::version::TLS13.version {
-                    versions.push(&rustls::version::TLS13);
-                }
-            }
+            let versions = match protocol_versions_from_u16s(tls_versions) {
+                Ok(versions) => versions,
+                Err(e) => return e,
+            };
 
             let result = rustls::ServerConfig::builder().with_cipher_suites(&cs_vec).with_safe_default_kx_groups().with_protocol_versions(&versions);
             let base = match result {
@@ -258,7 +254,8 @@ impl rustls_server_config_builder {
     /// The built configuration will keep a reference to all certified keys
     /// provided. The client may `rustls_certified_key_free()` afterwards
     /// without the configuration losing them. The same certified key may also
-    /// be used in multiple configs.
+    /// be used in multiple configs. Returns RUSTLS_RESULT_INVALID_PARAMETER,
+    /// leaving the builder unchanged, if `certified_keys_len` is 0.
     ///
     /// EXPERIMENTAL: installing a client_hello callback will replace any
     /// configured certified keys and vice versa.
@@ -271,6 +268,9 @@ impl rustls_server_config_builder {
         ffi_panic_boundary! {
         let builder: &mut ServerConfigBuilder = try_mut_from_ptr!(builder);
         let keys_ptrs: &[*const rustls_certified_key] = try_slice!(certified_keys, certified_keys_len);
+        if keys_ptrs.is_empty() {
+            return rustls_result::InvalidParameter;
+        }
         let mut keys: Vec<Arc<CertifiedKey>> = Vec::new();
         for &key_ptr in keys_ptrs {
             let certified_key: Arc<CertifiedKey> = try_arc_from_ptr!(key_ptr);
@@ -282,32 +282,131 @@ impl rustls_server_config_builder {
     }
 
     /// Turn a *rustls_server_config_builder (mutable) into a const *rustls_server_config
-    /// (read-only).
+    /// (read-only). Returns NULL if no certificate was configured, with
+    /// rustls_server_config_builder_set_certified_keys or
+    /// rustls_server_config_builder_set_hello_callback; use
+    /// rustls_server_config_builder_build_ex to tell that apart from other
+    /// failures.
     #[no_mangle]
     pub extern "C" fn rustls_server_config_builder_build(
         builder: *mut rustls_server_config_builder,
     ) -> *const rustls_server_config {
         ffi_panic_boundary! {
-            let builder = try_box_from_ptr!(builder);
-            let base = builder.base.with_client_cert_verifier(builder.verifier);
-            let mut config = if let Some(r) = builder.cert_resolver {
-                base.with_cert_resolver(r)
-            } else {
-                return null();
-            };
-            if let Some(ss) = builder.session_storage {
-                config.session_storage = ss;
+            let builder: Box<ServerConfigBuilder> = try_box_from_ptr!(builder);
+            match builder.build() {
+                Ok(config) => ArcCastPtr::to_const_ptr(config),
+                Err(_) => null(),
             }
-            config.alpn_protocols = builder.alpn_protocols;
-            if let Some(ignore_client_order) = builder.ignore_client_order {
-                config.ignore_client_order = ignore_client_order;
+        }
+    }
+
+    /// Build a rustls_server_config as rustls_server_config_builder_build
+    /// does, reporting why that failed. Returns NoCertificateConfigured if
+    /// the builder has no certified keys or hello callback to pick a
+    /// certificate with. On success the memory pointed to by `config_out` is
+    /// set to the new config, which the caller frees with
+    /// rustls_server_config_free; otherwise it is left unchanged.
+    ///
+    /// Like rustls_server_config_builder_build, this consumes the builder
+    /// whether or not building succeeds, unless `config_out` is NULL.
+    #[no_mangle]
+    pub extern "C" fn rustls_server_config_builder_build_ex(
+        builder: *mut rustls_server_config_builder,
+        config_out: *mut *const rustls_server_config,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            if config_out.is_null() {
+                return NullParameter;
+            }
+            let builder: Box<ServerConfigBuilder> = try_box_from_ptr!(builder);
+            match builder.build() {
+                Ok(config) => {
+                    unsafe {
+                        *config_out = ArcCastPtr::to_const_ptr(config);
+                    }
+                    rustls_result::Ok
+                }
+                Err(rr) => rr,
             }
-            ArcCastPtr::to_const_ptr(config)
         }
     }
 }
 
+impl ServerConfigBuilder {
+    fn build(self) -> Result<ServerConfig, rustls_result> {
+        let cert_resolver = self
+            .cert_resolver
+            .ok_or(rustls_result::NoCertificateConfigured)?;
+        let mut config = self
+            .base
+            .with_client_cert_verifier(self.verifier)
+            .with_cert_resolver(cert_resolver);
+        if let Some(ss) = self.session_storage {
+            config.session_storage = ss;
+        }
+        config.alpn_protocols = self.alpn_protocols;
+        if let Some(ignore_client_order) = self.ignore_client_order {
+            config.ignore_client_order = ignore_client_order;
+        }
+        Ok(config)
+    }
+}
+
 impl rustls_server_config {
+    /// Build a server config that presents one certificate chain, with the
+    /// defaults from rustls_server_config_builder_new and no client
+    /// authentication. `cert_chain` holds `cert_chain_len` bytes of
+    /// PEM-encoded certificates, end entity first, and `private_key` the
+    /// end-entity certificate's PEM-encoded private key. For anything else,
+    /// use a rustls_server_config_builder.
+    ///
+    /// Returns `NoCertificatesFound` if `cert_chain` has no certificates,
+    /// `CertificateParseError` if it can't be parsed, the errors of
+    /// rustls_certified_key_build for an unusable `private_key`, and
+    /// `CertKeyMismatch` if the key isn't the end-entity certificate's.
+    ///
+    /// On success, this stores the new config in *config_out; free it with
+    /// rustls_server_config_free. The inputs are copied and may be freed
+    /// once this returns.
+    #[no_mangle]
+    pub extern "C" fn rustls_server_config_new(
+        cert_chain: *const u8,
+        cert_chain_len: size_t,
+        private_key: *const u8,
+        private_key_len: size_t,
+        config_out: *mut *const rustls_server_config,
+    ) -> rustls_result {
+        ffi_panic_boundary! {
+            if config_out.is_null() {
+                return NullParameter;
+            }
+            let certified_key = match rustls_certified_key::certified_key_build(
+                cert_chain,
+                cert_chain_len,
+                private_key,
+                private_key_len,
+            ) {
+                Ok(key) => key,
+                Err(rr) => return rr,
+            };
+            if certified_key.cert.is_empty() {
+                return rustls_result::NoCertificatesFound;
+            }
+            if let Err(rr) = certified_key_matches(&certified_key) {
+                return rr;
+            }
+            let resolver = ResolvesServerCertFromChoices::new(&[Arc::new(certified_key)]);
+            let config = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_client_cert_verifier(NoClientAuth::boxed())
+                .with_cert_resolver(Arc::new(resolver));
+            unsafe {
+                *config_out = ArcCastPtr::to_const_ptr(config);
+            }
+            rustls_result::Ok
+        }
+    }
+
     /// "Free" a rustls_server_config previously returned from
     /// rustls_server_config_builder_build. Since rustls_server_config is actually an
     /// atomically reference-counted pointer, extant server connections may still
@@ -538,8 +637,8 @@ impl ResolvesServerCert for ClientHelloResolver {
             Err(_) => return None,
         };
         let key_ptr: *const rustls_certified_key = unsafe { cb(userdata, &hello) };
-        let certified_key: &CertifiedKey = try_ref_from_ptr!(key_ptr);
-        Some(Arc::new(certified_key.clone()))
+        let certified_key: Arc<CertifiedKey> = try_arc_from_ptr!(key_ptr);
+        Some(certified_key)
     }
 }
 
@@ -719,6 +818,87 @@ mod tests {
         );
     }
 
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_server_config_builder_build_ex() {
+        let mut config: *const rustls_server_config = null();
+        let builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        assert_eq!(
+            rustls_server_config_builder::rustls_server_config_builder_build_ex(
+                builder,
+                &mut config
+            ),
+            rustls_result::NoCertificateConfigured
+        );
+        assert_eq!(config, null());
+
+        // An empty list of certified keys is rejected, and doesn't count as
+        // a certificate.
+        let builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        let keys: [*const rustls_certified_key; 0] = [];
+        assert_eq!(
+            rustls_server_config_builder::rustls_server_config_builder_set_certified_keys(
+                builder,
+                keys.as_ptr(),
+                0
+            ),
+            rustls_result::InvalidParameter
+        );
+        assert_eq!(
+            rustls_server_config_builder::rustls_server_config_builder_build_ex(
+                builder,
+                &mut config
+            ),
+            rustls_result::NoCertificateConfigured
+        );
+
+        let cert_pem = include_str!("../testdata/localhost/cert.pem").as_bytes();
+        let key_pem = include_str!("../testdata/localhost/key.pem").as_bytes();
+        let mut certified_key: *const rustls_certified_key = null();
+        assert_eq!(
+            rustls_certified_key::rustls_certified_key_build(
+                cert_pem.as_ptr(),
+                cert_pem.len(),
+                key_pem.as_ptr(),
+                key_pem.len(),
+                &mut certified_key,
+            ),
+            rustls_result::Ok
+        );
+        let builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        assert_eq!(
+            rustls_server_config_builder::rustls_server_config_builder_build_ex(
+                builder,
+                null_mut()
+            ),
+            rustls_result::NullParameter
+        );
+        rustls_server_config_builder::rustls_server_config_builder_set_certified_keys(
+            builder,
+            &certified_key,
+            1,
+        );
+        // Rejecting an empty list keeps the keys set before.
+        assert_eq!(
+            rustls_server_config_builder::rustls_server_config_builder_set_certified_keys(
+                builder,
+                keys.as_ptr(),
+                0
+            ),
+            rustls_result::InvalidParameter
+        );
+        assert_eq!(
+            rustls_server_config_builder::rustls_server_config_builder_build_ex(
+                builder,
+                &mut config
+            ),
+            rustls_result::Ok
+        );
+        assert_ne!(config, null());
+        rustls_certified_key::rustls_certified_key_free(certified_key);
+        rustls_server_config::rustls_server_config_free(config);
+    }
+
     #[test]
     #[cfg_attr(miri, ignore)]
     fn test_server_connection_new() {
@@ -790,4 +970,227 @@ mod tests {
         );
         rustls_connection::rustls_connection_free(conn);
     }
+
+    // The SNI names seen by sni_hello_callback, and the keys it picks from.
+    struct SniLog {
+        example_com: *const rustls_certified_key,
+        default: *const rustls_certified_key,
+        server_names: Vec<String>,
+    }
+
+    // Serve the example.com certificate to clients asking for it by SNI, and
+    // the default certificate to everyone else.
+    unsafe extern "C" fn sni_hello_callback(
+        userdata: rustls_client_hello_userdata,
+        hello: *const rustls_client_hello,
+    ) -> *const rustls_certified_key {
+        let log = &mut *(userdata as *mut SniLog);
+        let name = &(*hello).server_name;
+        let name = slice::from_raw_parts(name.data as *const u8, name.len);
+        let name = String::from_utf8(name.to_vec()).unwrap();
+        let key = if name == "example.com" {
+            log.example_com
+        } else {
+            log.default
+        };
+        log.server_names.push(name);
+        key
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_client_sni_disabled() {
+        use crate::client::{rustls_client_config, rustls_client_config_builder};
+        use crate::connection::tests::*;
+
+        let builder = rustls_server_config_builder::rustls_server_config_builder_new();
+        let result = rustls_server_config_builder::rustls_server_config_builder_set_hello_callback(
+            builder,
+            Some(sni_hello_callback),
+        );
+        assert_eq!(result, rustls_result::Ok);
+        let server_config =
+            rustls_server_config_builder::rustls_server_config_builder_build(builder);
+        let mut log = SniLog {
+            example_com: make_certified_key(
+                include_str!("../testdata/example.com/cert.pem"),
+                include_str!("../testdata/example.com/key.pem"),
+            ),
+            default: make_certified_key(
+                include_str!("../testdata/localhost/cert.pem"),
+                include_str!("../testdata/localhost/key.pem"),
+            ),
+            server_names: vec![],
+        };
+
+        // Returns the handshake result and the SNI name the server saw.
+        let mut handshake = |enable_sni: bool, server_name: &str| {
+            let builder = rustls_client_config_builder::rustls_client_config_builder_new();
+            rustls_client_config_builder::rustls_client_config_builder_set_enable_sni(
+                builder, enable_sni,
+            );
+            let client_config = build_verifying_client_config(builder);
+            let client = make_client_connection(client_config, server_name);
+            let server = make_server_connection(server_config);
+            rustls_connection::rustls_connection_set_userdata(
+                server,
+                &mut log as *mut _ as *mut c_void,
+            );
+            let result = do_handshake(client, server);
+            let mut sni = [0u8; 64];
+            let mut n = 1;
+            let sni_result = rustls_server_connection_get_server_name(
+                server,
+                sni.as_mut_ptr(),
+                sni.len(),
+                &mut n,
+            );
+            assert_eq!(sni_result, rustls_result::Ok);
+            rustls_connection::rustls_connection_free(client);
+            rustls_connection::rustls_connection_free(server);
+            rustls_client_config::rustls_client_config_free(client_config);
+            (result, String::from_utf8(sni[..n].to_vec()).unwrap())
+        };
+
+        assert_eq!(
+            handshake(true, "example.com"),
+            (rustls_result::Ok, "example.com".to_string())
+        );
+        // Without SNI the server falls back to its default certificate, which
+        // is still checked against the name the client was given.
+        assert_eq!(
+            handshake(false, "example.com"),
+            (rustls_result::CertNotValidForName, "".to_string())
+        );
+        assert_eq!(
+            handshake(false, "localhost"),
+            (rustls_result::Ok, "".to_string())
+        );
+        assert_eq!(log.server_names, vec!["example.com", "", ""]);
+
+        rustls_certified_key::rustls_certified_key_free(log.example_com);
+        rustls_certified_key::rustls_certified_key_free(log.default);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    // Each connection holds its own reference to the config, so freeing
+    // connections (or the caller's config) leaves the rest usable.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_server_connection_new_many() {
+        use crate::client::{rustls_client_config, rustls_client_config_builder};
+        use crate::connection::tests::*;
+
+        let cert = include_str!("../testdata/localhost/cert.pem");
+        let key = include_str!("../testdata/localhost/key.pem");
+        let server_config = server_config_new(cert, key).unwrap();
+        let conns: Vec<*mut rustls_connection> = (0..100)
+            .map(|_| make_server_connection(server_config))
+            .collect();
+        for conn in conns {
+            rustls_connection::rustls_connection_free(conn);
+        }
+        // Freeing NULL is a no-op, as for client connections.
+        rustls_connection::rustls_connection_free(null_mut());
+
+        let server = make_server_connection(server_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+        let client_config = build_verifying_client_config(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        let client = make_client_connection(client_config, "localhost");
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+    }
+
+    fn server_config_new(
+        chain: &str,
+        key: &str,
+    ) -> Result<*const rustls_server_config, rustls_result> {
+        let mut config: *const rustls_server_config = null();
+        match rustls_server_config::rustls_server_config_new(
+            chain.as_ptr(),
+            chain.len(),
+            key.as_ptr(),
+            key.len(),
+            &mut config,
+        ) {
+            rustls_result::Ok => Ok(config),
+            result => {
+                assert_eq!(config, null());
+                Err(result)
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_server_config_new() {
+        use crate::client::{rustls_client_config, rustls_client_config_builder};
+        use crate::connection::tests::*;
+
+        let cert = include_str!("../testdata/localhost/cert.pem");
+        let key = include_str!("../testdata/localhost/key.pem");
+        let server_config = server_config_new(cert, key).unwrap();
+        let client_config = build_verifying_client_config(
+            rustls_client_config_builder::rustls_client_config_builder_new(),
+        );
+        let client = make_client_connection(client_config, "localhost");
+        let server = make_server_connection(server_config);
+        assert_eq!(do_handshake(client, server), rustls_result::Ok);
+        rustls_connection::rustls_connection_free(client);
+        rustls_connection::rustls_connection_free(server);
+        rustls_client_config::rustls_client_config_free(client_config);
+        rustls_server_config::rustls_server_config_free(server_config);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_server_config_new_errors() {
+        let cert = include_str!("../testdata/localhost/cert.pem");
+        let key = include_str!("../testdata/localhost/key.pem");
+        let other_key = include_str!("../testdata/example.com/key.pem");
+        assert_eq!(
+            server_config_new("not a certificate", key),
+            Err(rustls_result::NoCertificatesFound)
+        );
+        assert_eq!(
+            server_config_new(
+                "-----BEGIN CERTIFICATE-----\n!!!\n-----END CERTIFICATE-----\n",
+                key
+            ),
+            Err(rustls_result::CertificateParseError)
+        );
+        assert_eq!(
+            server_config_new(cert, "not a key"),
+            Err(rustls_result::NoPrivateKeyFound)
+        );
+        assert_eq!(
+            server_config_new(cert, other_key),
+            Err(rustls_result::CertKeyMismatch)
+        );
+        let mut config: *const rustls_server_config = null();
+        assert_eq!(
+            rustls_server_config::rustls_server_config_new(
+                null(),
+                0,
+                key.as_ptr(),
+                key.len(),
+                &mut config
+            ),
+            rustls_result::NullParameter
+        );
+        assert_eq!(
+            rustls_server_config::rustls_server_config_new(
+                cert.as_ptr(),
+                cert.len(),
+                key.as_ptr(),
+                key.len(),
+                null_mut()
+            ),
+            rustls_result::NullParameter
+        );
+    }
 }